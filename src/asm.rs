@@ -39,12 +39,39 @@ pub fn get_stack_pointer() -> u64 {
     sp
 }
 
+pub fn get_frame_pointer() -> u64 {
+    let fp: u64;
+    unsafe { asm!("mov {}, x29", out(reg) fp) };
+    fp
+}
+
 pub fn get_id_aa64mmfr0_el1() -> u64 {
     let id_aa64mmfr0_el1: u64;
     unsafe { asm!("mrs {}, id_aa64mmfr0_el1", out(reg) id_aa64mmfr0_el1) };
     id_aa64mmfr0_el1
 }
 
+pub fn get_id_aa64isar0_el1() -> u64 {
+    let id_aa64isar0_el1: u64;
+    unsafe { asm!("mrs {}, id_aa64isar0_el1", out(reg) id_aa64isar0_el1) };
+    id_aa64isar0_el1
+}
+
+/// RNDR から乱数を読み出す(乱数が用意できなかった場合は None)
+pub fn get_rndr() -> Option<u64> {
+    let rndr: u64;
+    let is_failed: u64;
+    unsafe {
+        asm!("
+            mrs  {r}, s3_3_c2_c4_0
+            cset {f}, eq",
+            r = out(reg) rndr,
+            f = out(reg) is_failed
+        )
+    };
+    if is_failed == 0 { Some(rndr) } else { None }
+}
+
 pub fn get_vtcr_el2() -> u64 {
     let vtcr_el2: u64;
     unsafe { asm!("mrs {}, vtcr_el2", out(reg) vtcr_el2) };
@@ -155,8 +182,18 @@ pub fn get_icc_iar1_el1() -> u64 {
     icc_iar1_el1
 }
 
-pub unsafe fn invalidate_cache(address: usize) {
-    unsafe { asm!("dc ivac, {}", in(reg) address) };
+pub unsafe fn clean_and_invalidate_cache(address: usize) {
+    unsafe { asm!("dc civac, {}", in(reg) address) };
+}
+
+pub fn data_synchronization_barrier() {
+    unsafe { asm!("dsb sy") };
+}
+
+pub fn get_ctr_el0() -> u64 {
+    let ctr_el0: u64;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr_el0) };
+    ctr_el0
 }
 
 pub fn get_midr_el1() -> u64 {
@@ -289,6 +326,10 @@ pub unsafe fn set_daif(daif: u64) {
     };
 }
 
+pub fn wait_for_interrupt() {
+    unsafe { asm!("wfi") };
+}
+
 pub fn get_tpidr_el2() -> u64 {
     let tpidr_el2: u64;
     unsafe { asm!("mrs {}, tpidr_el2", out(reg) tpidr_el2) };
@@ -298,3 +339,483 @@ pub fn get_tpidr_el2() -> u64 {
 pub unsafe fn set_tpidr_el2(tpidr_el2: u64) {
     unsafe { asm!("msr tpidr_el2, {}", in(reg) tpidr_el2) };
 }
+
+pub fn get_sp_el0() -> u64 {
+    let sp_el0: u64;
+    unsafe { asm!("mrs {}, sp_el0", out(reg) sp_el0) };
+    sp_el0
+}
+
+pub unsafe fn set_sp_el0(sp_el0: u64) {
+    unsafe { asm!("msr sp_el0, {}", in(reg) sp_el0) };
+}
+
+pub fn get_sp_el1() -> u64 {
+    let sp_el1: u64;
+    unsafe { asm!("mrs {}, sp_el1", out(reg) sp_el1) };
+    sp_el1
+}
+
+pub unsafe fn set_sp_el1(sp_el1: u64) {
+    unsafe { asm!("msr sp_el1, {}", in(reg) sp_el1) };
+}
+
+pub fn get_sctlr_el1() -> u64 {
+    let sctlr_el1: u64;
+    unsafe { asm!("mrs {}, sctlr_el1", out(reg) sctlr_el1) };
+    sctlr_el1
+}
+
+pub unsafe fn set_sctlr_el1(sctlr_el1: u64) {
+    unsafe { asm!("msr sctlr_el1, {}", in(reg) sctlr_el1) };
+}
+
+pub fn get_ttbr0_el1() -> u64 {
+    let ttbr0_el1: u64;
+    unsafe { asm!("mrs {}, ttbr0_el1", out(reg) ttbr0_el1) };
+    ttbr0_el1
+}
+
+pub unsafe fn set_ttbr0_el1(ttbr0_el1: u64) {
+    unsafe { asm!("msr ttbr0_el1, {}", in(reg) ttbr0_el1) };
+}
+
+pub fn get_ttbr1_el1() -> u64 {
+    let ttbr1_el1: u64;
+    unsafe { asm!("mrs {}, ttbr1_el1", out(reg) ttbr1_el1) };
+    ttbr1_el1
+}
+
+pub unsafe fn set_ttbr1_el1(ttbr1_el1: u64) {
+    unsafe { asm!("msr ttbr1_el1, {}", in(reg) ttbr1_el1) };
+}
+
+pub fn get_tcr_el1() -> u64 {
+    let tcr_el1: u64;
+    unsafe { asm!("mrs {}, tcr_el1", out(reg) tcr_el1) };
+    tcr_el1
+}
+
+pub unsafe fn set_tcr_el1(tcr_el1: u64) {
+    unsafe { asm!("msr tcr_el1, {}", in(reg) tcr_el1) };
+}
+
+pub fn get_mair_el1() -> u64 {
+    let mair_el1: u64;
+    unsafe { asm!("mrs {}, mair_el1", out(reg) mair_el1) };
+    mair_el1
+}
+
+pub unsafe fn set_mair_el1(mair_el1: u64) {
+    unsafe { asm!("msr mair_el1, {}", in(reg) mair_el1) };
+}
+
+pub fn get_amair_el1() -> u64 {
+    let amair_el1: u64;
+    unsafe { asm!("mrs {}, amair_el1", out(reg) amair_el1) };
+    amair_el1
+}
+
+pub unsafe fn set_amair_el1(amair_el1: u64) {
+    unsafe { asm!("msr amair_el1, {}", in(reg) amair_el1) };
+}
+
+pub fn get_vbar_el1() -> u64 {
+    let vbar_el1: u64;
+    unsafe { asm!("mrs {}, vbar_el1", out(reg) vbar_el1) };
+    vbar_el1
+}
+
+pub unsafe fn set_vbar_el1(vbar_el1: u64) {
+    unsafe { asm!("msr vbar_el1, {}", in(reg) vbar_el1) };
+}
+
+pub fn get_contextidr_el1() -> u64 {
+    let contextidr_el1: u64;
+    unsafe { asm!("mrs {}, contextidr_el1", out(reg) contextidr_el1) };
+    contextidr_el1
+}
+
+pub unsafe fn set_contextidr_el1(contextidr_el1: u64) {
+    unsafe { asm!("msr contextidr_el1, {}", in(reg) contextidr_el1) };
+}
+
+pub fn get_tpidr_el0() -> u64 {
+    let tpidr_el0: u64;
+    unsafe { asm!("mrs {}, tpidr_el0", out(reg) tpidr_el0) };
+    tpidr_el0
+}
+
+pub unsafe fn set_tpidr_el0(tpidr_el0: u64) {
+    unsafe { asm!("msr tpidr_el0, {}", in(reg) tpidr_el0) };
+}
+
+pub fn get_tpidrro_el0() -> u64 {
+    let tpidrro_el0: u64;
+    unsafe { asm!("mrs {}, tpidrro_el0", out(reg) tpidrro_el0) };
+    tpidrro_el0
+}
+
+pub unsafe fn set_tpidrro_el0(tpidrro_el0: u64) {
+    unsafe { asm!("msr tpidrro_el0, {}", in(reg) tpidrro_el0) };
+}
+
+pub fn get_tpidr_el1() -> u64 {
+    let tpidr_el1: u64;
+    unsafe { asm!("mrs {}, tpidr_el1", out(reg) tpidr_el1) };
+    tpidr_el1
+}
+
+pub unsafe fn set_tpidr_el1(tpidr_el1: u64) {
+    unsafe { asm!("msr tpidr_el1, {}", in(reg) tpidr_el1) };
+}
+
+pub fn get_esr_el1() -> u64 {
+    let esr_el1: u64;
+    unsafe { asm!("mrs {}, esr_el1", out(reg) esr_el1) };
+    esr_el1
+}
+
+pub unsafe fn set_esr_el1(esr_el1: u64) {
+    unsafe { asm!("msr esr_el1, {}", in(reg) esr_el1) };
+}
+
+pub fn get_far_el1() -> u64 {
+    let far_el1: u64;
+    unsafe { asm!("mrs {}, far_el1", out(reg) far_el1) };
+    far_el1
+}
+
+pub unsafe fn set_far_el1(far_el1: u64) {
+    unsafe { asm!("msr far_el1, {}", in(reg) far_el1) };
+}
+
+pub fn get_afsr0_el1() -> u64 {
+    let afsr0_el1: u64;
+    unsafe { asm!("mrs {}, afsr0_el1", out(reg) afsr0_el1) };
+    afsr0_el1
+}
+
+pub unsafe fn set_afsr0_el1(afsr0_el1: u64) {
+    unsafe { asm!("msr afsr0_el1, {}", in(reg) afsr0_el1) };
+}
+
+pub fn get_afsr1_el1() -> u64 {
+    let afsr1_el1: u64;
+    unsafe { asm!("mrs {}, afsr1_el1", out(reg) afsr1_el1) };
+    afsr1_el1
+}
+
+pub unsafe fn set_afsr1_el1(afsr1_el1: u64) {
+    unsafe { asm!("msr afsr1_el1, {}", in(reg) afsr1_el1) };
+}
+
+pub fn get_par_el1() -> u64 {
+    let par_el1: u64;
+    unsafe { asm!("mrs {}, par_el1", out(reg) par_el1) };
+    par_el1
+}
+
+pub unsafe fn set_par_el1(par_el1: u64) {
+    unsafe { asm!("msr par_el1, {}", in(reg) par_el1) };
+}
+
+pub fn get_cpacr_el1() -> u64 {
+    let cpacr_el1: u64;
+    unsafe { asm!("mrs {}, cpacr_el1", out(reg) cpacr_el1) };
+    cpacr_el1
+}
+
+pub unsafe fn set_cpacr_el1(cpacr_el1: u64) {
+    unsafe { asm!("msr cpacr_el1, {}", in(reg) cpacr_el1) };
+}
+
+pub fn get_elr_el1() -> u64 {
+    let elr_el1: u64;
+    unsafe { asm!("mrs {}, elr_el1", out(reg) elr_el1) };
+    elr_el1
+}
+
+pub unsafe fn set_elr_el1(elr_el1: u64) {
+    unsafe { asm!("msr elr_el1, {}", in(reg) elr_el1) };
+}
+
+pub fn get_spsr_el1() -> u64 {
+    let spsr_el1: u64;
+    unsafe { asm!("mrs {}, spsr_el1", out(reg) spsr_el1) };
+    spsr_el1
+}
+
+pub unsafe fn set_spsr_el1(spsr_el1: u64) {
+    unsafe { asm!("msr spsr_el1, {}", in(reg) spsr_el1) };
+}
+
+pub fn get_cntkctl_el1() -> u64 {
+    let cntkctl_el1: u64;
+    unsafe { asm!("mrs {}, cntkctl_el1", out(reg) cntkctl_el1) };
+    cntkctl_el1
+}
+
+pub unsafe fn set_cntkctl_el1(cntkctl_el1: u64) {
+    unsafe { asm!("msr cntkctl_el1, {}", in(reg) cntkctl_el1) };
+}
+
+pub fn get_csselr_el1() -> u64 {
+    let csselr_el1: u64;
+    unsafe { asm!("mrs {}, csselr_el1", out(reg) csselr_el1) };
+    csselr_el1
+}
+
+pub unsafe fn set_csselr_el1(csselr_el1: u64) {
+    unsafe { asm!("msr csselr_el1, {}", in(reg) csselr_el1) };
+}
+
+pub fn get_mdscr_el1() -> u64 {
+    let mdscr_el1: u64;
+    unsafe { asm!("mrs {}, mdscr_el1", out(reg) mdscr_el1) };
+    mdscr_el1
+}
+
+pub unsafe fn set_mdscr_el1(mdscr_el1: u64) {
+    unsafe { asm!("msr mdscr_el1, {}", in(reg) mdscr_el1) };
+}
+
+pub fn get_cntv_ctl_el0() -> u64 {
+    let cntv_ctl_el0: u64;
+    unsafe { asm!("mrs {}, cntv_ctl_el0", out(reg) cntv_ctl_el0) };
+    cntv_ctl_el0
+}
+
+pub unsafe fn set_cntv_ctl_el0(cntv_ctl_el0: u64) {
+    unsafe { asm!("msr cntv_ctl_el0, {}", in(reg) cntv_ctl_el0) };
+}
+
+pub fn get_cntv_cval_el0() -> u64 {
+    let cntv_cval_el0: u64;
+    unsafe { asm!("mrs {}, cntv_cval_el0", out(reg) cntv_cval_el0) };
+    cntv_cval_el0
+}
+
+pub unsafe fn set_cntv_cval_el0(cntv_cval_el0: u64) {
+    unsafe { asm!("msr cntv_cval_el0, {}", in(reg) cntv_cval_el0) };
+}
+
+pub fn get_ich_vmcr_el2() -> u64 {
+    let ich_vmcr_el2: u64;
+    unsafe { asm!("mrs {}, ich_vmcr_el2", out(reg) ich_vmcr_el2) };
+    ich_vmcr_el2
+}
+
+pub unsafe fn set_ich_vmcr_el2(ich_vmcr_el2: u64) {
+    unsafe { asm!("msr ich_vmcr_el2, {}", in(reg) ich_vmcr_el2) };
+}
+
+pub fn get_ich_ap1r0_el2() -> u64 {
+    let ich_ap1r0_el2: u64;
+    unsafe { asm!("mrs {}, ich_ap1r0_el2", out(reg) ich_ap1r0_el2) };
+    ich_ap1r0_el2
+}
+
+pub unsafe fn set_ich_ap1r0_el2(ich_ap1r0_el2: u64) {
+    unsafe { asm!("msr ich_ap1r0_el2, {}", in(reg) ich_ap1r0_el2) };
+}
+
+/* FPCR/FPSR は浮動小数点命令を無効化したターゲットでは名前で指定できないため、エンコードで指定する */
+pub fn get_fpcr() -> u64 {
+    let fpcr: u64;
+    unsafe { asm!("mrs {}, s3_3_c4_c4_0", out(reg) fpcr) };
+    fpcr
+}
+
+pub unsafe fn set_fpcr(fpcr: u64) {
+    unsafe { asm!("msr s3_3_c4_c4_0, {}", in(reg) fpcr) };
+}
+
+pub fn get_fpsr() -> u64 {
+    let fpsr: u64;
+    unsafe { asm!("mrs {}, s3_3_c4_c4_1", out(reg) fpsr) };
+    fpsr
+}
+
+pub unsafe fn set_fpsr(fpsr: u64) {
+    unsafe { asm!("msr s3_3_c4_c4_1, {}", in(reg) fpsr) };
+}
+
+pub fn get_spsr_el2() -> u64 {
+    let spsr_el2: u64;
+    unsafe { asm!("mrs {}, spsr_el2", out(reg) spsr_el2) };
+    spsr_el2
+}
+
+pub fn get_cntpct_el0() -> u64 {
+    let cntpct_el0: u64;
+    unsafe { asm!("mrs {}, cntpct_el0", out(reg) cntpct_el0) };
+    cntpct_el0
+}
+
+pub fn get_cntfrq_el0() -> u64 {
+    let cntfrq_el0: u64;
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) cntfrq_el0) };
+    cntfrq_el0
+}
+
+pub fn get_cntvoff_el2() -> u64 {
+    let cntvoff_el2: u64;
+    unsafe { asm!("mrs {}, cntvoff_el2", out(reg) cntvoff_el2) };
+    cntvoff_el2
+}
+
+pub fn get_vmpidr_el2() -> u64 {
+    let vmpidr_el2: u64;
+    unsafe { asm!("mrs {}, vmpidr_el2", out(reg) vmpidr_el2) };
+    vmpidr_el2
+}
+
+pub fn save_fp_registers(buffer: &mut [u128; 32]) {
+    unsafe {
+        asm!("
+            .arch_extension fp
+            .arch_extension simd
+            stp  q0,  q1, [{0}, #( 0 * 32)]
+            stp  q2,  q3, [{0}, #( 1 * 32)]
+            stp  q4,  q5, [{0}, #( 2 * 32)]
+            stp  q6,  q7, [{0}, #( 3 * 32)]
+            stp  q8,  q9, [{0}, #( 4 * 32)]
+            stp q10, q11, [{0}, #( 5 * 32)]
+            stp q12, q13, [{0}, #( 6 * 32)]
+            stp q14, q15, [{0}, #( 7 * 32)]
+            stp q16, q17, [{0}, #( 8 * 32)]
+            stp q18, q19, [{0}, #( 9 * 32)]
+            stp q20, q21, [{0}, #(10 * 32)]
+            stp q22, q23, [{0}, #(11 * 32)]
+            stp q24, q25, [{0}, #(12 * 32)]
+            stp q26, q27, [{0}, #(13 * 32)]
+            stp q28, q29, [{0}, #(14 * 32)]
+            stp q30, q31, [{0}, #(15 * 32)]",
+        in(reg) buffer.as_mut_ptr()
+        )
+    };
+}
+
+pub unsafe fn restore_fp_registers(buffer: &[u128; 32]) {
+    unsafe {
+        asm!("
+            .arch_extension fp
+            .arch_extension simd
+            ldp  q0,  q1, [{0}, #( 0 * 32)]
+            ldp  q2,  q3, [{0}, #( 1 * 32)]
+            ldp  q4,  q5, [{0}, #( 2 * 32)]
+            ldp  q6,  q7, [{0}, #( 3 * 32)]
+            ldp  q8,  q9, [{0}, #( 4 * 32)]
+            ldp q10, q11, [{0}, #( 5 * 32)]
+            ldp q12, q13, [{0}, #( 6 * 32)]
+            ldp q14, q15, [{0}, #( 7 * 32)]
+            ldp q16, q17, [{0}, #( 8 * 32)]
+            ldp q18, q19, [{0}, #( 9 * 32)]
+            ldp q20, q21, [{0}, #(10 * 32)]
+            ldp q22, q23, [{0}, #(11 * 32)]
+            ldp q24, q25, [{0}, #(12 * 32)]
+            ldp q26, q27, [{0}, #(13 * 32)]
+            ldp q28, q29, [{0}, #(14 * 32)]
+            ldp q30, q31, [{0}, #(15 * 32)]",
+        in(reg) buffer.as_ptr()
+        )
+    };
+}
+
+pub fn get_mdcr_el2() -> u64 {
+    let mdcr_el2: u64;
+    unsafe { asm!("mrs {}, mdcr_el2", out(reg) mdcr_el2) };
+    mdcr_el2
+}
+
+pub unsafe fn set_mdcr_el2(mdcr_el2: u64) {
+    unsafe { asm!("msr mdcr_el2, {}", in(reg) mdcr_el2) };
+}
+
+pub fn get_id_aa64dfr0_el1() -> u64 {
+    let id_aa64dfr0_el1: u64;
+    unsafe { asm!("mrs {}, id_aa64dfr0_el1", out(reg) id_aa64dfr0_el1) };
+    id_aa64dfr0_el1
+}
+
+pub unsafe fn set_oslar_el1(oslar_el1: u64) {
+    unsafe { asm!("msr oslar_el1, {}", in(reg) oslar_el1) };
+}
+
+pub unsafe fn set_dbgbvr0_el1(dbgbvr0_el1: u64) {
+    unsafe { asm!("msr dbgbvr0_el1, {}", in(reg) dbgbvr0_el1) };
+}
+
+pub unsafe fn set_dbgbvr1_el1(dbgbvr1_el1: u64) {
+    unsafe { asm!("msr dbgbvr1_el1, {}", in(reg) dbgbvr1_el1) };
+}
+
+pub unsafe fn set_dbgbvr2_el1(dbgbvr2_el1: u64) {
+    unsafe { asm!("msr dbgbvr2_el1, {}", in(reg) dbgbvr2_el1) };
+}
+
+pub unsafe fn set_dbgbvr3_el1(dbgbvr3_el1: u64) {
+    unsafe { asm!("msr dbgbvr3_el1, {}", in(reg) dbgbvr3_el1) };
+}
+
+pub unsafe fn set_dbgbcr0_el1(dbgbcr0_el1: u64) {
+    unsafe { asm!("msr dbgbcr0_el1, {}", in(reg) dbgbcr0_el1) };
+}
+
+pub unsafe fn set_dbgbcr1_el1(dbgbcr1_el1: u64) {
+    unsafe { asm!("msr dbgbcr1_el1, {}", in(reg) dbgbcr1_el1) };
+}
+
+pub unsafe fn set_dbgbcr2_el1(dbgbcr2_el1: u64) {
+    unsafe { asm!("msr dbgbcr2_el1, {}", in(reg) dbgbcr2_el1) };
+}
+
+pub unsafe fn set_dbgbcr3_el1(dbgbcr3_el1: u64) {
+    unsafe { asm!("msr dbgbcr3_el1, {}", in(reg) dbgbcr3_el1) };
+}
+
+pub unsafe fn set_dbgwvr0_el1(dbgwvr0_el1: u64) {
+    unsafe { asm!("msr dbgwvr0_el1, {}", in(reg) dbgwvr0_el1) };
+}
+
+pub unsafe fn set_dbgwvr1_el1(dbgwvr1_el1: u64) {
+    unsafe { asm!("msr dbgwvr1_el1, {}", in(reg) dbgwvr1_el1) };
+}
+
+pub unsafe fn set_dbgwvr2_el1(dbgwvr2_el1: u64) {
+    unsafe { asm!("msr dbgwvr2_el1, {}", in(reg) dbgwvr2_el1) };
+}
+
+pub unsafe fn set_dbgwvr3_el1(dbgwvr3_el1: u64) {
+    unsafe { asm!("msr dbgwvr3_el1, {}", in(reg) dbgwvr3_el1) };
+}
+
+pub unsafe fn set_dbgwcr0_el1(dbgwcr0_el1: u64) {
+    unsafe { asm!("msr dbgwcr0_el1, {}", in(reg) dbgwcr0_el1) };
+}
+
+pub unsafe fn set_dbgwcr1_el1(dbgwcr1_el1: u64) {
+    unsafe { asm!("msr dbgwcr1_el1, {}", in(reg) dbgwcr1_el1) };
+}
+
+pub unsafe fn set_dbgwcr2_el1(dbgwcr2_el1: u64) {
+    unsafe { asm!("msr dbgwcr2_el1, {}", in(reg) dbgwcr2_el1) };
+}
+
+pub unsafe fn set_dbgwcr3_el1(dbgwcr3_el1: u64) {
+    unsafe { asm!("msr dbgwcr3_el1, {}", in(reg) dbgwcr3_el1) };
+}
+
+/// 命令キャッシュを全て無効化する(Inner Shareable)
+pub fn invalidate_instruction_cache() {
+    unsafe {
+        asm!(
+            "
+            dsb ish
+            ic  ialluis
+            dsb ish
+            isb"
+        )
+    };
+}
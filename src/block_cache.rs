@@ -0,0 +1,463 @@
+//!
+//! Block Cache
+//!
+//! Fat32 とホストのディスクの間で、ディスクの内容をブロック単位で保持する。
+//! キャッシュにあるブロックへの書き込みは遅延させ(Write-Back)、flush または追い出し時に書き戻す。
+//! キャッシュに無いブロック全体への書き込みはディスクへ直接行う。
+//!
+
+use crate::block_device::BlockDevice;
+use crate::lock::{Mutex, MutexGuard};
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+
+/// キャッシュの管理単位(バイト)
+pub const BLOCK_SIZE: usize = 4096;
+/// キャッシュに使うメモリの量の初期値(バイト)
+const DEFAULT_BUDGET: usize = 16 << 20;
+/// キャッシュに無いブロックを一度に読み込む最大数
+const MAX_BLOCKS_PER_READ: u64 = 64;
+
+struct CacheBlock {
+    data: Box<[u8]>,
+    is_dirty: bool,
+    /// 最後に使用した時刻(lru のキー)
+    last_used: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct CacheStatistics {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub write_backs: u64,
+}
+
+pub struct BlockCache {
+    /// ブロック番号ごとのキャッシュ
+    blocks: BTreeMap<u64, CacheBlock>,
+    /// 最後に使用した時刻からブロック番号への対応(先頭が最も古い)
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    /// キャッシュに使うメモリの上限(バイト)
+    budget: usize,
+    /// キャッシュを経由せずにディスクへ書き込んだ回数(fill で古い内容を登録しないために使う)
+    generation: u64,
+    /// 完了していないキャッシュを経由しない書き込みの数
+    active_writes: usize,
+    statistics: CacheStatistics,
+}
+
+impl BlockCache {
+    pub const fn new() -> Self {
+        Self {
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            budget: DEFAULT_BUDGET,
+            generation: 0,
+            active_writes: 0,
+            statistics: CacheStatistics {
+                hits: 0,
+                misses: 0,
+                evictions: 0,
+                write_backs: 0,
+            },
+        }
+    }
+
+    pub fn get_statistics(&self) -> CacheStatistics {
+        self.statistics
+    }
+
+    pub fn get_budget(&self) -> usize {
+        self.budget
+    }
+
+    /// (使用中のバイト数, 書き戻していないブロックの数)
+    pub fn get_usage(&self) -> (usize, usize) {
+        (
+            self.blocks.len() * BLOCK_SIZE,
+            self.blocks.values().filter(|b| b.is_dirty).count(),
+        )
+    }
+
+    /// キャッシュに使うメモリの上限を変更し、超えた分を追い出す
+    pub fn set_budget(&mut self, blk: &mut impl BlockDevice, budget: usize) -> Result<(), ()> {
+        self.budget = budget;
+        self.shrink(blk)
+    }
+
+    pub fn get_generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// block を最近使用したものとして記録する
+    fn touch(&mut self, block: u64) {
+        let Some(b) = self.blocks.get_mut(&block) else {
+            return;
+        };
+        self.lru.remove(&b.last_used);
+        self.clock += 1;
+        b.last_used = self.clock;
+        self.lru.insert(self.clock, block);
+    }
+
+    fn insert(&mut self, block: u64, data: Box<[u8]>, is_dirty: bool) {
+        self.clock += 1;
+        self.lru.insert(self.clock, block);
+        if let Some(old) = self.blocks.insert(
+            block,
+            CacheBlock {
+                data,
+                is_dirty,
+                last_used: self.clock,
+            },
+        ) {
+            self.lru.remove(&old.last_used);
+        }
+    }
+
+    /// ディスクの末尾を超えないよう、block のうちディスク上にあるバイト数を返す
+    fn get_block_length(blk: &impl BlockDevice, block: u64) -> usize {
+        let capacity = blk.get_size();
+        (capacity.saturating_sub(block * BLOCK_SIZE as u64) as usize).min(BLOCK_SIZE)
+    }
+
+    fn write_back_block(blk: &mut impl BlockDevice, block: u64, data: &[u8]) -> Result<(), ()> {
+        let length = Self::get_block_length(blk, block);
+        if length == 0 {
+            return Ok(());
+        }
+        blk.write(
+            data.as_ptr() as usize,
+            block * BLOCK_SIZE as u64,
+            length as u64,
+        )
+    }
+
+    /// 上限を超えている間、最も古いブロックから追い出す
+    fn shrink(&mut self, blk: &mut impl BlockDevice) -> Result<(), ()> {
+        while self.blocks.len() * BLOCK_SIZE > self.budget {
+            let Some((_, block)) = self.lru.pop_first() else {
+                break;
+            };
+            let b = self.blocks.remove(&block).unwrap();
+            if b.is_dirty {
+                if Self::write_back_block(blk, block, &b.data).is_err() {
+                    println!("Failed to write back the block: {:#X}", block);
+                    self.insert(block, b.data, true);
+                    return Err(());
+                }
+                self.statistics.write_backs += 1;
+            }
+            self.statistics.evictions += 1;
+        }
+        Ok(())
+    }
+
+    /// first_block から count 個のブロックをディスクから読み込む(ディスクの末尾より後ろは 0 とする)
+    fn read_blocks(
+        blk: &mut impl BlockDevice,
+        first_block: u64,
+        count: u64,
+    ) -> Result<Box<[u8]>, ()> {
+        let mut data = vec![0u8; BLOCK_SIZE * count as usize].into_boxed_slice();
+        let length = (0..count)
+            .map(|i| Self::get_block_length(blk, first_block + i))
+            .sum::<usize>();
+        if length != 0 {
+            blk.read(
+                data.as_mut_ptr() as usize,
+                first_block * BLOCK_SIZE as u64,
+                length as u64,
+            )?;
+        }
+        Ok(data)
+    }
+
+    /// ディスクの address から length バイトを buffer へ読み込む
+    pub fn read(
+        &mut self,
+        blk: &mut impl BlockDevice,
+        buffer: usize,
+        address: u64,
+        length: usize,
+    ) -> Result<(), ()> {
+        let end = address + length as u64;
+        let mut position = address;
+        while position < end {
+            let block = position / BLOCK_SIZE as u64;
+            let block_offset = (position % BLOCK_SIZE as u64) as usize;
+            let destination = buffer + (position - address) as usize;
+
+            if let Some(b) = self.blocks.get(&block) {
+                let size = (BLOCK_SIZE - block_offset).min((end - position) as usize);
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        b.data[block_offset..].as_ptr(),
+                        destination as *mut u8,
+                        size,
+                    )
+                };
+                self.statistics.hits += 1;
+                self.touch(block);
+                position += size as u64;
+                continue;
+            }
+
+            /* キャッシュに無いブロックが続く分をまとめて読み込む */
+            let mut count = 1;
+            while count < MAX_BLOCKS_PER_READ
+                && (block + count) * (BLOCK_SIZE as u64) < end
+                && !self.blocks.contains_key(&(block + count))
+            {
+                count += 1;
+            }
+            let data = Self::read_blocks(blk, block, count)?;
+            let size = ((block + count) * BLOCK_SIZE as u64).min(end) - position;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[block_offset..].as_ptr(),
+                    destination as *mut u8,
+                    size as usize,
+                )
+            };
+            for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+                self.insert(block + i as u64, Box::from(chunk), false);
+            }
+            self.statistics.misses += count;
+            position += size;
+        }
+        self.shrink(blk)
+    }
+
+    /// buffer から length バイトをディスクの address へ書き込む
+    ///
+    /// キャッシュにあるブロックと、ブロックの一部への書き込みはキャッシュに保持し、後で書き戻す
+    pub fn write(
+        &mut self,
+        blk: &mut impl BlockDevice,
+        buffer: usize,
+        address: u64,
+        length: usize,
+    ) -> Result<(), ()> {
+        let end = address + length as u64;
+        let mut position = address;
+        while position < end {
+            let block = position / BLOCK_SIZE as u64;
+            let block_offset = (position % BLOCK_SIZE as u64) as usize;
+            let size = (BLOCK_SIZE - block_offset).min((end - position) as usize);
+            let source = buffer + (position - address) as usize;
+
+            if !self.blocks.contains_key(&block) {
+                if size == BLOCK_SIZE {
+                    /* キャッシュに無いブロック全体への書き込みが続く分は直接書き込む */
+                    let mut count = 1;
+                    while (block + count + 1) * (BLOCK_SIZE as u64) <= end
+                        && !self.blocks.contains_key(&(block + count))
+                    {
+                        count += 1;
+                    }
+                    blk.write(source, position, count * BLOCK_SIZE as u64)?;
+                    self.generation += 1;
+                    position += count * BLOCK_SIZE as u64;
+                    continue;
+                }
+                /* ブロックの一部のみ書き込む場合は、先にディスクから読み込む */
+                let data = Self::read_blocks(blk, block, 1)?;
+                self.insert(block, data, false);
+                self.statistics.misses += 1;
+            } else {
+                self.statistics.hits += 1;
+                self.touch(block);
+            }
+
+            let b = self.blocks.get_mut(&block).unwrap();
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    source as *const u8,
+                    b.data[block_offset..].as_mut_ptr(),
+                    size,
+                )
+            };
+            b.is_dirty = true;
+            position += size as u64;
+        }
+        self.shrink(blk)
+    }
+
+    /// 書き戻していないブロックを全てディスクへ書き込む
+    pub fn write_back(&mut self, blk: &mut impl BlockDevice) -> Result<(), ()> {
+        for (block, b) in self.blocks.iter_mut().filter(|(_, b)| b.is_dirty) {
+            Self::write_back_block(blk, *block, &b.data)?;
+            b.is_dirty = false;
+            self.statistics.write_backs += 1;
+        }
+        Ok(())
+    }
+
+    /// 書き戻していないブロックを全てディスクへ書き込み、ディスクのキャッシュも書き戻す
+    pub fn flush(&mut self, blk: &mut impl BlockDevice) -> Result<(), ()> {
+        self.write_back(blk)?;
+        blk.flush()
+    }
+
+    /// キャッシュにある部分を buffer へ写し、無い部分を (address, buffer, length) として on_miss へ渡す
+    ///
+    /// ゲストのディスクへの読み込みに使う。連続して無い部分はまとめて渡す
+    pub fn read_cached(
+        &mut self,
+        address: u64,
+        buffer: usize,
+        length: usize,
+        mut on_miss: impl FnMut(u64, usize, usize),
+    ) {
+        let end = address + length as u64;
+        let mut position = address;
+        let mut miss: Option<(u64, usize, usize)> = None;
+        while position < end {
+            let block = position / BLOCK_SIZE as u64;
+            let block_offset = (position % BLOCK_SIZE as u64) as usize;
+            let size = (BLOCK_SIZE - block_offset).min((end - position) as usize);
+            let destination = buffer + (position - address) as usize;
+
+            if let Some(b) = self.blocks.get(&block) {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        b.data[block_offset..].as_ptr(),
+                        destination as *mut u8,
+                        size,
+                    )
+                };
+                self.statistics.hits += 1;
+                self.touch(block);
+                if let Some((a, b, l)) = miss.take() {
+                    on_miss(a, b, l);
+                }
+            } else {
+                self.statistics.misses += 1;
+                match &mut miss {
+                    Some((_, _, l)) => *l += size,
+                    None => miss = Some((position, destination, size)),
+                }
+            }
+            position += size as u64;
+        }
+        if let Some((a, b, l)) = miss {
+            on_miss(a, b, l);
+        }
+    }
+
+    /// キャッシュを経由しない書き込みを始める(完了したら end_update を呼ぶ)
+    pub fn begin_update(&mut self) {
+        self.active_writes += 1;
+    }
+
+    /// begin_update で始めた書き込みが完了(または失敗)した
+    pub fn end_update(&mut self) {
+        self.active_writes -= 1;
+    }
+
+    /// ディスクへ直接書き込む内容のうち、キャッシュにあるブロックの部分を更新する
+    ///
+    /// ゲストのディスクへの書き込みに使う。begin_update を先に呼ぶ
+    pub fn update(&mut self, address: u64, buffer: usize, length: usize) {
+        let end = address + length as u64;
+        let mut position = address;
+        while position < end {
+            let block = position / BLOCK_SIZE as u64;
+            let block_offset = (position % BLOCK_SIZE as u64) as usize;
+            let size = (BLOCK_SIZE - block_offset).min((end - position) as usize);
+            if let Some(b) = self.blocks.get_mut(&block) {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        (buffer + (position - address) as usize) as *const u8,
+                        b.data[block_offset..].as_mut_ptr(),
+                        size,
+                    )
+                };
+            }
+            position += size as u64;
+        }
+        self.generation += 1;
+    }
+
+    /// ディスクから直接読み込んだ内容のうち、ブロック全体を含みキャッシュに無いものを登録する
+    ///
+    /// 読み込みを始めてから generation が変わっている場合や、書き込みが完了していない場合は、
+    /// 内容が古い可能性があるため登録しない
+    pub fn fill(
+        &mut self,
+        blk: &mut impl BlockDevice,
+        generation: u64,
+        address: u64,
+        buffer: usize,
+        length: usize,
+    ) -> Result<(), ()> {
+        if generation != self.generation || self.active_writes != 0 {
+            return Ok(());
+        }
+        let end = address + length as u64;
+        let mut block = address.div_ceil(BLOCK_SIZE as u64);
+        while (block + 1) * (BLOCK_SIZE as u64) <= end {
+            if !self.blocks.contains_key(&block) {
+                let source = buffer + (block * BLOCK_SIZE as u64 - address) as usize;
+                let data = unsafe { core::slice::from_raw_parts(source as *const u8, BLOCK_SIZE) };
+                self.insert(block, Box::from(data), false);
+            }
+            block += 1;
+        }
+        self.shrink(blk)
+    }
+}
+
+/// キャッシュを経由して読み書きするディスク
+///
+/// ディスク、キャッシュの順にロックを取り、両方を保持する
+pub struct CachedBlockDevice<'a, B: BlockDevice> {
+    device: MutexGuard<'a, B>,
+    cache: MutexGuard<'a, BlockCache>,
+}
+
+impl<'a, B: BlockDevice> CachedBlockDevice<'a, B> {
+    pub fn lock(device: &'a Mutex<B>, cache: &'a Mutex<BlockCache>) -> Self {
+        let device = device.lock();
+        let cache = cache.lock();
+        Self { device, cache }
+    }
+}
+
+impl<B: BlockDevice> BlockDevice for CachedBlockDevice<'_, B> {
+    fn read(&mut self, buffer_address: usize, block_address: u64, length: u64) -> Result<(), ()> {
+        self.cache.read(
+            &mut *self.device,
+            buffer_address,
+            block_address,
+            length as usize,
+        )
+    }
+
+    fn write(&mut self, buffer_address: usize, block_address: u64, length: u64) -> Result<(), ()> {
+        self.cache.write(
+            &mut *self.device,
+            buffer_address,
+            block_address,
+            length as usize,
+        )
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        self.cache.flush(&mut *self.device)
+    }
+
+    fn get_size(&self) -> u64 {
+        self.device.get_size()
+    }
+
+    fn get_block_size(&self) -> usize {
+        self.device.get_block_size()
+    }
+}
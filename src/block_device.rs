@@ -0,0 +1,95 @@
+//!
+//! Block Device
+//!
+//! FAT32 や仮想マシンのディスクの読み書き先となるストレージの共通の操作
+//!
+
+use alloc::vec::Vec;
+
+pub trait BlockDevice {
+    /// ディスク上の block_address から length バイトを buffer_address(物理アドレス)へ読み込む
+    ///
+    /// block_address と length は get_block_size の倍数であること
+    fn read(&mut self, buffer_address: usize, block_address: u64, length: u64) -> Result<(), ()>;
+
+    /// buffer_address(物理アドレス)から length バイトをディスク上の block_address へ書き込む
+    ///
+    /// block_address と length は get_block_size の倍数であること
+    fn write(&mut self, buffer_address: usize, block_address: u64, length: u64) -> Result<(), ()>;
+
+    /// 書き込んだ内容を永続化する
+    fn flush(&mut self) -> Result<(), ()>;
+
+    /// ディスクの大きさ(バイト)
+    fn get_size(&self) -> u64;
+
+    /// 読み書きの単位(バイト)
+    fn get_block_size(&self) -> usize;
+}
+
+/// メモリ上のディスク
+///
+/// 内容は破棄されるまで保持し、永続化はしない
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    /// ディスクの内容を data とする(大きさは get_block_size の倍数であること)
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    /// ディスク上の block_address から length バイトの範囲
+    fn get_range(&self, block_address: u64, length: u64) -> Result<core::ops::Range<usize>, ()> {
+        let start = usize::try_from(block_address).or(Err(()))?;
+        let end = usize::try_from(length)
+            .ok()
+            .and_then(|l| start.checked_add(l))
+            .ok_or(())?;
+        if end > self.data.len() {
+            println!(
+                "Access beyond the end of the RAM disk: {:#X}",
+                block_address
+            );
+            return Err(());
+        }
+        Ok(start..end)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read(&mut self, buffer_address: usize, block_address: u64, length: u64) -> Result<(), ()> {
+        let range = self.get_range(block_address, length)?;
+        let source = &self.data[range];
+        unsafe {
+            core::ptr::copy_nonoverlapping(source.as_ptr(), buffer_address as *mut u8, source.len())
+        };
+        Ok(())
+    }
+
+    fn write(&mut self, buffer_address: usize, block_address: u64, length: u64) -> Result<(), ()> {
+        let range = self.get_range(block_address, length)?;
+        let destination = &mut self.data[range];
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                buffer_address as *const u8,
+                destination.as_mut_ptr(),
+                destination.len(),
+            )
+        };
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn get_size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn get_block_size(&self) -> usize {
+        512
+    }
+}
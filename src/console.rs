@@ -2,6 +2,10 @@
 //! Console
 //!
 
+use crate::block_device::BlockDevice;
+
+use alloc::string::String;
+
 use core::str::SplitWhitespace;
 use core::sync::atomic::Ordering;
 
@@ -14,11 +18,25 @@ pub struct Console {
 impl Console {
     const BUFFER_SIZE: usize = 64;
     #[allow(clippy::type_complexity)]
-    const COMMAND_LIST: [(&str, fn(SplitWhitespace) -> bool); 4] = [
+    const COMMAND_LIST: [(&str, fn(SplitWhitespace) -> bool); 18] = [
         ("boot", Self::boot_vm),
         ("switch", Self::switch_vm),
         ("echo", Self::echo),
         ("poweroff", Self::power_off),
+        ("dirtylog", Self::dirty_log),
+        ("save", Self::save_vm),
+        ("restore", Self::restore_vm),
+        ("pause", Self::pause_vm),
+        ("resume", Self::resume_vm),
+        ("destroy", Self::destroy_vm),
+        ("share", Self::share),
+        ("regs", Self::show_registers),
+        ("x", Self::dump_memory),
+        ("walk", Self::walk_stage2),
+        ("gdb", Self::attach_gdb),
+        ("disks", Self::list_disks),
+        ("cache", Self::block_cache),
+        ("overlay", Self::create_overlay),
     ];
 
     pub const fn new() -> Self {
@@ -89,17 +107,40 @@ impl Console {
 
     pub fn power_off(_: SplitWhitespace) -> bool {
         println!("The host machine will shutdown!");
+        if crate::lock_boot_disk().flush().is_err() {
+            println!("Failed to write back the block cache");
+        }
         crate::psci::system_off()
     }
 
+    pub fn list_disks(_: SplitWhitespace) -> bool {
+        for (i, device) in crate::VIRTIO_BLK_DEVICES.iter().enumerate() {
+            let device = device.lock();
+            if device.get_base_address() == 0 {
+                continue;
+            }
+            println!(
+                "Disk{i}: {:#X}, {} MiB, {} request(s) in flight{}",
+                device.get_base_address(),
+                device.get_capacity() >> 11,
+                device.get_number_of_inflight_requests(),
+                if i == 0 { " (boot disk)" } else { "" }
+            );
+        }
+        true
+    }
+
     pub fn boot_vm(_: SplitWhitespace) -> bool {
-        if crate::launch_cpu() {
-            /* Active VM は自動的に切り替わる */
-            println!("Booted a new VM");
-            false
-        } else {
-            println!("Failed to boot a VM");
-            true
+        match crate::launch_cpu() {
+            Ok(()) => {
+                /* Active VM は自動的に切り替わる */
+                println!("Booted a new VM");
+                false
+            }
+            Err(e) => {
+                println!("Failed to boot a VM: {e}");
+                true
+            }
         }
     }
 
@@ -120,4 +161,437 @@ impl Console {
             true
         }
     }
+
+    pub fn dirty_log(mut args: SplitWhitespace) -> bool {
+        const USAGE: &str = "Usage: dirtylog vm_id start|stop|show";
+        let (Some(arg), Some(operation)) = (args.next(), args.next()) else {
+            println!("Missing arguments\n{USAGE}");
+            return true;
+        };
+        let Some(vm_id) = crate::str_to_usize(arg) else {
+            println!("\"{arg}\" is not a number");
+            return true;
+        };
+        let Some(vm) = crate::vm::get_vm(vm_id) else {
+            println!("VM{vm_id} is not available");
+            return true;
+        };
+        match operation {
+            "start" => {
+                if vm.start_dirty_log().is_ok() {
+                    println!("Started dirty logging of VM{vm_id}");
+                }
+            }
+            "stop" => {
+                if vm.stop_dirty_log().is_ok() {
+                    println!("Stopped dirty logging of VM{vm_id}");
+                }
+            }
+            "show" => {
+                let Some(bitmap) = vm.get_and_clear_dirty_log() else {
+                    println!("Dirty log of VM{vm_id} is not enabled");
+                    return true;
+                };
+                let dirty_pages: u32 = bitmap.iter().map(|b| b.count_ones()).sum();
+                println!("VM{vm_id}: {dirty_pages} dirty pages");
+            }
+            _ => {
+                println!("Unknown operation: {operation}\n{USAGE}");
+            }
+        }
+        true
+    }
+
+    pub fn save_vm(mut args: SplitWhitespace) -> bool {
+        let (Some(arg), Some(file_name)) = (args.next(), args.next()) else {
+            println!("Missing arguments\nUsage: save vm_id file_name");
+            return true;
+        };
+        let Some(vm_id) = crate::str_to_usize(arg) else {
+            println!("\"{arg}\" is not a number");
+            return true;
+        };
+        let Some(vm) = crate::vm::get_vm(vm_id) else {
+            println!("VM{vm_id} is not available");
+            return true;
+        };
+        /* 保存は仮想マシンが動作しているpCPUで行う */
+        if vm
+            .request_vcpu(crate::vm::VcpuRequest::Save(String::from(file_name)))
+            .is_ok()
+        {
+            println!("Saving VM{vm_id} to {file_name}");
+        }
+        true
+    }
+
+    pub fn restore_vm(mut args: SplitWhitespace) -> bool {
+        let Some(file_name) = args.next() else {
+            println!("Missing file_name\nUsage: restore file_name");
+            return true;
+        };
+        let fat32 = crate::FAT32.read();
+        let Some(file) = fat32.search_file(file_name) else {
+            println!("{file_name} is not found");
+            return true;
+        };
+        if crate::snapshot::read_snapshot(&fat32, &mut crate::lock_boot_disk(), &file).is_err() {
+            println!("{file_name} is not a valid snapshot");
+            return true;
+        }
+        if !crate::vm::request_restore(file) {
+            println!("Another VM is being restored");
+            return true;
+        }
+        match crate::launch_cpu() {
+            Ok(()) => {
+                /* Active VM は自動的に切り替わる */
+                println!("Restoring a VM from {file_name}");
+                false
+            }
+            Err(e) => {
+                let _ = crate::vm::take_restore_request();
+                println!("Failed to restore a VM: {e}");
+                true
+            }
+        }
+    }
+
+    pub fn pause_vm(mut args: SplitWhitespace) -> bool {
+        let Some(arg) = args.next() else {
+            println!("Missing vm_id\nUsage: pause vm_id");
+            return true;
+        };
+        let Some(vm_id) = crate::str_to_usize(arg) else {
+            println!("\"{arg}\" is not a number");
+            return true;
+        };
+        let Some(vm) = crate::vm::get_vm(vm_id) else {
+            println!("VM{vm_id} is not available");
+            return true;
+        };
+        let _ = vm.pause();
+        true
+    }
+
+    pub fn resume_vm(mut args: SplitWhitespace) -> bool {
+        let Some(arg) = args.next() else {
+            println!("Missing vm_id\nUsage: resume vm_id");
+            return true;
+        };
+        let Some(vm_id) = crate::str_to_usize(arg) else {
+            println!("\"{arg}\" is not a number");
+            return true;
+        };
+        let Some(vm) = crate::vm::get_vm(vm_id) else {
+            println!("VM{vm_id} is not available");
+            return true;
+        };
+        let _ = vm.resume();
+        true
+    }
+
+    pub fn destroy_vm(mut args: SplitWhitespace) -> bool {
+        let Some(arg) = args.next() else {
+            println!("Missing vm_id\nUsage: destroy vm_id");
+            return true;
+        };
+        let Some(vm_id) = crate::str_to_usize(arg) else {
+            println!("\"{arg}\" is not a number");
+            return true;
+        };
+        let Some(vm) = crate::vm::get_vm(vm_id) else {
+            println!("VM{vm_id} is not available");
+            return true;
+        };
+        let _ = vm.destroy();
+        true
+    }
+
+    pub fn share(mut args: SplitWhitespace) -> bool {
+        match args.next() {
+            Some("ro") => {
+                crate::mmio::virtio_9p::set_writable(false);
+                println!("The shared folder is read-only");
+            }
+            Some("rw") => {
+                crate::mmio::virtio_9p::set_writable(true);
+                println!("The shared folder is writable");
+            }
+            _ => println!("Usage: share ro|rw"),
+        }
+        true
+    }
+
+    pub fn block_cache(mut args: SplitWhitespace) -> bool {
+        const USAGE: &str = "Usage: cache [flush|size KiB]";
+        let mut blk = crate::VIRTIO_BLK.lock();
+        let mut cache = crate::BLOCK_CACHE.lock();
+        match args.next() {
+            None => {
+                let (used, dirty) = cache.get_usage();
+                let s = cache.get_statistics();
+                let total = s.hits + s.misses;
+                println!(
+                    "Block Cache: {} KiB / {} KiB ({} dirty blocks)",
+                    used >> 10,
+                    cache.get_budget() >> 10,
+                    dirty
+                );
+                println!(
+                    "Hits: {}, Misses: {} (Hit Ratio: {}%)",
+                    s.hits,
+                    s.misses,
+                    if total == 0 { 0 } else { s.hits * 100 / total }
+                );
+                println!("Evictions: {}, Write-Backs: {}", s.evictions, s.write_backs);
+            }
+            Some("flush") => {
+                if cache.flush(&mut *blk).is_ok() {
+                    println!("Wrote back the block cache");
+                } else {
+                    println!("Failed to write back the block cache");
+                }
+            }
+            Some("size") => {
+                let Some(size) = args.next().and_then(crate::str_to_usize) else {
+                    println!("Missing size\n{USAGE}");
+                    return true;
+                };
+                if cache.set_budget(&mut *blk, size << 10).is_ok() {
+                    println!("The block cache size is {size} KiB");
+                } else {
+                    println!("Failed to shrink the block cache");
+                }
+            }
+            Some(_) => println!("{USAGE}"),
+        }
+        true
+    }
+
+    pub fn create_overlay(mut args: SplitWhitespace) -> bool {
+        let (Some(base_name), Some(overlay_name), Some(size)) =
+            (args.next(), args.next(), args.next())
+        else {
+            println!("Missing arguments\nUsage: overlay base_file overlay_file size_MiB");
+            return true;
+        };
+        let Some(size) = crate::str_to_usize(size) else {
+            println!("\"{size}\" is not a number");
+            return true;
+        };
+        if crate::overlay::create_overlay(
+            &mut crate::FAT32.write(),
+            &mut crate::lock_boot_disk(),
+            base_name,
+            overlay_name,
+            size << 20,
+        )
+        .is_ok()
+        {
+            println!("Created {overlay_name} on {base_name}");
+        } else {
+            println!("Failed to create {overlay_name}");
+        }
+        true
+    }
+
+    pub fn show_registers(mut args: SplitWhitespace) -> bool {
+        let Some(arg) = args.next() else {
+            println!("Missing vm_id\nUsage: regs vm_id");
+            return true;
+        };
+        let Some(vm_id) = crate::str_to_usize(arg) else {
+            println!("\"{arg}\" is not a number");
+            return true;
+        };
+        let Some(vm) = crate::vm::get_vm(vm_id) else {
+            println!("VM{vm_id} is not available");
+            return true;
+        };
+        let Some(context) = vm.get_paused_context() else {
+            println!("VM{vm_id} is not paused");
+            return true;
+        };
+        for (i, r) in context.registers.as_array()[0..31].iter().enumerate() {
+            print!("X{:<2}: {:#018X}", i, r);
+            if (i % 4) == 3 {
+                println!();
+            } else {
+                print!(" ");
+            }
+        }
+        println!();
+        println!(
+            "ELR_EL2: {:#018X} SPSR_EL2: {:#018X} VMPIDR_EL2: {:#018X}",
+            context.elr_el2, context.spsr_el2, context.vmpidr_el2
+        );
+        for (i, (name, value)) in context.get_system_registers().enumerate() {
+            print!("{:<14}: {:#018X}", name, value);
+            if (i % 3) == 2 {
+                println!();
+            } else {
+                print!(" ");
+            }
+        }
+        println!();
+        println!(
+            "CNTV_CTL_EL0: {:#X} Virtual Count: {:#X}",
+            context.cntv_ctl_el0, context.virtual_count
+        );
+        for (i, lr) in context.vgic.list_registers.iter().enumerate() {
+            println!("ICH_LR{i}_EL2: {:#018X}", lr);
+        }
+        println!("ICH_VMCR_EL2: {:#018X}", context.vgic.ich_vmcr_el2);
+        true
+    }
+
+    pub fn dump_memory(mut args: SplitWhitespace) -> bool {
+        const USAGE: &str = "Usage: x -p|-v vm_id address length\n\
+            (-p: IPA, -v: VA of the paused VM)";
+        const MAX_LENGTH: usize = 0x1000;
+        let (Some(mode), Some(arg), Some(address), Some(length)) =
+            (args.next(), args.next(), args.next(), args.next())
+        else {
+            println!("Missing arguments\n{USAGE}");
+            return true;
+        };
+        let is_intermediate_physical_address = match mode {
+            "-p" => true,
+            "-v" => false,
+            _ => {
+                println!("Invalid mode: {mode}\n{USAGE}");
+                return true;
+            }
+        };
+        let (Some(vm_id), Some(address), Some(length)) = (
+            crate::str_to_usize(arg),
+            crate::str_to_usize(address),
+            crate::str_to_usize(length),
+        ) else {
+            println!("Invalid arguments\n{USAGE}");
+            return true;
+        };
+        let length = length.min(MAX_LENGTH);
+        let Some(end) = address.checked_add(length) else {
+            println!("Invalid range: {:#X} + {:#X}", address, length);
+            return true;
+        };
+        let Some(vm) = crate::vm::get_vm(vm_id) else {
+            println!("VM{vm_id} is not available");
+            return true;
+        };
+        let context = vm.get_paused_context();
+        if !is_intermediate_physical_address && context.is_none() {
+            println!("VM{vm_id} must be paused to access virtual addresses");
+            return true;
+        }
+
+        /* ページ単位で変換結果を保持する */
+        let mut cached_page: Option<(usize, usize)> = None;
+        let mut translate = |address: usize| -> Option<usize> {
+            let page = address & !(crate::paging::PAGE_SIZE - 1);
+            let offset = address - page;
+            if let Some((v, p)) = cached_page
+                && v == page
+            {
+                return Some(p + offset);
+            }
+            let intermediate_physical_address = if is_intermediate_physical_address {
+                page
+            } else {
+                context
+                    .as_ref()?
+                    .translate_virtual_address(&vm, page)
+                    .ok()?
+            };
+            let physical_address = vm.get_physical_address(intermediate_physical_address)?;
+            cached_page = Some((page, physical_address));
+            Some(physical_address + offset)
+        };
+
+        for line_address in (address..end).step_by(16) {
+            let line_length = 16.min(end - line_address);
+            let mut line = [0u8; 16];
+            for (i, c) in line[0..line_length].iter_mut().enumerate() {
+                let Some(physical_address) = translate(line_address + i) else {
+                    println!("{:#X} is not mapped", line_address + i);
+                    return true;
+                };
+                *c = unsafe { core::ptr::read_volatile(physical_address as *const u8) };
+            }
+            print!("{:#018X}:", line_address);
+            for c in &line[0..line_length] {
+                print!(" {:02X}", c);
+            }
+            for _ in line_length..16 {
+                print!("   ");
+            }
+            print!("  |");
+            for c in &line[0..line_length] {
+                let c = *c;
+                print!(
+                    "{}",
+                    if c.is_ascii_graphic() || c == b' ' {
+                        c as char
+                    } else {
+                        '.'
+                    }
+                );
+            }
+            println!("|");
+        }
+        true
+    }
+
+    pub fn walk_stage2(mut args: SplitWhitespace) -> bool {
+        let (Some(arg), Some(address)) = (args.next(), args.next()) else {
+            println!("Missing arguments\nUsage: walk vm_id ipa");
+            return true;
+        };
+        let (Some(vm_id), Some(address)) = (crate::str_to_usize(arg), crate::str_to_usize(address))
+        else {
+            println!("Invalid arguments\nUsage: walk vm_id ipa");
+            return true;
+        };
+        let Some(vm) = crate::vm::get_vm(vm_id) else {
+            println!("VM{vm_id} is not available");
+            return true;
+        };
+        for (level, descriptor) in
+            crate::paging::get_stage2_descriptor_chain(vm.get_stage2_table_address(), address)
+        {
+            let descriptor_type = match descriptor & 0b11 {
+                0b11 if level < 3 => "Table",
+                0b11 => "Page",
+                0b01 if level < 3 => "Block",
+                _ => "Invalid",
+            };
+            println!("Level {level}: {:#018X} ({descriptor_type})", descriptor);
+        }
+        true
+    }
+
+    pub fn attach_gdb(mut args: SplitWhitespace) -> bool {
+        let Some(arg) = args.next() else {
+            println!("Missing vm_id\nUsage: gdb vm_id");
+            return true;
+        };
+        let Some(vm_id) = crate::str_to_usize(arg) else {
+            println!("\"{arg}\" is not a number");
+            return true;
+        };
+        let Some(vm) = crate::vm::get_vm(vm_id) else {
+            println!("VM{vm_id} is not available");
+            return true;
+        };
+        /* 接続後はコンソールの出力を保留するため、先に表示する */
+        println!("Waiting for GDB on this serial port...");
+        if crate::gdb::attach(vm).is_err() {
+            println!("Failed to attach GDB to VM{vm_id}");
+            return true;
+        }
+        /* 以降の入力は GDB のパケットとして扱う */
+        false
+    }
 }
@@ -0,0 +1,291 @@
+//!
+//! ハイパーバイザ自身の異常の報告
+//!
+//! EL2で発生した例外やpanicの情報を出力し、全てのpCPUを停止させる。
+//! 出力するアドレスはハイパーバイザのELFファイルのシンボルテーブルを使って関数名に変換する。
+//!
+
+use crate::asm;
+use crate::drivers::gicv3::GicRedistributor;
+use crate::elf::SymbolTable;
+use crate::exception::Registers;
+use crate::registers::*;
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// 他のpCPUを停止させるSGI
+pub const HALT_INT_ID: u32 = 13;
+const MAX_BACKTRACE_DEPTH: usize = 32;
+
+static mut SYMBOL_TABLE: Option<SymbolTable> = None;
+static IS_CRASHED: AtomicBool = AtomicBool::new(false);
+
+/// クラッシュ時の報告に使うシンボルテーブルを設定する
+///
+/// 他のpCPUを起動する前に呼び出すこと
+pub fn set_symbol_table(symbol_table: SymbolTable) {
+    unsafe { SYMBOL_TABLE = Some(symbol_table) };
+}
+
+/// アドレスを "アドレス <関数名+オフセット>" の形式で表示する
+struct Symbolized(u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018X}", self.0)?;
+        let symbol_table = unsafe { (&raw const SYMBOL_TABLE).as_ref().unwrap() };
+        if let Some((name, offset)) = symbol_table
+            .as_ref()
+            .and_then(|s| s.lookup(self.0 as usize))
+        {
+            write!(f, " <{}+{:#X}>", Demangled(name), offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rust の legacy 形式のシンボル名(_ZN...E)を "::" 区切りで表示する
+///
+/// 末尾のハッシュは省略する。他の形式はそのまま表示する。
+struct Demangled<'a>(&'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(mut rest) = self.0.strip_prefix("_ZN").and_then(|s| s.strip_suffix('E')) else {
+            return f.write_str(self.0);
+        };
+        let mut is_first = true;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let Some(length) = rest[0..digits].parse::<usize>().ok() else {
+                return f.write_str(self.0);
+            };
+            let Some(element) = rest.get(digits..(digits + length)) else {
+                return f.write_str(self.0);
+            };
+            rest = &rest[(digits + length)..];
+            if rest.is_empty()
+                && element.len() == 17
+                && element.starts_with('h')
+                && element[1..].bytes().all(|c| c.is_ascii_hexdigit())
+            {
+                break;
+            }
+            if !is_first {
+                f.write_str("::")?;
+            }
+            is_first = false;
+            write_demangled_element(f, element)?;
+        }
+        Ok(())
+    }
+}
+
+/// "$LT$" などのエスケープを元の文字に戻して出力する
+fn write_demangled_element(f: &mut fmt::Formatter<'_>, element: &str) -> fmt::Result {
+    let mut rest = element
+        .strip_prefix("_$")
+        .map_or(element, |_| &element[1..]);
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = r;
+            continue;
+        }
+        if let Some(r) = rest.strip_prefix('$')
+            && let Some(end) = r.find('$')
+        {
+            let unescaped = match &r[0..end] {
+                "LT" => "<",
+                "GT" => ">",
+                "RF" => "&",
+                "BP" => "*",
+                "C" => ",",
+                "SP" => "@",
+                "u20" => " ",
+                "u27" => "'",
+                "u5b" => "[",
+                "u5d" => "]",
+                "u7b" => "{",
+                "u7d" => "}",
+                _ => &rest[0..(end + 2)],
+            };
+            f.write_str(unescaped)?;
+            rest = &r[(end + 1)..];
+            continue;
+        }
+        let length = rest.chars().next().map_or(1, char::len_utf8);
+        f.write_str(&rest[0..length])?;
+        rest = &rest[length..];
+    }
+    Ok(())
+}
+
+fn get_exception_class_name(ec: u64) -> &'static str {
+    match ec {
+        0b000000 => "Unknown reason",
+        0b000001 => "WFI/WFE",
+        0b000111 => "SVE/SIMD/Floating-point access",
+        0b001110 => "Illegal Execution state",
+        0b010101 => "SVC",
+        0b010110 => "HVC",
+        0b010111 => "SMC",
+        0b011000 => "MSR/MRS/System instruction",
+        0b100000 => "Instruction Abort from a lower EL",
+        0b100001 => "Instruction Abort from the current EL",
+        0b100010 => "PC alignment fault",
+        0b100100 => "Data Abort from a lower EL",
+        0b100101 => "Data Abort from the current EL",
+        0b100110 => "SP alignment fault",
+        0b101111 => "SError",
+        0b110000 | 0b110001 => "Breakpoint",
+        0b110010 | 0b110011 => "Software Step",
+        0b110100 | 0b110101 => "Watchpoint",
+        0b111100 => "BRK instruction",
+        _ => "Reserved",
+    }
+}
+
+fn get_fault_status_code_name(fsc: u64) -> &'static str {
+    match fsc & ESR_EL2_ISS_FSC_TYPE {
+        0b000000 => "Address size fault",
+        ESR_EL2_ISS_FSC_TRANSLATION_FAULT => "Translation fault",
+        0b001000 => "Access flag fault",
+        ESR_EL2_ISS_FSC_PERMISSION_FAULT => "Permission fault",
+        _ => match fsc {
+            0b010000 => "Synchronous External abort",
+            0b100001 => "Alignment fault",
+            0b110000 => "TLB conflict abort",
+            _ => "Unknown fault",
+        },
+    }
+}
+
+fn print_esr_el2(esr_el2: u64) {
+    let ec = (esr_el2 & ESR_EL2_EC) >> ESR_EL2_EC_BITS_OFFSET;
+    let iss = esr_el2 & ((1 << 25) - 1);
+    println!(
+        "ESR_EL2 : {:#018X} (EC: {:#04X} {}, IL: {}, ISS: {:#X})",
+        esr_el2,
+        ec,
+        get_exception_class_name(ec),
+        if (esr_el2 & (1 << 25)) != 0 { 32 } else { 16 },
+        iss
+    );
+    if matches!(ec, 0b100000 | 0b100001 | 0b100100 | 0b100101) {
+        let fsc = iss & 0b111111;
+        print!(
+            "          {} (FSC: {:#04X}",
+            get_fault_status_code_name(fsc),
+            fsc
+        );
+        if fsc < 0b010000 {
+            print!(", Level: {}", fsc & 0b11);
+        }
+        if (ec & 0b100) != 0 {
+            print!(
+                ", {}",
+                if (iss & ESR_EL2_ISS_WNR) != 0 {
+                    "Write"
+                } else {
+                    "Read"
+                }
+            );
+        }
+        println!(")");
+    }
+}
+
+/// フレームポインタを辿って呼び出し元を出力する
+///
+/// pc を指定した場合は最初のフレームとして出力する
+fn print_backtrace(pc: Option<u64>, frame_pointer: u64) {
+    println!("Backtrace:");
+    let mut depth = 0;
+    if let Some(pc) = pc {
+        println!("  #{:<2} {}", depth, Symbolized(pc));
+        depth += 1;
+    }
+    let mut frame_pointer = frame_pointer;
+    while depth < MAX_BACKTRACE_DEPTH {
+        if frame_pointer == 0 || (frame_pointer & 0b111) != 0 {
+            break;
+        }
+        /* [x29] = 呼び出し元の x29, [x29 + 8] = 戻りアドレス */
+        let next_frame_pointer = unsafe { *(frame_pointer as *const u64) };
+        let return_address = unsafe { *((frame_pointer + 8) as *const u64) };
+        if return_address == 0 {
+            break;
+        }
+        /* 戻りアドレスの直前が呼び出し命令 */
+        println!("  #{:<2} {}", depth, Symbolized(return_address - 4));
+        depth += 1;
+        if next_frame_pointer <= frame_pointer {
+            break;
+        }
+        frame_pointer = next_frame_pointer;
+    }
+}
+
+/// 報告を開始する
+///
+/// 既に他のpCPUで報告中の場合は報告せずに停止する
+fn begin_report() {
+    if IS_CRASHED.swap(true, Ordering::AcqRel) {
+        halt();
+    }
+    /* 他のpCPUを停止させる */
+    GicRedistributor::send_sgi_to_all_others(HALT_INT_ID);
+    crate::serial::discard_held_output();
+    println!(
+        "\n==== Hypervisor crashed on CPU(Affinity: {:#X}) ====",
+        asm::mpidr_to_affinity(asm::get_mpidr_el1())
+    );
+}
+
+/// EL2で処理できない例外の情報を出力して停止する
+pub fn report_exception(name: &str, registers: &Registers) -> ! {
+    begin_report();
+    let esr_el2 = asm::get_esr_el2();
+    let elr_el2 = asm::get_elr_el2();
+    println!("{name}");
+    print_esr_el2(esr_el2);
+    println!("FAR_EL2 : {:#018X}", asm::get_far_el2());
+    println!("ELR_EL2 : {}", Symbolized(elr_el2));
+    println!("SPSR_EL2: {:#018X}", asm::get_spsr_el2());
+    for (i, r) in registers.as_array()[0..31].iter().enumerate() {
+        print!("X{:<2}: {:#018X}", i, r);
+        if (i % 4) == 3 {
+            println!();
+        } else {
+            print!(" ");
+        }
+    }
+    /* 例外発生時のSPはレジスタの退避分だけ上にある */
+    println!(
+        "SP : {:#018X}",
+        registers as *const _ as usize + size_of::<Registers>()
+    );
+    if (asm::get_spsr_el2() & 0b1100) == (2 << 2) {
+        /* EL2で発生した例外のみ辿る */
+        print_backtrace(Some(elr_el2), registers.x29);
+    }
+    halt()
+}
+
+/// panic の情報を出力して停止する
+pub fn report_panic(info: &core::panic::PanicInfo) -> ! {
+    begin_report();
+    println!("{info}");
+    print_backtrace(None, asm::get_frame_pointer());
+    halt()
+}
+
+/// 割り込みを禁止して停止する
+pub fn halt() -> ! {
+    unsafe { asm::get_daif_and_disable_irq_fiq() };
+    loop {
+        asm::wait_for_interrupt();
+    }
+}
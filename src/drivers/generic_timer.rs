@@ -38,11 +38,13 @@ pub fn init_generic_timer_local(redistributor: &gicv3::GicRedistributor) {
 }
 
 pub fn generic_timer_interrupt_handler() {
-    vm::get_current_vm()
-        .get_gic_redistributor_mmio()
-        .lock()
-        .trigger_interrupt(
-            GENERIC_TIMER_VIRTUAL_INT_ID,
-            Some(unsafe { GENERIC_TIMER_PHYSICAL_INT_ID }),
-        );
+    let Ok(vm) = vm::get_current_vm() else {
+        /* 注入先の仮想マシンが無いため、ここで Deactivate する */
+        gicv3::GicRedistributor::deactivate(unsafe { GENERIC_TIMER_PHYSICAL_INT_ID });
+        return;
+    };
+    vm.get_gic_redistributor_mmio().lock().trigger_interrupt(
+        GENERIC_TIMER_VIRTUAL_INT_ID,
+        Some(unsafe { GENERIC_TIMER_PHYSICAL_INT_ID }),
+    );
 }
@@ -281,6 +281,25 @@ impl GicRedistributor {
         unsafe { asm::set_icc_dir_el1(int_id as u64) };
     }
 
+    /// 指定したアフィニティのpCPUへSGIを送信する
+    pub fn send_sgi(int_id: u32, mpidr: u64) {
+        let affinity = asm::mpidr_to_affinity(mpidr);
+        let aff3 = (affinity >> 32) & 0xff;
+        let aff2 = (affinity >> 16) & 0xff;
+        let aff1 = (affinity >> 8) & 0xff;
+        let aff0 = affinity & 0xff;
+        assert!(aff0 < 16);
+        let icc_sgi1r_el1 =
+            (aff3 << 48) | (aff2 << 32) | ((int_id as u64) << 24) | (aff1 << 16) | (1 << aff0);
+        unsafe { asm::set_icc_sgi1r_el1(icc_sgi1r_el1) };
+    }
+
+    /// 自身以外の全てのpCPUへSGIを送信する
+    pub fn send_sgi_to_all_others(int_id: u32) {
+        const ICC_SGI1R_EL1_IRM: u64 = 1 << 40;
+        unsafe { asm::set_icc_sgi1r_el1(ICC_SGI1R_EL1_IRM | ((int_id as u64) << 24)) };
+    }
+
     fn wait_rwp(&self) {
         while (self.read_register(Self::GICR_CTLR) & Self::GICR_CTLR_RWP) != 0 {
             core::hint::spin_loop();
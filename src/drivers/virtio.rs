@@ -8,7 +8,9 @@ pub const VIRTIO_MMIO_VERSION: usize = 0x04;
 pub const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
 pub const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c;
 pub const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+pub const VIRTIO_MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
 pub const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+pub const VIRTIO_MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
 pub const VIRTIO_MMIO_GUEST_PAGE_SIZE: usize = 0x028;
 pub const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030;
 pub const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034;
@@ -19,6 +21,13 @@ pub const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050;
 pub const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060;
 pub const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064;
 pub const VIRTIO_MMIO_STATUS: usize = 0x070;
+pub const VIRTIO_MMIO_QUEUE_DESC_LOW: usize = 0x080;
+pub const VIRTIO_MMIO_QUEUE_DESC_HIGH: usize = 0x084;
+pub const VIRTIO_MMIO_QUEUE_DRIVER_LOW: usize = 0x090;
+pub const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: usize = 0x094;
+pub const VIRTIO_MMIO_QUEUE_DEVICE_LOW: usize = 0x0a0;
+pub const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+pub const VIRTIO_MMIO_CONFIG_GENERATION: usize = 0x0fc;
 
 pub const VIRTIO_CONFIG_OFFSET: usize = 0x100;
 
@@ -27,6 +36,15 @@ pub const VIRTIO_DEVICE_STATUS_DRIVER: u32 = 2;
 pub const VIRTIO_DEVICE_STATUS_DRIVER_OK: u32 = 4;
 pub const VIRTIO_DEVICE_STATUS_FEATURES_OK: u32 = 8;
 
+/// Descriptor が別の Descriptor Table を指せるかどうか
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+/// used_event と avail_event で通知と割り込みを抑制できるかどうか
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+/// Modern(Version 2)のデバイスで必須の機能
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// Packed Virtqueue を使えるかどうか
+pub const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+
 #[repr(C)]
 pub struct VirtQueueDesc {
     pub address: u64,
@@ -37,35 +55,23 @@ pub struct VirtQueueDesc {
 
 pub const VIRT_QUEUE_DESC_FLAGS_NEXT: u16 = 1;
 pub const VIRT_QUEUE_DESC_FLAGS_WRITE: u16 = 1 << 1;
+pub const VIRT_QUEUE_DESC_FLAGS_INDIRECT: u16 = 1 << 2;
+/// Packed Virtqueue で Descriptor の状態を表すフラグ
+pub const VIRT_QUEUE_DESC_FLAGS_AVAIL: u16 = 1 << 7;
+pub const VIRT_QUEUE_DESC_FLAGS_USED: u16 = 1 << 15;
+
+/// ドライバが割り込みを不要としている(EVENT_IDX を使わない場合)
+pub const VIRT_QUEUE_AVAIL_FLAGS_NO_INTERRUPT: u16 = 1;
+
+/// Packed Virtqueue の通知の抑制(ENABLE は 0)
+pub const VIRT_QUEUE_EVENT_FLAGS_DISABLE: u16 = 1;
+pub const VIRT_QUEUE_EVENT_FLAGS_DESC: u16 = 2;
 
-pub const NUMBER_OF_DESCRIPTORS: usize = 64;
 pub const VIRTIO_PAGE_SHIFT: usize = 12;
 pub const VIRTIO_PAGE_SIZE: usize = 1 << VIRTIO_PAGE_SHIFT;
 
-#[repr(C)]
-pub struct VirtQueueAvail {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [u16; NUMBER_OF_DESCRIPTORS],
-    pub used_event: u16,
-}
-
 #[repr(C)]
 pub struct VirtQueueUsedElement {
     pub id: u32,
     pub length: u32, /* The number of bytes written into buffers */
 }
-
-#[repr(C)]
-pub struct VirtQueueUsed {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [VirtQueueUsedElement; NUMBER_OF_DESCRIPTORS],
-    pub avail_event: u16,
-}
-
-pub const NUMBER_OF_PAGES_QUEUE: usize = (((size_of::<VirtQueueDesc>() * NUMBER_OF_DESCRIPTORS
-    + size_of::<VirtQueueAvail>())
-    >> VIRTIO_PAGE_SHIFT)
-    + 1)
-    + ((size_of::<VirtQueueUsed>() >> VIRTIO_PAGE_SHIFT) + 1);
@@ -2,15 +2,46 @@
 //! Virtio-Blkの実装
 //!
 
+use crate::asm;
+use crate::block_device::BlockDevice;
 use crate::drivers::virtio::*;
+use crate::lock::Mutex;
+use crate::virtqueue::{DriverBuffer, DriverQueue};
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
 
 pub const VIRTIO_BLK_TYPE_IN: u32 = 0;
 pub const VIRTIO_BLK_TYPE_OUT: u32 = 1;
+pub const VIRTIO_BLK_TYPE_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_TYPE_GET_ID: u32 = 8;
+pub const VIRTIO_BLK_TYPE_DISCARD: u32 = 11;
+pub const VIRTIO_BLK_TYPE_WRITE_ZEROES: u32 = 13;
 pub const VIRTIO_BLK_S_OK: u8 = 0;
 pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
 
+/// 1つの Segment の最大サイズが設定領域にあるかどうか
+pub const VIRTIO_BLK_F_SIZE_MAX: u32 = 1 << 1;
+/// 1つの要求の最大 Segment 数が設定領域にあるかどうか
+pub const VIRTIO_BLK_F_SEG_MAX: u32 = 1 << 2;
+/// ディスクのジオメトリが設定領域にあるかどうか
+pub const VIRTIO_BLK_F_GEOMETRY: u32 = 1 << 4;
 /// ディスクがRead Onlyかどうか
-const VIRTIO_BLK_F_RO: u32 = 1 << 5;
+pub const VIRTIO_BLK_F_RO: u32 = 1 << 5;
+/// ブロックサイズが設定領域にあるかどうか
+pub const VIRTIO_BLK_F_BLK_SIZE: u32 = 1 << 6;
+/// キャッシュの書き戻し要求に対応しているか
+pub const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
+/// 複数の Virtqueue を使えるかどうか
+pub const VIRTIO_BLK_F_MQ: u32 = 1 << 12;
+pub const VIRTIO_BLK_F_DISCARD: u32 = 1 << 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 1 << 14;
+
+/// GET_ID で返す識別子の長さ
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
 
 #[repr(C)]
 pub struct VirtioBlkReq {
@@ -19,23 +50,86 @@ pub struct VirtioBlkReq {
     pub sector: u64,
 }
 
+/// DISCARD と WRITE_ZEROES の Segment
+#[repr(C)]
+pub struct VirtioBlkDiscardWriteZeroes {
+    pub sector: u64,
+    pub num_sectors: u32,
+    pub flags: u32,
+}
+
+/// Virtqueue の Descriptor の数(デバイスの上限の方が小さい場合はそちらに合わせる)
+const QUEUE_SIZE: usize = 128;
+/// データ部分以外に1つの要求で使う Descriptor の数(要求と Status)
+const HEADER_DESCRIPTORS: usize = 2;
+/// 要求の完了を待つ時間(ミリ秒)、超えた場合はデバイスをリセットする
+const REQUEST_TIMEOUT_MS: u64 = 5000;
+
+/// 完了を待たずにデバイスへ渡す要求
+pub struct AsyncRequest {
+    pub req_type: u32,
+    /// データ部分の(アドレス, 長さ)の一覧(各部分は別の Descriptor になる)
+    pub buffers: Vec<(usize, usize)>,
+    pub block_address: u64,
+    /// 完了時に返す識別子
+    pub token: u64,
+}
+
+impl AsyncRequest {
+    /// データ部分の合計のバイト数
+    pub fn get_length(&self) -> u64 {
+        self.buffers.iter().map(|(_, l)| *l as u64).sum()
+    }
+}
+
+/// デバイスへ渡した要求
+struct PendingRequest {
+    /// Descriptor が参照しているため、完了まで保持する
+    #[allow(dead_code)]
+    request: Box<VirtioBlkReq>,
+    status: Box<u8>,
+    /// デバイスが書き込むデータ部分(完了後にキャッシュを無効化する)
+    writable_buffers: Vec<(usize, usize)>,
+    /// 非同期の要求の場合は完了時に返す識別子
+    token: Option<u64>,
+    /// この時刻(CNTPCT_EL0)までに完了しない場合はデバイスをリセットする
+    deadline: u64,
+}
+
 pub struct VirtioBlk {
     base_address: usize,
-    descriptors: *mut [VirtQueueDesc; NUMBER_OF_DESCRIPTORS],
-    avail: *mut VirtQueueAvail,
-    #[allow(dead_code)]
-    used: *mut VirtQueueUsed,
-    free_bitmap: [u8; NUMBER_OF_DESCRIPTORS / (u8::BITS as usize)],
+    queue: Option<DriverQueue>,
+    is_flush_supported: bool,
+    /// 1つの要求で使えるデータ部分の Descriptor の最大数
+    max_segments: usize,
+    /// デバイスへ渡した要求(Descriptor Chain の先頭の番号ごと)
+    pending: BTreeMap<u16, PendingRequest>,
+    /// Descriptor の空きを待っている非同期の要求
+    waiting: VecDeque<AsyncRequest>,
+    /// 完了した非同期の要求の(識別子, 結果)
+    completed: Vec<(u64, Result<(), ()>)>,
+}
+
+/// address から size バイトを含むデータキャッシュの各ラインを書き戻して無効化する
+fn clean_and_invalidate_cache_range(address: usize, size: usize) {
+    let line_size = 4usize << ((asm::get_ctr_el0() >> 16) & 0xF);
+    let mut line = address & !(line_size - 1);
+    while line < address + size {
+        unsafe { asm::clean_and_invalidate_cache(line) };
+        line += line_size;
+    }
 }
 
 impl VirtioBlk {
     pub const fn invalid() -> Self {
         Self {
             base_address: 0,
-            descriptors: core::ptr::null_mut(),
-            avail: core::ptr::null_mut(),
-            used: core::ptr::null_mut(),
-            free_bitmap: [0; NUMBER_OF_DESCRIPTORS / (u8::BITS as usize)],
+            queue: None,
+            is_flush_supported: false,
+            max_segments: 0,
+            pending: BTreeMap::new(),
+            waiting: VecDeque::new(),
+            completed: Vec::new(),
         }
     }
 
@@ -51,6 +145,17 @@ impl VirtioBlk {
         {
             return Err(());
         }
+        let mut blk = Self {
+            base_address,
+            ..Self::invalid()
+        };
+        blk.setup_device()?;
+        Ok(blk)
+    }
+
+    /// デバイスをリセットし、機能の設定と Virtqueue の登録を行う
+    fn setup_device(&mut self) -> Result<(), ()> {
+        let base_address = self.base_address;
         /* デバイスのリセット */
         Self::write_register(base_address, VIRTIO_MMIO_STATUS, 0);
         /* デバイスを認識した事を通知 */
@@ -73,8 +178,8 @@ impl VirtioBlk {
             println!("Disk is readonly.");
             return Err(());
         }
-        /* ドライバの対応状況を設定 */
-        features = 0;
+        /* ドライバの対応状況を設定(キャッシュの書き戻しと Segment 数の上限のみ使用する) */
+        features &= VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_SEG_MAX;
         Self::write_register(base_address, VIRTIO_MMIO_DRIVER_FEATURES, features);
         Self::write_register(
             base_address,
@@ -91,29 +196,29 @@ impl VirtioBlk {
         );
         Self::write_register(base_address, VIRTIO_MMIO_QUEUE_SEL, 0);
         let queue_max = Self::read_register(base_address, VIRTIO_MMIO_QUEUE_NUM_MAX);
-        if (queue_max as usize) < NUMBER_OF_DESCRIPTORS {
+        if (queue_max as usize) <= HEADER_DESCRIPTORS {
             println!("Virtio Queue Size is invalid: {queue_max}");
             return Err(());
         }
-        Self::write_register(
-            base_address,
-            VIRTIO_MMIO_QUEUE_NUM,
-            NUMBER_OF_DESCRIPTORS as u32,
-        );
-        let queue = crate::allocate_pages(NUMBER_OF_PAGES_QUEUE, VIRTIO_PAGE_SHIFT)
-            .expect("Failed to allocate virtio queue");
-        unsafe {
-            core::ptr::write_bytes(
-                queue as *mut u8,
-                0,
-                NUMBER_OF_PAGES_QUEUE << VIRTIO_PAGE_SHIFT,
-            )
+        /* リセットからの復帰の場合は確保済みの Virtqueue を使い回す */
+        let queue = match self.queue.take() {
+            Some(mut queue) => {
+                queue.reset();
+                queue
+            }
+            None => DriverQueue::new((queue_max as usize).min(QUEUE_SIZE))?,
         };
-        Self::write_register(
-            base_address,
-            VIRTIO_MMIO_QUEUE_PFN,
-            (queue >> VIRTIO_PAGE_SHIFT) as u32,
-        );
+        Self::write_register(base_address, VIRTIO_MMIO_QUEUE_NUM, queue.get_size() as u32);
+        Self::write_register(base_address, VIRTIO_MMIO_QUEUE_PFN, queue.get_pfn());
+
+        self.max_segments = queue.get_size() - HEADER_DESCRIPTORS;
+        if (features & VIRTIO_BLK_F_SEG_MAX) != 0 {
+            /* struct virtio_blk_config の seg_max */
+            let seg_max = Self::read_register(base_address, VIRTIO_CONFIG_OFFSET + 12) as usize;
+            self.max_segments = self.max_segments.min(seg_max.max(1));
+        }
+        self.is_flush_supported = (features & VIRTIO_BLK_F_FLUSH) != 0;
+        self.queue = Some(queue);
 
         /* 設定完了を通知 */
         Self::write_register(
@@ -121,20 +226,7 @@ impl VirtioBlk {
             VIRTIO_MMIO_STATUS,
             Self::read_register(base_address, VIRTIO_MMIO_STATUS) | VIRTIO_DEVICE_STATUS_DRIVER_OK,
         );
-
-        /* VirtQueueの各要素のアドレス計算 */
-        let descriptor_table = queue;
-        let available_ring = descriptor_table + size_of::<VirtQueueDesc>() * NUMBER_OF_DESCRIPTORS;
-        let used_ring = ((available_ring + size_of::<VirtQueueAvail>() - 1)
-            & !(VIRTIO_PAGE_SIZE - 1))
-            + VIRTIO_PAGE_SIZE;
-        Ok(Self {
-            base_address,
-            descriptors: descriptor_table as *mut _,
-            avail: available_ring as *mut _,
-            used: used_ring as *mut _,
-            free_bitmap: [u8::MAX; NUMBER_OF_DESCRIPTORS / (u8::BITS as usize)],
-        })
+        Ok(())
     }
 
     fn read_register(base_address: usize, offset: usize) -> u32 {
@@ -145,131 +237,325 @@ impl VirtioBlk {
         unsafe { core::ptr::write_volatile((base_address + offset) as *mut u32, data) }
     }
 
-    fn allocate_descriptor(&mut self) -> Option<(u16, &'static mut VirtQueueDesc)> {
-        for (byte, c) in self.free_bitmap.iter_mut().enumerate() {
-            for bit in 0..(u8::BITS as usize) {
-                if (*c & (1 << bit)) != 0 {
-                    *c &= !(1 << bit);
-                    let index = (byte * u8::BITS as usize) + bit;
-                    return Some((index as u16, &mut unsafe { &mut *self.descriptors }[index]));
-                }
-            }
-        }
-        None
-    }
-
-    fn free_descriptor(&mut self, index: u16) {
-        assert!((index as usize) < NUMBER_OF_DESCRIPTORS);
-        self.free_bitmap[(index as usize) / (u8::BITS as usize)] |=
-            1 << (index & ((1 << u8::BITS.ilog2() as u16) - 1));
-    }
-
-    fn operation_sync(
-        &mut self,
-        buffer_address: usize,
-        block_address: u64,
-        length: u64,
-        is_write: bool,
-    ) -> Result<(), ()> {
-        if (block_address & ((1 << 9) - 1)) != 0 || (length & ((1 << 9) - 1) != 0) {
+    fn check_request(&self, request: &AsyncRequest) -> Result<(), ()> {
+        let length = request.get_length();
+        if (request.block_address & ((1 << 9) - 1)) != 0 || (length & ((1 << 9) - 1) != 0) {
             println!(
                 "Block Address({:#X}) and Length({:#X}) must be 512Byte-Aligned.",
-                block_address, length
+                request.block_address, length
             );
             return Err(());
         }
+        if request.buffers.len() > self.max_segments {
+            println!(
+                "Too many segments: {} (max: {})",
+                request.buffers.len(),
+                self.max_segments
+            );
+            return Err(());
+        }
+        if self.queue.is_none() {
+            println!("Virtio Block Device is not initialized");
+            return Err(());
+        }
+        Ok(())
+    }
+
+    /// 要求を Virtqueue へ追加し、Descriptor Chain の先頭の番号を返す
+    ///
+    /// Descriptor が足りない場合は None を返す
+    fn add_request(&mut self, request: &AsyncRequest, token: Option<u64>) -> Option<u16> {
+        let queue = self.queue.as_mut()?;
+        let is_writable = request.req_type != VIRTIO_BLK_TYPE_OUT;
 
         /* Virtio BLK Requestの設定 */
-        let virtio_blk_req = VirtioBlkReq {
-            req_type: if is_write {
-                VIRTIO_BLK_TYPE_OUT
-            } else {
-                VIRTIO_BLK_TYPE_IN
-            },
+        let virtio_blk_req = Box::new(VirtioBlkReq {
+            req_type: request.req_type,
             reserved: 0,
-            sector: (block_address >> 9),
-        };
-        let Some((first_idx, first_descriptor)) = self.allocate_descriptor() else {
-            println!("Failed to allocate descriptor");
-            return Err(());
-        };
-        first_descriptor.address = &virtio_blk_req as *const _ as usize as u64;
-        first_descriptor.length = size_of::<VirtioBlkReq>() as u32;
-        first_descriptor.flags = VIRT_QUEUE_DESC_FLAGS_NEXT;
+            sector: (request.block_address >> 9),
+        });
+        let mut buffers = vec![DriverBuffer {
+            address: &*virtio_blk_req as *const _ as usize,
+            length: size_of::<VirtioBlkReq>() as u32,
+            is_writable: false,
+        }];
 
         /* Bufferの設定 */
-        let Some((second_idx, second_descriptor)) = self.allocate_descriptor() else {
-            println!("Failed to allocate descriptor");
-            return Err(());
-        };
-
-        second_descriptor.address = buffer_address as _;
-        second_descriptor.length = length as _;
-        second_descriptor.flags = VIRT_QUEUE_DESC_FLAGS_NEXT;
-        if !is_write {
-            second_descriptor.flags |= VIRT_QUEUE_DESC_FLAGS_WRITE;
+        for (address, length) in request.buffers.iter() {
+            buffers.push(DriverBuffer {
+                address: *address,
+                length: *length as u32,
+                is_writable,
+            });
         }
-        first_descriptor.next = second_idx;
 
         /* Statusの設定 */
-        let mut status: u8 = 0xFF;
-        let Some((third_idx, third_descriptor)) = self.allocate_descriptor() else {
-            println!("Failed to allocate descriptor");
-            return Err(());
+        let status = Box::new(0xFFu8);
+        buffers.push(DriverBuffer {
+            address: &*status as *const _ as usize,
+            length: size_of::<u8>() as u32,
+            is_writable: true,
+        });
+
+        /* デバイスが読み書きする前にキャッシュの内容をメモリへ反映させる */
+        for buffer in buffers.iter() {
+            clean_and_invalidate_cache_range(buffer.address, buffer.length as usize);
+        }
+        asm::data_synchronization_barrier();
+
+        let head = queue.add(&buffers)?;
+        let timeout = asm::get_cntfrq_el0() * REQUEST_TIMEOUT_MS / 1000;
+        self.pending.insert(
+            head,
+            PendingRequest {
+                request: virtio_blk_req,
+                status,
+                writable_buffers: if is_writable {
+                    request.buffers.clone()
+                } else {
+                    Vec::new()
+                },
+                token,
+                deadline: asm::get_cntpct_el0() + timeout,
+            },
+        );
+        Some(head)
+    }
+
+    /// 完了した要求を1つ回収し、(先頭の番号, 非同期の要求の識別子, 結果)を返す
+    fn pop_completed(&mut self) -> Option<(u16, Option<u64>, Result<(), ()>)> {
+        let (head, _) = self.queue.as_mut()?.pop_used()?;
+        let Some(pending) = self.pending.remove(&head) else {
+            println!("Unknown request is completed: {head}");
+            return None;
+        };
+        /* デバイスが書き込んだ内容を読むため、古いキャッシュを捨てる */
+        let status = &*pending.status as *const u8;
+        clean_and_invalidate_cache_range(status as usize, size_of::<u8>());
+        for (address, length) in pending.writable_buffers.iter() {
+            clean_and_invalidate_cache_range(*address, *length);
+        }
+        let result = if unsafe { core::ptr::read_volatile(status) } == VIRTIO_BLK_S_OK {
+            Ok(())
+        } else {
+            Err(())
         };
-        third_descriptor.address = &mut status as *mut _ as usize as u64;
-        third_descriptor.length = size_of::<u8>() as u32;
-        third_descriptor.flags = VIRT_QUEUE_DESC_FLAGS_WRITE;
-        second_descriptor.next = third_idx;
+        Some((head, pending.token, result))
+    }
+
+    /// 完了した要求を全て回収し、非同期の要求の結果を completed へ移す
+    fn collect_completed(&mut self) {
+        while let Some((_, token, result)) = self.pop_completed() {
+            if let Some(token) = token {
+                self.completed.push((token, result));
+            }
+        }
+    }
+
+    /// Descriptor の空きを待っている要求をデバイスへ渡す
+    fn submit_waiting(&mut self) {
+        if self.queue.is_none() {
+            /* デバイスを使えないため、全て失敗させる */
+            for r in self.waiting.drain(..) {
+                self.completed.push((r.token, Err(())));
+            }
+            return;
+        }
+        let mut is_added = false;
+        while let Some(r) = self.waiting.pop_front() {
+            if self.add_request(&r, Some(r.token)).is_none() {
+                self.waiting.push_front(r);
+                break;
+            }
+            is_added = true;
+        }
+        if is_added {
+            Self::write_register(self.base_address, VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+        }
+    }
+
+    /// 期限までに完了していない要求があるか
+    pub fn has_timed_out_request(&self) -> bool {
+        let now = asm::get_cntpct_el0();
+        self.pending.values().any(|p| p.deadline <= now)
+    }
+
+    /// 応答しないデバイスをリセットし、処理中の要求を全て失敗させる
+    fn recover(&mut self) {
+        println!(
+            "Virtio-Blk({:#X}) does not respond. Resetting the device.",
+            self.base_address
+        );
+        Self::write_register(self.base_address, VIRTIO_MMIO_STATUS, 0);
+        for (_, pending) in core::mem::take(&mut self.pending) {
+            if let Some(token) = pending.token {
+                self.completed.push((token, Err(())));
+            }
+        }
+        if self.setup_device().is_err() {
+            println!("Failed to reset Virtio-Blk({:#X})", self.base_address);
+            self.queue = None;
+        }
+    }
 
-        /* Available Ring の更新 */
-        let avail_ring = unsafe { &mut *self.avail };
-        let idx = avail_ring.idx as usize;
-        avail_ring.ring[idx % NUMBER_OF_DESCRIPTORS] = first_idx;
-        avail_ring.idx += 1;
+    /// 要求を送信し、完了まで待つ
+    fn operation_sync(&mut self, request: AsyncRequest) -> Result<(), ()> {
+        self.check_request(&request)?;
+
+        /* 非同期の要求で Descriptor が埋まっている場合は空くまで待つ */
+        let head = loop {
+            if let Some(head) = self.add_request(&request, None) {
+                break head;
+            }
+            self.collect_completed();
+            if self.has_timed_out_request() {
+                self.recover();
+            }
+            if self.queue.is_none() {
+                return Err(());
+            }
+            core::hint::spin_loop();
+        };
 
         /* デバイスに通知 */
         Self::write_register(self.base_address, VIRTIO_MMIO_QUEUE_NOTIFY, 0);
 
-        /* Spin Wait */
-        let result;
+        /* Spin Wait(先に完了した非同期の要求は completed へ移す) */
         loop {
-            unsafe { crate::asm::invalidate_cache(&status as *const _ as usize) };
-            let s = unsafe { core::ptr::read_volatile(&status) };
-            if s != 0xFF {
-                if s == VIRTIO_BLK_S_OK {
-                    result = Ok(());
-                } else {
-                    result = Err(());
+            match self.pop_completed() {
+                Some((id, _, result)) if id == head => return result,
+                Some((_, Some(token), result)) => self.completed.push((token, result)),
+                _ => {
+                    if self.has_timed_out_request() {
+                        /* リセットによりこの要求も失敗する */
+                        self.recover();
+                        return Err(());
+                    }
+                    core::hint::spin_loop()
                 }
-                break;
             }
-            core::hint::spin_loop();
         }
-        self.free_descriptor(first_idx);
-        self.free_descriptor(second_idx);
-        self.free_descriptor(third_idx);
+    }
+
+    /// 要求をデバイスへ渡し、完了を待たずに戻る
+    ///
+    /// Descriptor が足りない場合は空くまで保持する。完了した要求は take_completed で取り出す
+    pub fn submit(&mut self, request: AsyncRequest) -> Result<(), ()> {
+        self.check_request(&request)?;
+        self.waiting.push_back(request);
+        self.submit_waiting();
+        Ok(())
+    }
+
+    /// 割り込みを受け付け、完了した非同期の要求の(識別子, 結果)を全て取り出す
+    ///
+    /// 期限を過ぎた要求がある場合はデバイスをリセットし、処理中の要求を失敗として返す
+    pub fn take_completed(&mut self) -> Vec<(u64, Result<(), ()>)> {
+        if self.queue.is_some() {
+            let interrupt_status =
+                Self::read_register(self.base_address, VIRTIO_MMIO_INTERRUPT_STATUS);
+            Self::write_register(
+                self.base_address,
+                VIRTIO_MMIO_INTERRUPT_ACK,
+                interrupt_status,
+            );
+            self.collect_completed();
+            if self.has_timed_out_request() {
+                self.recover();
+            }
+        }
+        self.submit_waiting();
+        core::mem::take(&mut self.completed)
+    }
+
+    /// キャッシュの書き戻し要求に対応しているか
+    pub fn is_flush_supported(&self) -> bool {
+        self.is_flush_supported
+    }
+
+    /// 1つの要求で使えるデータ部分の最大数
+    pub fn get_max_segments(&self) -> usize {
+        self.max_segments
+    }
+
+    pub fn get_base_address(&self) -> usize {
+        self.base_address
+    }
+
+    /// 完了していない要求の数
+    pub fn get_number_of_inflight_requests(&self) -> usize {
+        self.pending.len() + self.waiting.len()
+    }
+
+    /// セクタ数で表したディスクの大きさ
+    pub fn get_capacity(&self) -> u64 {
+        /* struct virtio_blk_config の capacity */
+        let low = Self::read_register(self.base_address, VIRTIO_CONFIG_OFFSET) as u64;
+        let high = Self::read_register(self.base_address, VIRTIO_CONFIG_OFFSET + 4) as u64;
+        (high << 32) | low
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn read(&mut self, buffer_address: usize, block_address: u64, length: u64) -> Result<(), ()> {
+        self.operation_sync(AsyncRequest {
+            req_type: VIRTIO_BLK_TYPE_IN,
+            buffers: vec![(buffer_address, length as usize)],
+            block_address,
+            token: 0,
+        })
+    }
 
-        result
+    fn write(&mut self, buffer_address: usize, block_address: u64, length: u64) -> Result<(), ()> {
+        self.operation_sync(AsyncRequest {
+            req_type: VIRTIO_BLK_TYPE_OUT,
+            buffers: vec![(buffer_address, length as usize)],
+            block_address,
+            token: 0,
+        })
     }
 
-    pub fn read(
-        &mut self,
-        buffer_address: usize,
-        block_address: u64,
-        length: u64,
-    ) -> Result<(), ()> {
-        self.operation_sync(buffer_address, block_address, length, false)
+    /// デバイスのキャッシュを書き戻す(対応していない場合は何もしない)
+    fn flush(&mut self) -> Result<(), ()> {
+        if !self.is_flush_supported {
+            return Ok(());
+        }
+        self.operation_sync(AsyncRequest {
+            req_type: VIRTIO_BLK_TYPE_FLUSH,
+            buffers: Vec::new(),
+            block_address: 0,
+            token: 0,
+        })
     }
 
-    pub fn write(
-        &mut self,
-        buffer_address: usize,
-        block_address: u64,
-        length: u64,
-    ) -> Result<(), ()> {
-        self.operation_sync(buffer_address, block_address, length, true)
+    fn get_size(&self) -> u64 {
+        self.get_capacity() << 9
+    }
+
+    fn get_block_size(&self) -> usize {
+        512
     }
 }
 
-unsafe impl core::marker::Send for VirtioBlk {}
+/// 起動ディスク以外のホストのディスク(読み書きの度にロックを取る)
+impl BlockDevice for &Mutex<VirtioBlk> {
+    fn read(&mut self, buffer_address: usize, block_address: u64, length: u64) -> Result<(), ()> {
+        self.lock().read(buffer_address, block_address, length)
+    }
+
+    fn write(&mut self, buffer_address: usize, block_address: u64, length: u64) -> Result<(), ()> {
+        self.lock().write(buffer_address, block_address, length)
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        self.lock().flush()
+    }
+
+    fn get_size(&self) -> u64 {
+        self.lock().get_size()
+    }
+
+    fn get_block_size(&self) -> usize {
+        self.lock().get_block_size()
+    }
+}
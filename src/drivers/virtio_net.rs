@@ -0,0 +1,244 @@
+//!
+//! Virtio-Netの実装
+//!
+//! 受信用と送信用の Virtqueue を1つずつ使う。
+//! 各 Descriptor Chain には予め確保したフレーム1つ分のバッファを割り当てる。
+//!
+
+use crate::drivers::virtio::*;
+use crate::virtqueue::{DriverBuffer, DriverQueue};
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::ptr::{read_volatile, write_volatile};
+
+/// MAC アドレスが設定領域にあるかどうか
+const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+/// virtio_net_hdr のサイズ(VIRTIO_NET_F_MRG_RXBUF を使わない場合)
+const VIRTIO_NET_HDR_SIZE: usize = 10;
+/// 1つのバッファのサイズ(virtio_net_hdr + 最大のフレーム)
+const BUFFER_SIZE: usize = 2048;
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+/// 各 Virtqueue の Descriptor の数の上限(送信用のバッファの空きを u64 で管理するため 64 まで)
+const QUEUE_SIZE: usize = 64;
+
+struct Queue {
+    queue: DriverQueue,
+    buffers: usize,
+    /// 各 Descriptor Chain に割り当てたバッファの番号
+    buffer_of_chain: Vec<usize>,
+}
+
+pub struct VirtioNet {
+    base_address: usize,
+    mac_address: Option<[u8; 6]>,
+    rx: Queue,
+    tx: Queue,
+    /// 送信用のバッファの空き(ビットが立っているものが空き)
+    tx_free_bitmap: u64,
+}
+
+impl VirtioNet {
+    pub fn new(base_address: usize) -> Result<Self, ()> {
+        if Self::read_register(base_address, VIRTIO_MMIO_MAGIC) != VIRTIO_MMIO_MAGIC_VALUE {
+            return Err(());
+        }
+        if Self::read_register(base_address, VIRTIO_MMIO_VERSION) != 1 {
+            return Err(());
+        }
+        if Self::read_register(base_address, VIRTIO_MMIO_DEVICE_ID) != 1
+            || Self::read_register(base_address, VIRTIO_MMIO_VENDOR_ID) != 0x554d4551
+        {
+            return Err(());
+        }
+        /* デバイスのリセット */
+        Self::write_register(base_address, VIRTIO_MMIO_STATUS, 0);
+        /* デバイスを認識した事を通知 */
+        Self::write_register(
+            base_address,
+            VIRTIO_MMIO_STATUS,
+            Self::read_register(base_address, VIRTIO_MMIO_STATUS)
+                | VIRTIO_DEVICE_STATUS_ACKNOWLEDGE,
+        );
+        Self::write_register(
+            base_address,
+            VIRTIO_MMIO_STATUS,
+            Self::read_register(base_address, VIRTIO_MMIO_STATUS) | VIRTIO_DEVICE_STATUS_DRIVER,
+        );
+
+        /* MAC アドレスのみ使用する */
+        let features =
+            Self::read_register(base_address, VIRTIO_MMIO_DEVICE_FEATURES) & VIRTIO_NET_F_MAC;
+        Self::write_register(base_address, VIRTIO_MMIO_DRIVER_FEATURES, features);
+        Self::write_register(
+            base_address,
+            VIRTIO_MMIO_STATUS,
+            Self::read_register(base_address, VIRTIO_MMIO_STATUS)
+                | VIRTIO_DEVICE_STATUS_FEATURES_OK,
+        );
+        let mac_address = if features != 0 {
+            let mut mac_address = [0u8; 6];
+            for (i, m) in mac_address.iter_mut().enumerate() {
+                *m = unsafe {
+                    read_volatile((base_address + VIRTIO_CONFIG_OFFSET + i) as *const u8)
+                };
+            }
+            Some(mac_address)
+        } else {
+            None
+        };
+
+        /* VirtQueueの設定 */
+        Self::write_register(
+            base_address,
+            VIRTIO_MMIO_GUEST_PAGE_SIZE,
+            VIRTIO_PAGE_SIZE as u32,
+        );
+        let mut rx = Self::setup_queue(base_address, RX_QUEUE)?;
+        let tx = Self::setup_queue(base_address, TX_QUEUE)?;
+
+        /* 設定完了を通知 */
+        Self::write_register(
+            base_address,
+            VIRTIO_MMIO_STATUS,
+            Self::read_register(base_address, VIRTIO_MMIO_STATUS) | VIRTIO_DEVICE_STATUS_DRIVER_OK,
+        );
+
+        /* 全ての受信用バッファをデバイスへ渡す */
+        for i in 0..rx.queue.get_size() {
+            rx.add_buffer(i, BUFFER_SIZE, true);
+        }
+        Self::write_register(base_address, VIRTIO_MMIO_QUEUE_NOTIFY, RX_QUEUE);
+
+        let tx_free_bitmap = u64::MAX >> (u64::BITS as usize - tx.queue.get_size());
+        Ok(Self {
+            base_address,
+            mac_address,
+            rx,
+            tx,
+            tx_free_bitmap,
+        })
+    }
+
+    fn setup_queue(base_address: usize, index: u32) -> Result<Queue, ()> {
+        Self::write_register(base_address, VIRTIO_MMIO_QUEUE_SEL, index);
+        let queue_max = Self::read_register(base_address, VIRTIO_MMIO_QUEUE_NUM_MAX);
+        if queue_max == 0 {
+            println!("Virtio Queue Size is invalid: {queue_max}");
+            return Err(());
+        }
+        let queue = DriverQueue::new((queue_max as usize).min(QUEUE_SIZE))?;
+        let buffers = crate::allocate_pages(
+            (queue.get_size() * BUFFER_SIZE).div_ceil(VIRTIO_PAGE_SIZE),
+            VIRTIO_PAGE_SHIFT,
+        )
+        .map_err(|_| ())?;
+        Self::write_register(base_address, VIRTIO_MMIO_QUEUE_NUM, queue.get_size() as u32);
+        Self::write_register(base_address, VIRTIO_MMIO_QUEUE_PFN, queue.get_pfn());
+        Ok(Queue {
+            buffer_of_chain: vec![0; queue.get_size()],
+            queue,
+            buffers,
+        })
+    }
+
+    fn read_register(base_address: usize, offset: usize) -> u32 {
+        unsafe { read_volatile((base_address + offset) as *const u32) }
+    }
+
+    fn write_register(base_address: usize, offset: usize, data: u32) {
+        unsafe { write_volatile((base_address + offset) as *mut u32, data) }
+    }
+
+    pub fn get_mac_address(&self) -> Option<[u8; 6]> {
+        self.mac_address
+    }
+
+    /// 割り込みを受け付け、受信したフレームを全て取り出す
+    pub fn receive(&mut self) -> Vec<Vec<u8>> {
+        let interrupt_status = Self::read_register(self.base_address, VIRTIO_MMIO_INTERRUPT_STATUS);
+        Self::write_register(
+            self.base_address,
+            VIRTIO_MMIO_INTERRUPT_ACK,
+            interrupt_status,
+        );
+
+        let mut frames = Vec::new();
+        while let Some((id, length)) = self.rx.queue.pop_used() {
+            let buffer_index = self.rx.buffer_of_chain[id as usize];
+            let length = (length as usize).min(BUFFER_SIZE);
+            if length > VIRTIO_NET_HDR_SIZE {
+                let buffer = self.rx.buffers + buffer_index * BUFFER_SIZE;
+                frames.push(
+                    unsafe { core::slice::from_raw_parts(buffer as *const u8, length) }
+                        [VIRTIO_NET_HDR_SIZE..]
+                        .to_vec(),
+                );
+            }
+            /* バッファをデバイスへ戻す */
+            self.rx.add_buffer(buffer_index, BUFFER_SIZE, true);
+        }
+        if !frames.is_empty() {
+            Self::write_register(self.base_address, VIRTIO_MMIO_QUEUE_NOTIFY, RX_QUEUE);
+        }
+        frames
+    }
+
+    /// 送信が完了したバッファを回収する
+    fn reclaim_tx_buffers(&mut self) {
+        while let Some((id, _)) = self.tx.queue.pop_used() {
+            self.tx_free_bitmap |= 1 << self.tx.buffer_of_chain[id as usize];
+        }
+    }
+
+    /// フレームを送信する(完了は待たない)
+    ///
+    /// 空いているバッファが無い場合は破棄する
+    pub fn transmit(&mut self, frame: &[u8]) -> Result<(), ()> {
+        if frame.len() > BUFFER_SIZE - VIRTIO_NET_HDR_SIZE {
+            return Err(());
+        }
+        self.reclaim_tx_buffers();
+        if self.tx_free_bitmap == 0 {
+            return Err(());
+        }
+        let id = self.tx_free_bitmap.trailing_zeros() as usize;
+        self.tx_free_bitmap &= !(1 << id);
+
+        let buffer = self.tx.buffers + id * BUFFER_SIZE;
+        unsafe {
+            core::ptr::write_bytes(buffer as *mut u8, 0, VIRTIO_NET_HDR_SIZE);
+            core::ptr::copy_nonoverlapping(
+                frame.as_ptr(),
+                (buffer + VIRTIO_NET_HDR_SIZE) as *mut u8,
+                frame.len(),
+            );
+        }
+        if !self
+            .tx
+            .add_buffer(id, VIRTIO_NET_HDR_SIZE + frame.len(), false)
+        {
+            self.tx_free_bitmap |= 1 << id;
+            return Err(());
+        }
+        Self::write_register(self.base_address, VIRTIO_MMIO_QUEUE_NOTIFY, TX_QUEUE);
+        Ok(())
+    }
+}
+
+impl Queue {
+    /// buffer_index 番目のバッファの先頭 length バイトをデバイスへ渡す
+    fn add_buffer(&mut self, buffer_index: usize, length: usize, is_writable: bool) -> bool {
+        let Some(id) = self.queue.add(&[DriverBuffer {
+            address: self.buffers + buffer_index * BUFFER_SIZE,
+            length: length as u32,
+            is_writable,
+        }]) else {
+            return false;
+        };
+        self.buffer_of_chain[id as usize] = buffer_index;
+        true
+    }
+}
@@ -17,6 +17,8 @@ pub struct Dtb {
 }
 
 pub struct DtbNode {
+    /* FDT_BEGIN_NODE の位置 */
+    begin_address: usize,
     /* node's name の直後の位置 */
     address: usize,
     address_cells: u32,
@@ -223,9 +225,11 @@ impl Dtb {
         if *self.read_node(*pointer)? != Self::FDT_BEGIN_NODE {
             return Err(());
         }
+        let begin_address = *pointer;
         *pointer += Self::FDT_TOKEN_BYTE;
         if self.compare_string(pointer, node_name, b"@")? {
             return Ok(Some(DtbNode {
+                begin_address,
                 address: *pointer,
                 address_cells,
                 size_cells,
@@ -308,6 +312,7 @@ impl Dtb {
         if *self.read_node(*pointer)? != Self::FDT_BEGIN_NODE {
             return Err(());
         }
+        let begin_address = *pointer;
         *pointer += Self::FDT_TOKEN_BYTE;
         while unsafe { *(*pointer as *const u8) } != b'\0' {
             *pointer += 1;
@@ -357,6 +362,7 @@ impl Dtb {
                         };
                         if self._is_device_compatible(&compatible_prop, compatible) {
                             return Ok(Some(DtbNode {
+                                begin_address,
                                 address: temporary_pointer,
                                 address_cells,
                                 size_cells,
@@ -520,6 +526,40 @@ impl Dtb {
         Some((address, size))
     }
 
+    /// reg の index 番目の組を書き換える(DTB がハイパーバイザの書き込めるメモリにあること)
+    pub fn write_reg_property(
+        &self,
+        node: &DtbNode,
+        index: usize,
+        address: usize,
+        size: usize,
+    ) -> Result<(), ()> {
+        let info = self.get_property(node, &Self::PROP_REG).ok_or(())?;
+        let offset = ((info.address_cells + info.size_cells) as usize) * 4 * index;
+        if offset + ((info.address_cells + info.size_cells) as usize) * 4 > info.len as usize {
+            return Err(());
+        }
+        let mut p = info.address + offset;
+        for (value, cells) in [(address, info.address_cells), (size, info.size_cells)] {
+            for i in (0..(cells * 4)).rev() {
+                let byte = value.checked_shr(i * 8).unwrap_or(0) as u8;
+                unsafe { *(p as *mut u8) = byte };
+                p += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// node を子ノードも含めて FDT_NOP で埋め、無かったことにする
+    pub fn remove_node(&self, node: &DtbNode) -> Result<(), ()> {
+        let mut end = node.address;
+        self._skip_to_next_node(&mut end)?;
+        for p in (node.begin_address..end).step_by(Self::FDT_TOKEN_BYTE) {
+            unsafe { *(p as *mut [u8; Self::FDT_TOKEN_BYTE]) = Self::FDT_NOP };
+        }
+        Ok(())
+    }
+
     pub fn read_property_as_u8_array(&self, info: &DtbProperty) -> &[u8] {
         unsafe {
             core::slice::from_raw_parts(
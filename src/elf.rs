@@ -9,6 +9,9 @@ const ELF_SUPPORTED_VERSION: u32 = 1;
 
 pub const ELF_PROGRAM_HEADER_SEGMENT_LOAD: u32 = 0x01;
 
+const ELF_SECTION_HEADER_SYMTAB: u32 = 0x02;
+const ELF_SYMBOL_TYPE_FUNC: u8 = 0x02;
+
 #[repr(C)]
 pub struct Elf64Header {
     e_ident: [u8; 16],
@@ -45,6 +48,42 @@ pub struct Elf64ProgramHeaderIter {
     remaining: u16,
 }
 
+#[repr(C)]
+pub struct Elf64SectionHeader {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: u64,
+    sh_addr: u64,
+    sh_offset: u64,
+    sh_size: u64,
+    sh_link: u32,
+    sh_info: u32,
+    sh_addralign: u64,
+    sh_entsize: u64,
+}
+
+pub struct Elf64SectionHeaderIter {
+    pointer: usize,
+    size: u16,
+    remaining: u16,
+}
+
+#[repr(C)]
+pub struct Elf64Symbol {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+/// ELFファイル内のシンボルテーブル(.symtab)と文字列テーブル
+pub struct SymbolTable {
+    symbols: &'static [Elf64Symbol],
+    strings: &'static [u8],
+}
+
 impl Elf64Header {
     pub fn new(address: usize) -> Result<&'static Self, ()> {
         let s = unsafe { &*(address as *const Self) };
@@ -77,6 +116,28 @@ impl Elf64Header {
             remaining: self.get_num_of_program_header(),
         }
     }
+
+    pub fn get_section_headers(&self) -> Elf64SectionHeaderIter {
+        Elf64SectionHeaderIter {
+            pointer: self as *const _ as usize + self.e_shoff as usize,
+            size: self.e_shentsize,
+            remaining: if self.e_shoff == 0 { 0 } else { self.e_shnum },
+        }
+    }
+
+    fn get_section_header(&self, index: usize) -> Option<&'static Elf64SectionHeader> {
+        self.get_section_headers().nth(index)
+    }
+
+    /// セクションの内容を返す
+    fn get_section_data(&self, section: &Elf64SectionHeader) -> &'static [u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                (self as *const _ as usize + section.sh_offset as usize) as *const u8,
+                section.sh_size as usize,
+            )
+        }
+    }
 }
 
 impl Iterator for Elf64ProgramHeaderIter {
@@ -94,6 +155,21 @@ impl Iterator for Elf64ProgramHeaderIter {
     }
 }
 
+impl Iterator for Elf64SectionHeaderIter {
+    type Item = &'static Elf64SectionHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            None
+        } else {
+            let r = unsafe { &*(self.pointer as *const Elf64SectionHeader) };
+            self.pointer += self.size as usize;
+            self.remaining -= 1;
+            Some(r)
+        }
+    }
+}
+
 impl Elf64ProgramHeader {
     pub const fn get_segment_type(&self) -> u32 {
         self.p_type
@@ -107,3 +183,59 @@ impl Elf64ProgramHeader {
         self.p_memsz
     }
 }
+
+impl SymbolTable {
+    /// ELFファイルからシンボルテーブルを探す
+    ///
+    /// ELFファイルがstripされている場合は None を返す
+    pub fn new(elf_header: &'static Elf64Header) -> Option<Self> {
+        let symbol_table_section = elf_header
+            .get_section_headers()
+            .find(|s| s.sh_type == ELF_SECTION_HEADER_SYMTAB)?;
+        if symbol_table_section.sh_entsize as usize != size_of::<Elf64Symbol>() {
+            return None;
+        }
+        let string_table_section =
+            elf_header.get_section_header(symbol_table_section.sh_link as usize)?;
+        let symbols = elf_header.get_section_data(symbol_table_section);
+        Some(Self {
+            symbols: unsafe {
+                core::slice::from_raw_parts(
+                    symbols.as_ptr() as *const Elf64Symbol,
+                    symbols.len() / size_of::<Elf64Symbol>(),
+                )
+            },
+            strings: elf_header.get_section_data(string_table_section),
+        })
+    }
+
+    /// シンボルテーブルと文字列テーブルが置かれている領域((開始アドレス, サイズ))
+    pub fn get_memory_regions(&self) -> [(usize, usize); 2] {
+        [
+            (self.symbols.as_ptr() as usize, size_of_val(self.symbols)),
+            (self.strings.as_ptr() as usize, self.strings.len()),
+        ]
+    }
+
+    fn get_name(&self, symbol: &Elf64Symbol) -> &'static str {
+        let Some(name) = self.strings.get(symbol.st_name as usize..) else {
+            return "";
+        };
+        let length = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        core::str::from_utf8(&name[0..length]).unwrap_or("")
+    }
+
+    /// アドレスを含む関数のシンボル名と、関数の先頭からのオフセットを返す
+    pub fn lookup(&self, address: usize) -> Option<(&'static str, usize)> {
+        let address = address as u64;
+        self.symbols
+            .iter()
+            .filter(|s| {
+                (s.st_info & 0xf) == ELF_SYMBOL_TYPE_FUNC
+                    && s.st_value <= address
+                    && address < s.st_value + s.st_size.max(1)
+            })
+            .max_by_key(|s| s.st_value)
+            .map(|s| (self.get_name(s), (address - s.st_value) as usize))
+    }
+}
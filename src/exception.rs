@@ -2,15 +2,20 @@
 //! 割り込み制御
 //!
 use crate::asm;
+use crate::crash;
 use crate::drivers::{generic_timer, gicv3::*};
-use crate::mmio::gicv3;
+use crate::gdb;
+use crate::hypercall;
+use crate::mmio::{gicv3, virtio_blk};
 use crate::registers::*;
 use crate::vgic;
-use crate::vm;
+use crate::vm::{self, VM, VmError};
+use crate::vswitch;
 
-use core::arch::global_asm;
+use core::arch::{asm, global_asm};
 
 #[repr(C)]
+#[derive(Clone, Default)]
 pub struct Registers {
     pub x0: u64,
     pub x1: u64,
@@ -46,6 +51,21 @@ pub struct Registers {
     padding: u64,
 }
 
+impl Registers {
+    pub fn as_array(&self) -> &[u64; 32] {
+        unsafe { &*(self as *const _ as usize as *const [u64; 32]) }
+    }
+
+    pub fn as_mut_array(&mut self) -> &mut [u64; 32] {
+        unsafe { &mut *(self as *mut _ as usize as *mut [u64; 32]) }
+    }
+}
+
+/* fault_handler と lower_el_fault_handler へ渡す例外の種類 */
+const EXCEPTION_SYNCHRONOUS: u64 = 0;
+const EXCEPTION_FIQ: u64 = 1;
+const EXCEPTION_S_ERROR: u64 = 2;
+
 /* 例外テーブル */
 global_asm!(
     "
@@ -73,7 +93,26 @@ s_error_current_el_stack_pointer_0:
 
 .balign 0x080
 synchronous_current_el_stack_pointer_x:
-    b   synchronous_current_el_stack_pointer_x
+    sub sp,   sp, #(8 * 32)
+    stp x30, xzr, [sp, #( 15 * 16)]
+    stp x28, x29, [sp, #( 14 * 16)]
+    stp x26, x27, [sp, #( 13 * 16)]
+    stp x24, x25, [sp, #( 12 * 16)]
+    stp x22, x23, [sp, #( 11 * 16)]
+    stp x20, x21, [sp, #( 10 * 16)]
+    stp x18, x19, [sp, #(  9 * 16)]
+    stp x16, x17, [sp, #(  8 * 16)]
+    stp x14, x15, [sp, #(  7 * 16)]
+    stp x12, x13, [sp, #(  6 * 16)]
+    stp x10, x11, [sp, #(  5 * 16)]
+    stp  x8,  x9, [sp, #(  4 * 16)]
+    stp  x6,  x7, [sp, #(  3 * 16)]
+    stp  x4,  x5, [sp, #(  2 * 16)]
+    stp  x2,  x3, [sp, #(  1 * 16)]
+    stp  x0,  x1, [sp, #(  0 * 16)]
+    mov  x0, sp
+    mov  x1, #{EXCEPTION_SYNCHRONOUS}
+    b   {fault_handler}
 
 .balign 0x080
 irq_current_el_stack_pointer_x:
@@ -100,11 +139,49 @@ irq_current_el_stack_pointer_x:
 
 .balign 0x080
 fiq_current_el_stack_pointer_x:
-    b   fiq_current_el_stack_pointer_x
+    sub sp,   sp, #(8 * 32)
+    stp x30, xzr, [sp, #( 15 * 16)]
+    stp x28, x29, [sp, #( 14 * 16)]
+    stp x26, x27, [sp, #( 13 * 16)]
+    stp x24, x25, [sp, #( 12 * 16)]
+    stp x22, x23, [sp, #( 11 * 16)]
+    stp x20, x21, [sp, #( 10 * 16)]
+    stp x18, x19, [sp, #(  9 * 16)]
+    stp x16, x17, [sp, #(  8 * 16)]
+    stp x14, x15, [sp, #(  7 * 16)]
+    stp x12, x13, [sp, #(  6 * 16)]
+    stp x10, x11, [sp, #(  5 * 16)]
+    stp  x8,  x9, [sp, #(  4 * 16)]
+    stp  x6,  x7, [sp, #(  3 * 16)]
+    stp  x4,  x5, [sp, #(  2 * 16)]
+    stp  x2,  x3, [sp, #(  1 * 16)]
+    stp  x0,  x1, [sp, #(  0 * 16)]
+    mov  x0, sp
+    mov  x1, #{EXCEPTION_FIQ}
+    b   {fault_handler}
 
 .balign 0x080
 s_error_current_el_stack_pointer_x:
-    b   s_error_current_el_stack_pointer_x
+    sub sp,   sp, #(8 * 32)
+    stp x30, xzr, [sp, #( 15 * 16)]
+    stp x28, x29, [sp, #( 14 * 16)]
+    stp x26, x27, [sp, #( 13 * 16)]
+    stp x24, x25, [sp, #( 12 * 16)]
+    stp x22, x23, [sp, #( 11 * 16)]
+    stp x20, x21, [sp, #( 10 * 16)]
+    stp x18, x19, [sp, #(  9 * 16)]
+    stp x16, x17, [sp, #(  8 * 16)]
+    stp x14, x15, [sp, #(  7 * 16)]
+    stp x12, x13, [sp, #(  6 * 16)]
+    stp x10, x11, [sp, #(  5 * 16)]
+    stp  x8,  x9, [sp, #(  4 * 16)]
+    stp  x6,  x7, [sp, #(  3 * 16)]
+    stp  x4,  x5, [sp, #(  2 * 16)]
+    stp  x2,  x3, [sp, #(  1 * 16)]
+    stp  x0,  x1, [sp, #(  0 * 16)]
+    mov  x0, sp
+    mov  x1, #{EXCEPTION_S_ERROR}
+    b   {fault_handler}
 
 .balign 0x080
 synchronous_lower_el_aarch64:
@@ -150,15 +227,53 @@ irq_lower_el_aarch64:
     stp  x0,  x1, [sp, #(  0 * 16)]
     mov  x0, sp
     adr x30, exit_exception
-    b   {irq_handler}
+    b   {irq_lower_el_handler}
 
 .balign 0x080
 fiq_lower_el_aarch64:
-    b   fiq_lower_el_aarch64
+    sub sp,   sp, #(8 * 32)
+    stp x30, xzr, [sp, #( 15 * 16)]
+    stp x28, x29, [sp, #( 14 * 16)]
+    stp x26, x27, [sp, #( 13 * 16)]
+    stp x24, x25, [sp, #( 12 * 16)]
+    stp x22, x23, [sp, #( 11 * 16)]
+    stp x20, x21, [sp, #( 10 * 16)]
+    stp x18, x19, [sp, #(  9 * 16)]
+    stp x16, x17, [sp, #(  8 * 16)]
+    stp x14, x15, [sp, #(  7 * 16)]
+    stp x12, x13, [sp, #(  6 * 16)]
+    stp x10, x11, [sp, #(  5 * 16)]
+    stp  x8,  x9, [sp, #(  4 * 16)]
+    stp  x6,  x7, [sp, #(  3 * 16)]
+    stp  x4,  x5, [sp, #(  2 * 16)]
+    stp  x2,  x3, [sp, #(  1 * 16)]
+    stp  x0,  x1, [sp, #(  0 * 16)]
+    mov  x0, sp
+    mov  x1, #{EXCEPTION_FIQ}
+    b   {lower_el_fault_handler}
 
 .balign 0x080
 s_error_lower_el_aarch64:
-    b   s_error_lower_el_aarch64
+    sub sp,   sp, #(8 * 32)
+    stp x30, xzr, [sp, #( 15 * 16)]
+    stp x28, x29, [sp, #( 14 * 16)]
+    stp x26, x27, [sp, #( 13 * 16)]
+    stp x24, x25, [sp, #( 12 * 16)]
+    stp x22, x23, [sp, #( 11 * 16)]
+    stp x20, x21, [sp, #( 10 * 16)]
+    stp x18, x19, [sp, #(  9 * 16)]
+    stp x16, x17, [sp, #(  8 * 16)]
+    stp x14, x15, [sp, #(  7 * 16)]
+    stp x12, x13, [sp, #(  6 * 16)]
+    stp x10, x11, [sp, #(  5 * 16)]
+    stp  x8,  x9, [sp, #(  4 * 16)]
+    stp  x6,  x7, [sp, #(  3 * 16)]
+    stp  x4,  x5, [sp, #(  2 * 16)]
+    stp  x2,  x3, [sp, #(  1 * 16)]
+    stp  x0,  x1, [sp, #(  0 * 16)]
+    mov  x0, sp
+    mov  x1, #{EXCEPTION_S_ERROR}
+    b   {lower_el_fault_handler}
 
 .balign 0x080
 synchronous_lower_el_aarch32:
@@ -176,6 +291,7 @@ fiq_lower_el_aarch32:
 s_error_lower_el_aarch32:
     b   s_error_lower_el_aarch32
 
+.global exit_exception
 exit_exception:
     ldp x30, xzr, [sp, #( 15 * 16)]
     ldp x28, x29, [sp, #( 14 * 16)]
@@ -197,6 +313,12 @@ exit_exception:
     eret
 ",
 irq_handler = sym irq_handler,
+fault_handler = sym fault_handler,
+lower_el_fault_handler = sym lower_el_fault_handler,
+EXCEPTION_SYNCHRONOUS = const EXCEPTION_SYNCHRONOUS,
+EXCEPTION_FIQ = const EXCEPTION_FIQ,
+EXCEPTION_S_ERROR = const EXCEPTION_S_ERROR,
+irq_lower_el_handler = sym irq_lower_el_handler,
 synchronous_handler = sym synchronous_handler,
 );
 
@@ -208,20 +330,104 @@ pub fn setup_exception() {
     unsafe { asm::set_icc_ctlr_el1(asm::get_icc_ctlr_el1() | ICC_CTLR1_EL1_EOI_MODE) };
 }
 
+/// 保存されたレジスタを読み込んで下位のELへ復帰する
+pub fn return_to_lower_el(registers: &Registers) -> ! {
+    unsafe {
+        asm!("
+            mov sp, {}
+            b   exit_exception",
+            in(reg) registers as *const _ as usize,
+            options(noreturn)
+        )
+    }
+}
+
+/// ハイパーバイザ(EL2)で発生した処理できない例外の情報を出力して停止する
+extern "C" fn fault_handler(registers: *const Registers, kind: u64) -> ! {
+    let name = match kind {
+        EXCEPTION_SYNCHRONOUS => "Synchronous Exception from EL2",
+        EXCEPTION_FIQ => "FIQ from EL2",
+        _ => "SError from EL2",
+    };
+    crash::report_exception(name, unsafe { &*registers })
+}
+
+/// 仮想マシンの実行中に発生した FIQ と SError を処理する
+///
+/// ハイパーバイザ全体は停止せず、原因となった仮想マシンのみを停止する
+extern "C" fn lower_el_fault_handler(_registers: *const Registers, kind: u64) -> ! {
+    let error = if kind == EXCEPTION_FIQ {
+        VmError::UnexpectedFiq
+    } else {
+        VmError::SError {
+            esr_el2: asm::get_esr_el2(),
+        }
+    };
+    vm::crash_current_vm(error)
+}
+
 extern "C" fn synchronous_handler(registers: *mut Registers) {
+    let registers = unsafe { &mut *registers };
+    if let Err(e) =
+        handle_synchronous_exception(registers).and_then(|_| vm::handle_vcpu_request(registers))
+    {
+        vm::crash_current_vm(e);
+    }
+}
+
+fn handle_synchronous_exception(registers: &mut Registers) -> Result<(), VmError> {
     let esr_el2 = asm::get_esr_el2();
     let ec = esr_el2 & ESR_EL2_EC;
     match ec {
-        ESR_EL2_EC_DATA_ABORT => data_abort_handler(unsafe { &mut *registers }, esr_el2),
-        _ => {
-            panic!("Unknown Exception: {}", ec >> ESR_EL2_EC_BITS_OFFSET);
+        ESR_EL2_EC_DATA_ABORT => data_abort_handler(registers, esr_el2),
+        ESR_EL2_EC_INSTRUCTION_ABORT => instruction_abort_handler(esr_el2),
+        ESR_EL2_EC_HVC64 => hypercall::hvc_handler(registers),
+        ESR_EL2_EC_SYSTEM_REGISTER => system_register_handler(registers, esr_el2),
+        ESR_EL2_EC_BREAKPOINT
+        | ESR_EL2_EC_SOFTWARE_STEP
+        | ESR_EL2_EC_WATCHPOINT
+        | ESR_EL2_EC_BRK => gdb::handle_debug_exception(esr_el2),
+        _ => Err(VmError::UnknownException { esr_el2 }),
+    }
+}
+
+fn get_fault_intermediate_physical_address() -> usize {
+    ((((asm::get_hpfar_el2() & HPFAR_EL2_FIPA) >> HPFAR_EL2_FIPA_BITS_OFFSET)
+        << crate::paging::PAGE_SHIFT)
+        | (asm::get_far_el2() & ((1 << crate::paging::PAGE_SHIFT) - 1))) as usize
+}
+
+/// RAMへのアクセスで発生した Stage 2 Fault を処理する
+///
+/// 処理済みの場合は true を返し、命令を再実行させる
+fn handle_ram_fault(vm: &VM, esr_el2: u64, address: usize) -> bool {
+    match esr_el2 & ESR_EL2_ISS_FSC_TYPE {
+        ESR_EL2_ISS_FSC_PERMISSION_FAULT => vm.handle_dirty_log_fault(address),
+        ESR_EL2_ISS_FSC_TRANSLATION_FAULT => {
+            /* Block descriptor の分割中に発生した Fault は再実行すればよい */
+            vm.get_physical_address(address).is_some()
         }
+        _ => false,
     }
 }
 
-fn data_abort_handler(registers: &mut Registers, esr_el2: u64) {
+fn instruction_abort_handler(esr_el2: u64) -> Result<(), VmError> {
+    let address = get_fault_intermediate_physical_address();
+    let vm = vm::get_current_vm()?;
+    if !handle_ram_fault(&vm, esr_el2, address) {
+        return Err(VmError::UnhandledAbort { address, esr_el2 });
+    }
+    Ok(())
+}
+
+fn data_abort_handler(registers: &mut Registers, esr_el2: u64) -> Result<(), VmError> {
+    let address = get_fault_intermediate_physical_address();
+    let vm = vm::get_current_vm()?;
+    if handle_ram_fault(&vm, esr_el2, address) {
+        return Ok(());
+    }
     if esr_el2 & ESR_EL2_ISS_ISV == 0 {
-        panic!("Data Abort Info is not available.");
+        return Err(VmError::NoSyndrome { address });
     }
     let is_64bit_register = (esr_el2 & ESR_EL2_ISS_SF) != 0;
     let access_width = match (esr_el2 & ESR_EL2_ISS_SAS) >> ESR_EL2_ISS_SAS_BITS_OFFSET {
@@ -234,12 +440,7 @@ fn data_abort_handler(registers: &mut Registers, esr_el2: u64) {
     let is_write_access = (esr_el2 & ESR_EL2_ISS_WNR) != 0;
 
     let register_number = ((esr_el2 & ESR_EL2_ISS_SRT) >> ESR_EL2_ISS_SRT_BITS_OFFSET) as usize;
-    let register: &mut u64 =
-        &mut unsafe { &mut *(registers as *mut _ as usize as *mut [u64; 32]) }[register_number];
-
-    let address = (((asm::get_hpfar_el2() & HPFAR_EL2_FIPA) >> HPFAR_EL2_FIPA_BITS_OFFSET)
-        << crate::paging::PAGE_SHIFT)
-        | (asm::get_far_el2() & ((1 << crate::paging::PAGE_SHIFT) - 1));
+    let register: &mut u64 = &mut registers.as_mut_array()[register_number];
 
     if is_write_access {
         let register_value = if is_64bit_register {
@@ -247,16 +448,25 @@ fn data_abort_handler(registers: &mut Registers, esr_el2: u64) {
         } else {
             *register & (u32::MAX as u64)
         };
-        vm::get_current_vm()
-            .handle_mmio_write(address as usize, access_width, register_value)
-            .expect("Failed to handle MMIO");
+        vm.handle_mmio_write(address, access_width, register_value)?;
     } else {
-        *register = vm::get_current_vm()
-            .handle_mmio_read(address as usize, access_width)
-            .expect("Failed to handle MMIO");
+        *register = vm.handle_mmio_read(address, access_width)?;
     }
 
     unsafe { asm::advance_elr_el2() };
+    Ok(())
+}
+
+/// MDCR_EL2.TDA によってトラップされたデバッグレジスタへのアクセスを処理する
+fn system_register_handler(registers: &mut Registers, esr_el2: u64) -> Result<(), VmError> {
+    if !vm::get_current_vm()?
+        .get_debug_state()
+        .lock()
+        .handle_system_register_access(registers, esr_el2)
+    {
+        return Err(VmError::UnknownSystemRegister { esr_el2 });
+    }
+    Ok(())
 }
 
 extern "C" fn irq_handler() {
@@ -264,12 +474,22 @@ extern "C" fn irq_handler() {
     let mut deactivate = true;
     if interrupt_number == unsafe { crate::PL011_INT_ID } {
         crate::handle_input(&crate::PL011_DEVICE);
+    } else if interrupt_number == unsafe { crate::VIRTIO_NET_INT_ID } {
+        vswitch::uplink_interrupt_handler();
+    } else if let Some(index) = crate::get_virtio_blk_index(interrupt_number) {
+        virtio_blk::host_interrupt_handler(index);
     } else if interrupt_number == vgic::MAINTENANCE_INTERRUPT_INTID {
         vgic::maintenance_interrupt_handler();
     } else if interrupt_number == gicv3::INJECT_INTERRUPT_INT_ID {
         gicv3::inject_interrupt_handler();
+    } else if interrupt_number == crash::HALT_INT_ID {
+        crash::halt();
+    } else if interrupt_number == vm::VCPU_REQUEST_INT_ID {
+        /* 要求は下位のELへ復帰する前に処理する */
     } else if interrupt_number == unsafe { generic_timer::GENERIC_TIMER_PHYSICAL_INT_ID } {
         generic_timer::generic_timer_interrupt_handler();
+        /* 割り込みが来ないまま止まったホストのディスクを見つけるため、ここで確認する */
+        virtio_blk::check_host_timeout();
         deactivate = false; /* Deactivate はVGICが処理する */
     }
     GicRedistributor::drop_priority(interrupt_number, group);
@@ -277,3 +497,10 @@ extern "C" fn irq_handler() {
         GicRedistributor::deactivate(interrupt_number);
     }
 }
+
+extern "C" fn irq_lower_el_handler(registers: *mut Registers) {
+    irq_handler();
+    if let Err(e) = vm::handle_vcpu_request(unsafe { &mut *registers }) {
+        vm::crash_current_vm(e);
+    }
+}
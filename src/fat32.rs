@@ -2,22 +2,38 @@
 //! FAT32の実装
 //!
 
-use crate::drivers::virtio_blk::VirtioBlk;
+use crate::block_device::BlockDevice;
 use crate::paging::PAGE_SHIFT;
 use crate::{allocate_pages, free_pages};
 
+use alloc::string::String;
+use alloc::vec::Vec;
+
 const FAT32_SIGNATURE: [u8; 8] = [b'F', b'A', b'T', b'3', b'2', b' ', b' ', b' '];
 
 const BYTES_PER_SECTOR_OFFSET: usize = 11;
 const SECTORS_PER_CLUSTER_OFFSET: usize = 13;
 const NUM_OF_RESERVED_CLUSTER_OFFSET: usize = 14;
 const NUM_OF_FATS_OFFSET: usize = 16;
+const TOTAL_SECTORS_OFFSET: usize = 32;
 const FAT_SIZE_OFFSET: usize = 36;
 const ROOT_CLUSTER_OFFSET: usize = 44;
+const FS_INFO_SECTOR_OFFSET: usize = 48;
 const FAT32_SIGNATURE_OFFSET: usize = 82;
 
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+const FS_INFO_STRUCT_SIGNATURE_OFFSET: usize = 484;
+const FS_INFO_FREE_COUNT_OFFSET: usize = 488;
+const FS_INFO_NEXT_FREE_OFFSET: usize = 492;
+
+const FAT32_ATTRIBUTE_READ_ONLY: u8 = 0x01;
 const FAT32_ATTRIBUTE_DIRECTORY: u8 = 0x10;
 const FAT32_ATTRIBUTE_LONG_FILE_NAME: u8 = 0x0F;
+const FAT32_ATTRIBUTE_ARCHIVE: u8 = 0x20;
+
+const FAT32_CLUSTER_MASK: u32 = 0x0FFFFFFF;
+const FAT32_END_OF_CHAIN: u32 = 0x0FFFFFFF;
 
 pub struct Fat32 {
     base_lba: usize,
@@ -28,12 +44,16 @@ pub struct Fat32 {
     fat_sectors: u32,
     number_of_fats: u16,
     fat: usize,
+    root_cluster: u32,
+    number_of_clusters: u32,
+    fs_info_sector: u16,
     root_directory_list: usize,
 }
 
 pub struct FileInfo {
     entry_cluster: u32,
     file_size: u32,
+    is_read_only: bool,
 }
 
 #[repr(C)]
@@ -50,7 +70,24 @@ struct DirectoryEntry {
 }
 
 impl Fat32 {
-    pub fn new(blk: &mut VirtioBlk, base_lba: usize, lba_size: usize) -> Result<Fat32, ()> {
+    pub const fn invalid() -> Self {
+        Self {
+            base_lba: 0,
+            lba_size: 0,
+            bytes_per_sector: 0,
+            sectors_per_cluster: 0,
+            reserved_sectors: 0,
+            fat_sectors: 0,
+            number_of_fats: 0,
+            fat: 0,
+            root_cluster: 0,
+            number_of_clusters: 0,
+            fs_info_sector: 0,
+            root_directory_list: 0,
+        }
+    }
+
+    pub fn new(blk: &mut impl BlockDevice, base_lba: usize, lba_size: usize) -> Result<Fat32, ()> {
         let mut bpb_buffer: [u8; 512] = [0; 512];
         let bpb_address = &mut bpb_buffer as *mut _ as usize;
         blk.read(bpb_address, (base_lba * lba_size) as u64, 512)?;
@@ -62,14 +99,29 @@ impl Fat32 {
         }
 
         /* BPBの読み取り */
-        let bytes_per_sector = unsafe { *((bpb_address + BYTES_PER_SECTOR_OFFSET) as *const u16) };
+        let bytes_per_sector = unsafe {
+            core::ptr::read_unaligned((bpb_address + BYTES_PER_SECTOR_OFFSET) as *const u16)
+        };
         let sectors_per_cluster =
             unsafe { *((bpb_address + SECTORS_PER_CLUSTER_OFFSET) as *const u8) };
-        let reserved_sectors =
-            unsafe { *((bpb_address + NUM_OF_RESERVED_CLUSTER_OFFSET) as *const u16) };
-        let number_of_fats = unsafe { *((bpb_address + NUM_OF_FATS_OFFSET) as *const u16) };
-        let fat_sectors = unsafe { *((bpb_address + FAT_SIZE_OFFSET) as *const u32) };
-        let root_cluster = unsafe { *((bpb_address + ROOT_CLUSTER_OFFSET) as *const u32) };
+        let reserved_sectors = unsafe {
+            core::ptr::read_unaligned((bpb_address + NUM_OF_RESERVED_CLUSTER_OFFSET) as *const u16)
+        };
+        let number_of_fats =
+            unsafe { core::ptr::read_unaligned((bpb_address + NUM_OF_FATS_OFFSET) as *const u16) };
+        let fat_sectors =
+            unsafe { core::ptr::read_unaligned((bpb_address + FAT_SIZE_OFFSET) as *const u32) };
+        let root_cluster =
+            unsafe { core::ptr::read_unaligned((bpb_address + ROOT_CLUSTER_OFFSET) as *const u32) };
+        let total_sectors = unsafe {
+            core::ptr::read_unaligned((bpb_address + TOTAL_SECTORS_OFFSET) as *const u32)
+        };
+        let fs_info_sector = unsafe {
+            core::ptr::read_unaligned((bpb_address + FS_INFO_SECTOR_OFFSET) as *const u16)
+        };
+        let number_of_clusters =
+            (total_sectors - (reserved_sectors as u32) - (number_of_fats as u32) * fat_sectors)
+                / (sectors_per_cluster as u32);
 
         /* FATの読み込み */
         let fat_size = (fat_sectors as usize) * (bytes_per_sector as usize);
@@ -104,6 +156,9 @@ impl Fat32 {
             fat_sectors,
             number_of_fats,
             fat,
+            root_cluster,
+            number_of_clusters,
+            fs_info_sector,
             root_directory_list,
         };
 
@@ -146,9 +201,225 @@ impl Fat32 {
         }
     }
 
+    fn get_fat_mut(&mut self) -> &mut [u32] {
+        let len = (self.fat_sectors as usize * self.bytes_per_sector as usize) / size_of::<u32>();
+        unsafe { core::slice::from_raw_parts_mut(self.fat as *mut u32, len) }
+    }
+
+    /// FATのエントリを書き換え、変更したFATのセクタ番号を記録する
+    fn set_fat_entry(&mut self, cluster: u32, value: u32, modified_sectors: &mut Vec<u32>) {
+        let entries_per_sector = self.bytes_per_sector as u32 / size_of::<u32>() as u32;
+        let entry = &mut self.get_fat_mut()[cluster as usize];
+        /* 上位4bitは予約されているため保持する */
+        *entry = (*entry & !FAT32_CLUSTER_MASK) | (value & FAT32_CLUSTER_MASK);
+        let sector = cluster / entries_per_sector;
+        if !modified_sectors.contains(&sector) {
+            modified_sectors.push(sector);
+        }
+    }
+
+    /// 空きクラスタを確保してチェーンを作成する
+    ///
+    /// 可能であれば連続したクラスタを確保し、無理な場合は空いているクラスタを順に使用する。
+    fn allocate_clusters(
+        &mut self,
+        number_of_clusters: usize,
+        modified_sectors: &mut Vec<u32>,
+    ) -> Result<u32, ()> {
+        let last_cluster = (self.number_of_clusters + 2).min(self.get_fat_mut().len() as u32);
+        let is_free = |fat: &[u32], c: u32| (fat[c as usize] & FAT32_CLUSTER_MASK) == 0;
+
+        let mut clusters = Vec::with_capacity(number_of_clusters);
+        /* 連続した領域を探す */
+        let fat = self.get_fat_mut();
+        let mut run_start = 2;
+        for c in 2..last_cluster {
+            if !is_free(fat, c) {
+                run_start = c + 1;
+                continue;
+            }
+            if (c - run_start + 1) as usize == number_of_clusters {
+                clusters.extend(run_start..=c);
+                break;
+            }
+        }
+        if clusters.is_empty() {
+            /* 断片化したクラスタを使用する */
+            for c in 2..last_cluster {
+                if is_free(fat, c) {
+                    clusters.push(c);
+                    if clusters.len() == number_of_clusters {
+                        break;
+                    }
+                }
+            }
+        }
+        if clusters.len() != number_of_clusters {
+            println!("No space left on the device");
+            return Err(());
+        }
+
+        for (i, c) in clusters.iter().enumerate() {
+            let next = clusters.get(i + 1).copied().unwrap_or(FAT32_END_OF_CHAIN);
+            self.set_fat_entry(*c, next, modified_sectors);
+        }
+        Ok(clusters[0])
+    }
+
+    /// 変更したFATのセクタを全てのFATへ書き戻す
+    fn write_fat(&self, blk: &mut impl BlockDevice, modified_sectors: &[u32]) -> Result<(), ()> {
+        for sector in modified_sectors {
+            let buffer = self.fat + (*sector as usize) * (self.bytes_per_sector as usize);
+            for i in 0..(self.number_of_fats as u32) {
+                let base_sector = (self.reserved_sectors as u32) + i * self.fat_sectors + *sector;
+                self.write_sectors(blk, buffer, base_sector, 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// FSInfo の空きクラスタ数と次の空きクラスタを不明に設定する
+    fn invalidate_fs_info(&self, blk: &mut impl BlockDevice) -> Result<(), ()> {
+        if self.fs_info_sector == 0 || self.fs_info_sector == 0xFFFF {
+            return Ok(());
+        }
+        let mut buffer = alloc::vec![0u8; self.bytes_per_sector as usize];
+        let address = buffer.as_mut_ptr() as usize;
+        self.read_sectors(blk, address, self.fs_info_sector as u32, 1)?;
+        let read_u32 = |offset: usize| unsafe { *((address + offset) as *const u32) };
+        if read_u32(0) != FS_INFO_LEAD_SIGNATURE
+            || read_u32(FS_INFO_STRUCT_SIGNATURE_OFFSET) != FS_INFO_STRUCT_SIGNATURE
+        {
+            return Ok(());
+        }
+        unsafe {
+            *((address + FS_INFO_FREE_COUNT_OFFSET) as *mut u32) = 0xFFFFFFFF;
+            *((address + FS_INFO_NEXT_FREE_OFFSET) as *mut u32) = 0xFFFFFFFF;
+        }
+        self.write_sectors(blk, address, self.fs_info_sector as u32, 1)
+    }
+
+    /// ファイル名を 8.3 形式のディレクトリエントリの名前に変換する
+    fn to_short_name(file_name: &str) -> Option<([u8; 8], [u8; 3])> {
+        let (name, extension) = file_name.split_once('.').unwrap_or((file_name, ""));
+        if name.is_empty() || name.len() > 8 || extension.len() > 3 {
+            return None;
+        }
+        let mut short_name = [b' '; 8];
+        let mut short_extension = [b' '; 3];
+        for (d, s) in short_name
+            .iter_mut()
+            .zip(name.bytes())
+            .chain(short_extension.iter_mut().zip(extension.bytes()))
+        {
+            if !(s.is_ascii_alphanumeric() || s == b'_' || s == b'-') {
+                return None;
+            }
+            *d = s.to_ascii_uppercase();
+        }
+        Some((short_name, short_extension))
+    }
+
+    /// ルートディレクトリにファイルを作成する
+    ///
+    /// 同名のファイルが存在する場合は、そのクラスタを解放して作り直す。
+    /// ファイルの内容は不定のため、write で書き込むこと。
+    pub fn create_file(
+        &mut self,
+        blk: &mut impl BlockDevice,
+        file_name: &str,
+        file_size: usize,
+    ) -> Result<FileInfo, ()> {
+        let Some((short_name, short_extension)) = Self::to_short_name(file_name) else {
+            println!("Invalid file name: {file_name}");
+            return Err(());
+        };
+        if file_size == 0 || file_size > u32::MAX as usize {
+            println!("Invalid file size: {:#X}", file_size);
+            return Err(());
+        }
+
+        let len = ((self.bytes_per_sector as usize) * self.sectors_per_cluster as usize)
+            / size_of::<DirectoryEntry>();
+        let entries = unsafe {
+            core::slice::from_raw_parts_mut(self.root_directory_list as *mut DirectoryEntry, len)
+        };
+
+        /* 同名のエントリもしくは空きエントリを探す */
+        let mut target_entry = None;
+        let mut free_entry = None;
+        for (i, e) in entries.iter().enumerate() {
+            if e.name[0] == 0 {
+                free_entry = free_entry.or(Some(i));
+                break;
+            } else if e.name[0] == 0xE5 {
+                free_entry = free_entry.or(Some(i));
+                continue;
+            }
+            if (e.attribute & 0x3F) == FAT32_ATTRIBUTE_LONG_FILE_NAME
+                || (e.attribute & FAT32_ATTRIBUTE_DIRECTORY) != 0
+            {
+                continue;
+            }
+            if e.name == short_name && e.name_extension == short_extension {
+                target_entry = Some(i);
+                break;
+            }
+        }
+        let mut modified_sectors = Vec::new();
+        let index = if let Some(i) = target_entry {
+            /* 既存のクラスタを解放する */
+            let e = &entries[i];
+            let mut cluster = ((e.starting_cluster_number_high as u32) << 16)
+                | (e.starting_cluster_number as u32);
+            while (2..0x0FFFFFF8).contains(&cluster) {
+                let next = self.get_next_cluster(cluster).unwrap_or(0);
+                self.set_fat_entry(cluster, 0, &mut modified_sectors);
+                cluster = next;
+            }
+            i
+        } else if let Some(i) = free_entry {
+            i
+        } else {
+            println!("The root directory is full.");
+            return Err(());
+        };
+
+        let bytes_per_cluster = self.sectors_per_cluster as usize * self.bytes_per_sector as usize;
+        let entry_cluster =
+            self.allocate_clusters(file_size.div_ceil(bytes_per_cluster), &mut modified_sectors)?;
+        self.write_fat(blk, &modified_sectors)?;
+
+        /* ディレクトリエントリの更新 */
+        entries[index] = DirectoryEntry {
+            name: short_name,
+            name_extension: short_extension,
+            attribute: FAT32_ATTRIBUTE_ARCHIVE,
+            reserved: [0; 8],
+            starting_cluster_number_high: (entry_cluster >> 16) as u16,
+            time_recorded: 0,
+            date_recorded: 0,
+            starting_cluster_number: entry_cluster as u16,
+            file_length: file_size as u32,
+        };
+        self.write_sectors(
+            blk,
+            self.root_directory_list,
+            self.cluster_to_sector(self.root_cluster),
+            self.sectors_per_cluster as u32,
+        )?;
+        self.invalidate_fs_info(blk)?;
+
+        Ok(FileInfo {
+            entry_cluster,
+            file_size: file_size as u32,
+            is_read_only: false,
+        })
+    }
+
     fn read_sectors(
         &self,
-        blk: &mut VirtioBlk,
+        blk: &mut impl BlockDevice,
         buffer: usize,
         base_sector: u32,
         sectors: u32,
@@ -163,7 +434,7 @@ impl Fat32 {
 
     fn write_sectors(
         &self,
-        blk: &mut VirtioBlk,
+        blk: &mut impl BlockDevice,
         buffer: usize,
         base_sector: u32,
         sectors: u32,
@@ -208,13 +479,88 @@ impl Fat32 {
     }
 
     pub fn list_files(&self) {
-        let len = ((self.bytes_per_sector as usize) * self.sectors_per_cluster as usize)
-            / size_of::<DirectoryEntry>();
+        for (file_name, file_info) in self.get_file_list() {
+            println!("{}: File Size:{:#X}", file_name, file_info.file_size);
+        }
+    }
+
+    /// ルートディレクトリにあるファイルの一覧を返す
+    pub fn get_file_list(&self) -> Vec<(String, FileInfo)> {
+        Self::parse_file_list(self.get_root_directory_entries())
+    }
+
+    /// ルートディレクトリにあるディレクトリ directory_name の中のファイルの一覧を返す
+    pub fn get_directory_file_list(
+        &self,
+        blk: &mut impl BlockDevice,
+        directory_name: &str,
+    ) -> Result<Vec<(String, FileInfo)>, ()> {
+        let mut entry_cluster = None;
+        for e in self.get_root_directory_entries() {
+            if e.name[0] == 0 {
+                break;
+            } else if e.name[0] == 0xE5
+                || (e.attribute & 0x3F) == FAT32_ATTRIBUTE_LONG_FILE_NAME
+                || (e.attribute & FAT32_ATTRIBUTE_DIRECTORY) == 0
+            {
+                continue;
+            }
+            let mut buffer = [0u8; 12];
+            if Self::get_file_name(e, &mut buffer)
+                .is_some_and(|name| name.eq_ignore_ascii_case(directory_name))
+            {
+                entry_cluster = Some(
+                    ((e.starting_cluster_number_high as u32) << 16)
+                        | (e.starting_cluster_number as u32),
+                );
+                break;
+            }
+        }
+        let Some(mut cluster) = entry_cluster else {
+            return Err(());
+        };
+
+        /* ディレクトリのクラスタを全て読み込む(DirectoryEntry に揃えるため u32 で確保する) */
+        let bytes_per_cluster = self.sectors_per_cluster as usize * self.bytes_per_sector as usize;
+        let mut buffer: Vec<u32> = Vec::new();
+        for _ in 0..self.number_of_clusters {
+            if cluster < 2 {
+                break;
+            }
+            let offset = buffer.len();
+            buffer.resize(offset + bytes_per_cluster / size_of::<u32>(), 0);
+            self.read_sectors(
+                blk,
+                buffer[offset..].as_mut_ptr() as usize,
+                self.cluster_to_sector(cluster),
+                self.sectors_per_cluster as u32,
+            )?;
+            match self.get_next_cluster(cluster) {
+                Some(next) => cluster = next,
+                None => break,
+            }
+        }
         let entries = unsafe {
-            core::slice::from_raw_parts(self.root_directory_list as *const DirectoryEntry, len)
+            core::slice::from_raw_parts(
+                buffer.as_ptr() as *const DirectoryEntry,
+                (buffer.len() * size_of::<u32>()) / size_of::<DirectoryEntry>(),
+            )
         };
+        Ok(Self::parse_file_list(entries))
+    }
+
+    fn get_root_directory_entries(&self) -> &[DirectoryEntry] {
+        let len = ((self.bytes_per_sector as usize) * self.sectors_per_cluster as usize)
+            / size_of::<DirectoryEntry>();
         assert_eq!(size_of::<DirectoryEntry>(), 32);
+        unsafe {
+            core::slice::from_raw_parts(self.root_directory_list as *const DirectoryEntry, len)
+        }
+    }
 
+    /// ディレクトリエントリの並びからファイルの一覧を作る
+    fn parse_file_list(entries: &[DirectoryEntry]) -> Vec<(String, FileInfo)> {
+        let mut list = Vec::new();
         for e in entries {
             if (e.attribute & 0x3F) == FAT32_ATTRIBUTE_LONG_FILE_NAME {
                 continue;
@@ -229,10 +575,23 @@ impl Fat32 {
             }
             let mut buffer = [0u8; 12];
             if let Some(file_name) = Self::get_file_name(e, &mut buffer) {
-                let file_size = e.file_length;
-                println!("{}: File Size:{:#X}", file_name, file_size);
+                let entry_cluster = ((e.starting_cluster_number_high as u32) << 16)
+                    | (e.starting_cluster_number as u32);
+                list.push((
+                    String::from(&*file_name),
+                    FileInfo {
+                        entry_cluster,
+                        file_size: e.file_length,
+                        is_read_only: (e.attribute & FAT32_ATTRIBUTE_READ_ONLY) != 0,
+                    },
+                ));
             }
         }
+        list
+    }
+
+    pub fn get_bytes_per_sector(&self) -> usize {
+        self.bytes_per_sector as usize
     }
 
     pub fn search_file(&self, target_name: &str) -> Option<FileInfo> {
@@ -270,6 +629,7 @@ impl Fat32 {
                     return Some(FileInfo {
                         entry_cluster,
                         file_size,
+                        is_read_only: (e.attribute & FAT32_ATTRIBUTE_READ_ONLY) != 0,
                     });
                 }
             }
@@ -277,10 +637,51 @@ impl Fat32 {
         None
     }
 
+    /// ファイルの offset から length バイトの領域が置かれているディスク上の範囲を返す
+    ///
+    /// 各要素は(ディスク上のバイト単位のアドレス, 長さ)で、連続したクラスタはまとめる
+    pub fn get_extents(
+        &self,
+        file_info: &FileInfo,
+        offset: usize,
+        length: usize,
+    ) -> Result<Vec<(u64, usize)>, ()> {
+        if offset + length > file_info.file_size as usize {
+            println!("Access beyond the end of the file: {:#X}", offset);
+            return Err(());
+        }
+        let bytes_per_cluster = self.sectors_per_cluster as usize * self.bytes_per_sector as usize;
+        let mut cluster = file_info.entry_cluster;
+        for _ in 0..(offset / bytes_per_cluster) {
+            cluster = self.get_next_cluster(cluster).ok_or(())?;
+        }
+
+        let mut cluster_offset = offset % bytes_per_cluster;
+        let mut remaining = length;
+        let mut extents: Vec<(u64, usize)> = Vec::new();
+        while remaining > 0 {
+            let size = (bytes_per_cluster - cluster_offset).min(remaining);
+            let address = ((self.base_lba * self.lba_size)
+                + (self.cluster_to_sector(cluster) as usize) * (self.bytes_per_sector as usize)
+                + cluster_offset) as u64;
+            match extents.last_mut() {
+                /* クラスタが連続している */
+                Some((a, l)) if *a + *l as u64 == address => *l += size,
+                _ => extents.push((address, size)),
+            }
+            remaining -= size;
+            cluster_offset = 0;
+            if remaining > 0 {
+                cluster = self.get_next_cluster(cluster).ok_or(())?;
+            }
+        }
+        Ok(extents)
+    }
+
     pub fn read(
         &self,
         file_info: &FileInfo,
-        blk: &mut VirtioBlk,
+        blk: &mut impl BlockDevice,
         buffer_address: usize,
         offset: usize,
         mut length: usize,
@@ -390,7 +791,7 @@ impl Fat32 {
     pub fn write(
         &self,
         file_info: &FileInfo,
-        blk: &mut VirtioBlk,
+        blk: &mut impl BlockDevice,
         buffer_address: usize,
         offset: usize,
         mut length: usize,
@@ -489,4 +890,14 @@ impl FileInfo {
     pub fn get_file_size(&self) -> usize {
         self.file_size as usize
     }
+
+    /// ファイルの先頭のクラスタ番号(ファイルの識別に使う)
+    pub fn get_entry_cluster(&self) -> u32 {
+        self.entry_cluster
+    }
+
+    /// 読み込み専用属性が付いているか
+    pub fn is_read_only(&self) -> bool {
+        self.is_read_only
+    }
 }
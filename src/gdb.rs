@@ -0,0 +1,758 @@
+//!
+//! GDB Remote Serial Protocol のスタブ
+//!
+//! コンソールの `gdb vm_id` で有効化し、ホストの PL011 を GDB との通信に使用する。
+//! GDB を切り離す(D/k)と、入力はゲストへ戻る。
+//! 対象の仮想CPUは一時停止の仕組みを使って停止させ、停止中に保存した状態を読み書きする。
+//!
+
+use crate::asm;
+use crate::exception::Registers;
+use crate::lock::Mutex;
+use crate::registers::*;
+use crate::serial::{self, SerialDevice};
+use crate::vcpu::VcpuContext;
+use crate::vm::{self, VM, VmError};
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use core::fmt::Write;
+
+const PACKET_SIZE: usize = 0x400;
+const BRK_INSTRUCTION: u32 = 0xd4200000;
+const MAX_BREAKPOINTS: usize = 4;
+const MAX_WATCHPOINTS: usize = 4;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/* GDB の AArch64 のレジスタ番号 */
+const GDB_REGISTER_SP: usize = 31;
+const GDB_REGISTER_PC: usize = 32;
+const GDB_REGISTER_CPSR: usize = 33;
+const GDB_REGISTER_V0: usize = 34;
+const GDB_REGISTER_FPSR: usize = 66;
+const GDB_REGISTER_FPCR: usize = 67;
+
+/* DBGBCR<n>_EL1: EL1とEL0で一致, BAS: 0b1111 */
+const DBGBCR_VALUE: u64 = (0b1111 << 5) | (0b11 << 1) | 1;
+/* DBGWCR<n>_EL1: EL1とEL0で一致 */
+const DBGWCR_PAC: u64 = 0b11 << 1;
+const DBGWCR_LSC_BITS_OFFSET: u64 = 3;
+const DBGWCR_BAS_BITS_OFFSET: u64 = 5;
+
+const SET_DBGBVRN_EL1: [unsafe fn(u64); MAX_BREAKPOINTS] = [
+    asm::set_dbgbvr0_el1,
+    asm::set_dbgbvr1_el1,
+    asm::set_dbgbvr2_el1,
+    asm::set_dbgbvr3_el1,
+];
+const SET_DBGBCRN_EL1: [unsafe fn(u64); MAX_BREAKPOINTS] = [
+    asm::set_dbgbcr0_el1,
+    asm::set_dbgbcr1_el1,
+    asm::set_dbgbcr2_el1,
+    asm::set_dbgbcr3_el1,
+];
+const SET_DBGWVRN_EL1: [unsafe fn(u64); MAX_WATCHPOINTS] = [
+    asm::set_dbgwvr0_el1,
+    asm::set_dbgwvr1_el1,
+    asm::set_dbgwvr2_el1,
+    asm::set_dbgwvr3_el1,
+];
+const SET_DBGWCRN_EL1: [unsafe fn(u64); MAX_WATCHPOINTS] = [
+    asm::set_dbgwcr0_el1,
+    asm::set_dbgwcr1_el1,
+    asm::set_dbgwcr2_el1,
+    asm::set_dbgwcr3_el1,
+];
+
+/// 仮想CPUに設定するデバッグ機能の状態
+///
+/// GDB 接続中は MDCR_EL2.TDE/TDA でゲストのデバッグレジスタへのアクセスをトラップし、
+/// ハードウェアのデバッグレジスタはハイパーバイザが使用する。
+pub struct DebugState {
+    is_enabled: bool,
+    /// apply でハードウェアへ設定済みか
+    is_applied: bool,
+    is_single_step: bool,
+    breakpoints: [Option<u64>; MAX_BREAKPOINTS],
+    /// (DBGWVR<n>_EL1, DBGWCR<n>_EL1)
+    watchpoints: [Option<(u64, u64)>; MAX_WATCHPOINTS],
+    /// ゲストから見える MDSCR_EL1
+    guest_mdscr_el1: u64,
+}
+
+impl DebugState {
+    pub const fn new() -> Self {
+        Self {
+            is_enabled: false,
+            is_applied: false,
+            is_single_step: false,
+            breakpoints: [None; MAX_BREAKPOINTS],
+            watchpoints: [None; MAX_WATCHPOINTS],
+            guest_mdscr_el1: 0,
+        }
+    }
+
+    /// 状態を仮想CPUが動作しているpCPUへ反映する
+    ///
+    /// 仮想CPUを再開する直前に呼び出すこと
+    pub fn apply(&mut self) {
+        let mdcr_el2 = asm::get_mdcr_el2() & !(MDCR_EL2_TDE | MDCR_EL2_TDA);
+        if !self.is_enabled {
+            if self.is_applied {
+                self.is_applied = false;
+                unsafe {
+                    for set in SET_DBGBCRN_EL1.iter().take(number_of_breakpoints()) {
+                        set(0);
+                    }
+                    for set in SET_DBGWCRN_EL1.iter().take(number_of_watchpoints()) {
+                        set(0);
+                    }
+                    asm::set_mdscr_el1(self.guest_mdscr_el1);
+                    asm::set_spsr_el2(asm::get_spsr_el2() & !SPSR_EL2_SS);
+                    asm::set_mdcr_el2(mdcr_el2);
+                }
+            }
+            return;
+        }
+        if !self.is_applied {
+            self.is_applied = true;
+            self.guest_mdscr_el1 = asm::get_mdscr_el1();
+            /* OS Lock を解除する */
+            unsafe { asm::set_oslar_el1(0) };
+        }
+        unsafe {
+            for (i, b) in self
+                .breakpoints
+                .iter()
+                .enumerate()
+                .take(number_of_breakpoints())
+            {
+                (SET_DBGBVRN_EL1[i])(b.unwrap_or(0));
+                (SET_DBGBCRN_EL1[i])(if b.is_some() { DBGBCR_VALUE } else { 0 });
+            }
+            for (i, w) in self
+                .watchpoints
+                .iter()
+                .enumerate()
+                .take(number_of_watchpoints())
+            {
+                let (wvr, wcr) = w.unwrap_or((0, 0));
+                (SET_DBGWVRN_EL1[i])(wvr);
+                (SET_DBGWCRN_EL1[i])(wcr);
+            }
+            let mut mdscr_el1 = MDSCR_EL1_MDE;
+            let mut spsr_el2 = asm::get_spsr_el2() & !SPSR_EL2_SS;
+            if self.is_single_step {
+                mdscr_el1 |= MDSCR_EL1_SS;
+                spsr_el2 |= SPSR_EL2_SS;
+            }
+            asm::set_mdscr_el1(mdscr_el1);
+            asm::set_spsr_el2(spsr_el2);
+            asm::set_mdcr_el2(mdcr_el2 | MDCR_EL2_TDE | MDCR_EL2_TDA);
+        }
+    }
+
+    /// トラップしたデバッグレジスタへのアクセスをエミュレートする
+    ///
+    /// MDSCR_EL1 以外は RAZ/WI とする
+    pub fn handle_system_register_access(
+        &mut self,
+        registers: &mut Registers,
+        esr_el2: u64,
+    ) -> bool {
+        if (esr_el2 & ESR_EL2_ISS_SYSREG_OP0) != (0b10 << ESR_EL2_ISS_SYSREG_OP0_BITS_OFFSET) {
+            return false;
+        }
+        let is_mdscr_el1 = (esr_el2 & ESR_EL2_ISS_SYSREG_ENCODING) == ESR_EL2_ISS_SYSREG_MDSCR_EL1;
+        let register_number =
+            ((esr_el2 & ESR_EL2_ISS_SYSREG_RT) >> ESR_EL2_ISS_SYSREG_RT_BITS_OFFSET) as usize;
+        if (esr_el2 & ESR_EL2_ISS_SYSREG_DIRECTION_READ) != 0 {
+            let value = if is_mdscr_el1 {
+                self.guest_mdscr_el1
+            } else {
+                0
+            };
+            if register_number != 31 {
+                registers.as_mut_array()[register_number] = value;
+            }
+        } else if is_mdscr_el1 {
+            self.guest_mdscr_el1 = if register_number != 31 {
+                registers.as_array()[register_number]
+            } else {
+                0
+            };
+        }
+        unsafe { asm::advance_elr_el2() };
+        true
+    }
+}
+
+fn number_of_breakpoints() -> usize {
+    let brps = ((asm::get_id_aa64dfr0_el1() >> ID_AA64DFR0_EL1_BRPS_BITS_OFFSET) & 0b1111) + 1;
+    (brps as usize).min(MAX_BREAKPOINTS)
+}
+
+fn number_of_watchpoints() -> usize {
+    let wrps = ((asm::get_id_aa64dfr0_el1() >> ID_AA64DFR0_EL1_WRPS_BITS_OFFSET) & 0b1111) + 1;
+    (wrps as usize).min(MAX_WATCHPOINTS)
+}
+
+#[derive(Clone, Copy)]
+enum StopReason {
+    Interrupt,
+    Trap,
+    Watchpoint(u64),
+}
+
+#[derive(Clone, Copy)]
+enum PacketState {
+    Idle,
+    Data,
+    Checksum(u8, u8),
+}
+
+struct GdbStub {
+    vm: Arc<VM>,
+    packet: Vec<u8>,
+    state: PacketState,
+    checksum: u8,
+    stop_reason: StopReason,
+    /// 仮想CPUが動作中で、停止の通知を待っているか
+    is_running: bool,
+    is_detached: bool,
+    /// (仮想アドレス, 元の命令)
+    software_breakpoints: Vec<(usize, u32)>,
+}
+
+static GDB_STUB: Mutex<Option<GdbStub>> = Mutex::new(None);
+
+fn put_bytes(data: &[u8]) {
+    let device = crate::PL011_DEVICE.lock();
+    for c in data {
+        let _ = device.putc(*c);
+    }
+}
+
+fn from_hex(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() {
+        return None;
+    }
+    let mut value = 0usize;
+    for c in s {
+        value = value.checked_mul(16)? | from_hex(*c)? as usize;
+    }
+    Some(value)
+}
+
+/// リトルエンディアンの16進数の列を読み取る
+fn parse_hex_bytes(s: &[u8], buffer: &mut [u8]) -> Option<()> {
+    if s.len() != buffer.len() * 2 {
+        return None;
+    }
+    for (b, c) in buffer.iter_mut().zip(s.chunks(2)) {
+        *b = (from_hex(c[0])? << 4) | from_hex(c[1])?;
+    }
+    Some(())
+}
+
+fn push_hex_bytes(reply: &mut String, data: &[u8]) {
+    for b in data {
+        let _ = write!(reply, "{b:02x}");
+    }
+}
+
+/// "addr,length" 形式の引数を読み取る
+fn parse_address_and_length(s: &[u8]) -> Option<(usize, usize)> {
+    let mut args = s.split(|c| *c == b',');
+    let address = parse_hex(args.next()?)?;
+    let length = parse_hex(args.next()?)?;
+    Some((address, length))
+}
+
+impl GdbStub {
+    fn send_packet(&self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, c| sum.wrapping_add(c));
+        put_bytes(b"$");
+        put_bytes(data.as_bytes());
+        let mut tail = String::new();
+        let _ = write!(tail, "#{checksum:02x}");
+        put_bytes(tail.as_bytes());
+    }
+
+    fn send_stop_reply(&self) {
+        let mut reply = String::new();
+        let _ = match self.stop_reason {
+            StopReason::Interrupt => write!(reply, "S{SIGINT:02x}"),
+            StopReason::Trap => write!(reply, "S{SIGTRAP:02x}"),
+            StopReason::Watchpoint(address) => {
+                write!(reply, "T{SIGTRAP:02x}watch:{address:x};")
+            }
+        };
+        self.send_packet(&reply);
+    }
+
+    fn input(&mut self, c: u8) {
+        match self.state {
+            PacketState::Idle => {
+                if c == b'$' {
+                    self.packet.clear();
+                    self.checksum = 0;
+                    self.state = PacketState::Data;
+                } else if c == 0x03 && self.is_running {
+                    /* Ctrl + C */
+                    self.stop_reason = StopReason::Interrupt;
+                    let _ = self.vm.pause();
+                }
+                /* '+' と '-' は無視する */
+            }
+            PacketState::Data => {
+                if c == b'#' {
+                    self.state = PacketState::Checksum(0, 0);
+                } else if self.packet.len() < PACKET_SIZE {
+                    self.checksum = self.checksum.wrapping_add(c);
+                    self.packet.push(c);
+                }
+            }
+            PacketState::Checksum(count, value) => {
+                let value = (value << 4) | from_hex(c).unwrap_or(0);
+                if count == 0 {
+                    self.state = PacketState::Checksum(1, value);
+                    return;
+                }
+                self.state = PacketState::Idle;
+                if value != self.checksum {
+                    put_bytes(b"-");
+                    return;
+                }
+                put_bytes(b"+");
+                let packet = core::mem::take(&mut self.packet);
+                if let Some(reply) = self.handle_packet(&packet) {
+                    self.send_packet(&reply);
+                }
+                self.packet = packet;
+            }
+        }
+    }
+
+    /// パケットを処理して応答を返す(応答しない場合は None)
+    fn handle_packet(&mut self, packet: &[u8]) -> Option<String> {
+        let Some((&command, args)) = packet.split_first() else {
+            return Some(String::new());
+        };
+        let mut reply = String::new();
+        match command {
+            b'?' => {
+                self.send_stop_reply();
+                return None;
+            }
+            b'g' => {
+                let Some(context) = self.vm.get_paused_context() else {
+                    return Some(String::from("E01"));
+                };
+                for i in 0..=GDB_REGISTER_CPSR {
+                    Self::read_register(&context, i, &mut reply);
+                }
+            }
+            b'G' => {
+                let Some(mut context) = self.vm.get_paused_context() else {
+                    return Some(String::from("E01"));
+                };
+                let mut pointer = 0;
+                for i in 0..=GDB_REGISTER_CPSR {
+                    let size = if i == GDB_REGISTER_CPSR { 4 } else { 8 } * 2;
+                    let Some(value) = args.get(pointer..(pointer + size)) else {
+                        break;
+                    };
+                    if Self::write_register(&mut context, i, value).is_none() {
+                        return Some(String::from("E02"));
+                    }
+                    pointer += size;
+                }
+                self.vm.set_paused_context(context);
+                reply.push_str("OK");
+            }
+            b'p' => {
+                let (Some(context), Some(number)) = (self.vm.get_paused_context(), parse_hex(args))
+                else {
+                    return Some(String::from("E01"));
+                };
+                if !Self::read_register(&context, number, &mut reply) {
+                    return Some(String::from("E02"));
+                }
+            }
+            b'P' => {
+                let mut args = args.split(|c| *c == b'=');
+                let (Some(mut context), Some(number), Some(value)) = (
+                    self.vm.get_paused_context(),
+                    args.next().and_then(parse_hex),
+                    args.next(),
+                ) else {
+                    return Some(String::from("E01"));
+                };
+                if Self::write_register(&mut context, number, value).is_none() {
+                    return Some(String::from("E02"));
+                }
+                self.vm.set_paused_context(context);
+                reply.push_str("OK");
+            }
+            b'm' => {
+                let Some((address, length)) = parse_address_and_length(args) else {
+                    return Some(String::from("E01"));
+                };
+                let mut buffer = alloc::vec![0u8; length.min(PACKET_SIZE / 2)];
+                if self.access_memory(address, &mut buffer, false).is_err() {
+                    return Some(String::from("E14"));
+                }
+                push_hex_bytes(&mut reply, &buffer);
+            }
+            b'M' => {
+                let mut args = args.split(|c| *c == b':');
+                let (Some((address, length)), Some(data)) =
+                    (args.next().and_then(parse_address_and_length), args.next())
+                else {
+                    return Some(String::from("E01"));
+                };
+                /* 長さはパケット内のデータの量で制限する */
+                if length.checked_mul(2) != Some(data.len()) {
+                    return Some(String::from("E01"));
+                }
+                let mut buffer = alloc::vec![0u8; length];
+                if parse_hex_bytes(data, &mut buffer).is_none() {
+                    return Some(String::from("E01"));
+                }
+                if self.access_memory(address, &mut buffer, true).is_err() {
+                    return Some(String::from("E14"));
+                }
+                reply.push_str("OK");
+            }
+            b'Z' | b'z' => {
+                let is_insert = command == b'Z';
+                let mut args = args.split(|c| *c == b',');
+                let (Some(kind), Some(address), Some(length)) = (
+                    args.next(),
+                    args.next().and_then(parse_hex),
+                    args.next().and_then(parse_hex),
+                ) else {
+                    return Some(String::from("E01"));
+                };
+                let result = match kind {
+                    b"0" => self.set_software_breakpoint(address, is_insert),
+                    b"1" => self.set_hardware_breakpoint(address, is_insert),
+                    b"2" => self.set_watchpoint(address, length, 0b10, is_insert),
+                    b"3" => self.set_watchpoint(address, length, 0b01, is_insert),
+                    b"4" => self.set_watchpoint(address, length, 0b11, is_insert),
+                    _ => return Some(String::new()),
+                };
+                reply.push_str(if result.is_ok() { "OK" } else { "E01" });
+            }
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    let (Some(mut context), Some(address)) =
+                        (self.vm.get_paused_context(), parse_hex(args))
+                    else {
+                        return Some(String::from("E01"));
+                    };
+                    context.elr_el2 = address as u64;
+                    self.vm.set_paused_context(context);
+                }
+                self.vm.get_debug_state().lock().is_single_step = command == b's';
+                self.is_running = true;
+                if self.vm.resume().is_err() {
+                    self.is_running = false;
+                    return Some(String::from("E01"));
+                }
+                return None;
+            }
+            b'D' | b'k' => {
+                self.detach();
+                if command == b'k' {
+                    return None;
+                }
+                reply.push_str("OK");
+            }
+            b'H' => reply.push_str("OK"),
+            b'q' => {
+                if args.starts_with(b"Supported") {
+                    let _ = write!(reply, "PacketSize={PACKET_SIZE:x}");
+                } else if args == b"Attached" {
+                    reply.push('1');
+                } else if args == b"C" {
+                    reply.push_str("QC1");
+                } else if args == b"fThreadInfo" {
+                    reply.push_str("m1");
+                } else if args == b"sThreadInfo" {
+                    reply.push('l');
+                }
+            }
+            _ => { /* 未対応のパケットには空の応答を返す */ }
+        }
+        Some(reply)
+    }
+
+    fn get_stack_pointer_name(context: &VcpuContext) -> &'static str {
+        if (context.spsr_el2 & 0b1111) == SPSR_EL2_M_EL1H {
+            "SP_EL1"
+        } else {
+            "SP_EL0"
+        }
+    }
+
+    fn read_register(context: &VcpuContext, number: usize, reply: &mut String) -> bool {
+        match number {
+            0..=30 => push_hex_bytes(reply, &context.registers.as_array()[number].to_le_bytes()),
+            GDB_REGISTER_SP => push_hex_bytes(
+                reply,
+                &context
+                    .get_system_register(Self::get_stack_pointer_name(context))
+                    .to_le_bytes(),
+            ),
+            GDB_REGISTER_PC => push_hex_bytes(reply, &context.elr_el2.to_le_bytes()),
+            GDB_REGISTER_CPSR => push_hex_bytes(reply, &(context.spsr_el2 as u32).to_le_bytes()),
+            GDB_REGISTER_V0..GDB_REGISTER_FPSR => push_hex_bytes(
+                reply,
+                &context.fp_registers[number - GDB_REGISTER_V0].to_le_bytes(),
+            ),
+            GDB_REGISTER_FPSR => push_hex_bytes(
+                reply,
+                &(context.get_system_register("FPSR") as u32).to_le_bytes(),
+            ),
+            GDB_REGISTER_FPCR => push_hex_bytes(
+                reply,
+                &(context.get_system_register("FPCR") as u32).to_le_bytes(),
+            ),
+            _ => return false,
+        }
+        true
+    }
+
+    fn write_register(context: &mut VcpuContext, number: usize, value: &[u8]) -> Option<()> {
+        if number == GDB_REGISTER_CPSR {
+            let mut buffer = [0u8; 4];
+            parse_hex_bytes(value, &mut buffer)?;
+            context.spsr_el2 = u32::from_le_bytes(buffer) as u64;
+            return Some(());
+        }
+        let mut buffer = [0u8; 8];
+        parse_hex_bytes(value, &mut buffer)?;
+        let value = u64::from_le_bytes(buffer);
+        match number {
+            0..=30 => context.registers.as_mut_array()[number] = value,
+            GDB_REGISTER_SP => {
+                context.set_system_register(Self::get_stack_pointer_name(context), value)
+            }
+            GDB_REGISTER_PC => context.elr_el2 = value,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// 停止中の仮想CPUから見た仮想アドレスのメモリを読み書きする
+    fn access_memory(&self, address: usize, buffer: &mut [u8], is_write: bool) -> Result<(), ()> {
+        let context = self.vm.get_paused_context().ok_or(())?;
+        let mut pointer = 0;
+        while pointer < buffer.len() {
+            let virtual_address = address + pointer;
+            let page_offset = virtual_address & (crate::paging::PAGE_SIZE - 1);
+            let size = (crate::paging::PAGE_SIZE - page_offset).min(buffer.len() - pointer);
+            let intermediate_physical_address =
+                context.translate_virtual_address(&self.vm, virtual_address)?;
+            let physical_address = self
+                .vm
+                .get_physical_address(intermediate_physical_address)
+                .ok_or(())?;
+            let data =
+                unsafe { core::slice::from_raw_parts_mut(physical_address as *mut u8, size) };
+            if is_write {
+                data.copy_from_slice(&buffer[pointer..(pointer + size)]);
+                self.vm.mark_dirty(intermediate_physical_address, size);
+            } else {
+                buffer[pointer..(pointer + size)].copy_from_slice(data);
+            }
+            pointer += size;
+        }
+        if is_write {
+            asm::invalidate_instruction_cache();
+        }
+        Ok(())
+    }
+
+    fn set_software_breakpoint(&mut self, address: usize, is_insert: bool) -> Result<(), ()> {
+        let index = self
+            .software_breakpoints
+            .iter()
+            .position(|(a, _)| *a == address);
+        if is_insert {
+            if index.is_some() {
+                return Ok(());
+            }
+            let mut original = [0u8; 4];
+            self.access_memory(address, &mut original, false)?;
+            self.access_memory(address, &mut BRK_INSTRUCTION.to_le_bytes(), true)?;
+            self.software_breakpoints
+                .push((address, u32::from_le_bytes(original)));
+        } else if let Some(index) = index {
+            let (address, original) = self.software_breakpoints.remove(index);
+            self.access_memory(address, &mut original.to_le_bytes(), true)?;
+        }
+        Ok(())
+    }
+
+    fn set_hardware_breakpoint(&mut self, address: usize, is_insert: bool) -> Result<(), ()> {
+        let mut debug_state = self.vm.get_debug_state().lock();
+        let address = (address as u64) & !0b11;
+        let breakpoints = &mut debug_state.breakpoints[0..number_of_breakpoints()];
+        let slot = if is_insert {
+            breakpoints.iter_mut().find(|b| b.is_none())
+        } else {
+            breakpoints.iter_mut().find(|b| **b == Some(address))
+        };
+        let Some(slot) = slot else {
+            return Err(());
+        };
+        *slot = is_insert.then_some(address);
+        Ok(())
+    }
+
+    fn set_watchpoint(
+        &mut self,
+        address: usize,
+        length: usize,
+        load_store_control: u64,
+        is_insert: bool,
+    ) -> Result<(), ()> {
+        let offset = address & 0b111;
+        if length == 0 || offset + length > 8 {
+            println!("Watchpoints must be in a 8-byte aligned doubleword");
+            return Err(());
+        }
+        let byte_address_select = ((1u64 << length) - 1) << offset;
+        let watchpoint = (
+            (address as u64) & !0b111,
+            (byte_address_select << DBGWCR_BAS_BITS_OFFSET)
+                | (load_store_control << DBGWCR_LSC_BITS_OFFSET)
+                | DBGWCR_PAC
+                | 1,
+        );
+        let mut debug_state = self.vm.get_debug_state().lock();
+        let watchpoints = &mut debug_state.watchpoints[0..number_of_watchpoints()];
+        let slot = if is_insert {
+            watchpoints.iter_mut().find(|w| w.is_none())
+        } else {
+            watchpoints.iter_mut().find(|w| **w == Some(watchpoint))
+        };
+        let Some(slot) = slot else {
+            return Err(());
+        };
+        *slot = is_insert.then_some(watchpoint);
+        Ok(())
+    }
+
+    fn detach(&mut self) {
+        while let Some((address, _)) = self.software_breakpoints.last() {
+            let address = *address;
+            if self.set_software_breakpoint(address, false).is_err() {
+                self.software_breakpoints.pop();
+            }
+        }
+        let mut debug_state = self.vm.get_debug_state().lock();
+        debug_state.is_enabled = false;
+        debug_state.is_single_step = false;
+        debug_state.breakpoints = [None; MAX_BREAKPOINTS];
+        debug_state.watchpoints = [None; MAX_WATCHPOINTS];
+        drop(debug_state);
+        if self.vm.is_paused() {
+            let _ = self.vm.resume();
+        }
+        self.is_detached = true;
+    }
+}
+
+/// 仮想マシンへ GDB を接続する
+///
+/// 仮想CPUを停止させ、以降のシリアル入力は GDB のパケットとして扱う。
+/// パケットと混ざらないよう、切断するまでハイパーバイザとゲストのコンソール出力は保留する
+pub fn attach(vm: Arc<VM>) -> Result<(), ()> {
+    let mut gdb_stub = GDB_STUB.lock();
+    if gdb_stub.is_some() {
+        println!("GDB is already attached");
+        return Err(());
+    }
+    serial::hold_output();
+    vm.get_debug_state().lock().is_enabled = true;
+    if !vm.is_paused() && vm.pause().is_err() {
+        vm.get_debug_state().lock().is_enabled = false;
+        serial::release_output();
+        return Err(());
+    }
+    *gdb_stub = Some(GdbStub {
+        vm,
+        packet: Vec::with_capacity(PACKET_SIZE),
+        state: PacketState::Idle,
+        checksum: 0,
+        stop_reason: StopReason::Trap,
+        is_running: false,
+        is_detached: false,
+        software_breakpoints: Vec::new(),
+    });
+    Ok(())
+}
+
+pub fn is_active() -> bool {
+    GDB_STUB.lock().is_some()
+}
+
+/// シリアルポートからの入力を処理する
+pub fn input(c: u8) {
+    let mut gdb_stub = GDB_STUB.lock();
+    let Some(stub) = gdb_stub.as_mut() else {
+        return;
+    };
+    stub.input(c);
+    if stub.is_detached {
+        *gdb_stub = None;
+        drop(gdb_stub);
+        serial::release_output();
+        println!("GDB is detached");
+    }
+}
+
+/// 仮想CPUが停止したことを GDB へ通知する
+///
+/// 仮想CPUが動作しているpCPUから呼ばれる
+pub fn notify_stop(vm: &VM) {
+    if let Some(stub) = GDB_STUB.lock().as_mut()
+        && core::ptr::eq(Arc::as_ptr(&stub.vm), vm)
+        && stub.is_running
+    {
+        stub.is_running = false;
+        stub.send_stop_reply();
+    }
+}
+
+/// ゲストで発生したデバッグ例外を処理する
+pub fn handle_debug_exception(esr_el2: u64) -> Result<(), VmError> {
+    let vm = vm::get_current_vm()?;
+    let ec = esr_el2 & ESR_EL2_EC;
+    if ec == ESR_EL2_EC_SOFTWARE_STEP {
+        vm.get_debug_state().lock().is_single_step = false;
+    }
+    if let Some(stub) = GDB_STUB.lock().as_mut()
+        && Arc::ptr_eq(&stub.vm, &vm)
+    {
+        stub.stop_reason = if ec == ESR_EL2_EC_WATCHPOINT {
+            StopReason::Watchpoint(asm::get_far_el2())
+        } else {
+            StopReason::Trap
+        };
+    }
+    let _ = vm.pause();
+    Ok(())
+}
@@ -0,0 +1,207 @@
+//!
+//! ハイパーコール
+//!
+//! ゲストからハイパーバイザの機能を呼び出すための HVC 命令のインターフェース。
+//! SMC Calling Convention(SMCCC) に従い、Vendor Specific Hypervisor Service(OEN = 6) の範囲を使用する。
+//!
+//! # ABI
+//!
+//! `hvc #0` で呼び出す。x0 に Function ID、x1 以降に引数を設定する。
+//! 戻り値は x0 から x3 に格納され、x4 以降のレジスタは保存される。
+//! 失敗した場合は x0 に負の値(-1: 未対応、-3: 引数が不正、-4: 権限がない、-5: 処理に失敗)が返る。
+//! アドレスはゲストの物理アドレス(IPA)で指定する。
+//!
+//! | Function ID   | 名前          | 引数                          | 戻り値                                   |
+//! |---------------|---------------|-------------------------------|------------------------------------------|
+//! | `0x8600_FF01` | Call UID      | なし                          | x0-x3: UID(`MINIVISOR_UID`)              |
+//! | `0x8600_FF03` | Revision      | なし                          | x0: ABIのメジャー, x1: マイナー          |
+//! | `0xC600_0000` | GET_VERSION   | なし                          | x0: メジャー, x1: マイナー, x2: パッチ   |
+//! | `0xC600_0001` | GET_VM_ID     | なし                          | x0: VM ID                                |
+//! | `0xC600_0002` | LOG_WRITE     | x1: 文字列, x2: バイト数      | x0: 出力したバイト数                     |
+//! | `0xC600_0003` | YIELD         | なし                          | x0: 0                                    |
+//! | `0xC600_0004` | SHUTDOWN      | なし                          | 復帰しない                               |
+//!
+//! LOG_WRITE で一度に出力できるのは `LOG_WRITE_MAX_SIZE` バイトまでで、超えた分は切り捨てる。
+//! VM_READ_CONSOLE も同様に、一度に読み出せるのは `READ_CONSOLE_MAX_SIZE` バイトまでとする。
+//! YIELD は仮想CPUがpCPUを専有しているため、何もせずに復帰する。
+//!
+//! ## 管理用VMのみ使用できるもの
+//!
+//! 管理用VMは設定ファイル(VM<n>.CFG)で `management` を指定した仮想マシンとする。
+//!
+//! | Function ID   | 名前            | 引数                                  | 戻り値                 |
+//! |---------------|-----------------|---------------------------------------|------------------------|
+//! | `0xC600_0100` | VM_LIST         | x1: バッファ, x2: 要素数              | x0: 仮想マシンの数     |
+//! | `0xC600_0101` | VM_CREATE       | なし                                  | x0: 0                  |
+//! | `0xC600_0102` | VM_DESTROY      | x1: VM ID                             | x0: 0                  |
+//! | `0xC600_0103` | VM_PAUSE        | x1: VM ID                             | x0: 0                  |
+//! | `0xC600_0104` | VM_RESUME       | x1: VM ID                             | x0: 0                  |
+//! | `0xC600_0105` | VM_READ_CONSOLE | x1: VM ID, x2: バッファ, x3: バイト数 | x0: 読み出したバイト数 |
+//!
+//! VM_LIST はバッファへ `{ u64 vm_id; u64 state; }` (state 0: 動作中, 1: 一時停止中, 2: 停止)を
+//! 最大で要素数分書き込む。
+//! VM_CREATE は空いているpCPUで仮想マシンの作成を開始し、作成の完了は待たない。
+//! VM_DESTROY は動作中の仮想マシンを停止して解放し、既に停止している仮想マシンの場合は残していたRAMなどを解放する。
+//! VM_READ_CONSOLE は仮想マシンがUARTへ出力した文字を古い順に取り出す。
+//!
+
+use crate::exception::Registers;
+use crate::vm::{self, VM, VmError};
+
+use alloc::string::String;
+
+const SMCCC_VERSION: u64 = 0x8000_0000;
+
+const MINIVISOR_HYP_CALL_UID: u64 = 0x8600_FF01;
+const MINIVISOR_HYP_REVISION: u64 = 0x8600_FF03;
+const MINIVISOR_HYP_GET_VERSION: u64 = 0xC600_0000;
+const MINIVISOR_HYP_GET_VM_ID: u64 = 0xC600_0001;
+const MINIVISOR_HYP_LOG_WRITE: u64 = 0xC600_0002;
+const MINIVISOR_HYP_YIELD: u64 = 0xC600_0003;
+const MINIVISOR_HYP_SHUTDOWN: u64 = 0xC600_0004;
+const MINIVISOR_HYP_VM_LIST: u64 = 0xC600_0100;
+const MINIVISOR_HYP_VM_CREATE: u64 = 0xC600_0101;
+const MINIVISOR_HYP_VM_DESTROY: u64 = 0xC600_0102;
+const MINIVISOR_HYP_VM_PAUSE: u64 = 0xC600_0103;
+const MINIVISOR_HYP_VM_RESUME: u64 = 0xC600_0104;
+const MINIVISOR_HYP_VM_READ_CONSOLE: u64 = 0xC600_0105;
+
+const SMCCC_RET_SUCCESS: u64 = 0;
+const SMCCC_RET_NOT_SUPPORTED: u64 = -1i64 as u64;
+const SMCCC_RET_INVALID_PARAMETER: u64 = -3i64 as u64;
+const MINIVISOR_RET_DENIED: u64 = -4i64 as u64;
+const MINIVISOR_RET_FAILED: u64 = -5i64 as u64;
+
+const VM_STATE_RUNNING: u64 = 0;
+const VM_STATE_PAUSED: u64 = 1;
+const VM_STATE_STOPPED: u64 = 2;
+
+/// SMCCC v1.1
+const SMCCC_VERSION_1_1: u64 = 0x10001;
+/// 本ABIのリビジョン(1.0)
+const ABI_REVISION: (u64, u64) = (1, 0);
+/// MiniVisor の UID(f12c4d0a-9e2b-4b6a-8d5e-4d696e695669) を w0-w3 の順に並べたもの
+const MINIVISOR_UID: [u64; 4] = [0x0A4D_2CF1, 0x6A4B_2B9E, 0x694D_5E8D, 0x6956_696E];
+const LOG_WRITE_MAX_SIZE: usize = 256;
+/// 仮想マシンが保持するコンソール出力の大きさ
+const READ_CONSOLE_MAX_SIZE: usize = 4096;
+
+/// HVC命令によるハイパーコールを処理する
+///
+/// HVC命令の優先復帰アドレスは次の命令のため、ELR_EL2 は進めない
+pub fn hvc_handler(registers: &mut Registers) -> Result<(), VmError> {
+    let function_id = registers.x0 & 0xFFFF_FFFF;
+    let result: [u64; 4] = match function_id {
+        SMCCC_VERSION => [SMCCC_VERSION_1_1, 0, 0, 0],
+        MINIVISOR_HYP_CALL_UID => MINIVISOR_UID,
+        MINIVISOR_HYP_REVISION => [ABI_REVISION.0, ABI_REVISION.1, 0, 0],
+        MINIVISOR_HYP_GET_VERSION => [
+            env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+            env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+            env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+            0,
+        ],
+        MINIVISOR_HYP_GET_VM_ID => [vm::get_current_vm()?.get_vm_id() as u64, 0, 0, 0],
+        MINIVISOR_HYP_LOG_WRITE => [log_write(registers.x1, registers.x2)?, 0, 0, 0],
+        MINIVISOR_HYP_YIELD => [SMCCC_RET_SUCCESS, 0, 0, 0],
+        MINIVISOR_HYP_SHUTDOWN => vm::shutdown_current_vm(),
+        MINIVISOR_HYP_VM_LIST..=MINIVISOR_HYP_VM_READ_CONSOLE => {
+            [management_call(function_id, registers)?, 0, 0, 0]
+        }
+        _ => [SMCCC_RET_NOT_SUPPORTED, 0, 0, 0],
+    };
+    registers.x0 = result[0];
+    registers.x1 = result[1];
+    registers.x2 = result[2];
+    registers.x3 = result[3];
+    Ok(())
+}
+
+/// ゲストのRAM上のバッファを参照する(範囲がRAMに収まらない場合は None)
+///
+/// 仮想マシンのRAMは VM が解放されるまで残るため、vm と同じ期間だけ参照できる
+#[allow(clippy::mut_from_ref)]
+fn get_guest_buffer(vm: &VM, address: u64, size: usize) -> Option<&mut [u8]> {
+    let physical_address = vm.get_physical_address(address as usize)?;
+    if size != 0 {
+        vm.get_physical_address((address as usize).checked_add(size - 1)?)?;
+    }
+    Some(unsafe { core::slice::from_raw_parts_mut(physical_address as *mut u8, size) })
+}
+
+/// ゲストのRAM上の文字列を仮想マシンのIDを付けて出力し、出力したバイト数を返す
+fn log_write(address: u64, size: u64) -> Result<u64, VmError> {
+    let vm = vm::get_current_vm()?;
+    let size = (size as usize).min(LOG_WRITE_MAX_SIZE);
+    let Some(buffer) = get_guest_buffer(&vm, address, size) else {
+        return Ok(SMCCC_RET_INVALID_PARAMETER);
+    };
+    let message = String::from_utf8_lossy(buffer);
+    println!("[VM{}] {}", vm.get_vm_id(), message.trim_end_matches('\n'));
+    Ok(size as u64)
+}
+
+/// 管理用VMからの仮想マシンの操作を処理する
+///
+/// 各操作はコンソールのコマンドと同じ処理を使う
+fn management_call(function_id: u64, registers: &Registers) -> Result<u64, VmError> {
+    let current_vm = vm::get_current_vm()?;
+    if !current_vm.is_management_vm() {
+        return Ok(MINIVISOR_RET_DENIED);
+    }
+    let to_result = |result: Result<(), ()>| {
+        if result.is_ok() {
+            SMCCC_RET_SUCCESS
+        } else {
+            MINIVISOR_RET_FAILED
+        }
+    };
+    match function_id {
+        MINIVISOR_HYP_VM_LIST => {
+            let vm_list = vm::get_vm_list();
+            let entries = (registers.x2 as usize).min(vm_list.len());
+            let Some(buffer) = get_guest_buffer(&current_vm, registers.x1, entries * 16) else {
+                return Ok(SMCCC_RET_INVALID_PARAMETER);
+            };
+            for (entry, vm) in buffer.chunks_exact_mut(16).zip(vm_list.iter()) {
+                let state = if vm.is_stopped() {
+                    VM_STATE_STOPPED
+                } else if vm.is_paused() {
+                    VM_STATE_PAUSED
+                } else {
+                    VM_STATE_RUNNING
+                };
+                entry[0..8].copy_from_slice(&(vm.get_vm_id() as u64).to_le_bytes());
+                entry[8..16].copy_from_slice(&state.to_le_bytes());
+            }
+            return Ok(vm_list.len() as u64);
+        }
+        MINIVISOR_HYP_VM_CREATE => {
+            return Ok(match crate::launch_cpu() {
+                Ok(()) => SMCCC_RET_SUCCESS,
+                Err(e) => {
+                    println!("Failed to create a VM: {e}");
+                    MINIVISOR_RET_FAILED
+                }
+            });
+        }
+        _ => {}
+    }
+
+    let Some(vm) = vm::get_vm(registers.x1 as usize) else {
+        return Ok(SMCCC_RET_INVALID_PARAMETER);
+    };
+    Ok(match function_id {
+        MINIVISOR_HYP_VM_DESTROY => to_result(vm.destroy()),
+        MINIVISOR_HYP_VM_PAUSE => to_result(vm.pause()),
+        MINIVISOR_HYP_VM_RESUME => to_result(vm.resume()),
+        MINIVISOR_HYP_VM_READ_CONSOLE => {
+            let size = (registers.x3 as usize).min(READ_CONSOLE_MAX_SIZE);
+            let Some(buffer) = get_guest_buffer(&current_vm, registers.x2, size) else {
+                return Ok(SMCCC_RET_INVALID_PARAMETER);
+            };
+            vm.read_console_output(buffer) as u64
+        }
+        _ => SMCCC_RET_NOT_SUPPORTED,
+    })
+}
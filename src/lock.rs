@@ -1,5 +1,5 @@
 //!
-//! Spin Lock による Mutex<T> の実装
+//! Spin Lock による Mutex<T> と RwLock<T> の実装
 //!
 
 use crate::asm::{get_daif_and_disable_irq_fiq, set_daif};
@@ -8,13 +8,22 @@ use core::cell::UnsafeCell;
 use core::hint::spin_loop;
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 pub struct Mutex<T: ?Sized> {
     lock: AtomicBool,
     data: UnsafeCell<T>,
 }
 
+/// 複数の読み込みと1つの書き込みを排他する Spin Lock
+pub struct RwLock<T: ?Sized> {
+    /// RW_LOCK_WRITER が立っている場合は書き込み中、それ以外は読み込み中の数
+    state: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+const RW_LOCK_WRITER: usize = 1 << (usize::BITS - 1);
+
 pub struct MutexGuard<'a, T: ?Sized + 'a> {
     lock: &'a AtomicBool,
     daif: u64,
@@ -22,6 +31,20 @@ pub struct MutexGuard<'a, T: ?Sized + 'a> {
     _forbid_send: PhantomData<*const ()>,
 }
 
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    state: &'a AtomicUsize,
+    daif: u64,
+    data: &'a T,
+    _forbid_send: PhantomData<*const ()>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    state: &'a AtomicUsize,
+    daif: u64,
+    data: &'a mut T,
+    _forbid_send: PhantomData<*const ()>,
+}
+
 impl<T> Mutex<T> {
     pub const fn new(d: T) -> Mutex<T> {
         Mutex {
@@ -53,6 +76,85 @@ impl<T: ?Sized> Mutex<T> {
             unsafe { set_daif(daif) };
         }
     }
+
+    /// ロックを取得できない場合は待たずに None を返す
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let daif = unsafe { get_daif_and_disable_irq_fiq() };
+        if self
+            .lock
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(MutexGuard {
+                lock: &self.lock,
+                daif,
+                data: unsafe { &mut *self.data.get() },
+                _forbid_send: PhantomData,
+            })
+        } else {
+            unsafe { set_daif(daif) };
+            None
+        }
+    }
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(d: T) -> RwLock<T> {
+        RwLock {
+            state: AtomicUsize::new(0),
+            data: UnsafeCell::new(d),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// 読み込みのためにロックする(書き込み中でなければ他の読み込みと同時に取得できる)
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if (state & RW_LOCK_WRITER) != 0 {
+                spin_loop();
+                continue;
+            }
+            let daif = unsafe { get_daif_and_disable_irq_fiq() };
+            if self
+                .state
+                .compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return RwLockReadGuard {
+                    state: &self.state,
+                    daif,
+                    data: unsafe { &*self.data.get() },
+                    _forbid_send: PhantomData,
+                };
+            }
+            unsafe { set_daif(daif) };
+        }
+    }
+
+    /// 書き込みのためにロックする(読み込み中のものが無くなるまで待つ)
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        loop {
+            while self.state.load(Ordering::Relaxed) != 0 {
+                spin_loop();
+            }
+            let daif = unsafe { get_daif_and_disable_irq_fiq() };
+            if self
+                .state
+                .compare_exchange(0, RW_LOCK_WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return RwLockWriteGuard {
+                    state: &self.state,
+                    daif,
+                    data: unsafe { &mut *self.data.get() },
+                    _forbid_send: PhantomData,
+                };
+            }
+            unsafe { set_daif(daif) };
+        }
+    }
 }
 
 unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
@@ -80,3 +182,40 @@ impl<T: ?Sized> Drop for MutexGuard<'_, T> {
         unsafe { set_daif(self.daif) };
     }
 }
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.state.fetch_sub(1, Ordering::Release);
+        unsafe { set_daif(self.daif) };
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &*self.data
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.data
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.state.store(0, Ordering::Release);
+        unsafe { set_daif(self.daif) };
+    }
+}
@@ -6,7 +6,10 @@ extern crate alloc;
 #[macro_use]
 mod serial;
 mod asm;
+mod block_cache;
+mod block_device;
 mod console;
+mod crash;
 mod dtb;
 mod drivers {
     pub mod generic_timer;
@@ -14,48 +17,79 @@ mod drivers {
     pub mod pl011;
     pub mod virtio;
     pub mod virtio_blk;
+    pub mod virtio_net;
 }
 mod elf;
 mod exception;
 mod fat32;
+mod gdb;
+mod hypercall;
 mod lock;
 mod memory_allocator;
 mod mmio {
     pub mod gicv3;
     pub mod pl011;
+    pub mod shmem;
+    pub mod virtio_9p;
     pub mod virtio_blk;
+    pub mod virtio_console;
+    pub mod virtio_net;
+    pub mod virtio_rng;
+    pub mod virtio_vsock;
 }
+mod overlay;
 mod paging;
 mod psci;
+mod random;
 mod registers;
+mod snapshot;
+mod vcpu;
 mod vgic;
+mod virtqueue;
 mod vm;
+mod vm_config;
+mod vswitch;
 
-use drivers::{generic_timer, gicv3, pl011, virtio_blk};
+use drivers::{generic_timer, gicv3, pl011, virtio_blk, virtio_net};
 use lock::Mutex;
 use psci::PsciErrorCodes;
 use serial::SerialDevice;
 
+use alloc::vec::Vec;
+
 use core::alloc::{GlobalAlloc, Layout};
 use core::ffi::CStr;
 use core::mem::MaybeUninit;
 use core::slice;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 struct GlobalAllocator {}
 
 /// グローバル変数置き場
 static PL011_DEVICE: Mutex<pl011::Pl011> = Mutex::new(pl011::Pl011::invalid());
 static mut PL011_INT_ID: u32 = 0;
+static mut VIRTIO_NET_INT_ID: u32 = 0;
+/// 各 Virtio-Blk の割り込み番号(0 の場合は割り込みを使わない)
+static mut VIRTIO_BLK_INT_IDS: [u32; MAX_VIRTIO_BLK_DEVICES] = [0; MAX_VIRTIO_BLK_DEVICES];
 static MEMORY_ALLOCATOR: Mutex<memory_allocator::MemoryAllocator> =
     Mutex::new(memory_allocator::MemoryAllocator::new());
-static VIRTIO_BLK: Mutex<virtio_blk::VirtioBlk> = Mutex::new(virtio_blk::VirtioBlk::invalid());
-static mut FAT32: MaybeUninit<fat32::Fat32> = MaybeUninit::uninit();
+/// 使用するホストの Virtio-Blk の最大数
+const MAX_VIRTIO_BLK_DEVICES: usize = 4;
+static VIRTIO_BLK_DEVICES: [Mutex<virtio_blk::VirtioBlk>; MAX_VIRTIO_BLK_DEVICES] =
+    [const { Mutex::new(virtio_blk::VirtioBlk::invalid()) }; MAX_VIRTIO_BLK_DEVICES];
+/// FAT32 を読み込む起動ディスク(DTB で最初に見つかった Virtio-Blk)
+static VIRTIO_BLK: &Mutex<virtio_blk::VirtioBlk> = &VIRTIO_BLK_DEVICES[0];
+/// 起動ディスクのキャッシュ(VIRTIO_BLK のロックを取ってからロックする)
+static BLOCK_CACHE: Mutex<block_cache::BlockCache> = Mutex::new(block_cache::BlockCache::new());
+/// 起動ディスクの FAT32(ファイルの作成時のみ書き込みでロックする。VIRTIO_BLK より先にロックする)
+static FAT32: lock::RwLock<fat32::Fat32> = lock::RwLock::new(fat32::Fat32::invalid());
 #[global_allocator]
 static GLOBAL_ALLOCATOR: GlobalAllocator = GlobalAllocator {};
 static CONSOLE: Mutex<console::Console> = Mutex::new(console::Console::new());
 static IS_CONSOLE_ACTIVE: AtomicBool = AtomicBool::new(false);
 static mut DTB: MaybeUninit<dtb::Dtb> = MaybeUninit::uninit();
+/// コンソールの割り込みを処理するpCPUの MPIDR_EL1
+static BSP_MPIDR: AtomicU64 = AtomicU64::new(0);
 
 /// 定数
 const STACK_SIZE: usize = 0x10000;
@@ -84,6 +118,7 @@ extern "C" fn main(argc: usize, argv: *const *const u8) -> usize {
     }
 
     println!("Hello, world!");
+    BSP_MPIDR.store(asm::get_mpidr_el1(), Ordering::Relaxed);
 
     let current_el = asm::get_currentel() >> 2;
     println!("CurrentEL: {}", current_el);
@@ -105,22 +140,49 @@ extern "C" fn main(argc: usize, argv: *const *const u8) -> usize {
 
     generic_timer::init_generic_timer_global(&dtb);
 
-    let mut virtblk = init_virtio_blk(&dtb).unwrap();
-    let fat32 = init_fat32(&mut virtblk);
+    let mut blk_devices = init_virtio_blk(&dtb);
+    let fat32 = init_fat32(&mut blk_devices.first_mut().expect("Virtio-Blk is not found").0);
 
-    let (boot_address, argument) = vm::create_vm(&fat32, &mut virtblk, &redistributor);
+    for (i, (blk, int_id)) in blk_devices.into_iter().enumerate() {
+        *VIRTIO_BLK_DEVICES[i].lock() = blk;
+        if let Some(int_id) = int_id {
+            enable_virtio_interrupt(int_id, &distributor);
+            unsafe { VIRTIO_BLK_INT_IDS[i] = int_id };
+        }
+    }
 
-    *VIRTIO_BLK.lock() = virtblk;
-    unsafe {
-        (&raw mut FAT32).as_mut().unwrap().write(fat32);
-        (&raw mut DTB).as_mut().unwrap().write(dtb);
+    if let Some((net, int_id)) = init_virtio_net(&dtb) {
+        if let Some(mac) = net.get_mac_address() {
+            println!(
+                "Host Virtio-Net: {:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+                mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+            );
+        }
+        vswitch::connect_uplink(net);
+        enable_virtio_interrupt(int_id, &distributor);
+        unsafe { VIRTIO_NET_INT_ID = int_id };
     }
+    *FAT32.write() = fat32;
+    unsafe { (&raw mut DTB).as_mut().unwrap().write(dtb) };
 
     /* PSCIのバージョンチェック */
     let (major_version, minor_version) = psci::check_psci_version().expect("PSCI is not supported");
     println!("PSCI version {major_version}.{minor_version}");
 
-    vm::boot_vm(boot_address, argument)
+    /* ロックを保持したまま仮想マシンへ移らないよう、結果を受け取ってから分岐する */
+    let result = vm::create_vm(&FAT32.read(), &mut lock_boot_disk(), &redistributor);
+    match result {
+        Ok((boot_address, argument)) => vm::boot_vm(boot_address, argument),
+        Err(e) => {
+            println!("Failed to create VM: {e}");
+            vm::release_cpu()
+        }
+    }
+}
+
+/// コンソールの割り込みを処理するpCPUか
+pub fn is_bsp() -> bool {
+    BSP_MPIDR.load(Ordering::Relaxed) == asm::get_mpidr_el1()
 }
 
 fn str_to_usize(s: &str) -> Option<usize> {
@@ -189,10 +251,7 @@ fn init_serial_port(dtb: &dtb::Dtb) -> Result<(), usize> {
 
 #[panic_handler]
 pub fn panic(info: &core::panic::PanicInfo) -> ! {
-    println!("{info}");
-    loop {
-        core::hint::spin_loop();
-    }
+    crash::report_panic(info)
 }
 
 pub fn setup_memory(dtb: &dtb::Dtb, dtb_address: usize, elf_address: usize, stack_pointer: usize) {
@@ -236,6 +295,27 @@ pub fn setup_memory(dtb: &dtb::Dtb, dtb_address: usize, elf_address: usize, stac
         }
     }
 
+    /* シンボルテーブルを除外 */
+    if let Some(symbol_table) = elf::SymbolTable::new(elf_header) {
+        let mut is_reserved = true;
+        for (address, size) in symbol_table.get_memory_regions() {
+            println!(
+                "Reserve [{:#X} ~ {:#X}] for Symbols",
+                address,
+                address + size
+            );
+            if let Err(e) = memory_allocator.reserve_memory(address, size, 0) {
+                println!("Failed to reserve memory for the symbol table: {:?}", e);
+                is_reserved = false;
+            }
+        }
+        if is_reserved {
+            crash::set_symbol_table(symbol_table);
+        }
+    } else {
+        println!("Symbol table is not found");
+    }
+
     /* Stackを除外 */
     let stack_end = ((stack_pointer - 1) & !(paging::PAGE_SIZE - 1)) + paging::PAGE_SIZE;
     let stack_start = stack_end - STACK_SIZE;
@@ -300,27 +380,91 @@ fn enable_serial_port_interrupt(pl011: &pl011::Pl011, distributor: &gicv3::GicDi
     pl011.enable_interrupt();
 }
 
-fn init_virtio_blk(dtb: &dtb::Dtb) -> Option<virtio_blk::VirtioBlk> {
+/// ホストの Virtio-Blk を全て探し、(デバイス, 割り込み番号)の一覧を返す
+///
+/// 割り込みを使えないデバイスでは、ゲストのディスクへの要求は完了しない
+fn init_virtio_blk(dtb: &dtb::Dtb) -> Vec<(virtio_blk::VirtioBlk, Option<u32>)> {
+    let mut devices = Vec::new();
+    let mut virtio = None;
+    while devices.len() < MAX_VIRTIO_BLK_DEVICES {
+        virtio = dtb.search_node_by_compatible(b"virtio,mmio", virtio.as_ref());
+        let Some(node) = virtio.as_ref() else {
+            break;
+        };
+        if !dtb.is_node_operational(node) {
+            continue;
+        }
+        let (base_address, _) = dtb.read_reg_property(node, 0).unwrap();
+        let Ok(blk) = virtio_blk::VirtioBlk::new(base_address) else {
+            continue;
+        };
+        let int_id = dtb
+            .get_property(node, b"interrupts")
+            .map(|p| dtb.read_property_as_u32_array(&p))
+            .filter(|interrupts| u32::from_be(interrupts[0]) == gicv3::DTB_GIC_SPI)
+            .map(|interrupts| gicv3::GIC_SPI_BASE + u32::from_be(interrupts[1]));
+        if int_id.is_none() {
+            println!(
+                "Virtio-Blk({:#X}) does not support interrupt.",
+                base_address
+            );
+        }
+        devices.push((blk, int_id));
+    }
+    devices
+}
+
+/// int_id を割り込み番号とするホストの Virtio-Blk の番号
+fn get_virtio_blk_index(int_id: u32) -> Option<usize> {
+    unsafe { VIRTIO_BLK_INT_IDS }
+        .iter()
+        .position(|i| *i != 0 && *i == int_id)
+}
+
+/// 起動ディスク以外の index 番目のホストの Virtio-Blk
+fn get_host_disk(index: usize) -> Option<&'static Mutex<virtio_blk::VirtioBlk>> {
+    VIRTIO_BLK_DEVICES
+        .get(index)
+        .filter(|device| index != 0 && device.lock().get_base_address() != 0)
+}
+
+/// FAT32 を読み書きするため、起動ディスクとそのキャッシュのロックを取る
+fn lock_boot_disk() -> block_cache::CachedBlockDevice<'static, virtio_blk::VirtioBlk> {
+    block_cache::CachedBlockDevice::lock(VIRTIO_BLK, &BLOCK_CACHE)
+}
+
+/// ホストの Virtio-Net と割り込み番号を探す
+fn init_virtio_net(dtb: &dtb::Dtb) -> Option<(virtio_net::VirtioNet, u32)> {
     let mut virtio = None;
     loop {
         virtio = dtb.search_node_by_compatible(b"virtio,mmio", virtio.as_ref());
-        match &virtio {
-            Some(virtio) => {
-                if dtb.is_node_operational(virtio) {
-                    let (base_address, _) = dtb.read_reg_property(virtio, 0).unwrap();
-                    if let Ok(blk) = virtio_blk::VirtioBlk::new(base_address) {
-                        return Some(blk);
-                    }
-                }
-            }
-            None => {
-                return None;
-            }
+        let node = virtio.as_ref()?;
+        if !dtb.is_node_operational(node) {
+            continue;
+        }
+        let (base_address, _) = dtb.read_reg_property(node, 0).unwrap();
+        let Ok(net) = virtio_net::VirtioNet::new(base_address) else {
+            continue;
+        };
+        let interrupts = dtb.read_property_as_u32_array(&dtb.get_property(node, b"interrupts")?);
+        if u32::from_be(interrupts[0]) != gicv3::DTB_GIC_SPI {
+            println!("Virtio-Net does not support interrupt.");
+            return None;
         }
+        return Some((net, gicv3::GIC_SPI_BASE + u32::from_be(interrupts[1])));
     }
 }
 
-pub fn init_fat32(blk: &mut virtio_blk::VirtioBlk) -> fat32::Fat32 {
+fn enable_virtio_interrupt(int_id: u32, distributor: &gicv3::GicDistributor) {
+    distributor.set_group(int_id, gicv3::GicGroup::NonSecureGroup1);
+    distributor.set_priority(int_id, 0x00);
+    distributor.set_routing(int_id, false, asm::get_mpidr_el1());
+    distributor.set_trigger_mode(int_id, true);
+    distributor.set_pending(int_id, false);
+    distributor.set_enable(int_id, true);
+}
+
+pub fn init_fat32(blk: &mut impl block_device::BlockDevice) -> fat32::Fat32 {
     #[repr(C)]
     struct PartitionTableEntry {
         boot_flag: u8,
@@ -375,12 +519,15 @@ unsafe impl GlobalAlloc for GlobalAllocator {
     }
 }
 
-pub fn launch_cpu() -> bool {
+/// 停止しているpCPUを起動し、新たな仮想マシンを作成させる
+///
+/// ハイパーコールからも呼ばれるため、失敗した場合はハイパーバイザを止めずにエラーを返す
+pub fn launch_cpu() -> Result<(), vm::VmError> {
     let dtb = unsafe { (&raw const DTB).as_ref().unwrap().assume_init_ref() };
     let mut cpu_node = None;
     let current_affinity = asm::mpidr_to_affinity(asm::get_mpidr_el1());
     let stack_address = allocate_pages(STACK_SIZE >> paging::PAGE_SHIFT, 0)
-        .expect("Failed to allocate memory")
+        .map_err(|_| vm::VmError::NoMemory)?
         + STACK_SIZE;
     while let Some(cpu) = dtb.search_node(b"cpu", cpu_node.as_ref()) {
         if let Some((affinity, _)) = dtb.read_reg_property(&cpu, 0)
@@ -391,7 +538,7 @@ pub fn launch_cpu() -> bool {
                 asm::core_entry as *const fn() as usize as u64,
                 stack_address as u64,
             ) {
-                Ok(_) => return true,
+                Ok(_) => return Ok(()),
                 Err(PsciErrorCodes::AlreadyOn) => { /* 次のノードを探索 */ }
                 Err(e) => {
                     println!("Failed to start CPU(Affinity: {:#X}): {:?}", affinity, e);
@@ -401,7 +548,7 @@ pub fn launch_cpu() -> bool {
         cpu_node = Some(cpu);
     }
     free_pages(stack_address - STACK_SIZE, STACK_SIZE >> paging::PAGE_SHIFT);
-    false
+    Err(vm::VmError::NoFreeCpu)
 }
 
 extern "C" fn core_main() -> ! {
@@ -412,12 +559,31 @@ extern "C" fn core_main() -> ! {
     let redistributor =
         init_gic_redistributor(unsafe { (&raw const DTB).as_ref().unwrap().assume_init_ref() });
 
-    let (boot_address, argument) = vm::create_vm(
-        unsafe { (&raw const FAT32).as_ref().unwrap().assume_init_ref() },
-        &mut VIRTIO_BLK.lock(),
-        &redistributor,
-    );
-    vm::boot_vm(boot_address, argument)
+    if let Some(snapshot) = vm::take_restore_request() {
+        let result = vm::restore_vm(
+            &FAT32.read(),
+            &mut lock_boot_disk(),
+            &redistributor,
+            &snapshot,
+        );
+        match result {
+            Ok(context) => vm::resume_vm(context),
+            Err(e) => {
+                println!("Failed to restore VM: {e}");
+                vm::release_cpu()
+            }
+        }
+    }
+
+    /* ロックを保持したまま仮想マシンへ移らないよう、結果を受け取ってから分岐する */
+    let result = vm::create_vm(&FAT32.read(), &mut lock_boot_disk(), &redistributor);
+    match result {
+        Ok((boot_address, argument)) => vm::boot_vm(boot_address, argument),
+        Err(e) => {
+            println!("Failed to create VM: {e}");
+            vm::release_cpu()
+        }
+    }
 }
 
 fn handle_input(device: &Mutex<dyn SerialDevice>) {
@@ -431,7 +597,9 @@ fn handle_input(device: &Mutex<dyn SerialDevice>) {
         if c == 0 {
             return;
         }
-        if c == CONSOLE_SWITCH_KEY {
+        if gdb::is_active() {
+            gdb::input(c);
+        } else if c == CONSOLE_SWITCH_KEY {
             let old = IS_CONSOLE_ACTIVE.fetch_xor(true, Ordering::Relaxed);
             if old {
                 /* コンソール無効化: プロンプトを上書き */
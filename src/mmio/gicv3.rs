@@ -3,9 +3,10 @@
 //!
 
 use crate::asm;
+use crate::drivers::gicv3::GicRedistributor;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
 use crate::vgic;
-use crate::vm;
-use crate::vm::MmioHandler;
+use crate::vm::{self, MmioHandler, VM, VmError};
 
 use alloc::collections::linked_list::LinkedList;
 
@@ -20,6 +21,8 @@ pub struct GicDistributorMmio {
     configuration: [u32; 64],
     group_modifier: [u32; 32],
     router: [u64; 1020],
+    /// 仮想CPUを実行しているpCPUのアフィニティ
+    physical_affinity: u64,
     to_inject_interrupt: LinkedList<u64>,
 }
 
@@ -62,7 +65,7 @@ pub const INJECT_INTERRUPT_INT_ID: u32 = 11;
 impl GicDistributorMmio {
     pub const MMIO_SIZE: usize = 0x10000;
 
-    pub fn new() -> Self {
+    pub fn new(mpidr_el1: u64) -> Self {
         Self {
             ctlr: 0,
             group: [0; 32],
@@ -73,6 +76,7 @@ impl GicDistributorMmio {
             configuration: [0; 64],
             group_modifier: [0; 32],
             router: [0; 1020],
+            physical_affinity: asm::mpidr_to_affinity(mpidr_el1),
             to_inject_interrupt: LinkedList::new(),
         }
     }
@@ -141,10 +145,6 @@ impl GicDistributorMmio {
         (self.group_modifier[register] >> offset) & 0b1
     }
 
-    fn get_router(&self, int_id: u32) -> u64 {
-        self.router[int_id as usize]
-    }
-
     pub fn trigger_interrupt(&mut self, int_id: u32, physical_int_id: Option<u32>) {
         if (self.ctlr & GICD_CTLR_ENABLE_GRP1NS) == 0 {
             println!("GIC Distributor is not enabled.");
@@ -160,46 +160,39 @@ impl GicDistributorMmio {
         }
         let group = self.get_group(int_id);
         let priority = self.get_priority(int_id);
-        let router = self.get_router(int_id);
         if (self.ctlr & GICD_CTLR_ARE_S) == 0 {
             println!("Target CPU Style interrupt is not supported.");
             return;
         }
-        let router_aff3 = (router & ((1 << 40) - 1)) >> 32;
-        let router_aff2 = (router & ((1 << 24) - 1)) >> 16;
-        let router_aff1 = (router & ((1 << 16) - 1)) >> 8;
-        let router_aff0 = router & ((1 << 8) - 1);
-        let mpidr_el1 = asm::get_mpidr_el1();
-        let mpidr_aff3 = (mpidr_el1 & ((1 << 40) - 1)) >> 32;
-        let mpidr_aff2 = (mpidr_el1 & ((1 << 24) - 1)) >> 16;
-        let mpidr_aff1 = (mpidr_el1 & ((1 << 16) - 1)) >> 8;
-        let mpidr_aff0 = mpidr_el1 & ((1 << 8) - 1);
 
+        /* 仮想CPUは1つのため、GICD_IROUTER に関わらず仮想CPUを実行しているpCPUへ送る */
         let list_entry = vgic::create_list_register_entry(int_id, group, priority, physical_int_id);
-
-        if router_aff3 == mpidr_aff3
-            && router_aff2 == mpidr_aff2
-            && router_aff1 == mpidr_aff1
-            && router_aff0 == mpidr_aff0
-        {
+        if self.physical_affinity == asm::mpidr_to_affinity(asm::get_mpidr_el1()) {
             /* 同じpCPU */
-            vgic::add_virtual_interrupt(list_entry);
+            if vm::get_current_vm().is_ok_and(|vm| vm.is_paused()) {
+                /* 一時停止中は再開時に注入する */
+                if !self.to_inject_interrupt.contains(&list_entry) {
+                    self.to_inject_interrupt.push_back(list_entry);
+                }
+            } else {
+                vgic::add_virtual_interrupt(list_entry);
+            }
         } else {
             /* 違うVMのpCPU */
             self.to_inject_interrupt.push_back(list_entry);
-            assert!(router_aff0 < 16);
-            let icc_sgi1r_el1 = (router_aff3 << 48)
-                | (router_aff2 << 32)
-                | ((INJECT_INTERRUPT_INT_ID as u64) << 24)
-                | (router_aff1 << 48)
-                | (1 << router_aff0);
-            unsafe { asm::set_icc_sgi1r_el1(icc_sgi1r_el1) };
+            GicRedistributor::send_sgi(INJECT_INTERRUPT_INT_ID, self.physical_affinity);
         }
     }
 }
 
 pub fn inject_interrupt_handler() {
-    let vm = vm::get_current_vm();
+    let Ok(vm) = vm::get_current_vm() else {
+        return;
+    };
+    if vm.is_paused() {
+        /* 再開時に注入する */
+        return;
+    }
     let mut distributor = vm.get_gic_distributor_mmio().lock();
     let mut redistributor = vm.get_gic_redistributor_mmio().lock();
     while let Some(entry) = distributor.to_inject_interrupt.pop_front() {
@@ -211,7 +204,7 @@ pub fn inject_interrupt_handler() {
 }
 
 impl MmioHandler for GicDistributorMmio {
-    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, ()> {
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, VmError> {
         let mut result = 0u64;
         if offset == GICD_CTLR && access_width == 32 {
             result = self.ctlr as u64;
@@ -264,7 +257,7 @@ impl MmioHandler for GicDistributorMmio {
         Ok(result)
     }
 
-    fn write(&mut self, offset: usize, access_width: u64, value: u64) -> Result<(), ()> {
+    fn write(&mut self, offset: usize, access_width: u64, value: u64) -> Result<(), VmError> {
         if offset == GICD_CTLR && access_width == 32 {
             self.ctlr = value as u32;
         } else if (GICD_IGROUPR0..=GICD_IGROUPR31).contains(&offset) && access_width == 32 {
@@ -333,11 +326,47 @@ impl MmioHandler for GicDistributorMmio {
         }
         Ok(())
     }
+
+    fn save_state(&self, _vm: &VM, writer: &mut SnapshotWriter) {
+        writer.write_u32(self.ctlr);
+        writer.write_u32_array(&self.group);
+        writer.write_u32_array(&self.enable);
+        writer.write_u32_array(&self.pending);
+        writer.write_u32_array(&self.active);
+        writer.write_u32_array(&self.priority);
+        writer.write_u32_array(&self.configuration);
+        writer.write_u32_array(&self.group_modifier);
+        writer.write_u64_array(&self.router);
+        writer.write_u64(self.to_inject_interrupt.len() as u64);
+        for e in &self.to_inject_interrupt {
+            writer.write_u64(*e);
+        }
+    }
+
+    fn load_state(&mut self, _vm: &VM, reader: &mut SnapshotReader) -> Result<(), ()> {
+        self.ctlr = reader.read_u32()?;
+        reader.read_u32_array(&mut self.group)?;
+        reader.read_u32_array(&mut self.enable)?;
+        reader.read_u32_array(&mut self.pending)?;
+        reader.read_u32_array(&mut self.active)?;
+        reader.read_u32_array(&mut self.priority)?;
+        reader.read_u32_array(&mut self.configuration)?;
+        reader.read_u32_array(&mut self.group_modifier)?;
+        reader.read_u64_array(&mut self.router)?;
+        self.to_inject_interrupt.clear();
+        for _ in 0..reader.read_u64()? {
+            self.to_inject_interrupt.push_back(reader.read_u64()?);
+        }
+        Ok(())
+    }
 }
 
 /* GIC Redistributor */
 pub struct GicRedistributorMmio {
+    /// ゲストから見えるアフィニティ
     affinity: u64,
+    /// 仮想CPUを実行しているpCPUのアフィニティ
+    physical_affinity: u64,
     ctlr: u32,
     waker: u32,
     group: u32,
@@ -382,6 +411,7 @@ impl GicRedistributorMmio {
         Self {
             ctlr: 0,
             affinity: asm::mpidr_to_affinity(mpidr_el1),
+            physical_affinity: asm::mpidr_to_affinity(mpidr_el1),
             waker: GICR_WAKER_CHILDREN_ASLEEP,
             group: 0,
             enable: 0,
@@ -463,29 +493,26 @@ impl GicRedistributorMmio {
         let priority = self.get_priority(int_id);
 
         let list_entry = vgic::create_list_register_entry(int_id, group, priority, physical_int_id);
-        if self.affinity == asm::mpidr_to_affinity(asm::get_mpidr_el1()) {
+        if self.physical_affinity == asm::mpidr_to_affinity(asm::get_mpidr_el1()) {
             /* 同じpCPU */
-            vgic::add_virtual_interrupt(list_entry);
+            if vm::get_current_vm().is_ok_and(|vm| vm.is_paused()) {
+                /* 一時停止中は再開時に注入する */
+                if !self.to_inject_interrupt.contains(&list_entry) {
+                    self.to_inject_interrupt.push_back(list_entry);
+                }
+            } else {
+                vgic::add_virtual_interrupt(list_entry);
+            }
         } else {
             /* 違うVMのpCPU */
             self.to_inject_interrupt.push_back(list_entry);
-            let mpidr_aff3 = (self.affinity & ((1 << 40) - 1)) >> 32;
-            let mpidr_aff2 = (self.affinity & ((1 << 24) - 1)) >> 16;
-            let mpidr_aff1 = (self.affinity & ((1 << 16) - 1)) >> 8;
-            let mpidr_aff0 = self.affinity & ((1 << 8) - 1);
-            assert!(mpidr_aff0 < 16);
-            let icc_sgi1r_el1 = (mpidr_aff3 << 48)
-                | (mpidr_aff2 << 32)
-                | ((INJECT_INTERRUPT_INT_ID as u64) << 24)
-                | (mpidr_aff1 << 48)
-                | (1 << mpidr_aff0);
-            unsafe { asm::set_icc_sgi1r_el1(icc_sgi1r_el1) };
+            GicRedistributor::send_sgi(INJECT_INTERRUPT_INT_ID, self.physical_affinity);
         }
     }
 }
 
 impl MmioHandler for GicRedistributorMmio {
-    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, ()> {
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, VmError> {
         let mut result = 0u64;
         if offset == GICR_CTLR && access_width == 32 {
             result = self.ctlr as u64;
@@ -517,7 +544,7 @@ impl MmioHandler for GicRedistributorMmio {
         Ok(result)
     }
 
-    fn write(&mut self, offset: usize, access_width: u64, value: u64) -> Result<(), ()> {
+    fn write(&mut self, offset: usize, access_width: u64, value: u64) -> Result<(), VmError> {
         if offset == GICR_CTLR && access_width == 32 {
             self.ctlr = value as u32;
         }
@@ -582,4 +609,39 @@ impl MmioHandler for GicRedistributorMmio {
         }
         Ok(())
     }
+
+    fn save_state(&self, _vm: &VM, writer: &mut SnapshotWriter) {
+        writer.write_u64(self.affinity);
+        writer.write_u32(self.ctlr);
+        writer.write_u32(self.waker);
+        writer.write_u32(self.group);
+        writer.write_u32(self.enable);
+        writer.write_u32(self.pending);
+        writer.write_u32(self.active);
+        writer.write_u32_array(&self.priority);
+        writer.write_u32_array(&self.configuration);
+        writer.write_u32(self.group_modifier);
+        writer.write_u64(self.to_inject_interrupt.len() as u64);
+        for e in &self.to_inject_interrupt {
+            writer.write_u64(*e);
+        }
+    }
+
+    fn load_state(&mut self, _vm: &VM, reader: &mut SnapshotReader) -> Result<(), ()> {
+        self.affinity = reader.read_u64()?;
+        self.ctlr = reader.read_u32()?;
+        self.waker = reader.read_u32()?;
+        self.group = reader.read_u32()?;
+        self.enable = reader.read_u32()?;
+        self.pending = reader.read_u32()?;
+        self.active = reader.read_u32()?;
+        reader.read_u32_array(&mut self.priority)?;
+        reader.read_u32_array(&mut self.configuration)?;
+        self.group_modifier = reader.read_u32()?;
+        self.to_inject_interrupt.clear();
+        for _ in 0..reader.read_u64()? {
+            self.to_inject_interrupt.push_back(reader.read_u64()?);
+        }
+        Ok(())
+    }
 }
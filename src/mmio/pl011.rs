@@ -3,7 +3,8 @@
 //!
 
 use crate::mmio::gicv3::GicDistributorMmio;
-use crate::vm::MmioHandler;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::vm::{MmioHandler, VM, VmError, get_current_vm};
 
 const UART_DR: usize = 0x000;
 const UART_FR: usize = 0x018;
@@ -64,7 +65,7 @@ impl Pl011Mmio {
 }
 
 impl MmioHandler for Pl011Mmio {
-    fn read(&mut self, offset: usize, _access_width: u64) -> Result<u64, ()> {
+    fn read(&mut self, offset: usize, _access_width: u64) -> Result<u64, VmError> {
         let value: u64;
         match offset {
             UART_DR => {
@@ -120,10 +121,10 @@ impl MmioHandler for Pl011Mmio {
         Ok(value)
     }
 
-    fn write(&mut self, offset: usize, _access_width: u64, value: u64) -> Result<(), ()> {
+    fn write(&mut self, offset: usize, _access_width: u64, value: u64) -> Result<(), VmError> {
         match offset {
             UART_DR => {
-                print!("{}", value as u8 as char);
+                get_current_vm()?.write_console(&[value as u8]);
             }
             UART_CR => {
                 self.control = value as u16;
@@ -138,4 +139,20 @@ impl MmioHandler for Pl011Mmio {
         }
         Ok(())
     }
+
+    fn save_state(&self, _vm: &VM, writer: &mut SnapshotWriter) {
+        writer.write_u16(self.flag);
+        writer.write_u16(self.interrupt_mask);
+        writer.write_u16(self.raw_interrupt_status);
+        writer.write_u16(self.control);
+        writer.write_bytes(&self.read_buffer);
+    }
+
+    fn load_state(&mut self, _vm: &VM, reader: &mut SnapshotReader) -> Result<(), ()> {
+        self.flag = reader.read_u16()?;
+        self.interrupt_mask = reader.read_u16()?;
+        self.raw_interrupt_status = reader.read_u16()?;
+        self.control = reader.read_u16()?;
+        reader.read_bytes(&mut self.read_buffer)
+    }
 }
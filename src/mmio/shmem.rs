@@ -0,0 +1,145 @@
+//!
+//! 仮想マシン間の共有メモリ
+//!
+//! ivshmem のように、設定ファイルの `shmem` で同じ名前を指定した仮想マシンへ同じホストのページを割り当てる。
+//! 割り当てる IPA とサイズは仮想マシンごとの設定に従い、`shmem` を指定していない仮想マシンにはデバイスを追加しない。
+//! レジスタ領域の DOORBELL に相手の VM ID を書き込むと、同じ共有メモリを持つ相手の仮想マシンへ割り込みが発生する。
+//! ゲストへ渡す Devicetree の "minivisor,shmem" のノードは、設定に合わせて reg を書き換えるか削除する。
+//!
+//! | オフセット | 名前     | 内容                                         |
+//! |------------|----------|----------------------------------------------|
+//! | 0x00       | ID       | 自身の VM ID(読み込みのみ)                  |
+//! | 0x04       | SIZE     | 共有メモリのバイト数(読み込みのみ)          |
+//! | 0x08       | DOORBELL | 割り込みを送る相手の VM ID(書き込みのみ)    |
+//! | 0x10       | ADDRESS  | 共有メモリの IPA(読み込みのみ)              |
+//!
+
+use crate::dtb::Dtb;
+use crate::lock::Mutex;
+use crate::paging::PAGE_SHIFT;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::vm::{self, MmioHandler, VM, VmError};
+use crate::vm_config::SharedMemoryConfig;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// レジスタ領域のIPA
+pub const SHMEM_MMIO_ADDRESS: usize = 0xb000000;
+pub const SHMEM_MMIO_SIZE: usize = 0x1000;
+
+const SHMEM_ID: usize = 0x00;
+const SHMEM_SIZE: usize = 0x04;
+const SHMEM_DOORBELL: usize = 0x08;
+const SHMEM_ADDRESS: usize = 0x10;
+
+const SHMEM_INT_ID: u32 = 41;
+
+struct SharedMemory {
+    name: String,
+    physical_address: usize,
+    size: usize,
+}
+
+/// 確保済みの共有メモリ(最初に使う仮想マシンの作成時に確保し、解放しない)
+static SHARED_MEMORY_LIST: Mutex<Vec<SharedMemory>> = Mutex::new(Vec::new());
+
+/// config.name の共有メモリの物理アドレスを返す
+///
+/// 確保されていない場合は確保して0で初期化する。既に確保されている場合はサイズが同じであること
+pub fn get_shared_memory(config: &SharedMemoryConfig) -> Result<usize, VmError> {
+    let mut list = SHARED_MEMORY_LIST.lock();
+    if let Some(e) = list.iter().find(|e| e.name == config.name) {
+        if e.size != config.size {
+            return Err(VmError::InvalidConfig(format!(
+                "the size of {} differs from other VMs ({:#X})",
+                config.name, e.size
+            )));
+        }
+        return Ok(e.physical_address);
+    }
+    let address = crate::allocate_pages(config.size >> PAGE_SHIFT, PAGE_SHIFT)
+        .map_err(|_| VmError::NoMemory)?;
+    unsafe { core::ptr::write_bytes(address as *mut u8, 0, config.size) };
+    list.push(SharedMemory {
+        name: config.name.clone(),
+        physical_address: address,
+        size: config.size,
+    });
+    Ok(address)
+}
+
+/// ゲストの Devicetree の共有メモリのノードを設定に合わせる
+///
+/// reg は (レジスタ領域, 共有メモリ) の順とし、共有メモリを使わない場合はノードを削除する
+pub fn update_dtb(dtb: &Dtb, config: Option<&SharedMemoryConfig>) -> Result<(), VmError> {
+    let Some(node) = dtb.search_node_by_compatible(b"minivisor,shmem", None) else {
+        if config.is_some() {
+            println!("The Devicetree has no shared memory node");
+        }
+        return Ok(());
+    };
+    let result = match config {
+        Some(config) => dtb
+            .write_reg_property(&node, 0, SHMEM_MMIO_ADDRESS, SHMEM_MMIO_SIZE)
+            .and_then(|_| dtb.write_reg_property(&node, 1, config.address, config.size)),
+        None => dtb.remove_node(&node),
+    };
+    result.map_err(|_| VmError::InvalidConfig(String::from("the shared memory node in DTB")))
+}
+
+pub struct ShmemMmio {
+    vm_id: usize,
+    name: String,
+    address: usize,
+    size: usize,
+}
+
+impl ShmemMmio {
+    pub fn new(vm_id: usize, config: &SharedMemoryConfig) -> Self {
+        Self {
+            vm_id,
+            name: config.name.clone(),
+            address: config.address,
+            size: config.size,
+        }
+    }
+}
+
+impl MmioHandler for ShmemMmio {
+    fn read(&mut self, offset: usize, _access_width: u64) -> Result<u64, VmError> {
+        Ok(match offset {
+            SHMEM_ID => self.vm_id as u64,
+            SHMEM_SIZE => self.size as u64,
+            SHMEM_ADDRESS => self.address as u64,
+            _ => 0,
+        })
+    }
+
+    fn write(&mut self, offset: usize, _access_width: u64, value: u64) -> Result<(), VmError> {
+        if offset == SHMEM_DOORBELL {
+            /* 相手のpCPUへの通知は trigger_interrupt が INJECT_INTERRUPT_INT_ID で行う */
+            match vm::get_vm(value as usize) {
+                Some(peer)
+                    if !peer.is_stopped()
+                        && peer.get_shared_memory_name() == Some(self.name.as_str()) =>
+                {
+                    peer.get_gic_distributor_mmio()
+                        .lock()
+                        .trigger_interrupt(SHMEM_INT_ID, None)
+                }
+                _ => println!("VM{}: VM{} is not available", self.vm_id, value),
+            }
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, _vm: &VM, _writer: &mut SnapshotWriter) {
+        /* 保存する状態はない(共有メモリの内容は保存しない) */
+    }
+
+    fn load_state(&mut self, _vm: &VM, _reader: &mut SnapshotReader) -> Result<(), ()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,685 @@
+//!
+//! Virtio-9P MMIO Driver
+//!
+//! ハイパーバイザの FAT32 のルートディレクトリにある SHARE ディレクトリを 9P2000.L でゲストへ共有する。
+//! 仮想マシンのディスクやスナップショットはルートディレクトリに置かれるため、ゲストからは見えない。
+//! ゲストでは `mount -t 9p -o trans=virtio,version=9p2000.L minivisor /mnt` でマウントできる。
+//! 既定では読み込み専用で、コンソールの share コマンドで書き込みを許可できる。
+//! FAT32 の実装の都合上、ファイルの作成や削除、ファイルサイズを超える書き込みはできない。
+//!
+
+use crate::FAT32;
+use crate::drivers::virtio::*;
+use crate::fat32::{Fat32, FileInfo};
+use crate::lock::RwLockReadGuard;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::virtqueue::DeviceQueue;
+use crate::vm::{MmioHandler, VM, VmError, get_current_vm};
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const VIRTIO_9P_MMIO_ADDRESS: usize = 0xa000a00;
+pub const VIRTIO_9P_MMIO_SIZE: usize = 0x200;
+const VIRTIO_9P_INT_ID: u32 = 46;
+
+const VIRTIO_9P_MOUNT_TAG: u32 = 1 << 0;
+const MOUNT_TAG: &[u8] = b"minivisor";
+/// 共有するディレクトリ(FAT32 のルートディレクトリにあるもの)
+const SHARE_DIRECTORY: &str = "SHARE";
+
+const QUEUE_NUM_MAX: u64 = 128;
+/// 1つのメッセージの最大サイズ
+const MAX_MESSAGE_SIZE: u32 = 128 * 1024;
+/// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+/// 1つのデバイスで同時に使える fid の最大数
+const MAX_FIDS: usize = 1024;
+
+/* メッセージの種類(応答は要求 + 1) */
+const P9_RLERROR: u8 = 7;
+const P9_TSTATFS: u8 = 8;
+const P9_TLOPEN: u8 = 12;
+const P9_TLCREATE: u8 = 14;
+const P9_TSYMLINK: u8 = 16;
+const P9_TMKNOD: u8 = 18;
+const P9_TRENAME: u8 = 20;
+const P9_TGETATTR: u8 = 24;
+const P9_TSETATTR: u8 = 26;
+const P9_TREADDIR: u8 = 40;
+const P9_TFSYNC: u8 = 50;
+const P9_TLINK: u8 = 70;
+const P9_TMKDIR: u8 = 72;
+const P9_TRENAMEAT: u8 = 74;
+const P9_TUNLINKAT: u8 = 76;
+const P9_TVERSION: u8 = 100;
+const P9_TATTACH: u8 = 104;
+const P9_TFLUSH: u8 = 108;
+const P9_TWALK: u8 = 110;
+const P9_TREAD: u8 = 116;
+const P9_TWRITE: u8 = 118;
+const P9_TCLUNK: u8 = 120;
+
+const P9_QTDIR: u8 = 0x80;
+const P9_QTFILE: u8 = 0x00;
+/// Rgetattr で返す項目(mode から blocks まで)
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+const V9FS_MAGIC: u32 = 0x01021997;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const O_ACCMODE: u32 = 0o3;
+const O_TRUNC: u32 = 0o1000;
+
+/* Linux のエラー番号 */
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const ENOTDIR: u32 = 20;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const EMFILE: u32 = 24;
+const EFBIG: u32 = 27;
+const EROFS: u32 = 30;
+const EOPNOTSUPP: u32 = 95;
+
+/// ゲストからの書き込みを許可するか(全ての仮想マシンで共通)
+static IS_WRITABLE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_writable(is_writable: bool) {
+    IS_WRITABLE.store(is_writable, Ordering::Relaxed);
+}
+
+/// 要求メッセージの読み出し
+struct MessageReader<'a> {
+    data: &'a [u8],
+    pointer: usize,
+}
+
+impl<'a> MessageReader<'a> {
+    fn read_bytes(&mut self, size: usize) -> Result<&'a [u8], u32> {
+        let data = self
+            .data
+            .get(self.pointer..(self.pointer + size))
+            .ok_or(EINVAL)?;
+        self.pointer += size;
+        Ok(data)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, u32> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, u32> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, u32> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn read_string(&mut self) -> Result<&'a str, u32> {
+        let length = self.read_u16()? as usize;
+        core::str::from_utf8(self.read_bytes(length)?).map_err(|_| EINVAL)
+    }
+}
+
+/// 応答メッセージの作成(ヘッダは最後に付ける)
+struct MessageWriter {
+    data: Vec<u8>,
+}
+
+impl MessageWriter {
+    fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    fn write_u8(&mut self, data: u8) {
+        self.data.push(data);
+    }
+
+    fn write_u16(&mut self, data: u16) {
+        self.data.extend_from_slice(&data.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, data: u32) {
+        self.data.extend_from_slice(&data.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, data: u64) {
+        self.data.extend_from_slice(&data.to_le_bytes());
+    }
+
+    fn write_string(&mut self, data: &str) {
+        self.write_u16(data.len() as u16);
+        self.data.extend_from_slice(data.as_bytes());
+    }
+
+    fn write_qid(&mut self, qid: Qid) {
+        self.write_u8(qid.qid_type);
+        self.write_u32(0 /* version */);
+        self.write_u64(qid.path);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Qid {
+    qid_type: u8,
+    path: u64,
+}
+
+const ROOT_QID: Qid = Qid {
+    qid_type: P9_QTDIR,
+    path: 0,
+};
+
+fn get_fat32() -> RwLockReadGuard<'static, Fat32> {
+    FAT32.read()
+}
+
+/// 共有するディレクトリにあるファイルの一覧(ディレクトリが無い場合は空)
+fn get_shared_files() -> Vec<(String, FileInfo)> {
+    get_fat32()
+        .get_directory_file_list(&mut crate::lock_boot_disk(), SHARE_DIRECTORY)
+        .unwrap_or_default()
+}
+
+/// 名前からファイルを探し、Qid とともに返す
+fn lookup_file(name: &str) -> Option<(Qid, FileInfo)> {
+    get_shared_files()
+        .into_iter()
+        .enumerate()
+        .find(|(_, (n, _))| n == name)
+        .map(|(i, (_, file))| {
+            (
+                Qid {
+                    qid_type: P9_QTFILE,
+                    path: i as u64 + 1,
+                },
+                file,
+            )
+        })
+}
+
+/// ファイルの offset から最大 count バイトを読み込む
+fn read_file(file: &FileInfo, offset: usize, count: usize) -> Result<Vec<u8>, u32> {
+    let file_size = file.get_file_size();
+    if offset >= file_size {
+        return Ok(Vec::new());
+    }
+    let length = count.min(file_size - offset);
+    /* Fat32::read はセクタ単位で読み込むため、セクタ境界に揃えたバッファを使う */
+    let sector_size = get_fat32().get_bytes_per_sector();
+    let start = offset & !(sector_size - 1);
+    let end = (offset + length).next_multiple_of(sector_size);
+    let mut buffer = vec![0u8; end - start];
+    get_fat32()
+        .read(
+            file,
+            &mut crate::lock_boot_disk(),
+            buffer.as_mut_ptr() as usize,
+            start,
+            end - start,
+        )
+        .map_err(|_| EIO)?;
+    Ok(buffer[(offset - start)..(offset - start + length)].to_vec())
+}
+
+/// ファイルの offset へ data を書き込み、書き込んだバイト数を返す
+///
+/// Fat32::write はファイルサイズを超えて書き込めないため、最後のセクタの途中までのファイルの末尾には書き込めない
+fn write_file(file: &FileInfo, offset: usize, data: &[u8]) -> Result<usize, u32> {
+    let sector_size = get_fat32().get_bytes_per_sector();
+    let writable_end = file.get_file_size() & !(sector_size - 1);
+    let length = data.len().min(writable_end.saturating_sub(offset));
+    if length == 0 {
+        return if data.is_empty() { Ok(0) } else { Err(EFBIG) };
+    }
+    let start = offset & !(sector_size - 1);
+    let end = (offset + length).next_multiple_of(sector_size);
+    let mut buffer = read_file(file, start, end - start)?;
+    if buffer.len() != end - start {
+        return Err(EIO);
+    }
+    buffer[(offset - start)..(offset - start + length)].copy_from_slice(&data[..length]);
+    get_fat32()
+        .write(
+            file,
+            &mut crate::lock_boot_disk(),
+            buffer.as_ptr() as usize,
+            start,
+            end - start,
+        )
+        .map_err(|_| EIO)?;
+    Ok(length)
+}
+
+pub struct Virtio9pMmio {
+    interrupt_status: u32,
+    status: u32,
+    page_size: usize,
+    queue_select: usize,
+    device_features_select: u32,
+    queue: DeviceQueue,
+    message_size: u32,
+    /// fid とそれが指すファイルの名前(ルートディレクトリの場合は None)
+    fids: BTreeMap<u32, Option<String>>,
+}
+
+impl Virtio9pMmio {
+    pub fn new() -> Self {
+        Self {
+            interrupt_status: 0,
+            status: 0,
+            page_size: 1 << 12,
+            queue_select: 0,
+            device_features_select: 0,
+            queue: DeviceQueue::new(),
+            message_size: MAX_MESSAGE_SIZE,
+            fids: BTreeMap::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.page_size = 1 << 12;
+        self.queue_select = 0;
+        self.device_features_select = 0;
+        self.queue.reset();
+        self.message_size = MAX_MESSAGE_SIZE;
+        self.fids.clear();
+    }
+
+    /// 全ての要求を処理する
+    fn handle_requests(&mut self, vm: &VM) {
+        let mut is_handled = false;
+        while let Some(chain) = self.queue.pop_avail(vm) {
+            let request = chain
+                .read(vm, self.message_size as usize)
+                .unwrap_or_default();
+            let mut response = Vec::new();
+            if request.len() >= HEADER_SIZE {
+                let message_type = request[4];
+                let tag = u16::from_le_bytes([request[5], request[6]]);
+                let mut reader = MessageReader {
+                    data: &request,
+                    pointer: HEADER_SIZE,
+                };
+                let mut writer = MessageWriter::new();
+                let response_type =
+                    match self.handle_message(message_type, &mut reader, &mut writer) {
+                        Ok(()) => message_type + 1,
+                        Err(error) => {
+                            writer = MessageWriter::new();
+                            writer.write_u32(error);
+                            P9_RLERROR
+                        }
+                    };
+                response
+                    .extend_from_slice(&((HEADER_SIZE + writer.data.len()) as u32).to_le_bytes());
+                response.push(response_type);
+                response.extend_from_slice(&tag.to_le_bytes());
+                response.extend_from_slice(&writer.data);
+            }
+            let length = chain.write(vm, &response);
+            self.queue.push_used(vm, &chain, length as u32);
+            is_handled = true;
+        }
+        if is_handled {
+            self.interrupt_status |= 1;
+            vm.get_gic_distributor_mmio()
+                .lock()
+                .trigger_interrupt(VIRTIO_9P_INT_ID, None);
+        }
+    }
+
+    /// fid を登録する(上限に達している場合は EMFILE)
+    fn insert_fid(&mut self, fid: u32, name: Option<String>) -> Result<(), u32> {
+        if !self.fids.contains_key(&fid) && self.fids.len() >= MAX_FIDS {
+            return Err(EMFILE);
+        }
+        self.fids.insert(fid, name);
+        Ok(())
+    }
+
+    fn get_fid(&self, fid: u32) -> Result<Option<&str>, u32> {
+        self.fids.get(&fid).map(|name| name.as_deref()).ok_or(EBADF)
+    }
+
+    /// fid が指すファイルを返す(ディレクトリの場合は EISDIR)
+    fn get_file(&self, fid: u32) -> Result<(Qid, FileInfo), u32> {
+        let name = self.get_fid(fid)?.ok_or(EISDIR)?;
+        lookup_file(name).ok_or(ENOENT)
+    }
+
+    fn handle_message(
+        &mut self,
+        message_type: u8,
+        reader: &mut MessageReader,
+        writer: &mut MessageWriter,
+    ) -> Result<(), u32> {
+        let is_writable = IS_WRITABLE.load(Ordering::Relaxed);
+        match message_type {
+            P9_TVERSION => {
+                let message_size = reader.read_u32()?;
+                let version = reader.read_string()?;
+                /* Rread のヘッダ(size[4] type[1] tag[2] count[4])が入らない大きさは受け付けない */
+                if message_size < HEADER_SIZE as u32 + 4 {
+                    return Err(EINVAL);
+                }
+                self.message_size = message_size.min(MAX_MESSAGE_SIZE);
+                self.fids.clear();
+                writer.write_u32(self.message_size);
+                writer.write_string(if version == "9P2000.L" {
+                    "9P2000.L"
+                } else {
+                    "unknown"
+                });
+            }
+            P9_TATTACH => {
+                let fid = reader.read_u32()?;
+                self.insert_fid(fid, None)?;
+                writer.write_qid(ROOT_QID);
+            }
+            P9_TWALK => {
+                let fid = reader.read_u32()?;
+                let new_fid = reader.read_u32()?;
+                let number_of_names = reader.read_u16()?;
+                let mut current = self.get_fid(fid)?.map(String::from);
+                let mut qids = Vec::new();
+                for _ in 0..number_of_names {
+                    let name = reader.read_string()?;
+                    let qid = match (current.as_deref(), name) {
+                        (_, "..") | (None, ".") => {
+                            current = None;
+                            ROOT_QID
+                        }
+                        (None, _) => match lookup_file(name) {
+                            Some((qid, _)) => {
+                                current = Some(String::from(name));
+                                qid
+                            }
+                            None => break,
+                        },
+                        (Some(_), _) => break,
+                    };
+                    qids.push(qid);
+                }
+                if qids.is_empty() && number_of_names != 0 {
+                    return Err(ENOENT);
+                }
+                if qids.len() == number_of_names as usize {
+                    self.insert_fid(new_fid, current)?;
+                }
+                writer.write_u16(qids.len() as u16);
+                for qid in qids {
+                    writer.write_qid(qid);
+                }
+            }
+            P9_TLOPEN => {
+                let fid = reader.read_u32()?;
+                let flags = reader.read_u32()?;
+                let qid = match self.get_fid(fid)? {
+                    None => ROOT_QID,
+                    Some(_) => self.get_file(fid)?.0,
+                };
+                if (flags & O_ACCMODE) != 0 && !is_writable {
+                    return Err(EROFS);
+                }
+                if (flags & O_TRUNC) != 0 {
+                    return Err(if is_writable { EOPNOTSUPP } else { EROFS });
+                }
+                writer.write_qid(qid);
+                writer.write_u32(0 /* iounit */);
+            }
+            P9_TGETATTR => {
+                let fid = reader.read_u32()?;
+                let _request_mask = reader.read_u64()?;
+                let permission = if is_writable { 0o644 } else { 0o444 };
+                let (qid, mode, size, nlink) = match self.get_fid(fid)? {
+                    None => (ROOT_QID, S_IFDIR | permission | 0o111, 0, 2),
+                    Some(_) => {
+                        let (qid, file) = self.get_file(fid)?;
+                        (qid, S_IFREG | permission, file.get_file_size() as u64, 1)
+                    }
+                };
+                writer.write_u64(P9_GETATTR_BASIC);
+                writer.write_qid(qid);
+                writer.write_u32(mode);
+                writer.write_u32(0 /* uid */);
+                writer.write_u32(0 /* gid */);
+                writer.write_u64(nlink);
+                writer.write_u64(0 /* rdev */);
+                writer.write_u64(size);
+                writer.write_u64(4096 /* blksize */);
+                writer.write_u64(size.div_ceil(512) /* blocks */);
+                /* atime, mtime, ctime, btime, gen, data_version */
+                for _ in 0..10 {
+                    writer.write_u64(0);
+                }
+            }
+            P9_TREADDIR => {
+                let fid = reader.read_u32()?;
+                let offset = reader.read_u64()? as usize;
+                let count = reader.read_u32()? as usize;
+                if self.get_fid(fid)?.is_some() {
+                    return Err(ENOTDIR);
+                }
+                let mut entries = vec![
+                    (ROOT_QID, DT_DIR, String::from(".")),
+                    (ROOT_QID, DT_DIR, String::from("..")),
+                ];
+                for (i, (name, _)) in get_shared_files().into_iter().enumerate() {
+                    let qid = Qid {
+                        qid_type: P9_QTFILE,
+                        path: i as u64 + 1,
+                    };
+                    entries.push((qid, DT_REG, name));
+                }
+                let mut data = MessageWriter::new();
+                for (i, (qid, entry_type, name)) in entries.iter().enumerate().skip(offset) {
+                    /* qid[13] offset[8] type[1] name[s] */
+                    if data.data.len() + 13 + 8 + 1 + 2 + name.len() > count {
+                        break;
+                    }
+                    data.write_qid(*qid);
+                    data.write_u64(i as u64 + 1);
+                    data.write_u8(*entry_type);
+                    data.write_string(name);
+                }
+                writer.write_u32(data.data.len() as u32);
+                writer.data.extend_from_slice(&data.data);
+            }
+            P9_TREAD => {
+                let fid = reader.read_u32()?;
+                let offset = reader.read_u64()? as usize;
+                let count = (reader.read_u32()?).min(self.message_size - (HEADER_SIZE as u32 + 4));
+                let (_, file) = self.get_file(fid)?;
+                let data = read_file(&file, offset, count as usize)?;
+                writer.write_u32(data.len() as u32);
+                writer.data.extend_from_slice(&data);
+            }
+            P9_TWRITE => {
+                let fid = reader.read_u32()?;
+                let offset = reader.read_u64()? as usize;
+                let count = reader.read_u32()? as usize;
+                let data = reader.read_bytes(count)?;
+                if !is_writable {
+                    return Err(EROFS);
+                }
+                let (_, file) = self.get_file(fid)?;
+                writer.write_u32(write_file(&file, offset, data)? as u32);
+            }
+            P9_TCLUNK => {
+                let fid = reader.read_u32()?;
+                self.fids.remove(&fid).ok_or(EBADF)?;
+            }
+            P9_TSTATFS => {
+                let _fid = reader.read_u32()?;
+                writer.write_u32(V9FS_MAGIC);
+                writer.write_u32(get_fat32().get_bytes_per_sector() as u32);
+                /* blocks, bfree, bavail, files, ffree, fsid */
+                for _ in 0..6 {
+                    writer.write_u64(0);
+                }
+                writer.write_u32(12 /* namelen(8.3形式) */);
+            }
+            P9_TFLUSH | P9_TFSYNC => { /* 全ての要求は同期的に処理している */ }
+            P9_TSETATTR | P9_TLCREATE | P9_TSYMLINK | P9_TMKNOD | P9_TRENAME | P9_TLINK
+            | P9_TMKDIR | P9_TRENAMEAT | P9_TUNLINKAT => {
+                return Err(if is_writable { EOPNOTSUPP } else { EROFS });
+            }
+            _ => {
+                return Err(EOPNOTSUPP);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MmioHandler for Virtio9pMmio {
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, VmError> {
+        let mut value = 0u64;
+        match offset {
+            VIRTIO_MMIO_MAGIC => {
+                value = VIRTIO_MMIO_MAGIC_VALUE as u64;
+            }
+            VIRTIO_MMIO_VERSION => {
+                value = 0x01;
+            }
+            VIRTIO_MMIO_DEVICE_ID => {
+                value = 0x09;
+            }
+            VIRTIO_MMIO_VENDOR_ID => {
+                value = 0x554d4551;
+            }
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                if self.device_features_select == 0 {
+                    value = VIRTIO_9P_MOUNT_TAG as u64;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => {
+                if self.queue_select == 0 {
+                    value = QUEUE_NUM_MAX;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_PFN => {
+                if self.queue_select == 0 {
+                    value = self.queue.get_pfn(self.page_size);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_STATUS => {
+                value = self.interrupt_status as u64;
+            }
+            VIRTIO_MMIO_STATUS => {
+                value = self.status as u64;
+            }
+            _ if offset >= VIRTIO_CONFIG_OFFSET => {
+                /* tag_len(u16), tag */
+                let mut config = Vec::with_capacity(2 + MOUNT_TAG.len());
+                config.extend_from_slice(&(MOUNT_TAG.len() as u16).to_le_bytes());
+                config.extend_from_slice(MOUNT_TAG);
+                let config_offset = offset - VIRTIO_CONFIG_OFFSET;
+                for i in 0..((access_width / 8) as usize) {
+                    if let Some(b) = config.get(config_offset + i) {
+                        value |= (*b as u64) << (i * 8);
+                    }
+                }
+            }
+            _ => { /* Unimplemented */ }
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: usize, _access_width: u64, value: u64) -> Result<(), VmError> {
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => {
+                self.device_features_select = value as u32;
+            }
+            VIRTIO_MMIO_GUEST_PAGE_SIZE => match DeviceQueue::check_page_size(value) {
+                Some(page_size) => self.page_size = page_size,
+                None => println!("Invalid guest page size: {:#X}", value),
+            },
+            VIRTIO_MMIO_QUEUE_SEL => {
+                self.queue_select = value as usize;
+            }
+            VIRTIO_MMIO_QUEUE_NUM => {
+                if self.queue_select == 0 {
+                    self.queue.set_size(value as usize, QUEUE_NUM_MAX as usize);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_PFN => {
+                let vm = get_current_vm()?;
+                if self.queue_select == 0 {
+                    self.queue.set_pfn(&*vm, value, self.page_size);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                let vm = get_current_vm()?;
+                if value == 0 {
+                    self.handle_requests(&vm);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                self.interrupt_status &= !(value as u32);
+            }
+            VIRTIO_MMIO_STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else {
+                    self.status = value as u32;
+                }
+            }
+            _ => { /* Unimplemented */ }
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, _vm: &VM, writer: &mut SnapshotWriter) {
+        writer.write_u32(self.interrupt_status);
+        writer.write_u32(self.status);
+        writer.write_u64(self.page_size as u64);
+        self.queue.save_state(writer);
+        writer.write_u32(self.message_size);
+        writer.write_u64(self.fids.len() as u64);
+        for (fid, name) in &self.fids {
+            writer.write_u32(*fid);
+            /* ルートディレクトリは長さ 0 で表す */
+            let name = name.as_deref().unwrap_or("");
+            writer.write_u64(name.len() as u64);
+            writer.write_bytes(name.as_bytes());
+        }
+    }
+
+    fn load_state(&mut self, vm: &VM, reader: &mut SnapshotReader) -> Result<(), ()> {
+        self.interrupt_status = reader.read_u32()?;
+        self.status = reader.read_u32()?;
+        self.page_size = DeviceQueue::check_page_size(reader.read_u64()?).ok_or(())?;
+        self.queue.load_state(vm, reader)?;
+        self.message_size = reader.read_u32()?;
+        if !(HEADER_SIZE as u32 + 4..=MAX_MESSAGE_SIZE).contains(&self.message_size) {
+            return Err(());
+        }
+        self.fids.clear();
+        let number_of_fids = reader.read_u64()?;
+        if number_of_fids > MAX_FIDS as u64 {
+            return Err(());
+        }
+        for _ in 0..number_of_fids {
+            let fid = reader.read_u32()?;
+            let mut name = vec![0u8; reader.read_u64()? as usize];
+            reader.read_bytes(&mut name)?;
+            let name = String::from_utf8(name).map_err(|_| ())?;
+            self.fids
+                .insert(fid, if name.is_empty() { None } else { Some(name) });
+        }
+        Ok(())
+    }
+}
@@ -1,207 +1,807 @@
 //!
 //! Virtio-Blk MMIO Driver
 //!
+//! Virtio-MMIO の Version 2(Modern)のレジスタで操作する。
+//!
 
+use crate::block_device::BlockDevice;
 use crate::drivers::{virtio::*, virtio_blk::*};
-use crate::fat32::FileInfo;
+use crate::fat32::{Fat32, FileInfo};
+use crate::lock::{Mutex, RwLockReadGuard};
+use crate::overlay::OverlayDisk;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::virtqueue::{Descriptor, DescriptorChain, DeviceQueue, QueueArea, QueueMemory};
 use crate::vm::*;
-use crate::{FAT32, VIRTIO_BLK};
+use crate::{BLOCK_CACHE, FAT32, VIRTIO_BLK, VIRTIO_BLK_DEVICES};
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
 
-use core::ptr::{null_mut, read_volatile, write_volatile};
+use core::ptr::{read_volatile, write_volatile};
 
 const VIRTIO_BLK_INT_ID: u32 = 40;
 
+/// Virtqueue の数(仮想CPUごとに1つ用意する。各仮想マシンの仮想CPUは1つ)
+const NUMBER_OF_QUEUES: usize = 1;
+const QUEUE_NUM_MAX: u64 = 1024;
+
+/// struct virtio_blk_config のうち使用する部分のサイズ
+const VIRTIO_BLK_CONFIG_SIZE: usize = 60;
+/// 1つの Segment の最大サイズ(MAX_SEGMENTS 個の合計が Descriptor Chain の上限に収まるようにする)
+const MAX_SEGMENT_SIZE: usize = 64 * 1024;
+/// 1つの要求の最大 Segment 数
+const MAX_SEGMENTS: u32 = 126;
+const MAX_DISCARD_SECTORS: u32 = u32::MAX;
+/// 1つの DISCARD の最大 Segment 数
+const MAX_DISCARD_SEGMENTS: u32 = 1;
+/// 1つの WRITE_ZEROES で書き込む最大セクタ数
+const MAX_WRITE_ZEROES_SECTORS: u32 = 2048;
+/// 1つの WRITE_ZEROES の最大 Segment 数
+const MAX_WRITE_ZEROES_SEGMENTS: u32 = 1;
+const WRITE_ZEROES_BUFFER_SIZE: usize = 64 * 1024;
+
+/// WRITE_ZEROES でホストのディスクへ書き込む領域
+static ZEROES: [u8; WRITE_ZEROES_BUFFER_SIZE] = [0; WRITE_ZEROES_BUFFER_SIZE];
+
+/// ホストのディスクへの要求の識別子のうち、要求の番号に使うビット数(上位は仮想マシンの番号)
+const REQUEST_ID_BITS: u32 = 48;
+const REQUEST_ID_MASK: u64 = (1 << REQUEST_ID_BITS) - 1;
+
+/// ホストのディスクの処理の完了を待っているゲストの要求
+struct InflightRequest {
+    queue_index: usize,
+    chain: DescriptorChain,
+    /// 完了していないホストのディスクへの要求の数
+    remaining: usize,
+    status: u8,
+    /// 成功した場合に Used Ring へ返す書き込んだバイト数(Status を除く)
+    written: u32,
+    /// 読み込み後にキャッシュへ登録する(ディスク上のアドレス, バッファ, 長さ)
+    fills: Vec<(u64, usize, usize)>,
+    /// 要求を作り始めた時点のキャッシュの generation
+    generation: u64,
+    /// キャッシュを経由せずに書き込んでいるか(完了時に end_update を呼ぶ)
+    is_updating_cache: bool,
+    /// デバイスのリセット後に完了した要求か(Used Ring へは返さない)
+    is_discarded: bool,
+}
+
+/// ゲストの1つの要求から作るホストのディスクへの要求
+struct HostRequests {
+    requests: Vec<AsyncRequest>,
+    fills: Vec<(u64, usize, usize)>,
+    generation: u64,
+    is_updating_cache: bool,
+    /// ホストのディスクへの1つの要求で使えるデータ部分の最大数
+    max_segments: usize,
+}
+
+impl HostRequests {
+    fn new() -> Self {
+        let max_segments = VIRTIO_BLK.lock().get_max_segments();
+        Self {
+            requests: Vec::new(),
+            fills: Vec::new(),
+            generation: BLOCK_CACHE.lock().get_generation(),
+            is_updating_cache: false,
+            max_segments,
+        }
+    }
+
+    /// 直前の要求とディスク上で連続している場合は、max_segments を超えない範囲でその要求のデータ部分として追加する
+    fn push(&mut self, req_type: u32, block_address: u64, buffer_address: usize, size: usize) {
+        match self.requests.last_mut() {
+            Some(r)
+                if r.req_type == req_type
+                    && r.block_address + r.get_length() == block_address
+                    && r.buffers.len() < self.max_segments =>
+            {
+                r.buffers.push((buffer_address, size))
+            }
+            _ => self.requests.push(AsyncRequest {
+                req_type,
+                buffers: vec![(buffer_address, size)],
+                block_address,
+                token: 0,
+            }),
+        }
+    }
+
+    /// キャッシュを経由しない書き込みを終える
+    fn end_update(&self) {
+        if self.is_updating_cache {
+            BLOCK_CACHE.lock().end_update();
+        }
+    }
+}
+
+/// 仮想マシンが使用している FAT32 上のファイル(先頭のクラスタ番号, 読み込み専用で共有しているか)
+///
+/// 書き込むファイルは1つの仮想マシンのみが使い、読み込み専用のファイルは複数の仮想マシンで共有できる
+static OPEN_DISK_FILES: Mutex<Vec<(u32, bool)>> = Mutex::new(Vec::new());
+
+/// 仮想マシンのディスクの実体
+pub enum DiskBackend {
+    /// ハイパーバイザの FAT32 上のファイル(起動ディスクへ完了を待たずに要求を送り、キャッシュを使う)
+    File(FileInfo),
+    /// FAT32 上のオーバーレイディスク(ベースイメージとオーバーレイファイルへ File と同様に要求を送る)
+    Overlay(OverlayDisk),
+    /// 任意のブロックデバイス(その場で読み書きする)
+    Device(Box<dyn BlockDevice + Send>),
+}
+
+impl DiskBackend {
+    /// 使用する FAT32 上のファイル(先頭のクラスタ番号, 読み込み専用で共有するか)
+    fn get_files(&self) -> Vec<(u32, bool)> {
+        match self {
+            Self::File(file) => vec![(file.get_entry_cluster(), file.is_read_only())],
+            Self::Overlay(overlay) => vec![
+                (overlay.get_overlay_file().get_entry_cluster(), false),
+                (overlay.get_base_file().get_entry_cluster(), true),
+            ],
+            Self::Device(_) => Vec::new(),
+        }
+    }
+}
+
+/// files を使用中として登録する(他の仮想マシンが書き込むファイルを含む場合は失敗する)
+fn open_disk_files(files: &[(u32, bool)]) -> Result<(), ()> {
+    let mut open_files = OPEN_DISK_FILES.lock();
+    for (cluster, is_shared) in files {
+        if open_files
+            .iter()
+            .any(|(c, s)| c == cluster && !(*s && *is_shared))
+        {
+            return Err(());
+        }
+    }
+    open_files.extend_from_slice(files);
+    Ok(())
+}
+
+/// open_disk_files で登録したファイルを外す
+fn close_disk_files(files: &[(u32, bool)]) {
+    let mut open_files = OPEN_DISK_FILES.lock();
+    for file in files {
+        if let Some(index) = open_files.iter().position(|f| f == file) {
+            open_files.swap_remove(index);
+        }
+    }
+}
+
 pub struct VirtioBlkMmio {
-    file: FileInfo,
+    backend: DiskBackend,
+    /// GET_ID で返す識別子
+    serial: [u8; VIRTIO_BLK_ID_BYTES],
     interrupt_status: u32,
     status: u32,
-    queue_size: usize,
-    queue_ready: bool,
-    page_size: usize,
-    descriptor: *mut VirtQueueDesc,
-    avail_ring: *mut VirtQueueAvail,
-    used_ring: *mut VirtQueueUsed,
-    last_avail_id: u16,
-    used_id: u16,
+    device_features_select: u32,
+    driver_features_select: u32,
+    driver_features: u64,
+    queue_select: u32,
+    queues: [DeviceQueue; NUMBER_OF_QUEUES],
+    /// ホストのディスクの処理を待っている要求(要求の番号ごと)
+    inflight: BTreeMap<u64, InflightRequest>,
+    next_request_id: u64,
 }
 
 impl VirtioBlkMmio {
-    pub fn new(file: FileInfo) -> Self {
-        if (file.get_file_size() & 0x1FF) != 0 {
-            panic!(
-                "File Size must be 512-Byte aligned(Size: {:#X})",
-                file.get_file_size()
-            );
+    pub fn new(backend: DiskBackend, file_name: &str) -> Result<Self, VmError> {
+        let disk_size = match &backend {
+            DiskBackend::File(file) => file.get_file_size(),
+            DiskBackend::Overlay(overlay) => overlay.get_disk_size(),
+            DiskBackend::Device(device) => device.get_size() as usize,
+        };
+        if (disk_size & 0x1FF) != 0 {
+            return Err(VmError::InvalidDiskSize(disk_size));
         }
-        Self {
-            file,
+        if open_disk_files(&backend.get_files()).is_err() {
+            return Err(VmError::DiskInUse(file_name.into()));
+        }
+        /* "MINIVISOR-<ファイル名>" を識別子とする */
+        let mut serial = [0u8; VIRTIO_BLK_ID_BYTES];
+        for (s, c) in serial
+            .iter_mut()
+            .zip(b"MINIVISOR-".iter().chain(file_name.as_bytes()))
+        {
+            *s = *c;
+        }
+        Ok(Self {
+            backend,
+            serial,
             interrupt_status: 0,
             status: 0,
-            queue_size: 0,
-            queue_ready: false,
-            page_size: 1 << 12,
-            descriptor: null_mut(),
-            avail_ring: null_mut(),
-            used_ring: null_mut(),
-            last_avail_id: 0,
-            used_id: 0,
-        }
-    }
-
-    fn get_descriptor(&self, id: u16) -> Option<VirtQueueDesc> {
-        if !self.descriptor.is_null() {
-            Some(unsafe {
-                read_volatile(
-                    (self.descriptor as usize + size_of::<VirtQueueDesc>() * (id as usize))
-                        as *const VirtQueueDesc,
-                )
-            })
+            device_features_select: 0,
+            driver_features_select: 0,
+            driver_features: 0,
+            queue_select: 0,
+            queues: [const { DeviceQueue::new() }; NUMBER_OF_QUEUES],
+            inflight: BTreeMap::new(),
+            next_request_id: 0,
+        })
+    }
+
+    fn reset(&mut self) {
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.device_features_select = 0;
+        self.driver_features_select = 0;
+        self.driver_features = 0;
+        self.queue_select = 0;
+        for queue in self.queues.iter_mut() {
+            queue.reset();
+        }
+        /*
+         * 処理中の要求はホストのディスクがゲストのRAMへ読み込んでいる途中のため、
+         * 完了まで残して wait_for_inflight_requests で待てるようにし、Used Ring へは返さない
+         */
+        for request in self.inflight.values_mut() {
+            request.is_discarded = true;
+        }
+    }
+
+    /// 提供する機能
+    fn get_device_features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_SIZE_MAX
+            | VIRTIO_BLK_F_SEG_MAX
+            | VIRTIO_BLK_F_GEOMETRY
+            | VIRTIO_BLK_F_BLK_SIZE
+            | VIRTIO_BLK_F_FLUSH
+            | VIRTIO_BLK_F_MQ
+            | VIRTIO_BLK_F_DISCARD
+            | VIRTIO_BLK_F_WRITE_ZEROES;
+        if self.is_read_only() {
+            features |= VIRTIO_BLK_F_RO;
+        }
+        VIRTIO_F_VERSION_1
+            | VIRTIO_F_INDIRECT_DESC
+            | VIRTIO_F_EVENT_IDX
+            | VIRTIO_F_RING_PACKED
+            | (features as u64)
+    }
+
+    /// QUEUE_SEL で選択されている Virtqueue
+    fn get_selected_queue(&mut self) -> Option<&mut DeviceQueue> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    /// queue_index の Virtqueue の要求を全て取り出して処理を始め、必要であればゲストへ割り込みを送る
+    ///
+    /// ホストのディスクへの読み書きは完了を待たず、完了時に host_interrupt_handler から返す
+    fn operation(&mut self, vm: &VM, queue_index: usize) {
+        let is_event_idx_enabled = (self.driver_features & VIRTIO_F_EVENT_IDX) != 0;
+        loop {
+            while let Some(chain) = self.queues[queue_index].pop_avail(vm) {
+                self.process_request(vm, queue_index, chain);
+            }
+            /* EVENT_IDX を使う場合、次の通知位置を知らせた後に追加された要求も処理する */
+            if !is_event_idx_enabled || !self.queues[queue_index].update_avail_event(vm) {
+                break;
+            }
+        }
+        self.notify_used(vm, queue_index);
+    }
+
+    /// Used Ring へ返した要求があり、必要であればゲストへ割り込みを送る
+    fn notify_used(&mut self, vm: &VM, queue_index: usize) {
+        let is_event_idx_enabled = (self.driver_features & VIRTIO_F_EVENT_IDX) != 0;
+        if self.queues[queue_index].needs_interrupt(is_event_idx_enabled) {
+            self.interrupt_status |= 1;
+            vm.get_gic_distributor_mmio()
+                .lock()
+                .trigger_interrupt(VIRTIO_BLK_INT_ID, None);
+        }
+    }
+
+    /// 1つの要求の処理を始める
+    ///
+    /// ホストのディスクへの要求が必要ない場合や失敗した場合は、その場で Used Ring へ返す
+    fn process_request(&mut self, vm: &VM, queue_index: usize, chain: DescriptorChain) {
+        /* 先頭は要求、最後は Status、その間はデータ部分の Descriptor */
+        let Some((request_descriptor, descriptors)) = chain.get_descriptors().split_first() else {
+            println!("Invalid VirtioBlkReq");
+            self.queues[queue_index].push_used(vm, &chain, 0);
+            return;
+        };
+        let Some((_, data)) = descriptors.split_last() else {
+            println!("Invalid VirtioBlkReq");
+            self.queues[queue_index].push_used(vm, &chain, 0);
+            return;
+        };
+        let blk_req = if request_descriptor.length as usize == size_of::<VirtioBlkReq>() {
+            vm.get_physical_address(request_descriptor.address as usize)
         } else {
             None
+        };
+        let Some(blk_req) = blk_req else {
+            println!("Invalid VirtioBlkReq");
+            self.queues[queue_index].push_used(vm, &chain, 0);
+            return;
+        };
+
+        /* リクエストの解析 */
+        let blk_req = unsafe { read_volatile(blk_req as *const VirtioBlkReq) };
+
+        let mut host = HostRequests::new();
+        let result = match blk_req.req_type {
+            VIRTIO_BLK_TYPE_IN | VIRTIO_BLK_TYPE_OUT => self.read_write(
+                vm,
+                blk_req.req_type == VIRTIO_BLK_TYPE_OUT,
+                blk_req.sector,
+                data,
+                &mut host,
+            ),
+            VIRTIO_BLK_TYPE_FLUSH => self.flush(&mut host).map(|_| 0),
+            VIRTIO_BLK_TYPE_GET_ID => self.get_id(vm, data),
+            VIRTIO_BLK_TYPE_DISCARD | VIRTIO_BLK_TYPE_WRITE_ZEROES => self
+                .discard_or_write_zeroes(
+                    vm,
+                    blk_req.req_type == VIRTIO_BLK_TYPE_WRITE_ZEROES,
+                    data,
+                    &mut host,
+                )
+                .map(|_| 0),
+            _ => Err(VIRTIO_BLK_S_UNSUPP),
+        };
+        match result {
+            Ok(written) if !host.requests.is_empty() => {
+                self.submit_host_requests(vm, queue_index, chain, host, written)
+            }
+            Ok(written) => {
+                host.end_update();
+                self.complete_request(vm, queue_index, &chain, VIRTIO_BLK_S_OK, written)
+            }
+            Err(status) => {
+                host.end_update();
+                self.complete_request(vm, queue_index, &chain, status, 0)
+            }
         }
     }
 
-    fn get_descriptor_id(&self, id: u16) -> Option<u16> {
-        if !self.avail_ring.is_null() {
-            Some(unsafe {
-                read_volatile(
-                    (self.avail_ring as usize
-                        + size_of::<u16>() * 2 /* flag + idx */
-                        + size_of::<u16>() * (id as usize)) as *const u16,
-                )
-            })
+    /// Status を書き込み、要求を Used Ring へ返す
+    fn complete_request(
+        &mut self,
+        vm: &VM,
+        queue_index: usize,
+        chain: &DescriptorChain,
+        status: u8,
+        written: u32,
+    ) {
+        let status_descriptor = chain.get_descriptors().last().unwrap();
+        if let Some(a) = vm.get_physical_address(status_descriptor.address as usize) {
+            unsafe { write_volatile(a as *mut u8, status) };
         } else {
-            None
+            println!("Failed to write the status");
         }
+        /* ホストのディスクからの読み込みが完了した後に記録する */
+        mark_written_descriptors(vm, chain);
+        self.queues[queue_index].push_used(vm, chain, written + size_of::<u8>() as u32);
     }
 
-    fn get_next_avail_id(&mut self) -> Option<u16> {
-        if self.last_avail_id == unsafe { &*self.avail_ring }.idx {
-            return None;
+    /// ホストのディスクへの要求を送り、完了を待つ要求として登録する
+    fn submit_host_requests(
+        &mut self,
+        vm: &VM,
+        queue_index: usize,
+        chain: DescriptorChain,
+        host: HostRequests,
+        written: u32,
+    ) {
+        let request_id = self.next_request_id;
+        self.next_request_id = (self.next_request_id + 1) & REQUEST_ID_MASK;
+        let token = ((vm.get_vm_id() as u64) << REQUEST_ID_BITS) | request_id;
+
+        let mut inflight = InflightRequest {
+            queue_index,
+            chain,
+            remaining: 0,
+            status: VIRTIO_BLK_S_OK,
+            written,
+            fills: host.fills,
+            generation: host.generation,
+            is_updating_cache: host.is_updating_cache,
+            is_discarded: false,
+        };
+        let mut virtio_blk = VIRTIO_BLK.lock();
+        for mut request in host.requests {
+            request.token = token;
+            if virtio_blk.submit(request).is_ok() {
+                inflight.remaining += 1;
+            } else {
+                inflight.status = VIRTIO_BLK_S_IOERR;
+            }
+        }
+        drop(virtio_blk);
+
+        if inflight.remaining == 0 {
+            if inflight.is_updating_cache {
+                BLOCK_CACHE.lock().end_update();
+            }
+            self.complete_request(vm, queue_index, &inflight.chain, inflight.status, 0);
+        } else {
+            self.inflight.insert(request_id, inflight);
         }
-        let next = self.last_avail_id % (self.queue_size as u16);
-        self.last_avail_id += 1;
-        Some(next)
     }
 
-    fn write_used(&mut self, id: u16, length: u32) {
-        let used_id = self.used_id % (self.queue_size as u16);
-        self.used_id += 1;
-        unsafe {
-            write_volatile(
-                (self.used_ring as usize
-                    + size_of::<u16>() * 2 /* flag + idx */
-                    + size_of::<VirtQueueUsedElement>() * (used_id as usize))
-                    as *mut VirtQueueUsedElement,
-                VirtQueueUsedElement {
-                    id: (id as u32),
-                    length,
-                },
-            )
+    /// ホストのディスクへの要求が1つ完了した際の処理
+    fn complete_host_request(&mut self, vm: &VM, request_id: u64, result: Result<(), ()>) {
+        let Some(request) = self.inflight.get_mut(&request_id) else {
+            return;
         };
-        unsafe { &mut *self.used_ring }.idx = self.used_id;
+        if result.is_err() {
+            request.status = VIRTIO_BLK_S_IOERR;
+        }
+        request.remaining -= 1;
+        if request.remaining != 0 {
+            return;
+        }
+        let request = self.inflight.remove(&request_id).unwrap();
+        if request.is_updating_cache {
+            BLOCK_CACHE.lock().end_update();
+        }
+        if request.is_discarded {
+            mark_written_descriptors(vm, &request.chain);
+            return;
+        }
+        let written = if request.status == VIRTIO_BLK_S_OK {
+            /* 読み込んだ内容をキャッシュへ登録する */
+            if !request.fills.is_empty() {
+                let mut blk = VIRTIO_BLK.lock();
+                let mut cache = BLOCK_CACHE.lock();
+                for (address, buffer, length) in request.fills.iter() {
+                    if cache
+                        .fill(&mut *blk, request.generation, *address, *buffer, *length)
+                        .is_err()
+                    {
+                        println!("Failed to update the block cache");
+                    }
+                }
+            }
+            request.written
+        } else {
+            0
+        };
+        self.complete_request(
+            vm,
+            request.queue_index,
+            &request.chain,
+            request.status,
+            written,
+        );
+        self.notify_used(vm, request.queue_index);
     }
 
-    fn operation(&mut self) {
-        while let Some(id) = self.get_next_avail_id() {
-            let Some(descriptor_id) = self.get_descriptor_id(id) else {
-                println!("Failed to get the next descriptor id");
-                return;
-            };
-            let Some(request_descriptor) = self.get_descriptor(descriptor_id) else {
-                println!("Failed to get the next descriptor");
-                return;
+    fn get_fat32() -> RwLockReadGuard<'static, Fat32> {
+        FAT32.read()
+    }
+
+    /// ディスクの大きさ(バイト)
+    fn get_disk_size(&self) -> usize {
+        match &self.backend {
+            DiskBackend::File(file) => file.get_file_size(),
+            DiskBackend::Overlay(overlay) => overlay.get_disk_size(),
+            DiskBackend::Device(device) => device.get_size() as usize,
+        }
+    }
+
+    /// セクタ数で表したディスクの大きさ
+    fn get_capacity(&self) -> u64 {
+        (self.get_disk_size() >> 9) as u64
+    }
+
+    fn is_read_only(&self) -> bool {
+        match &self.backend {
+            DiskBackend::File(file) => file.is_read_only(),
+            DiskBackend::Overlay(_) | DiskBackend::Device(_) => false,
+        }
+    }
+
+    /// ゲストへ知らせる読み書きの単位(バイト)
+    fn get_block_size(&self) -> u32 {
+        match &self.backend {
+            DiskBackend::File(_) | DiskBackend::Overlay(_) => 512,
+            DiskBackend::Device(device) => device.get_block_size() as u32,
+        }
+    }
+
+    /// ディスクの offset から length バイトの領域と buffer_address の間で読み書きする要求を host へ加える
+    ///
+    /// オーバーレイディスクの場合はベースイメージとオーバーレイファイル上の範囲へ変換する。
+    /// ブロックデバイスの場合は要求を作らず、その場で読み書きする
+    fn build_requests(
+        &mut self,
+        host: &mut HostRequests,
+        req_type: u32,
+        mut buffer_address: usize,
+        offset: usize,
+        length: usize,
+    ) -> Result<(), u8> {
+        match &mut self.backend {
+            DiskBackend::File(file) => {
+                Self::build_file_requests(host, req_type, file, buffer_address, offset, length)
+            }
+            DiskBackend::Overlay(overlay) if req_type == VIRTIO_BLK_TYPE_IN => {
+                let extents = overlay
+                    .map_read(offset, length)
+                    .or(Err(VIRTIO_BLK_S_IOERR))?;
+                for (file, file_offset, size) in extents {
+                    Self::build_file_requests(
+                        host,
+                        req_type,
+                        file,
+                        buffer_address,
+                        file_offset,
+                        size,
+                    )?;
+                    buffer_address += size;
+                }
+                Ok(())
+            }
+            DiskBackend::Overlay(overlay) => {
+                let write = overlay
+                    .map_write(offset, length)
+                    .or(Err(VIRTIO_BLK_S_IOERR))?;
+                let file = overlay.get_overlay_file();
+                for (file_offset, size) in write.data {
+                    Self::build_file_requests(
+                        host,
+                        req_type,
+                        file,
+                        buffer_address,
+                        file_offset,
+                        size,
+                    )?;
+                    buffer_address += size;
+                }
+                for (address, file_offset, size) in write.metadata {
+                    Self::build_file_requests(host, req_type, file, address, file_offset, size)?;
+                }
+                Ok(())
+            }
+            DiskBackend::Device(device) => {
+                let result = if req_type == VIRTIO_BLK_TYPE_IN {
+                    device.read(buffer_address, offset as u64, length as u64)
+                } else {
+                    device.write(buffer_address, offset as u64, length as u64)
+                };
+                result.or(Err(VIRTIO_BLK_S_IOERR))
+            }
+        }
+    }
+
+    /// file の offset から length バイトの領域と buffer_address の間で読み書きする要求を host へ加える
+    ///
+    /// 読み込みはキャッシュにある部分をその場で写し、書き込みはキャッシュにある部分を更新する
+    fn build_file_requests(
+        host: &mut HostRequests,
+        req_type: u32,
+        file: &FileInfo,
+        mut buffer_address: usize,
+        offset: usize,
+        length: usize,
+    ) -> Result<(), u8> {
+        let extents = Self::get_fat32()
+            .get_extents(file, offset, length)
+            .or(Err(VIRTIO_BLK_S_IOERR))?;
+        let mut cache = BLOCK_CACHE.lock();
+        for (block_address, size) in extents {
+            if req_type == VIRTIO_BLK_TYPE_IN {
+                cache.read_cached(block_address, buffer_address, size, |a, b, l| {
+                    host.push(req_type, a, b, l);
+                    host.fills.push((a, b, l));
+                });
+            } else {
+                if !host.is_updating_cache {
+                    host.is_updating_cache = true;
+                    cache.begin_update();
+                }
+                cache.update(block_address, buffer_address, size);
+                host.push(req_type, block_address, buffer_address, size);
+            }
+            buffer_address += size;
+        }
+        Ok(())
+    }
+
+    /// sector から各 Descriptor の領域へ読み込む、または書き込む要求を作り、書き込むバイト数を返す
+    fn read_write(
+        &mut self,
+        vm: &VM,
+        is_write: bool,
+        sector: u64,
+        data: &[Descriptor],
+        host: &mut HostRequests,
+    ) -> Result<u32, u8> {
+        if is_write && self.is_read_only() {
+            return Err(VIRTIO_BLK_S_IOERR);
+        }
+        let req_type = if is_write {
+            VIRTIO_BLK_TYPE_OUT
+        } else {
+            VIRTIO_BLK_TYPE_IN
+        };
+        /* sector はゲストが指定するため、桁あふれを確認する */
+        let mut offset = sector
+            .checked_mul(512)
+            .and_then(|o| usize::try_from(o).ok())
+            .ok_or(VIRTIO_BLK_S_IOERR)?;
+        let mut written = 0;
+        for descriptor in data {
+            let size = descriptor.length as usize;
+            if offset
+                .checked_add(size)
+                .is_none_or(|end| end > self.get_disk_size())
+            {
+                println!("Access beyond the end of the disk: {:#x}", offset);
+                return Err(VIRTIO_BLK_S_IOERR);
+            }
+            let Some(address) = vm.translate_range(descriptor.address as usize, size) else {
+                println!(
+                    "Failed to convert {:#x} to the physical address",
+                    descriptor.address
+                );
+                return Err(VIRTIO_BLK_S_IOERR);
             };
-            if request_descriptor.length as usize != size_of::<VirtioBlkReq>() {
-                println!("Invalid VirtioBlkReq size");
-                return;
+            self.build_requests(host, req_type, address, offset, size)?;
+            if !is_write {
+                written += descriptor.length;
             }
-            let Some(blk_req) =
-                get_current_vm().get_physical_address(request_descriptor.address as usize)
+            offset += size;
+        }
+        Ok(written)
+    }
+
+    /// ハイパーバイザのキャッシュを書き戻し、ホストのディスクのキャッシュの書き戻し要求を作る
+    fn flush(&mut self, host: &mut HostRequests) -> Result<(), u8> {
+        if let DiskBackend::Device(device) = &mut self.backend {
+            return device.flush().or(Err(VIRTIO_BLK_S_IOERR));
+        }
+        let mut blk = VIRTIO_BLK.lock();
+        if BLOCK_CACHE.lock().write_back(&mut *blk).is_err() {
+            return Err(VIRTIO_BLK_S_IOERR);
+        }
+        if blk.is_flush_supported() {
+            host.requests.push(AsyncRequest {
+                req_type: VIRTIO_BLK_TYPE_FLUSH,
+                buffers: Vec::new(),
+                block_address: 0,
+                token: 0,
+            });
+        }
+        Ok(())
+    }
+
+    /// ディスクの識別子を書き込み、書き込んだバイト数を返す
+    fn get_id(&self, vm: &VM, data: &[Descriptor]) -> Result<u32, u8> {
+        let Some(descriptor) = data.first() else {
+            return Err(VIRTIO_BLK_S_IOERR);
+        };
+        let length = (descriptor.length as usize).min(VIRTIO_BLK_ID_BYTES);
+        let Some(address) = vm.translate_range(descriptor.address as usize, length) else {
+            return Err(VIRTIO_BLK_S_IOERR);
+        };
+        unsafe { core::ptr::copy_nonoverlapping(self.serial.as_ptr(), address as *mut u8, length) };
+        Ok(length as u32)
+    }
+
+    /// DISCARD または WRITE_ZEROES の各 Segment を確認し、ホストのディスクへの要求を作る
+    ///
+    /// FAT32 上のファイルやブロックデバイスは領域を解放できないため、DISCARD は範囲の確認のみ行う。
+    /// 1つの要求で作るホストのディスクへの要求が増えすぎないよう、設定領域で示した上限を超える要求は拒否する
+    fn discard_or_write_zeroes(
+        &mut self,
+        vm: &VM,
+        is_write_zeroes: bool,
+        data: &[Descriptor],
+        host: &mut HostRequests,
+    ) -> Result<(), u8> {
+        if self.is_read_only() {
+            return Err(VIRTIO_BLK_S_IOERR);
+        }
+        let (max_segments, max_sectors) = if is_write_zeroes {
+            (MAX_WRITE_ZEROES_SEGMENTS, MAX_WRITE_ZEROES_SECTORS)
+        } else {
+            (MAX_DISCARD_SEGMENTS, MAX_DISCARD_SECTORS)
+        };
+        let total_segments: usize = data
+            .iter()
+            .map(|d| d.length as usize / size_of::<VirtioBlkDiscardWriteZeroes>())
+            .sum();
+        if total_segments > max_segments as usize {
+            return Err(VIRTIO_BLK_S_UNSUPP);
+        }
+        for descriptor in data {
+            let Some(address) =
+                vm.translate_range(descriptor.address as usize, descriptor.length as usize)
             else {
-                println!("Invalid VirtioBlkReq address");
-                return;
+                return Err(VIRTIO_BLK_S_IOERR);
             };
-
-            /* リクエストの解析 */
-            let blk_req = unsafe { &*(blk_req as *const VirtioBlkReq) };
-            let is_write = blk_req.req_type == VIRTIO_BLK_TYPE_OUT;
-            let mut offset = (blk_req.sector << 9) as usize;
-            if (request_descriptor.flags & VIRT_QUEUE_DESC_FLAGS_NEXT) == 0 {
-                println!("Invalid VirtioBlkReq");
-                return;
-            }
-
-            /* イメージファイルの読み書き */
-            let mut descriptor = request_descriptor;
-            let mut total_size = 0;
-            let mut status = VIRTIO_BLK_S_OK;
-            loop {
-                if let Some(d) = self.get_descriptor(descriptor.next) {
-                    descriptor = d;
-                    if (descriptor.flags & VIRT_QUEUE_DESC_FLAGS_NEXT) == 0 {
-                        break;
-                    }
-                } else {
-                    println!("Failed to get the next descriptor");
-                    return;
+            let number_of_segments =
+                descriptor.length as usize / size_of::<VirtioBlkDiscardWriteZeroes>();
+            for i in 0..number_of_segments {
+                let segment = unsafe {
+                    read_volatile(
+                        (address + i * size_of::<VirtioBlkDiscardWriteZeroes>())
+                            as *const VirtioBlkDiscardWriteZeroes,
+                    )
+                };
+                if !is_write_zeroes && segment.flags != 0 {
+                    return Err(VIRTIO_BLK_S_UNSUPP);
                 }
-                let size = descriptor.length;
-                total_size += size;
-                let Some(address) =
-                    get_current_vm().get_physical_address(descriptor.address as usize)
-                else {
-                    println!(
-                        "Failed to convert {:#x} to the physical address",
-                        descriptor.address
-                    );
-                    status = VIRTIO_BLK_S_IOERR;
+                if segment.num_sectors > max_sectors {
+                    return Err(VIRTIO_BLK_S_IOERR);
+                }
+                if segment
+                    .sector
+                    .checked_add(segment.num_sectors as u64)
+                    .is_none_or(|end| end > self.get_capacity())
+                {
+                    return Err(VIRTIO_BLK_S_IOERR);
+                }
+                if !is_write_zeroes {
                     continue;
-                };
-                let mut virtio_blk = VIRTIO_BLK.lock();
-                let fat32 = unsafe { (&raw mut FAT32).as_mut().unwrap().assume_init_mut() };
-                let result = if is_write {
-                    fat32.write(&self.file, &mut virtio_blk, address, offset, size as usize)
-                } else {
-                    fat32.read(&self.file, &mut virtio_blk, address, offset, size as usize)
-                };
-                if result.is_err() {
-                    println!(
-                        "Failed to {} {:#x} bytes from {:#x} to {:#x}",
-                        if is_write { "write" } else { "read" },
-                        size,
+                }
+                let mut offset = (segment.sector << 9) as usize;
+                let end = offset + ((segment.num_sectors as usize) << 9);
+                while offset < end {
+                    let size = (end - offset).min(WRITE_ZEROES_BUFFER_SIZE);
+                    self.build_requests(
+                        host,
+                        VIRTIO_BLK_TYPE_OUT,
+                        ZEROES.as_ptr() as usize,
                         offset,
-                        address
-                    );
-                    status = VIRTIO_BLK_S_IOERR;
+                        size,
+                    )?;
+                    offset += size;
                 }
-                offset += size as usize;
-            }
-            if let Some(a) = get_current_vm().get_physical_address(descriptor.address as usize) {
-                unsafe { write_volatile(a as *mut u8, status) };
-                total_size += descriptor.length;
-            } else {
-                println!("Failed to write the status");
             }
-            self.write_used(descriptor_id, total_size);
         }
-        self.interrupt_status |= 1;
-        get_current_vm()
-            .get_gic_distributor_mmio()
-            .lock()
-            .trigger_interrupt(VIRTIO_BLK_INT_ID, None);
+        Ok(())
+    }
+
+    /// 設定領域(struct virtio_blk_config)
+    fn get_config(&self) -> [u8; VIRTIO_BLK_CONFIG_SIZE] {
+        let mut config = [0u8; VIRTIO_BLK_CONFIG_SIZE];
+        let capacity = self.get_capacity();
+        /* CHS は 16 ヘッド、63 セクタとして計算する */
+        let cylinders = (capacity / (16 * 63)).min(u16::MAX as u64) as u16;
+        config[0..8].copy_from_slice(&capacity.to_le_bytes());
+        config[8..12].copy_from_slice(&(MAX_SEGMENT_SIZE as u32).to_le_bytes());
+        config[12..16].copy_from_slice(&MAX_SEGMENTS.to_le_bytes());
+        config[16..18].copy_from_slice(&cylinders.to_le_bytes());
+        config[18] = 16;
+        config[19] = 63;
+        config[20..24].copy_from_slice(&self.get_block_size().to_le_bytes());
+        /* num_queues */
+        config[34..36].copy_from_slice(&(NUMBER_OF_QUEUES as u16).to_le_bytes());
+        /* max_discard_sectors, max_discard_seg, discard_sector_alignment */
+        config[36..40].copy_from_slice(&MAX_DISCARD_SECTORS.to_le_bytes());
+        config[40..44].copy_from_slice(&MAX_DISCARD_SEGMENTS.to_le_bytes());
+        config[44..48].copy_from_slice(&1u32.to_le_bytes());
+        /* max_write_zeroes_sectors, max_write_zeroes_seg */
+        config[48..52].copy_from_slice(&MAX_WRITE_ZEROES_SECTORS.to_le_bytes());
+        config[52..56].copy_from_slice(&MAX_WRITE_ZEROES_SEGMENTS.to_le_bytes());
+        config
+    }
+}
+
+impl Drop for VirtioBlkMmio {
+    fn drop(&mut self) {
+        close_disk_files(&self.backend.get_files());
     }
 }
 
 impl MmioHandler for VirtioBlkMmio {
-    fn read(&mut self, offset: usize, _access_width: u64) -> Result<u64, ()> {
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, VmError> {
         let mut value = 0u64;
         match offset {
             VIRTIO_MMIO_MAGIC => {
                 value = VIRTIO_MMIO_MAGIC_VALUE as u64;
             }
             VIRTIO_MMIO_VERSION => {
-                value = 0x01;
+                value = 0x02;
             }
             VIRTIO_MMIO_DEVICE_ID => {
                 value = 0x02;
@@ -210,16 +810,20 @@ impl MmioHandler for VirtioBlkMmio {
                 value = 0x554d4551;
             }
             VIRTIO_MMIO_DEVICE_FEATURES => {
-                value = 0x00;
+                if self.device_features_select < 2 {
+                    value = (self.get_device_features() >> (self.device_features_select * 32))
+                        & (u32::MAX as u64);
+                }
             }
             VIRTIO_MMIO_QUEUE_NUM_MAX => {
-                value = 1024;
+                if self.get_selected_queue().is_some() {
+                    value = QUEUE_NUM_MAX;
+                }
             }
             VIRTIO_MMIO_QUEUE_READY => {
-                value = self.queue_ready as _;
-            }
-            VIRTIO_MMIO_QUEUE_PFN => {
-                value = (self.descriptor as usize / self.page_size) as u64;
+                if let Some(queue) = self.get_selected_queue() {
+                    value = queue.is_ready() as _;
+                }
             }
             VIRTIO_MMIO_INTERRUPT_STATUS => {
                 value = self.interrupt_status as u64;
@@ -227,12 +831,17 @@ impl MmioHandler for VirtioBlkMmio {
             VIRTIO_MMIO_STATUS => {
                 value = self.status as u64;
             }
+            VIRTIO_MMIO_CONFIG_GENERATION => {
+                /* 設定領域は変化しない */
+                value = 0;
+            }
             _ if offset >= VIRTIO_CONFIG_OFFSET => {
+                let config = self.get_config();
                 let config_offset = offset - VIRTIO_CONFIG_OFFSET;
-                if (0..8).contains(&config_offset) {
-                    /* ブロックデバイスのサイズ */
-                    let capacity = self.file.get_file_size() >> 9;
-                    value = (capacity >> (config_offset * 8)) as u64;
+                for i in 0..((access_width / 8) as usize) {
+                    if let Some(b) = config.get(config_offset + i) {
+                        value |= (*b as u64) << (i * 8);
+                    }
                 }
             }
             _ => { /* Unimplemented */ }
@@ -240,39 +849,70 @@ impl MmioHandler for VirtioBlkMmio {
         Ok(value)
     }
 
-    fn write(&mut self, offset: usize, _access_width: u64, value: u64) -> Result<(), ()> {
+    fn write(&mut self, offset: usize, _access_width: u64, value: u64) -> Result<(), VmError> {
+        /* 64bit のアドレスの上位または下位 32bit を書き換える */
+        let set_half = |target: u64, is_high: bool| {
+            let shift = if is_high { 32 } else { 0 };
+            (target & !((u32::MAX as u64) << shift)) | ((value & 0xFFFFFFFF) << shift)
+        };
         match offset {
-            VIRTIO_MMIO_GUEST_PAGE_SIZE => {
-                self.page_size = value as usize;
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => {
+                self.device_features_select = value as u32;
+            }
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => {
+                self.driver_features_select = value as u32;
+            }
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                if self.driver_features_select < 2 {
+                    let is_high = self.driver_features_select == 1;
+                    self.driver_features = set_half(self.driver_features, is_high);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_SEL => {
+                self.queue_select = value as u32;
             }
             VIRTIO_MMIO_QUEUE_NUM => {
-                self.queue_size = value as usize;
-                self.avail_ring = ((self.descriptor as usize)
-                    + size_of::<VirtQueueDesc>() * self.queue_size)
-                    as *mut _;
-                self.used_ring =
-                    ((((self.avail_ring as usize + size_of::<u16>() * (3 + self.queue_size)) - 1)
-                        & !(self.page_size - 1))
-                        + self.page_size) as *mut _;
-            }
-            VIRTIO_MMIO_QUEUE_PFN => {
-                if let Some(address) =
-                    get_current_vm().get_physical_address((value as usize) * self.page_size)
-                {
-                    self.descriptor = address as *mut _;
-                    self.avail_ring = ((self.descriptor as usize)
-                        + size_of::<VirtQueueDesc>() * self.queue_size)
-                        as *mut _;
-                    self.used_ring = ((((self.avail_ring as usize
-                        + size_of::<u16>() * (3 + self.queue_size))
-                        - 1)
-                        & !(self.page_size - 1))
-                        + self.page_size) as *mut _;
+                if let Some(queue) = self.get_selected_queue() {
+                    queue.set_size(value as usize, QUEUE_NUM_MAX as usize);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DESC_LOW | VIRTIO_MMIO_QUEUE_DESC_HIGH => {
+                let is_high = offset == VIRTIO_MMIO_QUEUE_DESC_HIGH;
+                if let Some(queue) = self.get_selected_queue() {
+                    let address = set_half(queue.get_area_address(QueueArea::Descriptor), is_high);
+                    queue.set_area_address(QueueArea::Descriptor, address);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DRIVER_LOW | VIRTIO_MMIO_QUEUE_DRIVER_HIGH => {
+                let is_high = offset == VIRTIO_MMIO_QUEUE_DRIVER_HIGH;
+                if let Some(queue) = self.get_selected_queue() {
+                    let address = set_half(queue.get_area_address(QueueArea::Driver), is_high);
+                    queue.set_area_address(QueueArea::Driver, address);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DEVICE_LOW | VIRTIO_MMIO_QUEUE_DEVICE_HIGH => {
+                let is_high = offset == VIRTIO_MMIO_QUEUE_DEVICE_HIGH;
+                if let Some(queue) = self.get_selected_queue() {
+                    let address = set_half(queue.get_area_address(QueueArea::Device), is_high);
+                    queue.set_area_address(QueueArea::Device, address);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_READY => {
+                let vm = get_current_vm()?;
+                let is_packed = (self.driver_features & VIRTIO_F_RING_PACKED) != 0;
+                if let Some(queue) = self.get_selected_queue() {
+                    if value == 1 {
+                        queue.enable(&*vm, is_packed);
+                    } else {
+                        queue.disable();
+                    }
                 }
             }
             VIRTIO_MMIO_QUEUE_NOTIFY => {
-                if value == 0 {
-                    self.operation();
+                let queue_index = value as usize;
+                if self.queues.get(queue_index).is_some_and(|q| q.is_ready()) {
+                    let vm = get_current_vm()?;
+                    self.operation(&vm, queue_index);
                 }
             }
             VIRTIO_MMIO_INTERRUPT_ACK => {
@@ -280,24 +920,90 @@ impl MmioHandler for VirtioBlkMmio {
             }
             VIRTIO_MMIO_STATUS => {
                 if value == 0 {
-                    self.queue_size = 0;
-                    self.queue_ready = false;
-                    self.page_size = 1 << 12;
-                    self.interrupt_status = 0;
-                    self.status = 0;
-                    self.descriptor = null_mut();
-                    self.avail_ring = null_mut();
-                    self.used_ring = null_mut();
-                    self.last_avail_id = 0;
-                    self.used_id = 0;
+                    self.reset();
                 } else {
-                    self.status = value as u32;
+                    let mut status = value as u32;
+                    if (status & VIRTIO_DEVICE_STATUS_FEATURES_OK) != 0
+                        && ((self.driver_features & VIRTIO_F_VERSION_1) == 0
+                            || (self.driver_features & !self.get_device_features()) != 0)
+                    {
+                        /* 受け入れられない機能の組み合わせ */
+                        status &= !VIRTIO_DEVICE_STATUS_FEATURES_OK;
+                    }
+                    self.status = status;
                 }
             }
             _ => { /* Unimplemented */ }
         }
         Ok(())
     }
+
+    fn save_state(&self, _vm: &VM, writer: &mut SnapshotWriter) {
+        writer.write_u32(self.interrupt_status);
+        writer.write_u32(self.status);
+        writer.write_u64(self.driver_features);
+        for queue in self.queues.iter() {
+            queue.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, vm: &VM, reader: &mut SnapshotReader) -> Result<(), ()> {
+        self.interrupt_status = reader.read_u32()?;
+        self.status = reader.read_u32()?;
+        self.driver_features = reader.read_u64()?;
+        for queue in self.queues.iter_mut() {
+            queue.load_state(vm, reader)?;
+        }
+        Ok(())
+    }
+}
+
+/// 要求の Descriptor のうち、デバイスが書き込める領域を Dirty Page として記録する
+fn mark_written_descriptors(vm: &VM, chain: &DescriptorChain) {
+    for descriptor in chain.get_descriptors() {
+        if descriptor.is_writable {
+            vm.mark_dirty(descriptor.address as usize, descriptor.length as usize);
+        }
+    }
 }
 
-unsafe impl core::marker::Send for VirtioBlkMmio {}
+/// ホストの Virtio-Blk で完了した要求を各仮想マシンのゲストへ返す
+fn complete_host_requests(device: &Mutex<VirtioBlk>) {
+    /* 各デバイスのロックを取る前にホストの Virtio-Blk のロックを外す */
+    let completed = device.lock().take_completed();
+    for (token, result) in completed {
+        let Some(vm) = get_vm((token >> REQUEST_ID_BITS) as usize) else {
+            println!("Unknown VM: {:#X}", token >> REQUEST_ID_BITS);
+            continue;
+        };
+        vm.get_virtio_blk_mmio()
+            .lock()
+            .complete_host_request(&vm, token & REQUEST_ID_MASK, result);
+    }
+}
+
+/// device_index 番目のホストの Virtio-Blk の割り込みを処理する
+pub fn host_interrupt_handler(device_index: usize) {
+    complete_host_requests(&VIRTIO_BLK_DEVICES[device_index]);
+}
+
+/// ホストの Virtio-Blk に期限を過ぎた要求が無いか確認する(Generic Timer の割り込みごとに呼ばれる)
+pub fn check_host_timeout() {
+    for device in VIRTIO_BLK_DEVICES.iter() {
+        /* 使用中の場合は、使用している側が確認する */
+        let is_timed_out = device.try_lock().is_some_and(|d| d.has_timed_out_request());
+        if is_timed_out {
+            complete_host_requests(device);
+        }
+    }
+}
+
+/// vm のホストのディスクへの要求が全て完了するまで待つ
+///
+/// 割り込みが禁止されている状態でも呼べるよう、完了をポーリングする
+pub fn wait_for_inflight_requests(vm: &VM) {
+    while !vm.get_virtio_blk_mmio().lock().inflight.is_empty() {
+        complete_host_requests(VIRTIO_BLK);
+        core::hint::spin_loop();
+    }
+}
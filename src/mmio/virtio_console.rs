@@ -0,0 +1,359 @@
+//!
+//! Virtio-Console MMIO Driver
+//!
+//! ポート0(hvc0)を PL011 の代わりに使えるコンソールとし、ポート1は出力をハイパーバイザのログとして表示する。
+//! ポート0の入力は vm::input_uart から渡され、ゲストのドライバが準備できていない場合は PL011 へ渡す。
+//!
+
+use crate::drivers::virtio::*;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::virtqueue::DeviceQueue;
+use crate::vm::{MmioHandler, VM, VmError, get_current_vm};
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const VIRTIO_CONSOLE_MMIO_ADDRESS: usize = 0xa000400;
+pub const VIRTIO_CONSOLE_MMIO_SIZE: usize = 0x200;
+const VIRTIO_CONSOLE_INT_ID: u32 = 43;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u32 = 1 << 2;
+
+/* 設定領域 */
+const VIRTIO_CONSOLE_CONFIG_MAX_NR_PORTS: usize = 4;
+const VIRTIO_CONSOLE_CONFIG_EMERG_WR: usize = 8;
+
+/* 制御メッセージの種類 */
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+/// struct virtio_console_control のサイズ
+const CONTROL_SIZE: usize = 8;
+
+const CONTROL_RX_QUEUE: usize = 2;
+const CONTROL_TX_QUEUE: usize = 3;
+const NUMBER_OF_PORTS: usize = 2;
+/// ポート0と制御用、ポート1以降の送受信用
+const NUMBER_OF_QUEUES: usize = 4 + (NUMBER_OF_PORTS - 1) * 2;
+const QUEUE_NUM_MAX: u64 = 128;
+/// ポート1の名前(/dev/virtio-ports/ 以下に現れる)
+const LOG_PORT_NAME: &[u8] = b"minivisor.log";
+/// ゲストが受け取るまで保持する入力の最大バイト数
+const MAX_INPUT_SIZE: usize = 4096;
+/// ゲストが出力する1つのバッファの最大バイト数(Linux の1回の書き込みの上限に合わせる)
+const MAX_OUTPUT_SIZE: usize = 32 * 1024;
+
+pub struct VirtioConsoleMmio {
+    interrupt_status: u32,
+    status: u32,
+    page_size: usize,
+    queue_select: usize,
+    device_features_select: u32,
+    driver_features: u32,
+    queues: [DeviceQueue; NUMBER_OF_QUEUES],
+    is_port_open: [bool; NUMBER_OF_PORTS],
+    /// ポート0への入力
+    input: VecDeque<u8>,
+    /// 受信用のバッファを待っている制御メッセージ
+    control: VecDeque<Vec<u8>>,
+}
+
+impl VirtioConsoleMmio {
+    pub fn new() -> Self {
+        Self {
+            interrupt_status: 0,
+            status: 0,
+            page_size: 1 << 12,
+            queue_select: 0,
+            device_features_select: 0,
+            driver_features: 0,
+            queues: core::array::from_fn(|_| DeviceQueue::new()),
+            is_port_open: [false; NUMBER_OF_PORTS],
+            input: VecDeque::new(),
+            control: VecDeque::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.page_size = 1 << 12;
+        self.queue_select = 0;
+        self.device_features_select = 0;
+        self.driver_features = 0;
+        for q in &mut self.queues {
+            q.reset();
+        }
+        self.is_port_open = [false; NUMBER_OF_PORTS];
+        self.input.clear();
+        self.control.clear();
+    }
+
+    fn is_multiport(&self) -> bool {
+        (self.driver_features & VIRTIO_CONSOLE_F_MULTIPORT) != 0
+    }
+
+    /// ポートの受信用 Virtqueue の番号
+    fn get_rx_queue(port: usize) -> usize {
+        if port == 0 { 0 } else { 2 + port * 2 }
+    }
+
+    /// ポート0への入力を受け付ける
+    ///
+    /// ゲストのドライバが受信用のバッファを用意していない場合は false を返す
+    pub fn push_input(&mut self, vm: &VM, c: u8) -> bool {
+        if (self.status & VIRTIO_DEVICE_STATUS_DRIVER_OK) == 0 || !self.queues[0].is_ready() {
+            return false;
+        }
+        if self.input.len() == MAX_INPUT_SIZE {
+            self.input.pop_front();
+        }
+        self.input.push_back(c);
+        self.deliver_input(vm);
+        true
+    }
+
+    /// 保持している入力をゲストの受信用バッファへまとめて書き込む
+    fn deliver_input(&mut self, vm: &VM) {
+        while !self.input.is_empty() {
+            let Some(chain) = self.queues[0].pop_avail(vm) else {
+                break;
+            };
+            let data: Vec<u8> = self.input.iter().copied().collect();
+            let length = chain.write(vm, &data);
+            self.input.drain(0..length);
+            self.queues[0].push_used(vm, &chain, length as u32);
+            self.notify_used(vm);
+        }
+    }
+
+    fn send_control(&mut self, vm: &VM, id: u32, event: u16, value: u16, data: &[u8]) {
+        let mut message = Vec::with_capacity(CONTROL_SIZE + data.len());
+        message.extend_from_slice(&id.to_le_bytes());
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(data);
+        self.control.push_back(message);
+        self.deliver_control(vm);
+    }
+
+    fn deliver_control(&mut self, vm: &VM) {
+        while !self.control.is_empty() {
+            let Some(chain) = self.queues[CONTROL_RX_QUEUE].pop_avail(vm) else {
+                break;
+            };
+            let message = self.control.pop_front().unwrap();
+            let length = chain.write(vm, &message);
+            self.queues[CONTROL_RX_QUEUE].push_used(vm, &chain, length as u32);
+            self.notify_used(vm);
+        }
+    }
+
+    /// ゲストからの制御メッセージを処理する
+    fn handle_control(&mut self, vm: &VM) {
+        while let Some(chain) = self.queues[CONTROL_TX_QUEUE].pop_avail(vm) {
+            let message = chain.read(vm, CONTROL_SIZE).unwrap_or_default();
+            self.queues[CONTROL_TX_QUEUE].push_used(vm, &chain, 0);
+            if message.len() < CONTROL_SIZE {
+                continue;
+            }
+            let id = u32::from_le_bytes(message[0..4].try_into().unwrap()) as usize;
+            let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+            let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+            match event {
+                VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                    for port in 0..NUMBER_OF_PORTS {
+                        self.send_control(vm, port as u32, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                    }
+                }
+                VIRTIO_CONSOLE_PORT_READY if value == 1 && id < NUMBER_OF_PORTS => {
+                    if id == 0 {
+                        self.send_control(vm, 0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                    } else {
+                        self.send_control(
+                            vm,
+                            id as u32,
+                            VIRTIO_CONSOLE_PORT_NAME,
+                            1,
+                            LOG_PORT_NAME,
+                        );
+                    }
+                    self.send_control(vm, id as u32, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                }
+                VIRTIO_CONSOLE_PORT_OPEN if id < NUMBER_OF_PORTS => {
+                    self.is_port_open[id] = value != 0;
+                }
+                _ => { /* 対応しない */ }
+            }
+            self.notify_used(vm);
+        }
+    }
+
+    /// ゲストの出力を処理する
+    fn handle_output(&mut self, vm: &VM, port: usize) {
+        let queue = Self::get_rx_queue(port) + 1;
+        while let Some(chain) = self.queues[queue].pop_avail(vm) {
+            let data = chain.read(vm, MAX_OUTPUT_SIZE).unwrap_or_default();
+            self.queues[queue].push_used(vm, &chain, 0);
+            if port == 0 {
+                vm.write_console(&data);
+            } else {
+                let message = String::from_utf8_lossy(&data);
+                println!("[VM{}] {}", vm.get_vm_id(), message.trim_end_matches('\n'));
+            }
+            self.notify_used(vm);
+        }
+    }
+
+    fn notify_used(&mut self, vm: &VM) {
+        self.interrupt_status |= 1;
+        vm.get_gic_distributor_mmio()
+            .lock()
+            .trigger_interrupt(VIRTIO_CONSOLE_INT_ID, None);
+    }
+}
+
+impl MmioHandler for VirtioConsoleMmio {
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, VmError> {
+        let mut value = 0u64;
+        match offset {
+            VIRTIO_MMIO_MAGIC => {
+                value = VIRTIO_MMIO_MAGIC_VALUE as u64;
+            }
+            VIRTIO_MMIO_VERSION => {
+                value = 0x01;
+            }
+            VIRTIO_MMIO_DEVICE_ID => {
+                value = 0x03;
+            }
+            VIRTIO_MMIO_VENDOR_ID => {
+                value = 0x554d4551;
+            }
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                if self.device_features_select == 0 {
+                    value = (VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE) as u64;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => {
+                if self.queue_select < NUMBER_OF_QUEUES {
+                    value = QUEUE_NUM_MAX;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_PFN => {
+                if let Some(q) = self.queues.get(self.queue_select) {
+                    value = q.get_pfn(self.page_size);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_STATUS => {
+                value = self.interrupt_status as u64;
+            }
+            VIRTIO_MMIO_STATUS => {
+                value = self.status as u64;
+            }
+            _ if offset >= VIRTIO_CONFIG_OFFSET => {
+                /* cols(u16), rows(u16), max_nr_ports(u32), emerg_wr(u32) */
+                let mut config = [0u8; 12];
+                config[VIRTIO_CONSOLE_CONFIG_MAX_NR_PORTS..VIRTIO_CONSOLE_CONFIG_EMERG_WR]
+                    .copy_from_slice(&(NUMBER_OF_PORTS as u32).to_le_bytes());
+                let config_offset = offset - VIRTIO_CONFIG_OFFSET;
+                for i in 0..((access_width / 8) as usize) {
+                    if let Some(b) = config.get(config_offset + i) {
+                        value |= (*b as u64) << (i * 8);
+                    }
+                }
+            }
+            _ => { /* Unimplemented */ }
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: usize, _access_width: u64, value: u64) -> Result<(), VmError> {
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => {
+                self.device_features_select = value as u32;
+            }
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                self.driver_features = value as u32;
+            }
+            VIRTIO_MMIO_GUEST_PAGE_SIZE => match DeviceQueue::check_page_size(value) {
+                Some(page_size) => self.page_size = page_size,
+                None => println!("Invalid guest page size: {:#X}", value),
+            },
+            VIRTIO_MMIO_QUEUE_SEL => {
+                self.queue_select = value as usize;
+            }
+            VIRTIO_MMIO_QUEUE_NUM => {
+                if let Some(q) = self.queues.get_mut(self.queue_select) {
+                    q.set_size(value as usize, QUEUE_NUM_MAX as usize);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_PFN => {
+                let vm = get_current_vm()?;
+                let page_size = self.page_size;
+                if let Some(q) = self.queues.get_mut(self.queue_select) {
+                    q.set_pfn(&*vm, value, page_size);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                let vm = get_current_vm()?;
+                match value as usize {
+                    0 => self.deliver_input(&vm),
+                    1 => self.handle_output(&vm, 0),
+                    CONTROL_RX_QUEUE if self.is_multiport() => self.deliver_control(&vm),
+                    CONTROL_TX_QUEUE if self.is_multiport() => self.handle_control(&vm),
+                    q if self.is_multiport() && q < NUMBER_OF_QUEUES && (q & 1) == 1 => {
+                        self.handle_output(&vm, (q - 3) / 2)
+                    }
+                    _ => { /* 受信用のバッファは出力時に使う */ }
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                self.interrupt_status &= !(value as u32);
+            }
+            VIRTIO_MMIO_STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else {
+                    self.status = value as u32;
+                }
+            }
+            _ if offset == VIRTIO_CONFIG_OFFSET + VIRTIO_CONSOLE_CONFIG_EMERG_WR => {
+                get_current_vm()?.write_console(&[value as u8]);
+            }
+            _ => { /* Unimplemented */ }
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, _vm: &VM, writer: &mut SnapshotWriter) {
+        writer.write_u32(self.interrupt_status);
+        writer.write_u32(self.status);
+        writer.write_u64(self.page_size as u64);
+        writer.write_u32(self.driver_features);
+        for q in &self.queues {
+            q.save_state(writer);
+        }
+        for is_open in self.is_port_open {
+            writer.write_u8(is_open as u8);
+        }
+    }
+
+    fn load_state(&mut self, vm: &VM, reader: &mut SnapshotReader) -> Result<(), ()> {
+        self.interrupt_status = reader.read_u32()?;
+        self.status = reader.read_u32()?;
+        self.page_size = DeviceQueue::check_page_size(reader.read_u64()?).ok_or(())?;
+        self.driver_features = reader.read_u32()?;
+        for q in &mut self.queues {
+            q.load_state(vm, reader)?;
+        }
+        for is_open in &mut self.is_port_open {
+            *is_open = reader.read_u8()? != 0;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+//!
+//! Virtio-Net MMIO Driver
+//!
+//! 送信されたフレームは vswitch で他の仮想マシンの Virtio-Net へ転送する。
+//!
+
+use crate::drivers::virtio::*;
+use crate::lock::Mutex;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::virtqueue::DeviceQueue;
+use crate::vm::{self, MmioHandler, VM, VmError};
+use crate::vswitch::{self, SwitchPort};
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub const VIRTIO_NET_MMIO_ADDRESS: usize = 0xa000200;
+pub const VIRTIO_NET_MMIO_SIZE: usize = 0x200;
+const VIRTIO_NET_INT_ID: u32 = 42;
+
+const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+/// virtio_net_hdr のサイズ(VIRTIO_NET_F_MRG_RXBUF を使わない場合)
+const VIRTIO_NET_HDR_SIZE: usize = 10;
+/// ゲストが送信する1つのバッファの最大サイズ(virtio_net_hdr を含む)
+const MAX_TX_SIZE: usize = 65550;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const QUEUE_NUM_MAX: u64 = 256;
+/// 受信用のバッファが用意されるまで保持するフレームの最大数
+const MAX_BACKLOG: usize = 64;
+
+struct VirtioNet {
+    vm_id: usize,
+    mac_address: [u8; 6],
+    interrupt_status: u32,
+    status: u32,
+    page_size: usize,
+    queue_select: usize,
+    device_features_select: u32,
+    queues: [DeviceQueue; 2],
+    /// 受信用のバッファが無いため保留しているフレーム(virtio_net_hdr を含む)
+    backlog: VecDeque<Vec<u8>>,
+}
+
+/// スイッチのポートとして他のpCPUからも受信処理を行うため、状態は Mutex で包んで共有する
+pub struct VirtioNetMmio {
+    net: Arc<Mutex<VirtioNet>>,
+    port_id: usize,
+}
+
+impl VirtioNetMmio {
+    pub fn new(vm_id: usize) -> Self {
+        let net = Arc::new(Mutex::new(VirtioNet {
+            vm_id,
+            mac_address: get_mac_address(vm_id),
+            interrupt_status: 0,
+            status: 0,
+            page_size: 1 << 12,
+            queue_select: 0,
+            device_features_select: 0,
+            queues: [DeviceQueue::new(), DeviceQueue::new()],
+            backlog: VecDeque::new(),
+        }));
+        let port_id = vswitch::add_port(net.clone());
+        Self { net, port_id }
+    }
+}
+
+/// ローカル管理アドレス 02:4D:<VM ID の下位32ビット>
+///
+/// VM ID は 0 から順に割り当てるため、2^32 個の仮想マシンを作成するまで重複しない
+fn get_mac_address(vm_id: usize) -> [u8; 6] {
+    let id = (vm_id as u32).to_be_bytes();
+    [0x02, 0x4D, id[0], id[1], id[2], id[3]]
+}
+
+impl VirtioNet {
+    fn reset(&mut self) {
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.page_size = 1 << 12;
+        self.queue_select = 0;
+        self.device_features_select = 0;
+        for q in &mut self.queues {
+            q.reset();
+        }
+        self.backlog.clear();
+    }
+
+    fn is_driver_ok(&self) -> bool {
+        (self.status & VIRTIO_DEVICE_STATUS_DRIVER_OK) != 0
+    }
+
+    /// 保留しているフレームを受信用のバッファへ書き込み、書き込んだか返す
+    fn deliver(&mut self, vm: &VM) -> bool {
+        let mut is_delivered = false;
+        while !self.backlog.is_empty() {
+            let Some(chain) = self.queues[RX_QUEUE].pop_avail(vm) else {
+                break;
+            };
+            let frame = self.backlog.pop_front().unwrap();
+            let length = chain.write(vm, &frame);
+            self.queues[RX_QUEUE].push_used(vm, &chain, length as u32);
+            is_delivered = true;
+        }
+        if is_delivered {
+            self.interrupt_status |= 1;
+        }
+        is_delivered
+    }
+
+    /// 送信用のバッファからフレームを取り出す
+    fn transmit(&mut self, vm: &VM) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while let Some(chain) = self.queues[TX_QUEUE].pop_avail(vm) {
+            let data = chain.read(vm, MAX_TX_SIZE).unwrap_or_default();
+            self.queues[TX_QUEUE].push_used(vm, &chain, 0);
+            if data.len() > VIRTIO_NET_HDR_SIZE {
+                frames.push(data[VIRTIO_NET_HDR_SIZE..].to_vec());
+            }
+        }
+        if !frames.is_empty() {
+            self.interrupt_status |= 1;
+        }
+        frames
+    }
+}
+
+impl SwitchPort for Mutex<VirtioNet> {
+    fn receive(&self, frame: &[u8]) {
+        let Some(vm) = vm::get_vm(self.lock().vm_id).filter(|vm| !vm.is_stopped()) else {
+            return;
+        };
+        let mut net = self.lock();
+        if !net.is_driver_ok() {
+            return;
+        }
+        if net.backlog.len() == MAX_BACKLOG {
+            net.backlog.pop_front();
+        }
+        let mut data = Vec::with_capacity(VIRTIO_NET_HDR_SIZE + frame.len());
+        data.resize(VIRTIO_NET_HDR_SIZE, 0);
+        data.extend_from_slice(frame);
+        net.backlog.push_back(data);
+        let is_delivered = net.deliver(&vm);
+        drop(net);
+        if is_delivered {
+            vm.get_gic_distributor_mmio()
+                .lock()
+                .trigger_interrupt(VIRTIO_NET_INT_ID, None);
+        }
+    }
+}
+
+impl MmioHandler for VirtioNetMmio {
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, VmError> {
+        let net = self.net.lock();
+        let mut value = 0u64;
+        match offset {
+            VIRTIO_MMIO_MAGIC => {
+                value = VIRTIO_MMIO_MAGIC_VALUE as u64;
+            }
+            VIRTIO_MMIO_VERSION => {
+                value = 0x01;
+            }
+            VIRTIO_MMIO_DEVICE_ID => {
+                value = 0x01;
+            }
+            VIRTIO_MMIO_VENDOR_ID => {
+                value = 0x554d4551;
+            }
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                if net.device_features_select == 0 {
+                    value = VIRTIO_NET_F_MAC as u64;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => {
+                if net.queue_select < net.queues.len() {
+                    value = QUEUE_NUM_MAX;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_PFN => {
+                if let Some(q) = net.queues.get(net.queue_select) {
+                    value = q.get_pfn(net.page_size);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_STATUS => {
+                value = net.interrupt_status as u64;
+            }
+            VIRTIO_MMIO_STATUS => {
+                value = net.status as u64;
+            }
+            _ if offset >= VIRTIO_CONFIG_OFFSET => {
+                /* 設定領域の先頭は MAC アドレス */
+                let config_offset = offset - VIRTIO_CONFIG_OFFSET;
+                for i in 0..((access_width / 8) as usize) {
+                    if let Some(b) = net.mac_address.get(config_offset + i) {
+                        value |= (*b as u64) << (i * 8);
+                    }
+                }
+            }
+            _ => { /* Unimplemented */ }
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: usize, _access_width: u64, value: u64) -> Result<(), VmError> {
+        let mut net = self.net.lock();
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => {
+                net.device_features_select = value as u32;
+            }
+            VIRTIO_MMIO_GUEST_PAGE_SIZE => match DeviceQueue::check_page_size(value) {
+                Some(page_size) => net.page_size = page_size,
+                None => println!("Invalid guest page size: {:#X}", value),
+            },
+            VIRTIO_MMIO_QUEUE_SEL => {
+                net.queue_select = value as usize;
+            }
+            VIRTIO_MMIO_QUEUE_NUM => {
+                let queue_select = net.queue_select;
+                if let Some(q) = net.queues.get_mut(queue_select) {
+                    q.set_size(value as usize, QUEUE_NUM_MAX as usize);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_PFN => {
+                let vm = vm::get_current_vm()?;
+                let page_size = net.page_size;
+                let queue_select = net.queue_select;
+                if let Some(q) = net.queues.get_mut(queue_select) {
+                    q.set_pfn(&*vm, value, page_size);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                let vm = vm::get_current_vm()?;
+                let mut frames = Vec::new();
+                let is_updated = match value as usize {
+                    RX_QUEUE => net.deliver(&vm),
+                    TX_QUEUE => {
+                        frames = net.transmit(&vm);
+                        !frames.is_empty()
+                    }
+                    _ => false,
+                };
+                drop(net);
+                if is_updated {
+                    vm.get_gic_distributor_mmio()
+                        .lock()
+                        .trigger_interrupt(VIRTIO_NET_INT_ID, None);
+                }
+                /* 転送先のポートのロックを取るため、自身のロックを外してから転送する */
+                for frame in frames {
+                    vswitch::forward(self.port_id, &frame);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                net.interrupt_status &= !(value as u32);
+            }
+            VIRTIO_MMIO_STATUS => {
+                if value == 0 {
+                    net.reset();
+                } else {
+                    net.status = value as u32;
+                }
+            }
+            _ => { /* Unimplemented */ }
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, _vm: &VM, writer: &mut SnapshotWriter) {
+        let net = self.net.lock();
+        writer.write_u32(net.interrupt_status);
+        writer.write_u32(net.status);
+        writer.write_u64(net.page_size as u64);
+        for q in &net.queues {
+            q.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, vm: &VM, reader: &mut SnapshotReader) -> Result<(), ()> {
+        let mut net = self.net.lock();
+        net.interrupt_status = reader.read_u32()?;
+        net.status = reader.read_u32()?;
+        net.page_size = DeviceQueue::check_page_size(reader.read_u64()?).ok_or(())?;
+        for q in &mut net.queues {
+            q.load_state(vm, reader)?;
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        vswitch::remove_port(self.port_id);
+    }
+}
@@ -0,0 +1,160 @@
+//!
+//! Virtio-RNG MMIO Driver
+//!
+//! ゲストが用意したバッファを random::fill_random で埋める。
+//!
+
+use crate::drivers::virtio::*;
+use crate::random;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::virtqueue::DeviceQueue;
+use crate::vm::{MmioHandler, VM, VmError, get_current_vm};
+
+use alloc::vec;
+
+pub const VIRTIO_RNG_MMIO_ADDRESS: usize = 0xa000800;
+pub const VIRTIO_RNG_MMIO_SIZE: usize = 0x200;
+const VIRTIO_RNG_INT_ID: u32 = 45;
+
+const QUEUE_NUM_MAX: u64 = 64;
+/// 1回の要求で書き込む最大のバイト数
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+pub struct VirtioRngMmio {
+    interrupt_status: u32,
+    status: u32,
+    page_size: usize,
+    queue_select: usize,
+    queue: DeviceQueue,
+}
+
+impl VirtioRngMmio {
+    pub fn new() -> Self {
+        Self {
+            interrupt_status: 0,
+            status: 0,
+            page_size: 1 << 12,
+            queue_select: 0,
+            queue: DeviceQueue::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.page_size = 1 << 12;
+        self.queue_select = 0;
+        self.queue.reset();
+    }
+
+    /// 要求された全てのバッファを乱数で埋める
+    fn fill_buffers(&mut self, vm: &VM) {
+        let mut is_filled = false;
+        while let Some(chain) = self.queue.pop_avail(vm) {
+            let mut data = vec![0u8; chain.get_writable_length().min(MAX_REQUEST_SIZE)];
+            random::fill_random(&mut data);
+            let length = chain.write(vm, &data);
+            self.queue.push_used(vm, &chain, length as u32);
+            is_filled = true;
+        }
+        if is_filled {
+            self.interrupt_status |= 1;
+            vm.get_gic_distributor_mmio()
+                .lock()
+                .trigger_interrupt(VIRTIO_RNG_INT_ID, None);
+        }
+    }
+}
+
+impl MmioHandler for VirtioRngMmio {
+    fn read(&mut self, offset: usize, _access_width: u64) -> Result<u64, VmError> {
+        let mut value = 0u64;
+        match offset {
+            VIRTIO_MMIO_MAGIC => {
+                value = VIRTIO_MMIO_MAGIC_VALUE as u64;
+            }
+            VIRTIO_MMIO_VERSION => {
+                value = 0x01;
+            }
+            VIRTIO_MMIO_DEVICE_ID => {
+                value = 0x04;
+            }
+            VIRTIO_MMIO_VENDOR_ID => {
+                value = 0x554d4551;
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => {
+                if self.queue_select == 0 {
+                    value = QUEUE_NUM_MAX;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_PFN => {
+                if self.queue_select == 0 {
+                    value = self.queue.get_pfn(self.page_size);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_STATUS => {
+                value = self.interrupt_status as u64;
+            }
+            VIRTIO_MMIO_STATUS => {
+                value = self.status as u64;
+            }
+            _ => { /* Unimplemented */ }
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: usize, _access_width: u64, value: u64) -> Result<(), VmError> {
+        match offset {
+            VIRTIO_MMIO_GUEST_PAGE_SIZE => match DeviceQueue::check_page_size(value) {
+                Some(page_size) => self.page_size = page_size,
+                None => println!("Invalid guest page size: {:#X}", value),
+            },
+            VIRTIO_MMIO_QUEUE_SEL => {
+                self.queue_select = value as usize;
+            }
+            VIRTIO_MMIO_QUEUE_NUM => {
+                if self.queue_select == 0 {
+                    self.queue.set_size(value as usize, QUEUE_NUM_MAX as usize);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_PFN => {
+                let vm = get_current_vm()?;
+                if self.queue_select == 0 {
+                    self.queue.set_pfn(&*vm, value, self.page_size);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                let vm = get_current_vm()?;
+                if value == 0 {
+                    self.fill_buffers(&vm);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                self.interrupt_status &= !(value as u32);
+            }
+            VIRTIO_MMIO_STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else {
+                    self.status = value as u32;
+                }
+            }
+            _ => { /* Unimplemented */ }
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, _vm: &VM, writer: &mut SnapshotWriter) {
+        writer.write_u32(self.interrupt_status);
+        writer.write_u32(self.status);
+        writer.write_u64(self.page_size as u64);
+        self.queue.save_state(writer);
+    }
+
+    fn load_state(&mut self, vm: &VM, reader: &mut SnapshotReader) -> Result<(), ()> {
+        self.interrupt_status = reader.read_u32()?;
+        self.status = reader.read_u32()?;
+        self.page_size = DeviceQueue::check_page_size(reader.read_u64()?).ok_or(())?;
+        self.queue.load_state(vm, reader)
+    }
+}
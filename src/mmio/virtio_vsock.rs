@@ -0,0 +1,606 @@
+//!
+//! Virtio-Vsock MMIO Driver
+//!
+//! ハイパーバイザの CID は VSOCK_HOST_CID、各仮想マシンの CID は VM ID + 3 とする。
+//! 他の仮想マシン宛てのパケットはそのまま宛先の Virtio-Vsock へ渡し、接続の管理は両端のゲストに任せる。
+//! ハイパーバイザ宛ての接続は HOST_SERVICES に登録したサービスが処理する。
+//!
+
+use crate::drivers::virtio::*;
+use crate::lock::Mutex;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::virtqueue::DeviceQueue;
+use crate::vm::{self, MmioHandler, VM, VmError};
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub const VIRTIO_VSOCK_MMIO_ADDRESS: usize = 0xa000600;
+pub const VIRTIO_VSOCK_MMIO_SIZE: usize = 0x200;
+const VIRTIO_VSOCK_INT_ID: u32 = 44;
+
+/// ハイパーバイザの CID
+const VSOCK_HOST_CID: u64 = 2;
+/// 仮想マシンの CID は VM ID にこの値を足したもの
+const VSOCK_GUEST_CID_BASE: u64 = 3;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// struct virtio_vsock_hdr のサイズ
+const VIRTIO_VSOCK_HDR_SIZE: usize = 44;
+/// 1つのパケットで渡すデータの最大サイズ(Linux の受信用バッファに合わせる)
+const MAX_PACKET_PAYLOAD: usize = 4096;
+/// ゲストが送信する1つのパケットの最大サイズ(Linux の送信用バッファの上限に合わせる)
+const MAX_TX_SIZE: usize = VIRTIO_VSOCK_HDR_SIZE + 64 * 1024;
+/// ハイパーバイザ側の受信バッファの大きさ(受け取ったデータはすぐに処理する)
+const HOST_BUFFER_SIZE: u32 = 64 * 1024;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const QUEUE_NUM_MAX: u64 = 128;
+/// 受信用のバッファが用意されるまで保持するパケットの最大数
+const MAX_BACKLOG: usize = 256;
+/// 1つの仮想マシンがハイパーバイザのサービスと同時に接続できる最大数
+const MAX_HOST_CONNECTIONS: usize = 64;
+
+const VSOCK_LOG_PORT: u32 = 1;
+const VSOCK_ECHO_PORT: u32 = 7;
+
+/// ハイパーバイザで待ち受けるサービス(ポート番号, 受信したデータを処理して返信を返す関数)
+#[allow(clippy::type_complexity)]
+const HOST_SERVICES: [(u32, fn(usize, &[u8]) -> Vec<u8>); 2] = [
+    (VSOCK_LOG_PORT, log_service),
+    (VSOCK_ECHO_PORT, echo_service),
+];
+
+/// 受信したデータをログとして表示する
+fn log_service(vm_id: usize, data: &[u8]) -> Vec<u8> {
+    let message = String::from_utf8_lossy(data);
+    for line in message.lines() {
+        println!("[VM{} vsock] {}", vm_id, line);
+    }
+    Vec::new()
+}
+
+/// 受信したデータをそのまま返す
+fn echo_service(_: usize, data: &[u8]) -> Vec<u8> {
+    data.to_vec()
+}
+
+/// 各仮想マシンの Virtio-Vsock (CID, デバイス)
+static VSOCK_DEVICES: Mutex<Vec<(u64, Arc<Mutex<VirtioVsock>>)>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy)]
+struct Header {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    socket_type: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < VIRTIO_VSOCK_HDR_SIZE {
+            return None;
+        }
+        let u16_at = |o: usize| u16::from_le_bytes(data[o..o + 2].try_into().unwrap());
+        let u32_at = |o: usize| u32::from_le_bytes(data[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_le_bytes(data[o..o + 8].try_into().unwrap());
+        Some(Self {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            socket_type: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        })
+    }
+
+    /// ヘッダと data を連結したパケットを作成する
+    fn to_packet(self, data: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(VIRTIO_VSOCK_HDR_SIZE + data.len());
+        packet.extend_from_slice(&self.src_cid.to_le_bytes());
+        packet.extend_from_slice(&self.dst_cid.to_le_bytes());
+        packet.extend_from_slice(&self.src_port.to_le_bytes());
+        packet.extend_from_slice(&self.dst_port.to_le_bytes());
+        packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&self.socket_type.to_le_bytes());
+        packet.extend_from_slice(&self.op.to_le_bytes());
+        packet.extend_from_slice(&self.flags.to_le_bytes());
+        packet.extend_from_slice(&self.buf_alloc.to_le_bytes());
+        packet.extend_from_slice(&self.fwd_cnt.to_le_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    /// 受信用のバッファに収まるようにデータを分割したパケットを作成する
+    fn to_packets(self, data: &[u8]) -> Vec<Vec<u8>> {
+        if data.is_empty() {
+            return alloc::vec![self.to_packet(data)];
+        }
+        data.chunks(MAX_PACKET_PAYLOAD)
+            .map(|c| self.to_packet(c))
+            .collect()
+    }
+
+    /// このパケットへの RST を作成する
+    fn to_reset(self) -> Vec<u8> {
+        Self {
+            src_cid: self.dst_cid,
+            dst_cid: self.src_cid,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            len: 0,
+            socket_type: VIRTIO_VSOCK_TYPE_STREAM,
+            op: VIRTIO_VSOCK_OP_RST,
+            flags: 0,
+            buf_alloc: 0,
+            fwd_cnt: 0,
+        }
+        .to_packet(&[])
+    }
+}
+
+/// ゲストとハイパーバイザのサービスとの接続
+struct HostConnection {
+    host_port: u32,
+    guest_port: u32,
+    /// ゲストから受け取ったバイト数
+    fwd_cnt: u32,
+    /// ゲストへ送ったバイト数
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl HostConnection {
+    /// ゲストの受信バッファの空き
+    fn get_peer_credit(&self) -> usize {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt)) as usize
+    }
+}
+
+struct VirtioVsock {
+    vm_id: usize,
+    interrupt_status: u32,
+    status: u32,
+    page_size: usize,
+    queue_select: usize,
+    device_features_select: u32,
+    /// Rx, Tx, Event
+    queues: [DeviceQueue; 3],
+    /// 受信用のバッファが無いため保留しているパケット(ヘッダを含む)
+    backlog: VecDeque<Vec<u8>>,
+    host_connections: Vec<HostConnection>,
+}
+
+pub struct VirtioVsockMmio {
+    vsock: Arc<Mutex<VirtioVsock>>,
+}
+
+impl VirtioVsockMmio {
+    pub fn new(vm_id: usize) -> Self {
+        let vsock = Arc::new(Mutex::new(VirtioVsock {
+            vm_id,
+            interrupt_status: 0,
+            status: 0,
+            page_size: 1 << 12,
+            queue_select: 0,
+            device_features_select: 0,
+            queues: [DeviceQueue::new(), DeviceQueue::new(), DeviceQueue::new()],
+            backlog: VecDeque::new(),
+            host_connections: Vec::new(),
+        }));
+        let cid = vm_id as u64 + VSOCK_GUEST_CID_BASE;
+        let mut devices = VSOCK_DEVICES.lock();
+        /* 同じ VM ID の仮想マシンを作り直した場合は置き換える */
+        devices.retain(|(c, _)| *c != cid);
+        devices.push((cid, vsock.clone()));
+        Self { vsock }
+    }
+}
+
+impl VirtioVsock {
+    fn get_cid(&self) -> u64 {
+        self.vm_id as u64 + VSOCK_GUEST_CID_BASE
+    }
+
+    fn reset(&mut self) {
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.page_size = 1 << 12;
+        self.queue_select = 0;
+        self.device_features_select = 0;
+        for q in &mut self.queues {
+            q.reset();
+        }
+        self.backlog.clear();
+        self.host_connections.clear();
+    }
+
+    fn is_driver_ok(&self) -> bool {
+        (self.status & VIRTIO_DEVICE_STATUS_DRIVER_OK) != 0
+    }
+
+    fn push_packets(&mut self, packets: Vec<Vec<u8>>) {
+        for packet in packets {
+            if self.backlog.len() == MAX_BACKLOG {
+                self.backlog.pop_front();
+            }
+            self.backlog.push_back(packet);
+        }
+    }
+
+    /// 保留しているパケットを受信用のバッファへ書き込み、書き込んだか返す
+    fn deliver(&mut self, vm: &VM) -> bool {
+        let mut is_delivered = false;
+        while !self.backlog.is_empty() {
+            let Some(chain) = self.queues[RX_QUEUE].pop_avail(vm) else {
+                break;
+            };
+            let packet = self.backlog.pop_front().unwrap();
+            let length = chain.write(vm, &packet);
+            self.queues[RX_QUEUE].push_used(vm, &chain, length as u32);
+            is_delivered = true;
+        }
+        if is_delivered {
+            self.interrupt_status |= 1;
+        }
+        is_delivered
+    }
+
+    /// 送信用のバッファからパケットを取り出し、他の仮想マシン宛てのものを返す
+    ///
+    /// ハイパーバイザ宛てのパケットはここで処理し、返信を backlog へ追加する
+    fn transmit(&mut self, vm: &VM) -> (Vec<(Header, Vec<u8>)>, bool) {
+        let mut packets = Vec::new();
+        let mut is_processed = false;
+        while let Some(chain) = self.queues[TX_QUEUE].pop_avail(vm) {
+            let data = chain.read(vm, MAX_TX_SIZE).unwrap_or_default();
+            self.queues[TX_QUEUE].push_used(vm, &chain, 0);
+            is_processed = true;
+            let Some(mut header) = Header::parse(&data) else {
+                continue;
+            };
+            /* 送信元の CID は詐称させない */
+            header.src_cid = self.get_cid();
+            let payload = &data[VIRTIO_VSOCK_HDR_SIZE..];
+            let payload = &payload[..(header.len as usize).min(payload.len())];
+            if header.socket_type != VIRTIO_VSOCK_TYPE_STREAM {
+                if header.op != VIRTIO_VSOCK_OP_RST {
+                    self.push_packets(alloc::vec![header.to_reset()]);
+                }
+            } else if header.dst_cid == VSOCK_HOST_CID {
+                self.handle_host_packet(header, payload);
+            } else {
+                packets.push((header, payload.to_vec()));
+            }
+        }
+        if is_processed {
+            self.interrupt_status |= 1;
+        }
+        (packets, is_processed)
+    }
+
+    /// ハイパーバイザ宛てのパケットを処理する
+    fn handle_host_packet(&mut self, header: Header, payload: &[u8]) {
+        let vm_id = self.vm_id;
+        let cid = self.get_cid();
+        let connection = self
+            .host_connections
+            .iter()
+            .position(|c| c.host_port == header.dst_port && c.guest_port == header.src_port);
+        let reply_header = |c: &HostConnection, op: u16| Header {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: cid,
+            src_port: c.host_port,
+            dst_port: c.guest_port,
+            len: 0,
+            socket_type: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags: 0,
+            buf_alloc: HOST_BUFFER_SIZE,
+            fwd_cnt: c.fwd_cnt,
+        };
+
+        let Some(index) = connection else {
+            let service = HOST_SERVICES.iter().find(|(p, _)| *p == header.dst_port);
+            /* 接続数が上限に達している場合は RST を返す */
+            if header.op == VIRTIO_VSOCK_OP_REQUEST
+                && service.is_some()
+                && self.host_connections.len() < MAX_HOST_CONNECTIONS
+            {
+                let c = HostConnection {
+                    host_port: header.dst_port,
+                    guest_port: header.src_port,
+                    fwd_cnt: 0,
+                    tx_cnt: 0,
+                    peer_buf_alloc: header.buf_alloc,
+                    peer_fwd_cnt: header.fwd_cnt,
+                };
+                let response = reply_header(&c, VIRTIO_VSOCK_OP_RESPONSE).to_packet(&[]);
+                self.host_connections.push(c);
+                self.push_packets(alloc::vec![response]);
+            } else if header.op != VIRTIO_VSOCK_OP_RST {
+                self.push_packets(alloc::vec![header.to_reset()]);
+            }
+            return;
+        };
+
+        let c = &mut self.host_connections[index];
+        c.peer_buf_alloc = header.buf_alloc;
+        c.peer_fwd_cnt = header.fwd_cnt;
+        let mut replies = Vec::new();
+        match header.op {
+            VIRTIO_VSOCK_OP_RW => {
+                c.fwd_cnt = c.fwd_cnt.wrapping_add(payload.len() as u32);
+                let service = HOST_SERVICES
+                    .iter()
+                    .find(|(p, _)| *p == c.host_port)
+                    .unwrap();
+                let mut data = (service.1)(vm_id, payload);
+                /* ゲストの受信バッファに入り切らない分は破棄する */
+                data.truncate(c.get_peer_credit());
+                c.tx_cnt = c.tx_cnt.wrapping_add(data.len() as u32);
+                if data.is_empty() {
+                    replies.push(reply_header(c, VIRTIO_VSOCK_OP_CREDIT_UPDATE).to_packet(&[]));
+                } else {
+                    replies.extend(reply_header(c, VIRTIO_VSOCK_OP_RW).to_packets(&data));
+                }
+            }
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                replies.push(reply_header(c, VIRTIO_VSOCK_OP_CREDIT_UPDATE).to_packet(&[]));
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN | VIRTIO_VSOCK_OP_REQUEST => {
+                replies.push(reply_header(c, VIRTIO_VSOCK_OP_RST).to_packet(&[]));
+                self.host_connections.remove(index);
+            }
+            VIRTIO_VSOCK_OP_RST => {
+                self.host_connections.remove(index);
+            }
+            _ => { /* CREDIT_UPDATE など */ }
+        }
+        self.push_packets(replies);
+    }
+}
+
+/// 他の仮想マシンから届いたパケットを受け取る
+///
+/// 受け取れない場合は false を返す
+fn receive_packets(vsock: &Mutex<VirtioVsock>, packets: Vec<Vec<u8>>) -> bool {
+    let Some(vm) = vm::get_vm(vsock.lock().vm_id).filter(|vm| !vm.is_stopped()) else {
+        return false;
+    };
+    let mut vsock = vsock.lock();
+    if !vsock.is_driver_ok() {
+        return false;
+    }
+    vsock.push_packets(packets);
+    let is_delivered = vsock.deliver(&vm);
+    drop(vsock);
+    if is_delivered {
+        vm.get_gic_distributor_mmio()
+            .lock()
+            .trigger_interrupt(VIRTIO_VSOCK_INT_ID, None);
+    }
+    true
+}
+
+/// 他の仮想マシン宛てのパケットを転送する
+///
+/// 宛先が存在しない場合は送信元へ RST を返す
+fn route(source: &Mutex<VirtioVsock>, vm: &VM, header: Header, payload: &[u8]) {
+    let target = VSOCK_DEVICES
+        .lock()
+        .iter()
+        .find(|(cid, _)| *cid == header.dst_cid)
+        .map(|(_, vsock)| vsock.clone());
+    if let Some(target) = target
+        && receive_packets(&target, header.to_packets(payload))
+    {
+        return;
+    }
+    if header.op == VIRTIO_VSOCK_OP_RST {
+        return;
+    }
+    let mut source = source.lock();
+    source.push_packets(alloc::vec![header.to_reset()]);
+    let is_delivered = source.deliver(vm);
+    drop(source);
+    if is_delivered {
+        vm.get_gic_distributor_mmio()
+            .lock()
+            .trigger_interrupt(VIRTIO_VSOCK_INT_ID, None);
+    }
+}
+
+impl MmioHandler for VirtioVsockMmio {
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, VmError> {
+        let vsock = self.vsock.lock();
+        let mut value = 0u64;
+        match offset {
+            VIRTIO_MMIO_MAGIC => {
+                value = VIRTIO_MMIO_MAGIC_VALUE as u64;
+            }
+            VIRTIO_MMIO_VERSION => {
+                value = 0x01;
+            }
+            VIRTIO_MMIO_DEVICE_ID => {
+                value = 19;
+            }
+            VIRTIO_MMIO_VENDOR_ID => {
+                value = 0x554d4551;
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => {
+                if vsock.queue_select < vsock.queues.len() {
+                    value = QUEUE_NUM_MAX;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_PFN => {
+                if let Some(q) = vsock.queues.get(vsock.queue_select) {
+                    value = q.get_pfn(vsock.page_size);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_STATUS => {
+                value = vsock.interrupt_status as u64;
+            }
+            VIRTIO_MMIO_STATUS => {
+                value = vsock.status as u64;
+            }
+            _ if offset >= VIRTIO_CONFIG_OFFSET => {
+                /* 設定領域は guest_cid(u64) のみ */
+                let config = vsock.get_cid().to_le_bytes();
+                let config_offset = offset - VIRTIO_CONFIG_OFFSET;
+                for i in 0..((access_width / 8) as usize) {
+                    if let Some(b) = config.get(config_offset + i) {
+                        value |= (*b as u64) << (i * 8);
+                    }
+                }
+            }
+            _ => { /* Unimplemented */ }
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: usize, _access_width: u64, value: u64) -> Result<(), VmError> {
+        let mut vsock = self.vsock.lock();
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => {
+                vsock.device_features_select = value as u32;
+            }
+            VIRTIO_MMIO_GUEST_PAGE_SIZE => match DeviceQueue::check_page_size(value) {
+                Some(page_size) => vsock.page_size = page_size,
+                None => println!("Invalid guest page size: {:#X}", value),
+            },
+            VIRTIO_MMIO_QUEUE_SEL => {
+                vsock.queue_select = value as usize;
+            }
+            VIRTIO_MMIO_QUEUE_NUM => {
+                let queue_select = vsock.queue_select;
+                if let Some(q) = vsock.queues.get_mut(queue_select) {
+                    q.set_size(value as usize, QUEUE_NUM_MAX as usize);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_PFN => {
+                let vm = vm::get_current_vm()?;
+                let page_size = vsock.page_size;
+                let queue_select = vsock.queue_select;
+                if let Some(q) = vsock.queues.get_mut(queue_select) {
+                    q.set_pfn(&*vm, value, page_size);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                let vm = vm::get_current_vm()?;
+                let mut packets = Vec::new();
+                let is_updated = match value as usize {
+                    RX_QUEUE => vsock.deliver(&vm),
+                    TX_QUEUE => {
+                        let is_processed;
+                        (packets, is_processed) = vsock.transmit(&vm);
+                        /* ハイパーバイザからの返信 */
+                        vsock.deliver(&vm);
+                        is_processed
+                    }
+                    _ => false, /* Event Queue は使用しない */
+                };
+                drop(vsock);
+                if is_updated {
+                    vm.get_gic_distributor_mmio()
+                        .lock()
+                        .trigger_interrupt(VIRTIO_VSOCK_INT_ID, None);
+                }
+                /* 宛先のデバイスのロックを取るため、自身のロックを外してから転送する */
+                for (header, payload) in packets {
+                    route(&self.vsock, &vm, header, &payload);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                vsock.interrupt_status &= !(value as u32);
+            }
+            VIRTIO_MMIO_STATUS => {
+                if value == 0 {
+                    vsock.reset();
+                } else {
+                    vsock.status = value as u32;
+                }
+            }
+            _ => { /* Unimplemented */ }
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, _vm: &VM, writer: &mut SnapshotWriter) {
+        let vsock = self.vsock.lock();
+        writer.write_u32(vsock.interrupt_status);
+        writer.write_u32(vsock.status);
+        writer.write_u64(vsock.page_size as u64);
+        for q in &vsock.queues {
+            q.save_state(writer);
+        }
+        writer.write_u64(vsock.host_connections.len() as u64);
+        for c in &vsock.host_connections {
+            writer.write_u32(c.host_port);
+            writer.write_u32(c.guest_port);
+            writer.write_u32(c.fwd_cnt);
+            writer.write_u32(c.tx_cnt);
+            writer.write_u32(c.peer_buf_alloc);
+            writer.write_u32(c.peer_fwd_cnt);
+        }
+    }
+
+    fn load_state(&mut self, vm: &VM, reader: &mut SnapshotReader) -> Result<(), ()> {
+        let mut vsock = self.vsock.lock();
+        vsock.interrupt_status = reader.read_u32()?;
+        vsock.status = reader.read_u32()?;
+        vsock.page_size = DeviceQueue::check_page_size(reader.read_u64()?).ok_or(())?;
+        for q in &mut vsock.queues {
+            q.load_state(vm, reader)?;
+        }
+        vsock.backlog.clear();
+        vsock.host_connections.clear();
+        let number_of_connections = reader.read_u64()?;
+        if number_of_connections > MAX_HOST_CONNECTIONS as u64 {
+            return Err(());
+        }
+        for _ in 0..number_of_connections {
+            let c = HostConnection {
+                host_port: reader.read_u32()?,
+                guest_port: reader.read_u32()?,
+                fwd_cnt: reader.read_u32()?,
+                tx_cnt: reader.read_u32()?,
+                peer_buf_alloc: reader.read_u32()?,
+                peer_fwd_cnt: reader.read_u32()?,
+            };
+            vsock.host_connections.push(c);
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        /* 同じ VM ID で作り直した仮想マシンのデバイスは残す */
+        VSOCK_DEVICES
+            .lock()
+            .retain(|(_, vsock)| !Arc::ptr_eq(vsock, &self.vsock));
+    }
+}
@@ -0,0 +1,370 @@
+//!
+//! Copy-on-Write のオーバーレイディスク
+//!
+//! 読み込み専用のベースイメージを複数の仮想マシンで共有し、各仮想マシンが書き込んだクラスタのみを
+//! オーバーレイファイルへ保存する。FAT32 上のファイルは大きさを変えられないため、
+//! 書き込み用のクラスタは作成時に確保しておき、先頭から順に割り当てる。
+//!
+//! ファイルの構成
+//! | オフセット     | 内容                                                   |
+//! |----------------|--------------------------------------------------------|
+//! | 0              | ヘッダ                                                 |
+//! | HEADER_SIZE    | クラスタの対応表(仮想ディスクのクラスタごとに u32)     |
+//! | data_offset    | 書き込んだクラスタの内容(CLUSTER_SIZE で整列)          |
+//!
+//! 対応表の値が 0 のクラスタはベースイメージから読み込み、n の場合はデータ領域の n - 1 番目を使う。
+//!
+
+use crate::block_device::BlockDevice;
+use crate::fat32::{Fat32, FileInfo};
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+const OVERLAY_MAGIC: [u8; 8] = *b"MVOVERLY";
+const OVERLAY_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 512;
+/// Copy-on-Write の単位
+const CLUSTER_SIZE: usize = 0x10000;
+const SECTOR_SIZE: usize = 512;
+const SECTORS_PER_CLUSTER: usize = CLUSTER_SIZE / SECTOR_SIZE;
+/// 書き込んだセクタの表のうち1つのクラスタの分の u64 の数
+const BITMAP_WORDS_PER_CLUSTER: usize = SECTORS_PER_CLUSTER / u64::BITS as usize;
+const BASE_NAME_LENGTH: usize = 16;
+
+pub struct OverlayDisk {
+    base: FileInfo,
+    overlay: FileInfo,
+    disk_size: usize,
+    data_offset: usize,
+    number_of_data_clusters: u32,
+    /// 割り当て済みのデータ領域のクラスタ数
+    used_clusters: u32,
+    /// クラスタの対応表(ファイル上の配置と同じリトルエンディアン)
+    map: Vec<u32>,
+    /// 書き込んだセクタの表(ファイル上の配置と同じリトルエンディアン)
+    bitmap: Vec<u64>,
+}
+
+/// 書き込みを置くオーバーレイファイル上の範囲
+pub struct OverlayWrite {
+    /// ゲストのデータを書き込む範囲(ファイル上のオフセット, 長さ)
+    pub data: Vec<(usize, usize)>,
+    /// 更新した対応表と書き込んだセクタの表の範囲(バッファの物理アドレス, ファイル上のオフセット, 長さ)
+    pub metadata: Vec<(usize, usize, usize)>,
+}
+
+/// 対応表の大きさ(バイト、HEADER_SIZE で整列)
+fn get_map_size(disk_size: usize) -> usize {
+    (disk_size.div_ceil(CLUSTER_SIZE) * size_of::<u32>()).next_multiple_of(HEADER_SIZE)
+}
+
+/// 書き込んだセクタの表の大きさ(バイト、HEADER_SIZE で整列)
+fn get_bitmap_size(disk_size: usize) -> usize {
+    (disk_size.div_ceil(CLUSTER_SIZE) * BITMAP_WORDS_PER_CLUSTER * size_of::<u64>())
+        .next_multiple_of(HEADER_SIZE)
+}
+
+/// ヘッダを読み込み、オーバーレイディスクであれば内容を返す
+fn read_header(
+    fat32: &Fat32,
+    blk: &mut impl BlockDevice,
+    file: &FileInfo,
+) -> Result<Option<[u8; HEADER_SIZE]>, ()> {
+    let mut header = [0u8; HEADER_SIZE];
+    if fat32.read(file, blk, header.as_mut_ptr() as usize, 0, HEADER_SIZE)? != HEADER_SIZE
+        || header[0..8] != OVERLAY_MAGIC
+    {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+/// file がオーバーレイディスクか
+pub fn is_overlay(fat32: &Fat32, blk: &mut impl BlockDevice, file: &FileInfo) -> bool {
+    matches!(read_header(fat32, blk, file), Ok(Some(_)))
+}
+
+/// base_name をベースイメージとするオーバーレイディスクを作成する
+///
+/// data_size は書き込みを保存できる量で、ベースイメージと同じ大きさにすると全体を書き換えられる
+pub fn create_overlay(
+    fat32: &mut Fat32,
+    blk: &mut impl BlockDevice,
+    base_name: &str,
+    overlay_name: &str,
+    data_size: usize,
+) -> Result<(), ()> {
+    if base_name.eq_ignore_ascii_case(overlay_name) {
+        println!("The overlay must be a different file from the base image.");
+        return Err(());
+    }
+    if base_name.len() > BASE_NAME_LENGTH {
+        println!("Base image name is too long.");
+        return Err(());
+    }
+    let Some(base) = fat32.search_file(base_name) else {
+        println!("{base_name} is not found");
+        return Err(());
+    };
+    if is_overlay(fat32, blk, &base) {
+        println!("{base_name} is an overlay disk.");
+        return Err(());
+    }
+    let disk_size = base.get_file_size();
+    if disk_size == 0 || (disk_size & 0x1FF) != 0 {
+        println!("The size of {base_name} must be 512-Byte aligned.");
+        return Err(());
+    }
+
+    let data_offset = (HEADER_SIZE + get_map_size(disk_size) + get_bitmap_size(disk_size))
+        .next_multiple_of(CLUSTER_SIZE);
+    let number_of_data_clusters = data_size.div_ceil(CLUSTER_SIZE);
+    let file_size = data_offset + number_of_data_clusters * CLUSTER_SIZE;
+    if number_of_data_clusters == 0 || file_size > u32::MAX as usize {
+        println!("Invalid overlay size: {:#X}", data_size);
+        return Err(());
+    }
+
+    /* ヘッダと空の対応表、書き込んだセクタの表を書き込む */
+    let mut buffer = vec![0u8; data_offset];
+    buffer[0..8].copy_from_slice(&OVERLAY_MAGIC);
+    buffer[8..12].copy_from_slice(&OVERLAY_VERSION.to_le_bytes());
+    buffer[12..16].copy_from_slice(&(CLUSTER_SIZE as u32).to_le_bytes());
+    buffer[16..24].copy_from_slice(&(disk_size as u64).to_le_bytes());
+    buffer[24..32].copy_from_slice(&(data_offset as u64).to_le_bytes());
+    buffer[32..36].copy_from_slice(&(number_of_data_clusters as u32).to_le_bytes());
+    buffer[40..(40 + base_name.len())].copy_from_slice(base_name.as_bytes());
+
+    let file = fat32.create_file(blk, overlay_name, file_size)?;
+    if fat32.write(&file, blk, buffer.as_ptr() as usize, 0, data_offset)? != data_offset {
+        return Err(());
+    }
+    Ok(())
+}
+
+impl OverlayDisk {
+    /// オーバーレイディスクを開き、ベースイメージを探す
+    pub fn open(fat32: &Fat32, blk: &mut impl BlockDevice, overlay: FileInfo) -> Result<Self, ()> {
+        let Some(header) = read_header(fat32, blk, &overlay)? else {
+            println!("Invalid overlay magic");
+            return Err(());
+        };
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(header[offset..(offset + 4)].try_into().unwrap());
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(header[offset..(offset + 8)].try_into().unwrap());
+        let version = read_u32(8);
+        if version != OVERLAY_VERSION {
+            println!("Unsupported overlay version: {version}");
+            return Err(());
+        }
+        let cluster_size = read_u32(12) as usize;
+        if cluster_size != CLUSTER_SIZE {
+            println!("Unsupported cluster size: {:#X}", cluster_size);
+            return Err(());
+        }
+        let disk_size = read_u64(16) as usize;
+        let data_offset = read_u64(24) as usize;
+        let number_of_data_clusters = read_u32(32);
+        let map_size = get_map_size(disk_size);
+        let bitmap_size = get_bitmap_size(disk_size);
+        if data_offset < HEADER_SIZE + map_size + bitmap_size
+            || data_offset + (number_of_data_clusters as usize) * CLUSTER_SIZE
+                > overlay.get_file_size()
+        {
+            println!("Overlay is truncated.");
+            return Err(());
+        }
+
+        let base_name = &header[40..(40 + BASE_NAME_LENGTH)];
+        let length = base_name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(BASE_NAME_LENGTH);
+        let base_name = core::str::from_utf8(&base_name[0..length]).or(Err(()))?;
+        let Some(base) = fat32.search_file(base_name) else {
+            println!("Base image {base_name} is not found");
+            return Err(());
+        };
+        if base.get_file_size() != disk_size {
+            println!("The size of the base image {base_name} has been changed.");
+            return Err(());
+        }
+
+        let mut map = vec![0u32; map_size / size_of::<u32>()];
+        let mut bitmap = vec![0u64; bitmap_size / size_of::<u64>()];
+        if fat32.read(
+            &overlay,
+            blk,
+            map.as_mut_ptr() as usize,
+            HEADER_SIZE,
+            map_size,
+        )? != map_size
+            || fat32.read(
+                &overlay,
+                blk,
+                bitmap.as_mut_ptr() as usize,
+                HEADER_SIZE + map_size,
+                bitmap_size,
+            )? != bitmap_size
+        {
+            return Err(());
+        }
+        let used_clusters = map.iter().copied().max().unwrap_or(0);
+        if used_clusters > number_of_data_clusters {
+            println!("Invalid overlay cluster map");
+            return Err(());
+        }
+
+        Ok(Self {
+            base,
+            overlay,
+            disk_size,
+            data_offset,
+            number_of_data_clusters,
+            used_clusters,
+            map,
+            bitmap,
+        })
+    }
+
+    /// ベースイメージのファイル
+    pub fn get_base_file(&self) -> &FileInfo {
+        &self.base
+    }
+
+    /// オーバーレイファイル
+    pub fn get_overlay_file(&self) -> &FileInfo {
+        &self.overlay
+    }
+
+    /// 仮想ディスクの大きさ(バイト)
+    pub fn get_disk_size(&self) -> usize {
+        self.disk_size
+    }
+
+    /// データ領域の data_cluster 番(1 から始まる)のファイル上の位置
+    fn get_data_offset(&self, data_cluster: u32) -> usize {
+        self.data_offset + (data_cluster as usize - 1) * CLUSTER_SIZE
+    }
+
+    /// sector を書き込み済みか
+    fn is_written(&self, sector: usize) -> bool {
+        let index = sector / u64::BITS as usize;
+        self.map[sector / SECTORS_PER_CLUSTER] != 0
+            && (self.bitmap[index] & (1 << (sector % u64::BITS as usize))) != 0
+    }
+
+    /// offset から length バイトがディスク上にあり、セクタ境界に揃っているか
+    ///
+    /// Linux の virtio-blk はデータ部分をセクタ単位で分けるため、揃っていない要求は扱わない
+    fn check_range(&self, offset: usize, length: usize) -> Result<(), ()> {
+        if offset
+            .checked_add(length)
+            .is_none_or(|end| end > self.disk_size)
+        {
+            println!("Access beyond the end of the overlay: {:#X}", offset);
+            return Err(());
+        }
+        if (offset | length) & (SECTOR_SIZE - 1) != 0 {
+            println!("Unaligned access to the overlay: {:#X}", offset);
+            return Err(());
+        }
+        Ok(())
+    }
+
+    /// 仮想ディスクの offset から length バイトの読み込み元を返す
+    ///
+    /// 各要素は(ファイル, ファイル上のオフセット, 長さ)で、先頭から順に並ぶ
+    pub fn map_read(
+        &self,
+        offset: usize,
+        length: usize,
+    ) -> Result<Vec<(&FileInfo, usize, usize)>, ()> {
+        self.check_range(offset, length)?;
+        let mut extents: Vec<(&FileInfo, usize, usize)> = Vec::new();
+        for sector in (offset / SECTOR_SIZE)..((offset + length) / SECTOR_SIZE) {
+            let (file, file_offset) = if self.is_written(sector) {
+                let data_cluster = self.map[sector / SECTORS_PER_CLUSTER];
+                let cluster_offset = (sector % SECTORS_PER_CLUSTER) * SECTOR_SIZE;
+                (
+                    &self.overlay,
+                    self.get_data_offset(data_cluster) + cluster_offset,
+                )
+            } else {
+                (&self.base, sector * SECTOR_SIZE)
+            };
+            match extents.last_mut() {
+                Some((f, o, l)) if core::ptr::eq(*f, file) && *o + *l == file_offset => {
+                    *l += SECTOR_SIZE
+                }
+                _ => extents.push((file, file_offset, SECTOR_SIZE)),
+            }
+        }
+        Ok(extents)
+    }
+
+    /// 仮想ディスクの offset から length バイトへの書き込み先を割り当てる
+    ///
+    /// 対応表と書き込んだセクタの表はデータと同時に書き込むため、
+    /// 書き込みの途中で停止した場合、そのセクタの内容は不定となる
+    pub fn map_write(&mut self, offset: usize, length: usize) -> Result<OverlayWrite, ()> {
+        self.check_range(offset, length)?;
+        let first_sector = offset / SECTOR_SIZE;
+        let end_sector = (offset + length) / SECTOR_SIZE;
+
+        /* 途中で容量が足りなくならないよう、先に必要なクラスタ数を確認する */
+        let new_clusters = (first_sector / SECTORS_PER_CLUSTER
+            ..end_sector.div_ceil(SECTORS_PER_CLUSTER))
+            .filter(|c| self.map[*c] == 0)
+            .count();
+        if self.used_clusters as usize + new_clusters > self.number_of_data_clusters as usize {
+            println!("The overlay disk is full.");
+            return Err(());
+        }
+
+        let mut data: Vec<(usize, usize)> = Vec::new();
+        /* 更新した表のセクタ(ファイル上のオフセット) */
+        let mut dirty_sectors: Vec<usize> = Vec::new();
+        let bitmap_offset = HEADER_SIZE + self.map.len() * size_of::<u32>();
+        for sector in first_sector..end_sector {
+            let cluster = sector / SECTORS_PER_CLUSTER;
+            if self.map[cluster] == 0 {
+                self.used_clusters += 1;
+                self.map[cluster] = self.used_clusters;
+                dirty_sectors
+                    .push(HEADER_SIZE + (cluster * size_of::<u32>()) / SECTOR_SIZE * SECTOR_SIZE);
+            }
+            let index = sector / u64::BITS as usize;
+            let bit = 1 << (sector % u64::BITS as usize);
+            if (self.bitmap[index] & bit) == 0 {
+                self.bitmap[index] |= bit;
+                dirty_sectors
+                    .push(bitmap_offset + (index * size_of::<u64>()) / SECTOR_SIZE * SECTOR_SIZE);
+            }
+
+            let cluster_offset = (sector % SECTORS_PER_CLUSTER) * SECTOR_SIZE;
+            let file_offset = self.get_data_offset(self.map[cluster]) + cluster_offset;
+            match data.last_mut() {
+                Some((o, l)) if *o + *l == file_offset => *l += SECTOR_SIZE,
+                _ => data.push((file_offset, SECTOR_SIZE)),
+            }
+        }
+
+        dirty_sectors.sort_unstable();
+        dirty_sectors.dedup();
+        let metadata = dirty_sectors
+            .into_iter()
+            .map(|file_offset| {
+                let address = if file_offset < bitmap_offset {
+                    self.map.as_ptr() as usize + (file_offset - HEADER_SIZE)
+                } else {
+                    self.bitmap.as_ptr() as usize + (file_offset - bitmap_offset)
+                };
+                (address, file_offset, SECTOR_SIZE)
+            })
+            .collect();
+        Ok(OverlayWrite { data, metadata })
+    }
+}
@@ -2,9 +2,11 @@
 //! Stage2 Pagingの実装
 //!
 
-use crate::allocate_pages;
 use crate::asm;
 use crate::registers::*;
+use crate::{allocate_pages, free_pages};
+
+use alloc::vec::Vec;
 
 use core::slice::from_raw_parts_mut;
 
@@ -60,6 +62,19 @@ impl Descriptor {
         (self.0 & 0b11) == 0b11
     }
 
+    const fn is_block_descriptor(&self) -> bool {
+        (self.0 & 0b11) == 0b01
+    }
+
+    const fn get_output_address(&self) -> usize {
+        (self.0 & Self::OUTPUT_ADDRESS_MASK) as usize
+    }
+
+    /// Output Address と Valid ビット以外の属性
+    const fn get_attributes(&self) -> u64 {
+        self.0 & !(Self::OUTPUT_ADDRESS_MASK | 0b11)
+    }
+
     const fn get_next_level_table_address(&self) -> usize {
         (self.0 & Self::TABLE_ADDRESS_MASK) as usize
     }
@@ -89,7 +104,8 @@ fn number_of_concatenated_page_tables(t0sz: u8, first_level: i8) -> usize {
     }
 }
 
-pub fn init_stage2_translation_table() {
+/// 物理アドレスの大きさから (PS, T0SZ, 最初の Lookup Level) を決める
+fn get_stage2_parameters() -> (u64, u64, i8) {
     let ps = asm::get_id_aa64mmfr0_el1() & ID_AA64MMFR0_EL1_PARANGE;
     let (t0sz, initial_lookup_level) = match ps {
         0b000 => (32u64, 1i8),
@@ -100,6 +116,17 @@ pub fn init_stage2_translation_table() {
         0b101 => (16u64, 0i8),
         _ => (16u64, 0i8),
     };
+    (ps, t0sz, initial_lookup_level)
+}
+
+/// Stage 2 Translation で扱える IPA の大きさ(バイト)
+pub fn get_stage2_ipa_size() -> usize {
+    let (_, t0sz, _) = get_stage2_parameters();
+    1 << (64 - t0sz)
+}
+
+pub fn init_stage2_translation_table() -> usize {
+    let (ps, t0sz, initial_lookup_level) = get_stage2_parameters();
     let number_of_tables = number_of_concatenated_page_tables(t0sz as u8, initial_lookup_level);
     let table = allocate_pages(number_of_tables, 12 + number_of_tables - 1).unwrap();
     for d in unsafe { from_raw_parts_mut(table as *mut Descriptor, number_of_tables * 512) } {
@@ -124,6 +151,47 @@ pub fn init_stage2_translation_table() {
         asm::set_vtcr_el2(vtcr_el2);
         asm::set_vttbr_el2(table as u64);
     }
+    table
+}
+
+/// init_stage2_translation_table で作成した Translation table を全て解放する
+///
+/// 解放した Translation table を使用しているpCPUが無いこと
+pub fn free_stage2_translation_table(table_address: usize) {
+    let (_, t0sz, initial_lookup_level) = get_stage2_parameters();
+    let number_of_tables = number_of_concatenated_page_tables(t0sz as u8, initial_lookup_level);
+    _free_stage2_translation_table(table_address, initial_lookup_level, number_of_tables * 512);
+    free_pages(table_address, number_of_tables);
+    asm::flush_tlb_el1();
+}
+
+fn _free_stage2_translation_table(table_address: usize, level: i8, num_of_descriptors: usize) {
+    if level == 3 {
+        return;
+    }
+    let table = unsafe {
+        core::slice::from_raw_parts(table_address as *const Descriptor, num_of_descriptors)
+    };
+    for descriptor in table.iter().filter(|d| d.is_table_descriptor()) {
+        let next_level_table_address = descriptor.get_next_level_table_address();
+        _free_stage2_translation_table(next_level_table_address, level + 1, 512);
+        free_pages(next_level_table_address, 1);
+    }
+}
+
+fn get_stage2_lookup_parameters() -> (i8, usize) {
+    let vtcr_el2 = asm::get_vtcr_el2();
+    let sl0 = ((vtcr_el2 & VTCR_EL2_SL0) >> VTCR_EL2_SL0_BITS_OFFSET) as u8;
+    let t0sz = ((vtcr_el2 & VTCR_EL2_T0SZ) >> VTCR_EL2_T0SZ_BITS_OFFSET) as u8;
+    let initial_lookup_level: i8 = match sl0 {
+        0b00 => 2,
+        0b01 => 1,
+        0b10 => 0,
+        0b11 => 3,
+        _ => unreachable!(),
+    };
+    let num_of_descriptors = number_of_concatenated_page_tables(t0sz, initial_lookup_level) * 512;
+    (initial_lookup_level, num_of_descriptors)
 }
 
 fn _map_address_stage2(
@@ -227,17 +295,7 @@ pub fn map_address_stage2(
         return Err(());
     }
     let table_address = (asm::get_vttbr_el2() & VTTBR_BADDR) as usize;
-    let vtcr_el2 = asm::get_vtcr_el2();
-    let sl0 = ((vtcr_el2 & VTCR_EL2_SL0) >> VTCR_EL2_SL0_BITS_OFFSET) as u8;
-    let t0sz = ((vtcr_el2 & VTCR_EL2_T0SZ) >> VTCR_EL2_T0SZ_BITS_OFFSET) as u8;
-    let initial_lookup_level: i8 = match sl0 {
-        0b00 => 2,
-        0b01 => 1,
-        0b10 => 0,
-        0b11 => 3,
-        _ => unreachable!(),
-    };
-    let num_of_descriptors = number_of_concatenated_page_tables(t0sz, initial_lookup_level) * 512;
+    let (initial_lookup_level, num_of_descriptors) = get_stage2_lookup_parameters();
 
     _map_address_stage2(
         &mut physical_address,
@@ -252,3 +310,145 @@ pub fn map_address_stage2(
     asm::flush_tlb_el1();
     Ok(())
 }
+
+/// Block descriptor を次のレベルの Translation table に分割する
+///
+/// Break-Before-Make に従い、一度 descriptor を無効化してから Table descriptor に置き換える
+fn split_block_descriptor(descriptor: &mut Descriptor, level: i8) -> Result<usize, ()> {
+    let next_level_table_address = allocate_pages(1, 12).map_err(|e| {
+        println!("Failed to allocate new translation table: {:?}", e);
+    })?;
+    let next_block_size = 1usize << (12 + 9 * (3 - (level + 1) as usize));
+    let output_address = descriptor.get_output_address();
+    let attributes = descriptor.get_attributes();
+    for (i, d) in unsafe { from_raw_parts_mut(next_level_table_address as *mut Descriptor, 512) }
+        .iter_mut()
+        .enumerate()
+    {
+        d.init();
+        d.0 = attributes;
+        d.set_output_address(output_address + i * next_block_size);
+        if level + 1 == 3 {
+            d.validate_as_page_descriptor();
+        } else {
+            d.validate_as_block_descriptor();
+        }
+    }
+
+    descriptor.init();
+    asm::flush_tlb_el1();
+    descriptor.set_output_address(next_level_table_address);
+    descriptor.validate_as_table_descriptor();
+    Ok(next_level_table_address)
+}
+
+fn _change_permission_stage2(
+    intermediate_physical_address: &mut usize,
+    remaining_size: &mut usize,
+    table_address: usize,
+    permission: u64,
+    level: i8,
+    num_of_descriptors: usize,
+) -> Result<(), ()> {
+    let shift = 12 + 9 * (3 - level as usize);
+    let index = (*intermediate_physical_address >> shift) & (num_of_descriptors - 1);
+    let table = unsafe { from_raw_parts_mut(table_address as *mut Descriptor, num_of_descriptors) };
+
+    for descriptor in table[index..num_of_descriptors].iter_mut() {
+        if level == 3 {
+            /* Page descriptor */
+            if !descriptor.is_table_descriptor() {
+                println!("{:#X} is not mapped.", *intermediate_physical_address);
+                return Err(());
+            }
+            descriptor.set_permission(permission);
+            *intermediate_physical_address += PAGE_SIZE;
+            *remaining_size -= PAGE_SIZE;
+        } else {
+            let block_size = 1usize << shift;
+            let mask = block_size - 1;
+            if descriptor.is_block_descriptor() {
+                if (*intermediate_physical_address & mask) == 0 && *remaining_size >= block_size {
+                    /* Block 全体の権限を変更 */
+                    descriptor.set_permission(permission);
+                    *intermediate_physical_address += block_size;
+                    *remaining_size -= block_size;
+                    if *remaining_size == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                split_block_descriptor(descriptor, level)?;
+            } else if !descriptor.is_table_descriptor() {
+                println!("{:#X} is not mapped.", *intermediate_physical_address);
+                return Err(());
+            }
+            _change_permission_stage2(
+                intermediate_physical_address,
+                remaining_size,
+                descriptor.get_next_level_table_address(),
+                permission,
+                level + 1,
+                512,
+            )?;
+        }
+        if *remaining_size == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// 既にマップされている領域のアクセス権限を変更する
+///
+/// 領域の一部だけを変更する場合、Block descriptor は Page 単位まで分割される
+pub fn change_permission_stage2(
+    table_address: usize,
+    mut intermediate_physical_address: usize,
+    mut size: usize,
+    is_readable: bool,
+    is_writable: bool,
+) -> Result<(), ()> {
+    if ((intermediate_physical_address | size) & (PAGE_SIZE - 1)) != 0 {
+        println!("Address and size are not aligned.");
+        return Err(());
+    }
+    if size == 0 {
+        return Ok(());
+    }
+    let (initial_lookup_level, num_of_descriptors) = get_stage2_lookup_parameters();
+    let result = _change_permission_stage2(
+        &mut intermediate_physical_address,
+        &mut size,
+        table_address,
+        ((is_writable as u64) << 1) | (is_readable as u64),
+        initial_lookup_level,
+        num_of_descriptors,
+    );
+
+    asm::flush_tlb_el1();
+    result
+}
+
+/// IPAの変換で参照する Stage 2 の Descriptor を (レベル, Descriptor の値) の列で返す
+pub fn get_stage2_descriptor_chain(
+    mut table_address: usize,
+    intermediate_physical_address: usize,
+) -> Vec<(i8, u64)> {
+    let (mut level, mut num_of_descriptors) = get_stage2_lookup_parameters();
+    let mut chain = Vec::new();
+    loop {
+        let shift = 12 + 9 * (3 - level as usize);
+        let index = (intermediate_physical_address >> shift) & (num_of_descriptors - 1);
+        let descriptor =
+            unsafe { &*((table_address + index * size_of::<Descriptor>()) as *const Descriptor) };
+        chain.push((level, descriptor.0));
+        if level == 3 || !descriptor.is_table_descriptor() {
+            break;
+        }
+        table_address = descriptor.get_next_level_table_address();
+        num_of_descriptors = 512;
+        level += 1;
+    }
+    chain
+}
@@ -6,6 +6,7 @@ use crate::asm::smc;
 
 const PSCI_VERSION: u64 = 0x8400_0000;
 const PSCI_SYSTEM_OFF: u64 = 0x8400_0008;
+const PSCI_CPU_OFF: u64 = 0x8400_0002;
 const PSCI_CPU_ON: u64 = 0xC400_0003;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -62,6 +63,13 @@ pub fn cpu_on(target_cpu: u64, entry_point: u64, argument: u64) -> Result<(), Ps
     }
 }
 
+/// 現在のpCPUを停止する
+///
+/// 成功した場合は戻らない
+pub fn cpu_off() -> PsciErrorCodes {
+    PsciErrorCodes::from(unsafe { smc(PSCI_CPU_OFF, 0, 0, 0) })
+}
+
 pub fn system_off() -> ! {
     unsafe { smc(PSCI_SYSTEM_OFF, 0, 0, 0) };
     unreachable!()
@@ -0,0 +1,106 @@
+//!
+//! 乱数生成
+//!
+//! FEAT_RNG が使える場合は RNDR を使う。
+//! 使えない場合は、タイマーの揺らぎから作ったシードで ChaCha20 を使った CSPRNG を初期化して使う。
+//! CSPRNG は出力の度に鍵を更新し、過去の出力を推測できないようにする。
+//!
+
+use crate::asm;
+use crate::lock::Mutex;
+use crate::registers::ID_AA64ISAR0_EL1_RNDR;
+
+/// RNDR の読み出しに失敗した場合に再試行する回数
+const RNDR_RETRY: usize = 16;
+/// シードの作成に使うタイマーの読み出し回数
+const JITTER_SAMPLES: usize = 4096;
+
+/// ChaCha20 の鍵(未初期化の場合は None)
+static CSPRNG_KEY: Mutex<Option<[u32; 8]>> = Mutex::new(None);
+
+/// buffer を乱数で埋める
+pub fn fill_random(buffer: &mut [u8]) {
+    if (asm::get_id_aa64isar0_el1() & ID_AA64ISAR0_EL1_RNDR) != 0 {
+        let mut is_failed = false;
+        for chunk in buffer.chunks_mut(size_of::<u64>()) {
+            let Some(r) = (0..RNDR_RETRY).find_map(|_| asm::get_rndr()) else {
+                is_failed = true;
+                break;
+            };
+            chunk.copy_from_slice(&r.to_le_bytes()[..chunk.len()]);
+        }
+        if !is_failed {
+            return;
+        }
+    }
+    fill_random_by_csprng(buffer);
+}
+
+fn fill_random_by_csprng(buffer: &mut [u8]) {
+    let mut key_lock = CSPRNG_KEY.lock();
+    let key = key_lock.get_or_insert_with(collect_jitter_seed);
+    let mut counter = 0u32;
+    /* 最初のブロックは次の鍵にする */
+    let next_key = chacha20_block(key, counter);
+    for chunk in buffer.chunks_mut(64) {
+        counter += 1;
+        let block = chacha20_block(key, counter);
+        for (i, b) in chunk.iter_mut().enumerate() {
+            *b = (block[i / 4] >> ((i % 4) * 8)) as u8;
+        }
+    }
+    key.copy_from_slice(&next_key[0..8]);
+}
+
+/// タイマーの読み出し間隔の揺らぎを集めてシードを作る
+fn collect_jitter_seed() -> [u32; 8] {
+    let mut pool = [0u32; 16];
+    let mut previous = asm::get_cntpct_el0();
+    for i in 0..JITTER_SAMPLES {
+        /* 実行時間が揺らぐように、前回の値に応じてメモリを読み書きする */
+        pool[(previous as usize) % pool.len()] ^= i as u32;
+        let now = asm::get_cntpct_el0();
+        let delta = now.wrapping_sub(previous) as u32;
+        let p = &mut pool[i % pool.len()];
+        *p = p.rotate_left(7) ^ delta ^ (now as u32);
+        previous = now;
+    }
+    /* 集めた値を ChaCha20 で撹拌する */
+    let key: [u32; 8] = core::array::from_fn(|i| pool[i] ^ pool[i + 8]);
+    let block = chacha20_block(&key, 0);
+    core::array::from_fn(|i| block[i])
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// ChaCha20 のブロック関数(RFC 8439、nonce は 0 で固定)
+fn chacha20_block(key: &[u32; 8], counter: u32) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[0..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (s, i) in state.iter_mut().zip(input.iter()) {
+        *s = s.wrapping_add(*i);
+    }
+    state
+}
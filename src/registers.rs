@@ -13,6 +13,27 @@ pub const HCR_EL2_VM: u64 = 1 << 0;
 /* SPSR_EL2 */
 pub const SPSR_EL2_M_EL1H: u64 = 0b0101;
 
+pub const SPSR_EL2_SS: u64 = 1 << 21;
+
+/* MDCR_EL2 */
+pub const MDCR_EL2_TDA: u64 = 1 << 9;
+pub const MDCR_EL2_TDE: u64 = 1 << 8;
+
+/* MDSCR_EL1 */
+pub const MDSCR_EL1_MDE: u64 = 1 << 15;
+pub const MDSCR_EL1_SS: u64 = 1 << 0;
+
+/* ID_AA64DFR0_EL1 */
+pub const ID_AA64DFR0_EL1_WRPS_BITS_OFFSET: u64 = 20;
+pub const ID_AA64DFR0_EL1_BRPS_BITS_OFFSET: u64 = 12;
+
+/* DAIF */
+pub const DAIF_IRQ: u64 = 1 << 7;
+pub const DAIF_FIQ: u64 = 1 << 6;
+
+/* CNTV_CTL_EL0 */
+pub const CNTV_CTL_EL0_IMASK: u64 = 1 << 1;
+
 /* VTTBR_EL2 */
 pub const VTTBR_BADDR: u64 = ((1 << 47) - 1) & !1;
 
@@ -31,10 +52,20 @@ pub const VTCR_EL2_T0SZ: u64 = 0b111111 << VTCR_EL2_T0SZ_BITS_OFFSET;
 /* ID_AA64MMFR0_EL1 */
 pub const ID_AA64MMFR0_EL1_PARANGE: u64 = 0b1111;
 
+/* ID_AA64ISAR0_EL1 */
+pub const ID_AA64ISAR0_EL1_RNDR: u64 = 0b1111 << 60;
+
 /* ESR_EL2 */
 pub const ESR_EL2_EC_BITS_OFFSET: u64 = 26;
 pub const ESR_EL2_EC: u64 = 0b111111 << ESR_EL2_EC_BITS_OFFSET;
+pub const ESR_EL2_EC_HVC64: u64 = 0b010110 << 26;
+pub const ESR_EL2_EC_SYSTEM_REGISTER: u64 = 0b011000 << 26;
+pub const ESR_EL2_EC_INSTRUCTION_ABORT: u64 = 0b100000 << 26;
 pub const ESR_EL2_EC_DATA_ABORT: u64 = 0b100100 << 26;
+pub const ESR_EL2_EC_BREAKPOINT: u64 = 0b110000 << 26;
+pub const ESR_EL2_EC_SOFTWARE_STEP: u64 = 0b110010 << 26;
+pub const ESR_EL2_EC_WATCHPOINT: u64 = 0b110100 << 26;
+pub const ESR_EL2_EC_BRK: u64 = 0b111100 << 26;
 pub const ESR_EL2_ISS_ISV: u64 = 1 << 24;
 pub const ESR_EL2_ISS_SAS_BITS_OFFSET: u64 = 22;
 pub const ESR_EL2_ISS_SAS: u64 = 0b11 << ESR_EL2_ISS_SAS_BITS_OFFSET;
@@ -42,6 +73,19 @@ pub const ESR_EL2_ISS_SRT_BITS_OFFSET: u64 = 16;
 pub const ESR_EL2_ISS_SRT: u64 = 0b11111 << ESR_EL2_ISS_SRT_BITS_OFFSET;
 pub const ESR_EL2_ISS_SF: u64 = 1 << 15;
 pub const ESR_EL2_ISS_WNR: u64 = 1 << 6;
+pub const ESR_EL2_ISS_SYSREG_OP0_BITS_OFFSET: u64 = 20;
+pub const ESR_EL2_ISS_SYSREG_OP0: u64 = 0b11 << ESR_EL2_ISS_SYSREG_OP0_BITS_OFFSET;
+/// Op0, Op2, Op1, CRn, CRm
+pub const ESR_EL2_ISS_SYSREG_ENCODING: u64 =
+    (0b11 << 20) | (0b111 << 17) | (0b111 << 14) | (0b1111 << 10) | (0b1111 << 1);
+/// Op0: 0b10, Op2: 0b010, Op1: 0b000, CRn: 0b0000, CRm: 0b0010
+pub const ESR_EL2_ISS_SYSREG_MDSCR_EL1: u64 = (0b10 << 20) | (0b010 << 17) | (0b0010 << 1);
+pub const ESR_EL2_ISS_SYSREG_RT_BITS_OFFSET: u64 = 5;
+pub const ESR_EL2_ISS_SYSREG_RT: u64 = 0b11111 << ESR_EL2_ISS_SYSREG_RT_BITS_OFFSET;
+pub const ESR_EL2_ISS_SYSREG_DIRECTION_READ: u64 = 1 << 0;
+pub const ESR_EL2_ISS_FSC_TYPE: u64 = 0b111100;
+pub const ESR_EL2_ISS_FSC_TRANSLATION_FAULT: u64 = 0b000100;
+pub const ESR_EL2_ISS_FSC_PERMISSION_FAULT: u64 = 0b001100;
 
 /* HPFAR_EL2 */
 pub const HPFAR_EL2_FIPA_BITS_OFFSET: u64 = 4;
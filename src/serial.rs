@@ -5,6 +5,7 @@ use crate::lock::Mutex;
 
 use core::fmt;
 use core::marker::Send;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /// シリアルデバイスの構造体が実装すべき関数
 /// 抽象化の為
@@ -20,6 +21,36 @@ pub struct Serial<'a> {
 /// print!やprintln!で書きまれる関数
 static SERIAL_DEVICE: Mutex<Serial> = Mutex::new(Serial { inner: None });
 
+/// 出力を保留しているか(GDB がシリアルポートを使っている間など)
+static IS_OUTPUT_HELD: AtomicBool = AtomicBool::new(false);
+/// 保留中の出力(割り込みやメモリ確保の失敗時にも使うため、固定長のバッファに溜める)
+static HELD_OUTPUT: Mutex<HeldOutput> = Mutex::new(HeldOutput {
+    buffer: [0; HeldOutput::BUFFER_SIZE],
+    length: 0,
+    dropped: 0,
+});
+
+struct HeldOutput {
+    buffer: [u8; Self::BUFFER_SIZE],
+    length: usize,
+    /// バッファに入りきらずに捨てたバイト数
+    dropped: usize,
+}
+
+impl HeldOutput {
+    const BUFFER_SIZE: usize = 16 * 1024;
+}
+
+impl fmt::Write for HeldOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let size = s.len().min(Self::BUFFER_SIZE - self.length);
+        self.buffer[self.length..(self.length + size)].copy_from_slice(&s.as_bytes()[0..size]);
+        self.length += size;
+        self.dropped += s.len() - size;
+        Ok(())
+    }
+}
+
 impl<'a> Serial<'a> {
     pub fn new(device: &'a Mutex<dyn SerialDevice + Send>) -> Self {
         Self {
@@ -52,9 +83,53 @@ pub fn init_default_serial_port(device: &'static Mutex<dyn SerialDevice + Send>)
 /// print!やprintln!から呼び出される関数
 pub fn print(args: fmt::Arguments) {
     use fmt::Write;
+    if IS_OUTPUT_HELD.load(Ordering::Acquire) {
+        let mut held_output = HELD_OUTPUT.lock();
+        /* release_output と競合した場合はそのまま出力する */
+        if IS_OUTPUT_HELD.load(Ordering::Acquire) {
+            let _ = held_output.write_fmt(args);
+            return;
+        }
+    }
     let _ = SERIAL_DEVICE.lock().write_fmt(args);
 }
 
+/// 以降の print!やprintln!の出力を release_output まで保留する
+pub fn hold_output() {
+    IS_OUTPUT_HELD.store(true, Ordering::Release);
+}
+
+/// 出力の保留を解除し、保留していた出力を書き出す
+pub fn release_output() {
+    use fmt::Write;
+    let mut held_output = HELD_OUTPUT.lock();
+    IS_OUTPUT_HELD.store(false, Ordering::Release);
+    let mut serial = SERIAL_DEVICE.lock();
+    let held = &held_output.buffer[0..held_output.length];
+    /* 末尾で切れた文字は捨てる */
+    let text = match core::str::from_utf8(held) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&held[0..e.valid_up_to()]).unwrap_or(""),
+    };
+    let _ = serial.write_str(text);
+    if held_output.dropped != 0 {
+        let _ = writeln!(
+            serial,
+            "\n({} bytes of output were dropped)",
+            held_output.dropped
+        );
+    }
+    held_output.length = 0;
+    held_output.dropped = 0;
+}
+
+/// 保留していた出力を捨てて、保留を解除する
+///
+/// クラッシュの報告から呼ばれるため、ロックを取らない
+pub fn discard_held_output() {
+    IS_OUTPUT_HELD.store(false, Ordering::Release);
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
@@ -0,0 +1,313 @@
+//!
+//! 仮想マシンのスナップショット
+//!
+//! ファイルの構成
+//! | オフセット     | 内容                                         |
+//! |----------------|----------------------------------------------|
+//! | 0              | SnapshotHeader                               |
+//! | HEADER_SIZE    | 仮想CPUとMMIOデバイスの状態                  |
+//! | ram_offset     | ゲストのRAMの内容(STATE_ALIGN で整列)        |
+//!
+
+use crate::block_device::BlockDevice;
+use crate::fat32::{Fat32, FileInfo};
+use crate::vcpu::VcpuContext;
+use crate::vm::VM;
+
+use alloc::vec::Vec;
+
+const SNAPSHOT_MAGIC: [u8; 8] = *b"MVSNAPSH";
+const SNAPSHOT_VERSION: u32 = 9;
+/// ヘッダとデバイスの状態を配置する単位(FAT32のクラスタ境界に揃えるため大きめに取る)
+const STATE_ALIGN: usize = 0x10000;
+const HEADER_SIZE: usize = 512;
+pub const DISK_NAME_LENGTH: usize = 16;
+
+pub struct SnapshotHeader {
+    pub ram_virtual_base_address: usize,
+    pub ram_size: usize,
+    pub state_size: usize,
+    pub ram_offset: usize,
+    pub disk_file_name: [u8; DISK_NAME_LENGTH],
+}
+
+/// 状態をリトルエンディアンで書き出すバッファ
+pub struct SnapshotWriter {
+    buffer: Vec<u8>,
+}
+
+/// SnapshotWriter で書き出した状態を読み込む
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+    pointer: usize,
+}
+
+impl SnapshotWriter {
+    pub const fn new() -> Self {
+        Self { buffer: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn write_u8(&mut self, data: u8) {
+        self.buffer.push(data);
+    }
+
+    pub fn write_u16(&mut self, data: u16) {
+        self.write_bytes(&data.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, data: u32) {
+        self.write_bytes(&data.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, data: u64) {
+        self.write_bytes(&data.to_le_bytes());
+    }
+
+    pub fn write_u128(&mut self, data: u128) {
+        self.write_bytes(&data.to_le_bytes());
+    }
+
+    pub fn write_u32_array(&mut self, data: &[u32]) {
+        for d in data {
+            self.write_u32(*d);
+        }
+    }
+
+    pub fn write_u64_array(&mut self, data: &[u64]) {
+        for d in data {
+            self.write_u64(*d);
+        }
+    }
+
+    fn align(&mut self, align: usize) {
+        self.buffer
+            .resize(self.buffer.len().next_multiple_of(align), 0);
+    }
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self { data, pointer: 0 }
+    }
+
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<(), ()> {
+        let Some(data) = self.data.get(self.pointer..(self.pointer + buffer.len())) else {
+            println!("Snapshot is truncated.");
+            return Err(());
+        };
+        buffer.copy_from_slice(data);
+        self.pointer += buffer.len();
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ()> {
+        let mut buffer = [0u8; 1];
+        self.read_bytes(&mut buffer)?;
+        Ok(buffer[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, ()> {
+        let mut buffer = [0u8; 2];
+        self.read_bytes(&mut buffer)?;
+        Ok(u16::from_le_bytes(buffer))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ()> {
+        let mut buffer = [0u8; 4];
+        self.read_bytes(&mut buffer)?;
+        Ok(u32::from_le_bytes(buffer))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, ()> {
+        let mut buffer = [0u8; 8];
+        self.read_bytes(&mut buffer)?;
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    pub fn read_u128(&mut self) -> Result<u128, ()> {
+        let mut buffer = [0u8; 16];
+        self.read_bytes(&mut buffer)?;
+        Ok(u128::from_le_bytes(buffer))
+    }
+
+    pub fn read_u32_array(&mut self, buffer: &mut [u32]) -> Result<(), ()> {
+        for d in buffer {
+            *d = self.read_u32()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_u64_array(&mut self, buffer: &mut [u64]) -> Result<(), ()> {
+        for d in buffer {
+            *d = self.read_u64()?;
+        }
+        Ok(())
+    }
+}
+
+impl SnapshotHeader {
+    fn write(&self, writer: &mut SnapshotWriter) {
+        writer.write_bytes(&SNAPSHOT_MAGIC);
+        writer.write_u32(SNAPSHOT_VERSION);
+        writer.write_u32(0);
+        writer.write_u64(self.ram_virtual_base_address as u64);
+        writer.write_u64(self.ram_size as u64);
+        writer.write_u64(self.state_size as u64);
+        writer.write_u64(self.ram_offset as u64);
+        writer.write_bytes(&self.disk_file_name);
+        writer.align(HEADER_SIZE);
+    }
+
+    fn read(reader: &mut SnapshotReader) -> Result<Self, ()> {
+        let mut magic = [0u8; 8];
+        reader.read_bytes(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            println!("Invalid snapshot magic");
+            return Err(());
+        }
+        let version = reader.read_u32()?;
+        if version != SNAPSHOT_VERSION {
+            println!("Unsupported snapshot version: {version}");
+            return Err(());
+        }
+        let _reserved = reader.read_u32()?;
+        let ram_virtual_base_address = reader.read_u64()? as usize;
+        let ram_size = reader.read_u64()? as usize;
+        let state_size = reader.read_u64()? as usize;
+        let ram_offset = reader.read_u64()? as usize;
+        let mut disk_file_name = [0u8; DISK_NAME_LENGTH];
+        reader.read_bytes(&mut disk_file_name)?;
+        Ok(Self {
+            ram_virtual_base_address,
+            ram_size,
+            state_size,
+            ram_offset,
+            disk_file_name,
+        })
+    }
+
+    pub fn get_disk_file_name(&self) -> Option<&str> {
+        let length = self
+            .disk_file_name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(DISK_NAME_LENGTH);
+        core::str::from_utf8(&self.disk_file_name[0..length]).ok()
+    }
+}
+
+/// 仮想マシンの状態とRAMをファイルへ書き出す
+///
+/// 対象の仮想マシンが動作しているpCPUで、仮想CPUが停止している間に呼び出すこと
+pub fn save_vm(
+    vm: &VM,
+    context: &VcpuContext,
+    fat32: &mut Fat32,
+    blk: &mut impl BlockDevice,
+    file_name: &str,
+) -> Result<(), ()> {
+    /* 状態の書き出し */
+    let mut state = SnapshotWriter::new();
+    context.save_state(&mut state);
+    vm.save_mmio_state(&mut state);
+    let state_size = state.len();
+
+    let (ram_virtual_base_address, ram_physical_base_address, ram_size) = vm.get_ram_region();
+    let mut disk_file_name = [0u8; DISK_NAME_LENGTH];
+    let name = vm.get_disk_file_name().as_bytes();
+    if name.len() > DISK_NAME_LENGTH {
+        println!("Disk file name is too long.");
+        return Err(());
+    }
+    disk_file_name[0..name.len()].copy_from_slice(name);
+
+    let ram_offset = (HEADER_SIZE + state_size).next_multiple_of(STATE_ALIGN);
+    let header = SnapshotHeader {
+        ram_virtual_base_address,
+        ram_size,
+        state_size,
+        ram_offset,
+        disk_file_name,
+    };
+    let mut writer = SnapshotWriter::new();
+    header.write(&mut writer);
+    writer.write_bytes(&state.buffer);
+    writer.align(STATE_ALIGN);
+
+    /* ファイルへの書き込み */
+    let file = fat32.create_file(blk, file_name, ram_offset + ram_size)?;
+    fat32.write(
+        &file,
+        blk,
+        writer.buffer.as_ptr() as usize,
+        0,
+        writer.buffer.len(),
+    )?;
+    fat32.write(&file, blk, ram_physical_base_address, ram_offset, ram_size)?;
+    Ok(())
+}
+
+/// スナップショットのヘッダと状態を読み込む
+pub fn read_snapshot(
+    fat32: &Fat32,
+    blk: &mut impl BlockDevice,
+    file: &FileInfo,
+) -> Result<(SnapshotHeader, Vec<u8>), ()> {
+    let mut buffer = alloc::vec![0u8; HEADER_SIZE];
+    if fat32.read(file, blk, buffer.as_mut_ptr() as usize, 0, HEADER_SIZE)? != HEADER_SIZE {
+        println!("Snapshot is truncated.");
+        return Err(());
+    }
+    let header = SnapshotHeader::read(&mut SnapshotReader::new(&buffer))?;
+    if header.ram_offset < HEADER_SIZE + header.state_size
+        || file.get_file_size() < header.ram_offset + header.ram_size
+    {
+        println!("Snapshot is truncated.");
+        return Err(());
+    }
+
+    let mut state =
+        alloc::vec![0u8; (HEADER_SIZE + header.state_size).next_multiple_of(HEADER_SIZE)];
+    fat32.read(file, blk, state.as_mut_ptr() as usize, 0, state.len())?;
+    state.drain(0..HEADER_SIZE);
+    state.truncate(header.state_size);
+    Ok((header, state))
+}
+
+/// スナップショットからRAMと各状態を復元する
+///
+/// 復元先の仮想マシンが動作するpCPUで呼び出すこと
+pub fn restore_vm(
+    vm: &VM,
+    fat32: &Fat32,
+    blk: &mut impl BlockDevice,
+    file: &FileInfo,
+    header: &SnapshotHeader,
+    state: &[u8],
+) -> Result<VcpuContext, ()> {
+    let (ram_virtual_base_address, ram_physical_base_address, ram_size) = vm.get_ram_region();
+    if header.ram_virtual_base_address != ram_virtual_base_address || header.ram_size != ram_size {
+        println!("The memory layout of the snapshot is not compatible.");
+        return Err(());
+    }
+    fat32.read(
+        file,
+        blk,
+        ram_physical_base_address,
+        header.ram_offset,
+        ram_size,
+    )?;
+
+    let mut reader = SnapshotReader::new(state);
+    let context = VcpuContext::load_state(&mut reader)?;
+    vm.load_mmio_state(&mut reader)?;
+    Ok(context)
+}
@@ -0,0 +1,253 @@
+//!
+//! 仮想CPUの状態の保存と復元
+//!
+
+use crate::asm;
+use crate::exception::Registers;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::vgic::{self, VgicContext};
+use crate::vm::VM;
+
+/// 仮想CPUの切り替え時に保存するEL1/EL0のシステムレジスタ
+#[allow(clippy::type_complexity)]
+const SYSTEM_REGISTERS: [(&str, fn() -> u64, unsafe fn(u64)); 27] = [
+    ("SP_EL0", asm::get_sp_el0, asm::set_sp_el0),
+    ("SP_EL1", asm::get_sp_el1, asm::set_sp_el1),
+    ("SCTLR_EL1", asm::get_sctlr_el1, asm::set_sctlr_el1),
+    ("TTBR0_EL1", asm::get_ttbr0_el1, asm::set_ttbr0_el1),
+    ("TTBR1_EL1", asm::get_ttbr1_el1, asm::set_ttbr1_el1),
+    ("TCR_EL1", asm::get_tcr_el1, asm::set_tcr_el1),
+    ("MAIR_EL1", asm::get_mair_el1, asm::set_mair_el1),
+    ("AMAIR_EL1", asm::get_amair_el1, asm::set_amair_el1),
+    ("VBAR_EL1", asm::get_vbar_el1, asm::set_vbar_el1),
+    (
+        "CONTEXTIDR_EL1",
+        asm::get_contextidr_el1,
+        asm::set_contextidr_el1,
+    ),
+    ("TPIDR_EL0", asm::get_tpidr_el0, asm::set_tpidr_el0),
+    ("TPIDRRO_EL0", asm::get_tpidrro_el0, asm::set_tpidrro_el0),
+    ("TPIDR_EL1", asm::get_tpidr_el1, asm::set_tpidr_el1),
+    ("ESR_EL1", asm::get_esr_el1, asm::set_esr_el1),
+    ("FAR_EL1", asm::get_far_el1, asm::set_far_el1),
+    ("AFSR0_EL1", asm::get_afsr0_el1, asm::set_afsr0_el1),
+    ("AFSR1_EL1", asm::get_afsr1_el1, asm::set_afsr1_el1),
+    ("PAR_EL1", asm::get_par_el1, asm::set_par_el1),
+    ("CPACR_EL1", asm::get_cpacr_el1, asm::set_cpacr_el1),
+    ("ELR_EL1", asm::get_elr_el1, asm::set_elr_el1),
+    ("SPSR_EL1", asm::get_spsr_el1, asm::set_spsr_el1),
+    ("CNTKCTL_EL1", asm::get_cntkctl_el1, asm::set_cntkctl_el1),
+    ("CSSELR_EL1", asm::get_csselr_el1, asm::set_csselr_el1),
+    ("MDSCR_EL1", asm::get_mdscr_el1, asm::set_mdscr_el1),
+    ("FPCR", asm::get_fpcr, asm::set_fpcr),
+    ("FPSR", asm::get_fpsr, asm::set_fpsr),
+    (
+        "CNTV_CVAL_EL0",
+        asm::get_cntv_cval_el0,
+        asm::set_cntv_cval_el0,
+    ),
+];
+
+#[derive(Clone)]
+pub struct VcpuContext {
+    pub registers: Registers,
+    pub elr_el2: u64,
+    pub spsr_el2: u64,
+    pub vmpidr_el2: u64,
+    pub system_registers: [u64; SYSTEM_REGISTERS.len()],
+    pub fp_registers: [u128; 32],
+    pub cntv_ctl_el0: u64,
+    /// 保存時点の仮想カウンタの値
+    pub virtual_count: u64,
+    pub vgic: VgicContext,
+}
+
+impl VcpuContext {
+    /// 現在のpCPUで停止している仮想CPUの状態を保存する
+    pub fn save(registers: &Registers) -> Self {
+        let mut context = Self {
+            registers: registers.clone(),
+            elr_el2: asm::get_elr_el2(),
+            spsr_el2: asm::get_spsr_el2(),
+            vmpidr_el2: asm::get_vmpidr_el2(),
+            system_registers: [0; SYSTEM_REGISTERS.len()],
+            fp_registers: [0; 32],
+            cntv_ctl_el0: asm::get_cntv_ctl_el0(),
+            virtual_count: asm::get_cntpct_el0() - asm::get_cntvoff_el2(),
+            vgic: vgic::save_vgic_context(),
+        };
+        for (i, (_, get, _)) in SYSTEM_REGISTERS.iter().enumerate() {
+            context.system_registers[i] = get();
+        }
+        asm::save_fp_registers(&mut context.fp_registers);
+        context
+    }
+
+    /// 保存した状態を現在のpCPUへ復元する
+    ///
+    /// 仮想カウンタは保存時点から再開するように CNTVOFF_EL2 を調整する
+    pub fn restore(&self, registers: &mut Registers, is_migrated: bool) {
+        *registers = self.registers.clone();
+        unsafe {
+            asm::set_elr_el2(self.elr_el2);
+            asm::set_spsr_el2(self.spsr_el2);
+            asm::set_vmpidr_el2(self.vmpidr_el2);
+            for (i, (_, _, set)) in SYSTEM_REGISTERS.iter().enumerate() {
+                set(self.system_registers[i]);
+            }
+            asm::restore_fp_registers(&self.fp_registers);
+            asm::set_cntvoff_el2(asm::get_cntpct_el0() - self.virtual_count);
+            asm::set_cntv_ctl_el0(self.cntv_ctl_el0);
+        }
+        vgic::restore_vgic_context(&self.vgic, is_migrated);
+    }
+
+    /// (レジスタ名, 値) の組を返す
+    pub fn get_system_registers(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        SYSTEM_REGISTERS
+            .iter()
+            .zip(self.system_registers.iter())
+            .map(|((name, _, _), value)| (*name, *value))
+    }
+
+    pub fn get_system_register(&self, name: &str) -> u64 {
+        self.get_system_registers()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value)
+            .unwrap()
+    }
+
+    pub fn set_system_register(&mut self, name: &str, value: u64) {
+        let index = SYSTEM_REGISTERS
+            .iter()
+            .position(|(n, _, _)| *n == name)
+            .unwrap();
+        self.system_registers[index] = value;
+    }
+
+    /// ゲストの Stage 1 の変換テーブルを辿り、仮想アドレスをIPAへ変換する
+    ///
+    /// 4KiB の Granule のみ対応する。
+    /// GDB からも呼ばれるため、変換に失敗してもメッセージは出力しない。
+    pub fn translate_virtual_address(&self, vm: &VM, virtual_address: usize) -> Result<usize, ()> {
+        const SCTLR_EL1_M: u64 = 1;
+        const TCR_EL1_T0SZ_OFFSET: u64 = 0;
+        const TCR_EL1_TG0_OFFSET: u64 = 14;
+        const TCR_EL1_T1SZ_OFFSET: u64 = 16;
+        const TCR_EL1_TG1_OFFSET: u64 = 30;
+        const TCR_EL1_EPD0: u64 = 1 << 7;
+        const TCR_EL1_EPD1: u64 = 1 << 23;
+        const TG0_4KIB: u64 = 0b00;
+        const TG1_4KIB: u64 = 0b10;
+        /* 4KiB Granule で扱える T0SZ/T1SZ の範囲(48bit から 16bit) */
+        const TSZ_MIN: u64 = 16;
+        const TSZ_MAX: u64 = 48;
+        const TTBR_BADDR: u64 = ((1 << 48) - 1) & !1;
+        const DESCRIPTOR_ADDRESS_MASK: u64 = ((1 << 48) - 1) & !((1 << 12) - 1);
+
+        if (self.get_system_register("SCTLR_EL1") & SCTLR_EL1_M) == 0 {
+            /* MMUが無効 */
+            return Ok(virtual_address);
+        }
+        let tcr_el1 = self.get_system_register("TCR_EL1");
+        let is_upper = (virtual_address & (1 << 55)) != 0;
+        let (tsz, granule, ttbr, disabled) = if is_upper {
+            (
+                (tcr_el1 >> TCR_EL1_T1SZ_OFFSET) & 0b111111,
+                (tcr_el1 >> TCR_EL1_TG1_OFFSET) & 0b11,
+                self.get_system_register("TTBR1_EL1"),
+                (tcr_el1 & TCR_EL1_EPD1) != 0,
+            )
+        } else {
+            (
+                (tcr_el1 >> TCR_EL1_T0SZ_OFFSET) & 0b111111,
+                (tcr_el1 >> TCR_EL1_TG0_OFFSET) & 0b11,
+                self.get_system_register("TTBR0_EL1"),
+                (tcr_el1 & TCR_EL1_EPD0) != 0,
+            )
+        };
+        if disabled || granule != if is_upper { TG1_4KIB } else { TG0_4KIB } {
+            return Err(());
+        }
+        /* ゲストが設定した値のため、アーキテクチャ上の範囲外であれば変換しない */
+        if !(TSZ_MIN..=TSZ_MAX).contains(&tsz) {
+            return Err(());
+        }
+        let address_bits = 64 - tsz as usize;
+        let mut level = 4 - (address_bits - 12).div_ceil(9);
+        let mut table_address = (ttbr & TTBR_BADDR) as usize;
+        let mut index_bits = address_bits - (12 + 9 * (3 - level));
+        loop {
+            let shift = 12 + 9 * (3 - level);
+            let index = (virtual_address >> shift) & ((1 << index_bits) - 1);
+            let descriptor_address = vm
+                .get_physical_address(table_address + index * size_of::<u64>())
+                .ok_or(())?;
+            let descriptor = unsafe { *(descriptor_address as *const u64) };
+            let output_address = (descriptor & DESCRIPTOR_ADDRESS_MASK) as usize;
+            let descriptor_type = descriptor & 0b11;
+            if descriptor_type == 0b11 && level < 3 {
+                /* Table descriptor */
+                table_address = output_address;
+                index_bits = 9;
+                level += 1;
+                continue;
+            }
+            if (descriptor_type == 0b11 && level == 3)
+                || (descriptor_type == 0b01 && (1..=2).contains(&level))
+            {
+                /* Page/Block descriptor */
+                let offset_mask = (1 << shift) - 1;
+                return Ok((output_address & !offset_mask) | (virtual_address & offset_mask));
+            }
+            return Err(());
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_u64_array(self.registers.as_array());
+        writer.write_u64(self.elr_el2);
+        writer.write_u64(self.spsr_el2);
+        writer.write_u64(self.vmpidr_el2);
+        writer.write_u64_array(&self.system_registers);
+        for r in &self.fp_registers {
+            writer.write_u128(*r);
+        }
+        writer.write_u64(self.cntv_ctl_el0);
+        writer.write_u64(self.virtual_count);
+        writer.write_u64_array(&self.vgic.list_registers);
+        writer.write_u64(self.vgic.ich_vmcr_el2);
+        writer.write_u64(self.vgic.ich_ap1r0_el2);
+    }
+
+    pub fn load_state(reader: &mut SnapshotReader) -> Result<Self, ()> {
+        let mut registers = Registers::default();
+        reader.read_u64_array(registers.as_mut_array())?;
+        let elr_el2 = reader.read_u64()?;
+        let spsr_el2 = reader.read_u64()?;
+        let vmpidr_el2 = reader.read_u64()?;
+        let mut system_registers = [0; SYSTEM_REGISTERS.len()];
+        reader.read_u64_array(&mut system_registers)?;
+        let mut fp_registers = [0; 32];
+        for r in &mut fp_registers {
+            *r = reader.read_u128()?;
+        }
+        let cntv_ctl_el0 = reader.read_u64()?;
+        let virtual_count = reader.read_u64()?;
+        let mut vgic = VgicContext::default();
+        reader.read_u64_array(&mut vgic.list_registers)?;
+        vgic.ich_vmcr_el2 = reader.read_u64()?;
+        vgic.ich_ap1r0_el2 = reader.read_u64()?;
+        Ok(Self {
+            registers,
+            elr_el2,
+            spsr_el2,
+            vmpidr_el2,
+            system_registers,
+            fp_registers,
+            cntv_ctl_el0,
+            virtual_count,
+            vgic,
+        })
+    }
+}
@@ -3,9 +3,10 @@
 //!
 
 use crate::asm;
+use crate::crash::HALT_INT_ID;
 use crate::drivers::gicv3::{GicGroup, GicRedistributor};
 use crate::mmio::gicv3::INJECT_INTERRUPT_INT_ID;
-use crate::vm;
+use crate::vm::{self, VCPU_REQUEST_INT_ID};
 
 pub const MAINTENANCE_INTERRUPT_INTID: u32 = 25;
 
@@ -19,6 +20,8 @@ const ICH_LRN_EL2_STATUS_PENDING: u64 = 0b01 << 62;
 const ICH_LRN_EL2_HW: u64 = 1 << 61;
 const ICH_LRN_EL2_EOI: u64 = 1 << 41;
 const ICH_LRN_EL2_VINTID: u64 = (1 << 32) - 1;
+const ICH_LRN_EL2_PINTID_BITS_OFFSET: u64 = 32;
+const ICH_LRN_EL2_PINTID: u64 = 0b1111111111 << ICH_LRN_EL2_PINTID_BITS_OFFSET;
 
 const GET_ICH_LRN_EL2: [fn() -> u64; 3] = [
     asm::get_ich_lr0_el2,
@@ -31,6 +34,14 @@ const SET_ICH_LRN_EL2: [unsafe fn(u64); 3] = [
     asm::set_ich_lr2_el2,
 ];
 
+/// 仮想CPUが使用している vGIC の状態
+#[derive(Clone, Default)]
+pub struct VgicContext {
+    pub list_registers: [u64; GET_ICH_LRN_EL2.len()],
+    pub ich_vmcr_el2: u64,
+    pub ich_ap1r0_el2: u64,
+}
+
 pub fn init_vgic(redistributor: &GicRedistributor) {
     let ich_hcr_el2 = ICH_HCR_EL2_EN;
     unsafe { asm::set_ich_hcr_el2(ich_hcr_el2) };
@@ -46,6 +57,18 @@ pub fn init_vgic(redistributor: &GicRedistributor) {
     redistributor.set_priority(INJECT_INTERRUPT_INT_ID, 0x00);
     redistributor.set_trigger_mode(INJECT_INTERRUPT_INT_ID, false);
     redistributor.set_enable(INJECT_INTERRUPT_INT_ID, true);
+
+    /* Enable VCPU_REQUEST_INT_ID */
+    redistributor.set_group(VCPU_REQUEST_INT_ID, GicGroup::NonSecureGroup1);
+    redistributor.set_priority(VCPU_REQUEST_INT_ID, 0x00);
+    redistributor.set_trigger_mode(VCPU_REQUEST_INT_ID, false);
+    redistributor.set_enable(VCPU_REQUEST_INT_ID, true);
+
+    /* Enable HALT_INT_ID */
+    redistributor.set_group(HALT_INT_ID, GicGroup::NonSecureGroup1);
+    redistributor.set_priority(HALT_INT_ID, 0x00);
+    redistributor.set_trigger_mode(HALT_INT_ID, false);
+    redistributor.set_enable(HALT_INT_ID, true);
 }
 
 pub fn create_list_register_entry(
@@ -92,7 +115,11 @@ pub fn maintenance_interrupt_handler() {
         if (eoi_bits & 1) != 0 {
             let entry = (GET_ICH_LRN_EL2[i])();
             let int_id = (entry & ICH_LRN_EL2_VINTID) as u32;
-            let vm = vm::get_current_vm();
+            let Ok(vm) = vm::get_current_vm() else {
+                unsafe { (SET_ICH_LRN_EL2[i])(0) };
+                eoi_bits >>= 1;
+                continue;
+            };
             if int_id >= 32 {
                 /* SPI */
                 let mut distributor = vm.get_gic_distributor_mmio().lock();
@@ -109,3 +136,72 @@ pub fn maintenance_interrupt_handler() {
         eoi_bits >>= 1;
     }
 }
+
+/// 全ての List Register を破棄して vGIC を無効にする
+///
+/// 物理割り込みと対応付けたエントリは、物理割り込みも Deactivate する
+pub fn clear_virtual_interrupts() {
+    let number_of_lrn = (asm::get_ich_vtr_el2() & ICH_VTR_EL2_LIST_REGS) as usize + 1;
+    let supported_lrn = number_of_lrn.min(GET_ICH_LRN_EL2.len());
+    for (get, set) in GET_ICH_LRN_EL2
+        .iter()
+        .zip(SET_ICH_LRN_EL2)
+        .take(supported_lrn)
+    {
+        let entry = get();
+        if (entry & ICH_LRN_EL2_HW) != 0
+            && (entry & ICH_LRN_EL2_STATUS) != ICH_LRN_EL2_STATUS_INACTIVE
+        {
+            GicRedistributor::deactivate(
+                ((entry & ICH_LRN_EL2_PINTID) >> ICH_LRN_EL2_PINTID_BITS_OFFSET) as u32,
+            );
+        }
+        unsafe { set(0) };
+    }
+    unsafe { asm::set_ich_hcr_el2(0) };
+}
+
+pub fn save_vgic_context() -> VgicContext {
+    let number_of_lrn = (asm::get_ich_vtr_el2() & ICH_VTR_EL2_LIST_REGS) as usize + 1;
+    let supported_lrn = number_of_lrn.min(GET_ICH_LRN_EL2.len());
+    let mut context = VgicContext {
+        ich_vmcr_el2: asm::get_ich_vmcr_el2(),
+        ich_ap1r0_el2: asm::get_ich_ap1r0_el2(),
+        ..Default::default()
+    };
+    for (entry, get) in context
+        .list_registers
+        .iter_mut()
+        .zip(GET_ICH_LRN_EL2)
+        .take(supported_lrn)
+    {
+        *entry = get();
+    }
+    context
+}
+
+/// vGIC の状態を復元する
+///
+/// `is_migrated` が true の場合、物理割り込みと紐付いたエントリは破棄する。
+/// 移動先のpCPUではその物理割り込みが Active になっておらず、
+/// Level-sensitive な割り込み(Generic Timer)は条件が満たされていれば再度発生するため。
+pub fn restore_vgic_context(context: &VgicContext, is_migrated: bool) {
+    let number_of_lrn = (asm::get_ich_vtr_el2() & ICH_VTR_EL2_LIST_REGS) as usize + 1;
+    let supported_lrn = number_of_lrn.min(GET_ICH_LRN_EL2.len());
+    unsafe {
+        asm::set_ich_vmcr_el2(context.ich_vmcr_el2);
+        asm::set_ich_ap1r0_el2(context.ich_ap1r0_el2);
+    }
+    for (entry, set) in context
+        .list_registers
+        .iter()
+        .zip(SET_ICH_LRN_EL2)
+        .take(supported_lrn)
+    {
+        let mut entry = *entry;
+        if is_migrated && (entry & ICH_LRN_EL2_HW) != 0 {
+            entry = 0;
+        }
+        unsafe { set(entry) };
+    }
+}
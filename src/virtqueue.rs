@@ -0,0 +1,953 @@
+//!
+//! Virtqueue の共通処理
+//!
+//! ハイパーバイザが実デバイスを操作するドライバ側(DriverQueue)と、
+//! ゲストへ見せる仮想デバイス側(DeviceQueue)の両方で使う。
+//! ドライバ側は Split Virtqueue、デバイス側は Split Virtqueue と Packed Virtqueue に対応する。
+//!
+//! デバイス側は Descriptor Chain の長さと合計のバイト数を制限し、
+//! 循環している Chain や入れ子の Indirect Descriptor を不正な要求として扱う。
+//! Virtqueue のサイズは有効にする前にのみ変更でき、各デバイスの QUEUE_NUM_MAX を超えない。
+//!
+
+use crate::drivers::virtio::*;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::vm::VM;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{Ordering, fence};
+
+/// Descriptor Chain の合計のバイト数の上限
+///
+/// 各デバイスが1つの要求で扱う大きさ(virtio-blk の Segment の合計など)より大きくしておく
+const MAX_CHAIN_LENGTH: u64 = 8 << 20;
+
+/// Virtqueue のサイズの上限(Packed Virtqueue の仕様上の上限)
+const MAX_QUEUE_SIZE: usize = 1 << 15;
+
+/// Packed Virtqueue の Descriptor
+#[repr(C)]
+struct VirtQueuePackedDesc {
+    address: u64,
+    length: u32,
+    id: u16,
+    flags: u16,
+}
+
+/// Packed Virtqueue の通知の抑制に使う構造体(Driver Area と Device Area に置かれる)
+#[repr(C)]
+struct VirtQueueEventSuppress {
+    off_wrap: u16,
+    flags: u16,
+}
+
+/// Virtqueue とバッファが置かれているメモリへのアクセス方法
+pub trait QueueMemory {
+    /// ドライバが指定したアドレスを、ハイパーバイザからアクセスできる物理アドレスへ変換する
+    fn translate(&self, address: usize) -> Option<usize>;
+
+    /// デバイスが書き込んだ範囲を通知する
+    fn mark_written(&self, address: usize, size: usize);
+
+    /// address から size バイトの領域を変換する(物理アドレスが連続していない場合は None)
+    fn translate_range(&self, address: usize, size: usize) -> Option<usize> {
+        let last = size.max(1) - 1;
+        let start = self.translate(address)?;
+        let end = self.translate(address.checked_add(last)?)?;
+        (end.checked_sub(start) == Some(last)).then_some(start)
+    }
+}
+
+impl QueueMemory for VM {
+    fn translate(&self, address: usize) -> Option<usize> {
+        self.get_physical_address(address)
+    }
+
+    fn mark_written(&self, address: usize, size: usize) {
+        self.mark_dirty(address, size);
+    }
+}
+
+/// vring_need_event: old から new まで進めた際に event の位置を通過したかどうか
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+/// Descriptor Chain に含まれる1つのバッファ
+pub struct Descriptor {
+    pub address: u64,
+    pub length: u32,
+    pub is_writable: bool,
+}
+
+/// デバイスが取り出した1つの要求
+pub struct DescriptorChain {
+    /// Used Ring へ返す番号(Split の場合は先頭の Descriptor の番号、Packed の場合は Buffer ID)
+    id: u16,
+    /// 使用した Descriptor Ring の要素数(Packed の場合のみ使用する)
+    ring_entries: u16,
+    descriptors: Vec<Descriptor>,
+    total_length: u64,
+}
+
+impl DescriptorChain {
+    const fn new(id: u16) -> Self {
+        Self {
+            id,
+            ring_entries: 0,
+            descriptors: Vec::new(),
+            total_length: 0,
+        }
+    }
+
+    fn push(&mut self, address: u64, length: u32, flags: u16) -> Option<()> {
+        self.total_length += length as u64;
+        if self.total_length > MAX_CHAIN_LENGTH {
+            return None;
+        }
+        self.descriptors.push(Descriptor {
+            address,
+            length,
+            is_writable: (flags & VIRT_QUEUE_DESC_FLAGS_WRITE) != 0,
+        });
+        Some(())
+    }
+
+    pub fn get_descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+
+    /// デバイスが読み込む部分を連結して返す
+    ///
+    /// 読み込む部分の合計が max_length を超える場合は None を返す
+    pub fn read(&self, memory: &impl QueueMemory, max_length: usize) -> Option<Vec<u8>> {
+        let readable_length: usize = self
+            .descriptors
+            .iter()
+            .filter(|d| !d.is_writable)
+            .map(|d| d.length as usize)
+            .sum();
+        if readable_length > max_length {
+            println!(
+                "Descriptor chain is too long: {:#X} > {:#X}",
+                readable_length, max_length
+            );
+            return None;
+        }
+        let mut data = Vec::with_capacity(readable_length);
+        for descriptor in self.descriptors.iter().filter(|d| !d.is_writable) {
+            let length = descriptor.length as usize;
+            let Some(address) = memory.translate_range(descriptor.address as usize, length) else {
+                println!("Invalid descriptor address: {:#X}", descriptor.address);
+                break;
+            };
+            data.extend_from_slice(unsafe {
+                core::slice::from_raw_parts(address as *const u8, length)
+            });
+        }
+        Some(data)
+    }
+
+    /// デバイスが書き込める部分の合計のバイト数を返す
+    pub fn get_writable_length(&self) -> usize {
+        self.descriptors
+            .iter()
+            .filter(|d| d.is_writable)
+            .map(|d| d.length as usize)
+            .sum()
+    }
+
+    /// デバイスが書き込む部分へ data を書き込み、書き込んだバイト数を返す
+    pub fn write(&self, memory: &impl QueueMemory, data: &[u8]) -> usize {
+        let mut written = 0;
+        for descriptor in self.descriptors.iter().filter(|d| d.is_writable) {
+            if written == data.len() {
+                break;
+            }
+            let length = (descriptor.length as usize).min(data.len() - written);
+            let Some(address) = memory.translate_range(descriptor.address as usize, length) else {
+                println!("Invalid descriptor address: {:#X}", descriptor.address);
+                break;
+            };
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[written..].as_ptr(), address as *mut u8, length)
+            };
+            memory.mark_written(descriptor.address as usize, length);
+            written += length;
+        }
+        written
+    }
+}
+
+/// ドライバが設定する Virtqueue の領域
+#[derive(Clone, Copy)]
+pub enum QueueArea {
+    /// Descriptor Table(Packed の場合は Descriptor Ring)
+    Descriptor,
+    /// Available Ring(Packed の場合は Driver Event Suppression)
+    Driver,
+    /// Used Ring(Packed の場合は Device Event Suppression)
+    Device,
+}
+
+/// ゲストが用意した Virtqueue をデバイスとして操作する
+pub struct DeviceQueue {
+    size: usize,
+    is_ready: bool,
+    is_packed: bool,
+    /// ドライバが設定した各領域のアドレス(QueueArea の順)
+    area_address: [u64; 3],
+    /// 上記を物理アドレスへ変換したもの
+    descriptor: usize,
+    driver_area: usize,
+    device_area: usize,
+    /// 次に読む Available Ring の位置(Packed の場合は Descriptor Ring の位置)
+    next_avail: u16,
+    /// 次に書く Used Ring の位置(Packed の場合は Descriptor Ring の位置)
+    next_used: u16,
+    /// Packed の Wrap Counter
+    avail_wrap_counter: bool,
+    used_wrap_counter: bool,
+    /// 最後に割り込みの要否を判定した時の next_used と used_wrap_counter
+    signalled_used: u16,
+    signalled_used_wrap_counter: bool,
+}
+
+impl DeviceQueue {
+    pub const fn new() -> Self {
+        Self {
+            size: 0,
+            is_ready: false,
+            is_packed: false,
+            area_address: [0; 3],
+            descriptor: 0,
+            driver_area: 0,
+            device_area: 0,
+            next_avail: 0,
+            next_used: 0,
+            avail_wrap_counter: true,
+            used_wrap_counter: true,
+            signalled_used: 0,
+            signalled_used_wrap_counter: true,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.is_ready
+    }
+
+    /// QUEUE_NUM への書き込みを処理する(max_size はデバイスの QUEUE_NUM_MAX)
+    ///
+    /// 使用中の Virtqueue のサイズを変えると、変換した領域の外へアクセスするため無視する
+    pub fn set_size(&mut self, size: usize, max_size: usize) {
+        if self.is_ready {
+            println!("Virtqueue size cannot be changed while the queue is ready");
+            return;
+        }
+        self.size = size.min(max_size).min(MAX_QUEUE_SIZE);
+    }
+
+    /// サイズが 0 でなく、Split Virtqueue の場合は2の冪であるか
+    fn is_valid_size(&self) -> bool {
+        self.size != 0
+            && self.size <= MAX_QUEUE_SIZE
+            && (self.is_packed || self.size.is_power_of_two())
+    }
+
+    pub fn get_area_address(&self, area: QueueArea) -> u64 {
+        self.area_address[area as usize]
+    }
+
+    pub fn set_area_address(&mut self, area: QueueArea, address: u64) {
+        self.area_address[area as usize] = address;
+    }
+
+    /// 設定された各領域を使い始める(変換できない場合は false を返す)
+    pub fn enable(&mut self, memory: &impl QueueMemory, is_packed: bool) -> bool {
+        self.is_packed = is_packed;
+        self.is_ready = self.update_area(memory);
+        self.is_ready
+    }
+
+    pub fn disable(&mut self) {
+        self.is_ready = false;
+    }
+
+    /// 各領域の大きさ(QueueArea の順)
+    fn get_area_size(&self) -> Option<[usize; 3]> {
+        if self.is_packed {
+            Some([
+                size_of::<VirtQueuePackedDesc>().checked_mul(self.size)?,
+                size_of::<VirtQueueEventSuppress>(),
+                size_of::<VirtQueueEventSuppress>(),
+            ])
+        } else {
+            Some([
+                size_of::<VirtQueueDesc>().checked_mul(self.size)?,
+                /* flags + idx + ring + used_event */
+                size_of::<u16>().checked_mul(self.size.checked_add(3)?)?,
+                size_of::<VirtQueueUsedElement>()
+                    .checked_mul(self.size)?
+                    .checked_add(size_of::<u16>() * 3)?,
+            ])
+        }
+    }
+
+    /// 各領域のアドレスを物理アドレスへ変換する
+    fn update_area(&mut self, memory: &impl QueueMemory) -> bool {
+        let area_size = self.get_area_size().filter(|_| self.is_valid_size());
+        let mut physical_address = [0usize; 3];
+        for (i, p) in physical_address.iter_mut().enumerate() {
+            match area_size
+                .and_then(|s| memory.translate_range(self.area_address[i] as usize, s[i]))
+            {
+                Some(a) => *p = a,
+                None => {
+                    self.descriptor = 0;
+                    self.driver_area = 0;
+                    self.device_area = 0;
+                    return false;
+                }
+            }
+        }
+        [self.descriptor, self.driver_area, self.device_area] = physical_address;
+        true
+    }
+
+    /// Legacy の GUEST_PAGE_SIZE に書き込まれた値を検査する
+    ///
+    /// QUEUE_PFN の計算で割る数や境界に使うため、0 や2の冪でない値は None を返す
+    pub fn check_page_size(page_size: u64) -> Option<usize> {
+        page_size.is_power_of_two().then_some(page_size as usize)
+    }
+
+    /// Legacy の QUEUE_PFN の値を返す
+    pub fn get_pfn(&self, page_size: usize) -> u64 {
+        self.area_address[QueueArea::Descriptor as usize] / (page_size as u64)
+    }
+
+    /// Legacy の QUEUE_PFN への書き込みを処理する(0 の場合は Virtqueue を解放する)
+    pub fn set_pfn(&mut self, memory: &impl QueueMemory, pfn: u64, page_size: usize) {
+        if pfn == 0 {
+            self.reset();
+            return;
+        }
+        /* Legacy のレイアウトでは Used Ring をページ境界に揃える */
+        self.is_packed = false;
+        let Some([descriptor_size, avail_ring_size, _]) = self.get_area_size() else {
+            self.disable();
+            return;
+        };
+        let area_address = (pfn as usize)
+            .checked_mul(page_size)
+            .and_then(|descriptor| {
+                let avail_ring = descriptor.checked_add(descriptor_size)?;
+                let used_ring = avail_ring
+                    .checked_add(avail_ring_size)?
+                    .checked_next_multiple_of(page_size)?;
+                Some([descriptor as u64, avail_ring as u64, used_ring as u64])
+            });
+        match area_address {
+            Some(a) => {
+                self.area_address = a;
+                self.enable(memory, false);
+            }
+            None => {
+                println!("Invalid Virtqueue PFN: {:#X}", pfn);
+                self.disable();
+            }
+        }
+    }
+
+    /// ゲストが追加した次の Descriptor Chain を取り出す
+    ///
+    /// 不正な Chain は長さ 0 で Used Ring へ返し、次の Chain を読む
+    pub fn pop_avail(&mut self, memory: &impl QueueMemory) -> Option<DescriptorChain> {
+        loop {
+            if !self.is_ready {
+                return None;
+            }
+            let result = if self.is_packed {
+                self.pop_packed(memory)?
+            } else {
+                self.pop_split(memory)?
+            };
+            match result {
+                Ok(chain) => return Some(chain),
+                Err((id, ring_entries)) => {
+                    println!("Invalid descriptor chain: {}", id);
+                    self.push_used_entry(memory, id, ring_entries, 0);
+                }
+            }
+        }
+    }
+
+    fn pop_split(
+        &mut self,
+        memory: &impl QueueMemory,
+    ) -> Option<Result<DescriptorChain, (u16, u16)>> {
+        if self.next_avail == self.get_split_avail_idx() {
+            return None;
+        }
+        /* idx を読んでから ring の要素を読む */
+        fence(Ordering::Acquire);
+        let index = (self.next_avail as usize) % self.size;
+        self.next_avail = self.next_avail.wrapping_add(1);
+        let head = unsafe {
+            read_volatile(
+                (self.driver_area
+                    + size_of::<u16>() * 2 /* flags + idx */
+                    + size_of::<u16>() * index) as *const u16,
+            )
+        };
+        Some(self.read_split_chain(memory, head).ok_or((head, 1)))
+    }
+
+    fn get_split_avail_idx(&self) -> u16 {
+        unsafe { read_volatile((self.driver_area + size_of::<u16>()) as *const u16) }
+    }
+
+    /// Split Virtqueue の Descriptor Chain を辿る
+    fn read_split_chain(&self, memory: &impl QueueMemory, head: u16) -> Option<DescriptorChain> {
+        let mut chain = DescriptorChain::new(head);
+        let mut visited = vec![false; self.size];
+        let mut id = head as usize;
+        loop {
+            if id >= self.size || visited[id] {
+                return None;
+            }
+            visited[id] = true;
+            let descriptor = unsafe {
+                read_volatile(
+                    (self.descriptor + size_of::<VirtQueueDesc>() * id) as *const VirtQueueDesc,
+                )
+            };
+            if (descriptor.flags & VIRT_QUEUE_DESC_FLAGS_INDIRECT) != 0 {
+                /* Indirect Descriptor は単独で使う */
+                if (descriptor.flags & VIRT_QUEUE_DESC_FLAGS_NEXT) != 0
+                    || !chain.descriptors.is_empty()
+                {
+                    return None;
+                }
+                Self::read_split_indirect_chain(memory, &descriptor, &mut chain)?;
+                return Some(chain);
+            }
+            chain.push(descriptor.address, descriptor.length, descriptor.flags)?;
+            if (descriptor.flags & VIRT_QUEUE_DESC_FLAGS_NEXT) == 0 {
+                return Some(chain);
+            }
+            id = descriptor.next as usize;
+        }
+    }
+
+    /// Split Virtqueue の Indirect Descriptor が指している Descriptor Table を辿る
+    fn read_split_indirect_chain(
+        memory: &impl QueueMemory,
+        indirect: &VirtQueueDesc,
+        chain: &mut DescriptorChain,
+    ) -> Option<()> {
+        let table = Self::translate_indirect_table(memory, indirect.address, indirect.length)?;
+        let table_size = indirect.length as usize / size_of::<VirtQueueDesc>();
+        let mut visited = vec![false; table_size];
+        let mut id = 0;
+        loop {
+            if id >= table_size || visited[id] {
+                return None;
+            }
+            visited[id] = true;
+            let descriptor = unsafe {
+                read_volatile((table + size_of::<VirtQueueDesc>() * id) as *const VirtQueueDesc)
+            };
+            if (descriptor.flags & VIRT_QUEUE_DESC_FLAGS_INDIRECT) != 0 {
+                /* Indirect Descriptor の入れ子は許されない */
+                return None;
+            }
+            chain.push(descriptor.address, descriptor.length, descriptor.flags)?;
+            if (descriptor.flags & VIRT_QUEUE_DESC_FLAGS_NEXT) == 0 {
+                return Some(());
+            }
+            id = descriptor.next as usize;
+        }
+    }
+
+    fn translate_indirect_table(
+        memory: &impl QueueMemory,
+        address: u64,
+        length: u32,
+    ) -> Option<usize> {
+        /* Split と Packed の Descriptor は同じ大きさ */
+        if length == 0 || (length as usize) % size_of::<VirtQueueDesc>() != 0 {
+            return None;
+        }
+        memory.translate_range(address as usize, length as usize)
+    }
+
+    fn read_packed_descriptor(&self, position: u16) -> VirtQueuePackedDesc {
+        unsafe {
+            read_volatile(
+                (self.descriptor + size_of::<VirtQueuePackedDesc>() * (position as usize))
+                    as *const VirtQueuePackedDesc,
+            )
+        }
+    }
+
+    /// Packed Virtqueue の Descriptor がデバイスへ渡されているかどうか
+    fn is_packed_available(&self, flags: u16) -> bool {
+        let is_avail = (flags & VIRT_QUEUE_DESC_FLAGS_AVAIL) != 0;
+        let is_used = (flags & VIRT_QUEUE_DESC_FLAGS_USED) != 0;
+        is_avail == self.avail_wrap_counter && is_used != self.avail_wrap_counter
+    }
+
+    fn pop_packed(
+        &mut self,
+        memory: &impl QueueMemory,
+    ) -> Option<Result<DescriptorChain, (u16, u16)>> {
+        if !self.is_packed_available(self.read_packed_descriptor(self.next_avail).flags) {
+            return None;
+        }
+        /* flags を読んでから Descriptor の内容を読む */
+        fence(Ordering::Acquire);
+        let mut chain = DescriptorChain::new(0);
+        let mut is_valid = true;
+        loop {
+            let descriptor = self.read_packed_descriptor(self.next_avail);
+            self.next_avail += 1;
+            if (self.next_avail as usize) >= self.size {
+                self.next_avail = 0;
+                self.avail_wrap_counter = !self.avail_wrap_counter;
+            }
+            chain.ring_entries += 1;
+            /* Buffer ID は Chain の最後の Descriptor に書かれる */
+            chain.id = descriptor.id;
+            if (descriptor.flags & VIRT_QUEUE_DESC_FLAGS_INDIRECT) != 0 {
+                is_valid &= (descriptor.flags & VIRT_QUEUE_DESC_FLAGS_NEXT) == 0
+                    && chain.descriptors.is_empty()
+                    && Self::read_packed_indirect_chain(memory, &descriptor, &mut chain).is_some();
+            } else {
+                is_valid &= chain
+                    .push(descriptor.address, descriptor.length, descriptor.flags)
+                    .is_some();
+            }
+            if (descriptor.flags & VIRT_QUEUE_DESC_FLAGS_NEXT) == 0 {
+                break;
+            }
+            if (chain.ring_entries as usize) >= self.size {
+                is_valid = false;
+                break;
+            }
+        }
+        Some(if is_valid {
+            Ok(chain)
+        } else {
+            Err((chain.id, chain.ring_entries))
+        })
+    }
+
+    /// Packed Virtqueue の Indirect Descriptor が指している Descriptor Table を読む
+    fn read_packed_indirect_chain(
+        memory: &impl QueueMemory,
+        indirect: &VirtQueuePackedDesc,
+        chain: &mut DescriptorChain,
+    ) -> Option<()> {
+        let table = Self::translate_indirect_table(memory, indirect.address, indirect.length)?;
+        /* Packed の Descriptor Table は先頭から順に全ての要素を使う */
+        for i in 0..(indirect.length as usize / size_of::<VirtQueuePackedDesc>()) {
+            let descriptor = unsafe {
+                read_volatile(
+                    (table + size_of::<VirtQueuePackedDesc>() * i) as *const VirtQueuePackedDesc,
+                )
+            };
+            if (descriptor.flags & VIRT_QUEUE_DESC_FLAGS_INDIRECT) != 0 {
+                return None;
+            }
+            chain.push(descriptor.address, descriptor.length, descriptor.flags)?;
+        }
+        Some(())
+    }
+
+    /// 処理した Descriptor Chain を Used Ring へ返す
+    pub fn push_used(&mut self, memory: &impl QueueMemory, chain: &DescriptorChain, length: u32) {
+        self.push_used_entry(memory, chain.id, chain.ring_entries, length);
+    }
+
+    fn push_used_entry(
+        &mut self,
+        memory: &impl QueueMemory,
+        id: u16,
+        ring_entries: u16,
+        length: u32,
+    ) {
+        if self.is_packed {
+            let position = self.next_used as usize;
+            let address = self.descriptor + size_of::<VirtQueuePackedDesc>() * position;
+            let flags = if self.used_wrap_counter {
+                VIRT_QUEUE_DESC_FLAGS_AVAIL | VIRT_QUEUE_DESC_FLAGS_USED
+            } else {
+                0
+            };
+            unsafe {
+                let descriptor = address as *mut VirtQueuePackedDesc;
+                write_volatile(&raw mut (*descriptor).id, id);
+                write_volatile(&raw mut (*descriptor).length, length);
+                /* id と length を書き込んでから flags を更新する */
+                fence(Ordering::Release);
+                write_volatile(&raw mut (*descriptor).flags, flags);
+            }
+            for _ in 0..ring_entries {
+                self.next_used += 1;
+                if (self.next_used as usize) >= self.size {
+                    self.next_used = 0;
+                    self.used_wrap_counter = !self.used_wrap_counter;
+                }
+            }
+            memory.mark_written(
+                self.area_address[QueueArea::Descriptor as usize] as usize
+                    + size_of::<VirtQueuePackedDesc>() * position,
+                size_of::<VirtQueuePackedDesc>(),
+            );
+        } else {
+            let index = (self.next_used as usize) % self.size;
+            self.next_used = self.next_used.wrapping_add(1);
+            unsafe {
+                write_volatile(
+                    (self.device_area
+                        + size_of::<u16>() * 2 /* flags + idx */
+                        + size_of::<VirtQueueUsedElement>() * index)
+                        as *mut VirtQueueUsedElement,
+                    VirtQueueUsedElement {
+                        id: (id as u32),
+                        length,
+                    },
+                );
+                /* 他のpCPUから書き込む場合もあるため、要素を書き込んでから idx を更新する */
+                fence(Ordering::Release);
+                write_volatile(
+                    (self.device_area + size_of::<u16>()) as *mut u16,
+                    self.next_used,
+                );
+            }
+            memory.mark_written(
+                self.area_address[QueueArea::Device as usize] as usize,
+                self.get_area_size()
+                    .map_or(0, |s| s[QueueArea::Device as usize]),
+            );
+        }
+    }
+
+    /// EVENT_IDX を使う場合に、次に通知してほしい位置をドライバへ知らせる
+    ///
+    /// 知らせるまでの間にドライバが追加した要求があるかどうかを返す
+    pub fn update_avail_event(&mut self, memory: &impl QueueMemory) -> bool {
+        if self.is_packed {
+            unsafe {
+                write_volatile(
+                    self.device_area as *mut VirtQueueEventSuppress,
+                    VirtQueueEventSuppress {
+                        off_wrap: self.next_avail | ((self.avail_wrap_counter as u16) << 15),
+                        flags: VIRT_QUEUE_EVENT_FLAGS_DESC,
+                    },
+                )
+            };
+        } else {
+            unsafe {
+                write_volatile(
+                    (self.device_area
+                        + size_of::<u16>() * 2 /* flags + idx */
+                        + size_of::<VirtQueueUsedElement>() * self.size)
+                        as *mut u16,
+                    self.next_avail,
+                )
+            };
+        }
+        memory.mark_written(
+            self.area_address[QueueArea::Device as usize] as usize,
+            self.get_area_size()
+                .map_or(0, |s| s[QueueArea::Device as usize]),
+        );
+        /* 通知位置を書いてから、新しい要求を確認する */
+        fence(Ordering::SeqCst);
+        if self.is_packed {
+            self.is_packed_available(self.read_packed_descriptor(self.next_avail).flags)
+        } else {
+            self.next_avail != self.get_split_avail_idx()
+        }
+    }
+
+    /// 前回の判定以降に Used Ring へ返した要求について、割り込みが必要かどうかを返す
+    pub fn needs_interrupt(&mut self, is_event_idx_enabled: bool) -> bool {
+        let old = self.signalled_used;
+        let old_wrap_counter = self.signalled_used_wrap_counter;
+        self.signalled_used = self.next_used;
+        self.signalled_used_wrap_counter = self.used_wrap_counter;
+        if old == self.next_used && old_wrap_counter == self.used_wrap_counter {
+            return false;
+        }
+        /* Used Ring を更新してからドライバの設定を読む */
+        fence(Ordering::SeqCst);
+        if self.is_packed {
+            let event = unsafe { read_volatile(self.driver_area as *const VirtQueueEventSuppress) };
+            match event.flags {
+                VIRT_QUEUE_EVENT_FLAGS_DISABLE => false,
+                VIRT_QUEUE_EVENT_FLAGS_DESC if is_event_idx_enabled => {
+                    /* Wrap Counter が異なる位置は1周前として比較する */
+                    let size = self.size as u16;
+                    let mut event_offset = event.off_wrap & !(1 << 15);
+                    if ((event.off_wrap >> 15) != 0) != self.used_wrap_counter {
+                        event_offset = event_offset.wrapping_sub(size);
+                    }
+                    let old = if old_wrap_counter != self.used_wrap_counter {
+                        old.wrapping_sub(size)
+                    } else {
+                        old
+                    };
+                    need_event(event_offset, self.next_used, old)
+                }
+                _ => true,
+            }
+        } else if is_event_idx_enabled {
+            let used_event = unsafe {
+                read_volatile(
+                    (self.driver_area
+                        + size_of::<u16>() * 2 /* flags + idx */
+                        + size_of::<u16>() * self.size) as *const u16,
+                )
+            };
+            need_event(used_event, self.next_used, old)
+        } else {
+            let flags = unsafe { read_volatile(self.driver_area as *const u16) };
+            (flags & VIRT_QUEUE_AVAIL_FLAGS_NO_INTERRUPT) == 0
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        /* 各領域の位置はドライバが設定したアドレスのまま保存する */
+        writer.write_u64(self.size as u64);
+        writer.write_u8(self.is_ready as u8);
+        writer.write_u8(self.is_packed as u8);
+        for a in self.area_address {
+            writer.write_u64(a);
+        }
+        writer.write_u16(self.next_avail);
+        writer.write_u16(self.next_used);
+        writer.write_u8(self.avail_wrap_counter as u8);
+        writer.write_u8(self.used_wrap_counter as u8);
+        writer.write_u16(self.signalled_used);
+        writer.write_u8(self.signalled_used_wrap_counter as u8);
+    }
+
+    pub fn load_state(
+        &mut self,
+        memory: &impl QueueMemory,
+        reader: &mut SnapshotReader,
+    ) -> Result<(), ()> {
+        self.reset();
+        self.size = reader.read_u64()? as usize;
+        if self.size > MAX_QUEUE_SIZE {
+            return Err(());
+        }
+        self.is_ready = reader.read_u8()? != 0;
+        self.is_packed = reader.read_u8()? != 0;
+        for a in self.area_address.iter_mut() {
+            *a = reader.read_u64()?;
+        }
+        self.next_avail = reader.read_u16()?;
+        self.next_used = reader.read_u16()?;
+        self.avail_wrap_counter = reader.read_u8()? != 0;
+        self.used_wrap_counter = reader.read_u8()? != 0;
+        self.signalled_used = reader.read_u16()?;
+        self.signalled_used_wrap_counter = reader.read_u8()? != 0;
+        if self.is_ready && !self.update_area(memory) {
+            println!(
+                "Invalid Virtqueue address: {:#X}",
+                self.area_address[QueueArea::Descriptor as usize]
+            );
+            return Err(());
+        }
+        Ok(())
+    }
+}
+
+/// ドライバがデバイスへ渡すバッファ
+pub struct DriverBuffer {
+    pub address: usize,
+    pub length: u32,
+    pub is_writable: bool,
+}
+
+/// ハイパーバイザが実デバイスへ渡す Split Virtqueue(Legacy のレイアウト)
+///
+/// 空いている Descriptor は next で連結して管理する
+pub struct DriverQueue {
+    size: usize,
+    descriptor: usize,
+    avail_ring: usize,
+    used_ring: usize,
+    /// 空いている Descriptor の連結リストの先頭
+    free_head: u16,
+    number_of_free_descriptors: usize,
+    avail_id: u16,
+    last_used_id: u16,
+}
+
+impl DriverQueue {
+    /// size 個の Descriptor を持つ Virtqueue を確保する
+    pub fn new(size: usize) -> Result<Self, ()> {
+        if size == 0 || size > (u16::MAX as usize) {
+            return Err(());
+        }
+        let avail_ring_offset = size_of::<VirtQueueDesc>() * size;
+        let used_ring_offset =
+            (avail_ring_offset + size_of::<u16>() * (3 + size)).next_multiple_of(VIRTIO_PAGE_SIZE);
+        let total_size =
+            used_ring_offset + size_of::<u16>() * 3 + size_of::<VirtQueueUsedElement>() * size;
+        let number_of_pages = total_size.div_ceil(VIRTIO_PAGE_SIZE);
+        let descriptor =
+            crate::allocate_pages(number_of_pages, VIRTIO_PAGE_SHIFT).map_err(|_| ())?;
+        unsafe {
+            core::ptr::write_bytes(
+                descriptor as *mut u8,
+                0,
+                number_of_pages << VIRTIO_PAGE_SHIFT,
+            )
+        };
+        let mut queue = Self {
+            size,
+            descriptor,
+            avail_ring: descriptor + avail_ring_offset,
+            used_ring: descriptor + used_ring_offset,
+            free_head: 0,
+            number_of_free_descriptors: size,
+            avail_id: 0,
+            last_used_id: 0,
+        };
+        for i in 0..size {
+            queue.get_descriptor(i as u16).next = (i + 1) as u16;
+        }
+        Ok(queue)
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    /// QUEUE_PFN へ書き込む値
+    pub fn get_pfn(&self) -> u32 {
+        (self.descriptor >> VIRTIO_PAGE_SHIFT) as u32
+    }
+
+    /// 全ての Descriptor を空きに戻し、各 Ring を初期状態にする(デバイスをリセットした後に使う)
+    pub fn reset(&mut self) {
+        let total_size = (self.used_ring - self.descriptor)
+            + size_of::<u16>() * 3
+            + size_of::<VirtQueueUsedElement>() * self.size;
+        unsafe { core::ptr::write_bytes(self.descriptor as *mut u8, 0, total_size) };
+        self.free_head = 0;
+        self.number_of_free_descriptors = self.size;
+        self.avail_id = 0;
+        self.last_used_id = 0;
+        for i in 0..self.size {
+            self.get_descriptor(i as u16).next = (i + 1) as u16;
+        }
+    }
+
+    fn get_descriptor(&mut self, id: u16) -> &mut VirtQueueDesc {
+        unsafe {
+            &mut *((self.descriptor + size_of::<VirtQueueDesc>() * (id as usize))
+                as *mut VirtQueueDesc)
+        }
+    }
+
+    /// buffers を1つの Descriptor Chain としてデバイスへ渡し、先頭の番号を返す
+    ///
+    /// Descriptor が足りない場合は None を返す
+    pub fn add(&mut self, buffers: &[DriverBuffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.number_of_free_descriptors {
+            return None;
+        }
+        let head = self.free_head;
+        let mut id = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let descriptor = self.get_descriptor(id);
+            descriptor.address = buffer.address as u64;
+            descriptor.length = buffer.length;
+            descriptor.flags = 0;
+            if buffer.is_writable {
+                descriptor.flags |= VIRT_QUEUE_DESC_FLAGS_WRITE;
+            }
+            if i + 1 < buffers.len() {
+                descriptor.flags |= VIRT_QUEUE_DESC_FLAGS_NEXT;
+            }
+            /* next は空きリストの次の Descriptor を指したまま使う */
+            id = descriptor.next;
+        }
+        self.free_head = id;
+        self.number_of_free_descriptors -= buffers.len();
+
+        let index = (self.avail_id as usize) % self.size;
+        self.avail_id = self.avail_id.wrapping_add(1);
+        unsafe {
+            write_volatile(
+                (self.avail_ring
+                    + size_of::<u16>() * 2 /* flags + idx */
+                    + size_of::<u16>() * index) as *mut u16,
+                head,
+            );
+            /* ring の要素を書き込んでから idx を更新する */
+            fence(Ordering::Release);
+            write_volatile(
+                (self.avail_ring + size_of::<u16>()) as *mut u16,
+                self.avail_id,
+            );
+        }
+        Some(head)
+    }
+
+    /// デバイスが処理を終えた Descriptor Chain を回収し、(先頭の番号, 書き込まれたバイト数)を返す
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        loop {
+            let used_idx =
+                unsafe { read_volatile((self.used_ring + size_of::<u16>()) as *const u16) };
+            if self.last_used_id == used_idx {
+                return None;
+            }
+            /* idx を読んでから ring の要素を読む */
+            fence(Ordering::Acquire);
+            let index = (self.last_used_id as usize) % self.size;
+            self.last_used_id = self.last_used_id.wrapping_add(1);
+            let element = unsafe {
+                read_volatile(
+                    (self.used_ring
+                        + size_of::<u16>() * 2 /* flags + idx */
+                        + size_of::<VirtQueueUsedElement>() * index)
+                        as *const VirtQueueUsedElement,
+                )
+            };
+            if (element.id as usize) >= self.size {
+                println!("Invalid used element: {}", element.id);
+                continue;
+            }
+            /* Chain 全体を空きリストへ戻す */
+            let head = element.id as u16;
+            let mut id = head;
+            let mut number_of_descriptors = 1;
+            while (self.get_descriptor(id).flags & VIRT_QUEUE_DESC_FLAGS_NEXT) != 0
+                && number_of_descriptors < self.size
+            {
+                id = self.get_descriptor(id).next;
+                number_of_descriptors += 1;
+            }
+            let free_head = self.free_head;
+            self.get_descriptor(id).next = free_head;
+            self.free_head = head;
+            self.number_of_free_descriptors += number_of_descriptors;
+            return Some((head, element.length));
+        }
+    }
+}
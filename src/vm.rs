@@ -3,27 +3,107 @@
 //!
 
 use crate::asm;
-use crate::drivers::{generic_timer, gicv3::GicRedistributor, virtio_blk::VirtioBlk};
-use crate::fat32::Fat32;
+use crate::block_device::{BlockDevice, RamDisk};
+use crate::drivers::{generic_timer, gicv3::GicRedistributor};
+use crate::dtb::Dtb;
+use crate::exception::{self, Registers};
+use crate::fat32::{Fat32, FileInfo};
+use crate::gdb::{self, DebugState};
 use crate::lock::Mutex;
 use crate::mmio::{
-    gicv3::{GicDistributorMmio, GicRedistributorMmio},
+    gicv3::{self, GicDistributorMmio, GicRedistributorMmio},
     pl011::Pl011Mmio,
-    virtio_blk::VirtioBlkMmio,
+    shmem::{self, ShmemMmio},
+    virtio_9p::{self, Virtio9pMmio},
+    virtio_blk::{DiskBackend, VirtioBlkMmio},
+    virtio_console::{self, VirtioConsoleMmio},
+    virtio_net::{self, VirtioNetMmio},
+    virtio_rng::{self, VirtioRngMmio},
+    virtio_vsock::{self, VirtioVsockMmio},
 };
+use crate::overlay::{self, OverlayDisk};
 use crate::paging::*;
+use crate::psci;
 use crate::registers::*;
+use crate::snapshot::{self, SnapshotReader, SnapshotWriter};
+use crate::vcpu::VcpuContext;
 use crate::vgic;
+use crate::vm_config::{SharedMemoryConfig, VmConfig};
 
 use core::marker::Send;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use alloc::boxed::Box;
 use alloc::collections::linked_list::LinkedList;
+use alloc::format;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 pub trait MmioHandler {
-    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, ()>;
-    fn write(&mut self, offset: usize, access_width: u64, value: u64) -> Result<(), ()>;
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, VmError>;
+    fn write(&mut self, offset: usize, access_width: u64, value: u64) -> Result<(), VmError>;
+    /// スナップショット用にデバイスの状態を書き出す
+    fn save_state(&self, vm: &VM, writer: &mut SnapshotWriter);
+    /// save_state で書き出した状態を読み込む
+    fn load_state(&mut self, vm: &VM, reader: &mut SnapshotReader) -> Result<(), ()>;
+    /// 仮想マシンの停止時に、他の仮想マシンとの接続を切る
+    fn disconnect(&mut self) {}
+}
+
+/// 仮想マシンが原因で処理を継続できないエラー
+///
+/// 例外ハンドラまで返し、該当の仮想マシンのみを停止させる
+#[derive(Debug)]
+pub enum VmError {
+    /// 現在のpCPUに仮想マシンが割り当てられていない
+    VmNotFound,
+    UnknownException {
+        esr_el2: u64,
+    },
+    UnhandledAbort {
+        address: usize,
+        esr_el2: u64,
+    },
+    /// Data Abort の命令の情報(ISV)が無い
+    NoSyndrome {
+        address: usize,
+    },
+    MmioAccess {
+        address: usize,
+        is_write: bool,
+    },
+    UnknownSystemRegister {
+        esr_el2: u64,
+    },
+    /// 仮想マシンの実行中に FIQ が発生した
+    UnexpectedFiq,
+    /// 仮想マシンの実行中に SError が発生した
+    SError {
+        esr_el2: u64,
+    },
+    NoMemory,
+    FileNotFound(String),
+    InvalidDiskSize(usize),
+    InvalidOverlay(String),
+    InvalidConfig(String),
+    /// 他の仮想マシンが書き込むディスク、または書き込むディスクが他の仮想マシンで使われている
+    DiskInUse(String),
+    InvalidKernelMagic(u32),
+    IoError,
+    InvalidSnapshot,
+    /// 新たな仮想マシンを動かすpCPUが無い
+    NoFreeCpu,
+}
+
+/// 仮想CPUが動作しているpCPUで処理する要求
+pub enum VcpuRequest {
+    /// 仮想マシンの状態をファイルへ保存する
+    Save(String),
+    /// 仮想CPUを一時停止する
+    Pause,
+    /// 仮想マシンを停止する
+    Shutdown,
 }
 
 pub struct MmioEntry {
@@ -37,10 +117,69 @@ pub struct VM {
     ram_virtual_base_address: usize,
     ram_physical_base_address: usize,
     ram_size: usize,
+    stage2_table_address: usize,
     mmio_handlers: LinkedList<MmioEntry>,
     gic_distributor_mmio: Arc<Mutex<GicDistributorMmio>>,
     gic_redistributor_mmio: Arc<Mutex<GicRedistributorMmio>>,
     pl011_mmio: Arc<Mutex<Pl011Mmio>>,
+    virtio_blk_mmio: Arc<Mutex<VirtioBlkMmio>>,
+    virtio_console_mmio: Arc<Mutex<VirtioConsoleMmio>>,
+    /// Dirty Page の記録用ビットマップ(記録が無効な場合は None)
+    dirty_log: Mutex<Option<Vec<u64>>>,
+    /// 仮想CPUを実行しているpCPUの MPIDR_EL1
+    cpu_mpidr: u64,
+    disk_file_name: String,
+    config: VmConfig,
+    vcpu_request: Mutex<Option<VcpuRequest>>,
+    is_paused: AtomicBool,
+    /// 一時停止中の仮想CPUの状態
+    paused_context: Mutex<Option<VcpuContext>>,
+    debug_state: Mutex<DebugState>,
+    console_output: Mutex<ConsoleOutput>,
+    /// ゲストが原因のエラーまたはシャットダウン要求で停止したか
+    ///
+    /// シャットダウンした仮想マシンはリストから外し、最後の参照が無くなった時点でRAMなどを解放する
+    is_stopped: AtomicBool,
+}
+
+/// ゲストのコンソール出力のリングバッファ(古いものから上書きする)
+struct ConsoleOutput {
+    buffer: [u8; Self::BUFFER_SIZE],
+    head: usize,
+    length: usize,
+}
+
+impl ConsoleOutput {
+    const BUFFER_SIZE: usize = 4096;
+
+    const fn new() -> Self {
+        Self {
+            buffer: [0; Self::BUFFER_SIZE],
+            head: 0,
+            length: 0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        for c in data {
+            self.buffer[(self.head + self.length) % Self::BUFFER_SIZE] = *c;
+            if self.length == Self::BUFFER_SIZE {
+                self.head = (self.head + 1) % Self::BUFFER_SIZE;
+            } else {
+                self.length += 1;
+            }
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let length = buffer.len().min(self.length);
+        for c in &mut buffer[0..length] {
+            *c = self.buffer[self.head];
+            self.head = (self.head + 1) % Self::BUFFER_SIZE;
+        }
+        self.length -= length;
+        length
+    }
 }
 
 #[repr(C)]
@@ -57,9 +196,65 @@ struct KernelHeader {
     res5: u32,
 }
 
+impl core::fmt::Display for VmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::VmNotFound => write!(f, "No VM is assigned to this CPU"),
+            Self::UnknownException { esr_el2 } => write!(
+                f,
+                "Unknown Exception: {}(ESR_EL2: {:#X})",
+                (esr_el2 & ESR_EL2_EC) >> ESR_EL2_EC_BITS_OFFSET,
+                esr_el2
+            ),
+            Self::UnhandledAbort { address, esr_el2 } => {
+                write!(f, "Unhandled Abort: {address:#X}(ESR_EL2: {esr_el2:#X})")
+            }
+            Self::NoSyndrome { address } => {
+                write!(f, "Data Abort Info is not available: {address:#X}")
+            }
+            Self::MmioAccess { address, is_write } => write!(
+                f,
+                "Failed to handle MMIO {}: {:#X}",
+                if *is_write { "write" } else { "read" },
+                address
+            ),
+            Self::UnknownSystemRegister { esr_el2 } => {
+                write!(f, "Unknown System Register Access: {esr_el2:#X}")
+            }
+            Self::UnexpectedFiq => write!(f, "Unexpected FIQ"),
+            Self::SError { esr_el2 } => write!(f, "SError(ESR_EL2: {esr_el2:#X})"),
+            Self::NoMemory => write!(f, "Failed to allocate memory for VM"),
+            Self::FileNotFound(name) => write!(f, "{name} is not found"),
+            Self::InvalidDiskSize(size) => {
+                write!(f, "File Size must be 512-Byte aligned(Size: {size:#X})")
+            }
+            Self::InvalidOverlay(name) => write!(f, "Failed to open the overlay disk {name}"),
+            Self::InvalidConfig(s) => write!(f, "Invalid VM configuration: {s}"),
+            Self::DiskInUse(name) => write!(f, "{name} is used by another VM"),
+            Self::InvalidKernelMagic(magic) => write!(f, "Invalid Kernel Magic: {magic:#X}"),
+            Self::IoError => write!(f, "Failed to read the file"),
+            Self::InvalidSnapshot => write!(f, "Failed to restore the snapshot"),
+            Self::NoFreeCpu => write!(f, "No CPU is available for a new VM"),
+        }
+    }
+}
+
 static VM_LIST: Mutex<LinkedList<Arc<VM>>> = Mutex::new(LinkedList::new());
-static NEXT_VM_ID: AtomicUsize = AtomicUsize::new(0);
+/// 使用中の VM ID(仮想マシンの解放時に外し、次に作成する仮想マシンで再利用する)
+static USED_VM_IDS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
 static ACTIVE_VM: Mutex<Option<Arc<VM>>> = Mutex::new(None);
+/// 次に起動するpCPUで復元するスナップショット
+static RESTORE_REQUEST: Mutex<Option<FileInfo>> = Mutex::new(None);
+
+const RAM_VIRTUAL_BASE: usize = 0x40000000;
+/// RAM SIZE: 256MiB
+const RAM_SIZE: usize = 0x10000000;
+/// 仮想デバイスのレジスタを配置する範囲
+const MMIO_AREA_BASE: usize = 0x8000000;
+const MMIO_AREA_END: usize = shmem::SHMEM_MMIO_ADDRESS + shmem::SHMEM_MMIO_SIZE;
+
+/// 仮想CPUへの要求を通知するSGI
+pub const VCPU_REQUEST_INT_ID: u32 = 12;
 
 impl VM {
     #[allow(clippy::too_many_arguments)]
@@ -68,24 +263,199 @@ impl VM {
         ram_virtual_base_address: usize,
         ram_physical_base_address: usize,
         ram_size: usize,
+        stage2_table_address: usize,
         mmio_handlers: LinkedList<MmioEntry>,
         gic_distributor_mmio: Arc<Mutex<GicDistributorMmio>>,
         gic_redistributor_mmio: Arc<Mutex<GicRedistributorMmio>>,
         pl011_mmio: Arc<Mutex<Pl011Mmio>>,
+        virtio_blk_mmio: Arc<Mutex<VirtioBlkMmio>>,
+        virtio_console_mmio: Arc<Mutex<VirtioConsoleMmio>>,
+        cpu_mpidr: u64,
+        disk_file_name: String,
+        config: VmConfig,
     ) -> Self {
         Self {
             vm_id,
             ram_virtual_base_address,
             ram_physical_base_address,
             ram_size,
+            stage2_table_address,
             mmio_handlers,
             gic_distributor_mmio,
             gic_redistributor_mmio,
             pl011_mmio,
+            virtio_blk_mmio,
+            virtio_console_mmio,
+            dirty_log: Mutex::new(None),
+            cpu_mpidr,
+            disk_file_name,
+            config,
+            vcpu_request: Mutex::new(None),
+            is_paused: AtomicBool::new(false),
+            paused_context: Mutex::new(None),
+            debug_state: Mutex::new(DebugState::new()),
+            console_output: Mutex::new(ConsoleOutput::new()),
+            is_stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// (RAMの仮想アドレス, RAMの物理アドレス, RAMのサイズ) を返す
+    pub fn get_ram_region(&self) -> (usize, usize, usize) {
+        (
+            self.ram_virtual_base_address,
+            self.ram_physical_base_address,
+            self.ram_size,
+        )
+    }
+
+    /// ゲストのコンソール出力を表示し、管理用VMが読み出せるように保持する
+    pub fn write_console(&self, data: &[u8]) {
+        let mut output = String::with_capacity(data.len());
+        output.extend(data.iter().map(|c| *c as char));
+        print!("{output}");
+        self.console_output.lock().push(data);
+    }
+
+    /// 保持しているコンソール出力を古い順に buffer へ取り出し、取り出したバイト数を返す
+    pub fn read_console_output(&self, buffer: &mut [u8]) -> usize {
+        self.console_output.lock().read(buffer)
+    }
+
+    pub fn get_vm_id(&self) -> usize {
+        self.vm_id
+    }
+
+    pub fn get_disk_file_name(&self) -> &str {
+        &self.disk_file_name
+    }
+
+    /// 仮想CPUを実行しているpCPUへ要求を送る
+    ///
+    /// 要求は仮想CPUが次にハイパーバイザへ遷移した際に処理される
+    pub fn request_vcpu(&self, request: VcpuRequest) -> Result<(), ()> {
+        if self.is_stopped() {
+            println!("VM{} has stopped.", self.vm_id);
+            return Err(());
+        }
+        let mut vcpu_request = self.vcpu_request.lock();
+        if vcpu_request.is_some() {
+            println!("VM{} has a pending request.", self.vm_id);
+            return Err(());
+        }
+        *vcpu_request = Some(request);
+        drop(vcpu_request);
+        GicRedistributor::send_sgi(VCPU_REQUEST_INT_ID, self.cpu_mpidr);
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.is_paused.load(Ordering::Acquire)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.is_stopped.load(Ordering::Acquire)
+    }
+
+    /// 一時停止中の仮想CPUの状態を返す(停止していない場合は None)
+    pub fn get_paused_context(&self) -> Option<VcpuContext> {
+        self.paused_context.lock().clone()
+    }
+
+    /// 一時停止中の仮想CPUの状態を書き換える
+    ///
+    /// 書き換えた汎用レジスタ、ELR_EL2、SPSR_EL2、スタックポインタは再開時に反映される
+    pub fn set_paused_context(&self, context: VcpuContext) {
+        let mut paused_context = self.paused_context.lock();
+        if paused_context.is_some() {
+            *paused_context = Some(context);
         }
     }
 
-    pub fn handle_mmio_read(&self, address: usize, access_width: u64) -> Result<u64, ()> {
+    pub fn get_debug_state(&self) -> &Mutex<DebugState> {
+        &self.debug_state
+    }
+
+    pub fn get_stage2_table_address(&self) -> usize {
+        self.stage2_table_address
+    }
+
+    /// 仮想CPUを一時停止する
+    ///
+    /// 仮想CPUは次にハイパーバイザへ遷移した際に停止する
+    pub fn pause(&self) -> Result<(), ()> {
+        if self.is_paused() {
+            println!("VM{} is already paused.", self.vm_id);
+            return Err(());
+        }
+        self.request_vcpu(VcpuRequest::Pause)
+    }
+
+    /// 仮想マシンを停止し、pCPUとRAMなどを解放する
+    ///
+    /// 既に停止している仮想マシンの場合は、調査のため残していたRAMなどを解放する
+    pub fn destroy(&self) -> Result<(), ()> {
+        if self.is_stopped() {
+            remove_vm(self);
+            println!("VM{} has been removed.", self.vm_id);
+            return Ok(());
+        }
+        self.request_vcpu(VcpuRequest::Shutdown)
+    }
+
+    /// 停止状態にし、他の仮想マシンとの接続を切る
+    fn set_stopped(&self) {
+        self.is_stopped.store(true, Ordering::Release);
+        for e in &self.mmio_handlers {
+            e.handler.lock().disconnect();
+        }
+    }
+
+    /// 特権的なハイパーコールで他の仮想マシンを管理できるか
+    pub fn is_management_vm(&self) -> bool {
+        self.config.is_management_vm
+    }
+
+    /// 割り当てられている共有メモリの名前
+    pub fn get_shared_memory_name(&self) -> Option<&str> {
+        self.config.shared_memory.as_ref().map(|s| s.name.as_str())
+    }
+
+    pub fn resume(&self) -> Result<(), ()> {
+        if !self.is_paused() {
+            println!("VM{} is not paused.", self.vm_id);
+            return Err(());
+        }
+        self.is_paused.store(false, Ordering::Release);
+        /* 待機しているpCPUを起こす */
+        GicRedistributor::send_sgi(VCPU_REQUEST_INT_ID, self.cpu_mpidr);
+        Ok(())
+    }
+
+    pub fn save_mmio_state(&self, writer: &mut SnapshotWriter) {
+        for e in &self.mmio_handlers {
+            writer.write_u64(e.base_address as u64);
+            writer.write_u64(e.length as u64);
+            e.handler.lock().save_state(self, writer);
+        }
+    }
+
+    pub fn load_mmio_state(&self, reader: &mut SnapshotReader) -> Result<(), ()> {
+        for e in &self.mmio_handlers {
+            let base_address = reader.read_u64()? as usize;
+            let length = reader.read_u64()? as usize;
+            if base_address != e.base_address || length != e.length {
+                println!(
+                    "MMIO layout mismatch: expected {:#X}, found {:#X}",
+                    e.base_address, base_address
+                );
+                return Err(());
+            }
+            e.handler.lock().load_state(self, reader)?;
+        }
+        Ok(())
+    }
+
+    pub fn handle_mmio_read(&self, address: usize, access_width: u64) -> Result<u64, VmError> {
         for e in &self.mmio_handlers {
             if e.base_address <= address && address < (e.base_address + e.length) {
                 return e
@@ -94,7 +464,10 @@ impl VM {
                     .read(address - e.base_address, access_width);
             }
         }
-        Err(())
+        Err(VmError::MmioAccess {
+            address,
+            is_write: false,
+        })
     }
 
     pub fn handle_mmio_write(
@@ -102,7 +475,7 @@ impl VM {
         address: usize,
         access_width: u64,
         value: u64,
-    ) -> Result<(), ()> {
+    ) -> Result<(), VmError> {
         for e in &self.mmio_handlers {
             if e.base_address <= address && address < (e.base_address + e.length) {
                 return e
@@ -111,7 +484,10 @@ impl VM {
                     .write(address - e.base_address, access_width, value);
             }
         }
-        Err(())
+        Err(VmError::MmioAccess {
+            address,
+            is_write: true,
+        })
     }
 
     pub fn get_physical_address(&self, virtual_address: usize) -> Option<usize> {
@@ -124,10 +500,134 @@ impl VM {
         }
     }
 
+    /// Dirty Page の記録を開始する
+    ///
+    /// ゲストのRAMを全て書き込み禁止にし、書き込みによる Permission Fault でページを記録する
+    pub fn start_dirty_log(&self) -> Result<(), ()> {
+        let mut dirty_log = self.dirty_log.lock();
+        if dirty_log.is_some() {
+            println!("Dirty log is already enabled.");
+            return Err(());
+        }
+        let number_of_pages = self.ram_size >> PAGE_SHIFT;
+        *dirty_log = Some(alloc::vec![0u64; number_of_pages.div_ceil(u64::BITS as usize)]);
+        change_permission_stage2(
+            self.stage2_table_address,
+            self.ram_virtual_base_address,
+            self.ram_size,
+            true,
+            false,
+        )
+        .inspect_err(|_| *dirty_log = None)
+    }
+
+    /// Dirty Page の記録を終了し、RAMへの書き込みを許可する
+    pub fn stop_dirty_log(&self) -> Result<(), ()> {
+        let mut dirty_log = self.dirty_log.lock();
+        if dirty_log.is_none() {
+            println!("Dirty log is not enabled.");
+            return Err(());
+        }
+        *dirty_log = None;
+        change_permission_stage2(
+            self.stage2_table_address,
+            self.ram_virtual_base_address,
+            self.ram_size,
+            true,
+            true,
+        )
+    }
+
+    /// 前回の取得以降に書き込まれたページのビットマップを返し、記録を初期化する
+    ///
+    /// ビット n はRAMの先頭から n 番目のページに対応する
+    pub fn get_and_clear_dirty_log(&self) -> Option<Vec<u64>> {
+        let mut dirty_log = self.dirty_log.lock();
+        let bitmap = dirty_log.as_mut()?;
+        /* 先に書き込み禁止へ戻すことで、取得後の書き込みを取りこぼさない */
+        let number_of_pages = self.ram_size >> PAGE_SHIFT;
+        let mut page = 0;
+        while page < number_of_pages {
+            let is_dirty =
+                |p: usize| (bitmap[p / u64::BITS as usize] >> (p % u64::BITS as usize)) & 1 != 0;
+            if !is_dirty(page) {
+                page += 1;
+                continue;
+            }
+            let first_page = page;
+            while page < number_of_pages && is_dirty(page) {
+                page += 1;
+            }
+            let _ = change_permission_stage2(
+                self.stage2_table_address,
+                self.ram_virtual_base_address + (first_page << PAGE_SHIFT),
+                (page - first_page) << PAGE_SHIFT,
+                true,
+                false,
+            );
+        }
+        let cleared = alloc::vec![0u64; bitmap.len()];
+        Some(core::mem::replace(bitmap, cleared))
+    }
+
+    /// ハイパーバイザがゲストのRAMへ直接書き込んだ範囲を記録する
+    pub fn mark_dirty(&self, intermediate_physical_address: usize, size: usize) {
+        if size == 0
+            || self
+                .get_physical_address(intermediate_physical_address)
+                .is_none()
+        {
+            return;
+        }
+        let mut dirty_log = self.dirty_log.lock();
+        let Some(bitmap) = dirty_log.as_mut() else {
+            return;
+        };
+        let first_page =
+            (intermediate_physical_address - self.ram_virtual_base_address) >> PAGE_SHIFT;
+        let last_page =
+            ((intermediate_physical_address + size - 1 - self.ram_virtual_base_address)
+                >> PAGE_SHIFT)
+                .min((self.ram_size >> PAGE_SHIFT) - 1);
+        for page in first_page..=last_page {
+            bitmap[page / u64::BITS as usize] |= 1 << (page % u64::BITS as usize);
+        }
+    }
+
+    /// Stage 2 の Permission Fault を Dirty Page として処理する
+    ///
+    /// 記録中のRAMへの書き込みであれば true を返す
+    pub fn handle_dirty_log_fault(&self, intermediate_physical_address: usize) -> bool {
+        if self
+            .get_physical_address(intermediate_physical_address)
+            .is_none()
+        {
+            return false;
+        }
+        let mut dirty_log = self.dirty_log.lock();
+        let Some(bitmap) = dirty_log.as_mut() else {
+            return false;
+        };
+        let page = (intermediate_physical_address - self.ram_virtual_base_address) >> PAGE_SHIFT;
+        bitmap[page / u64::BITS as usize] |= 1 << (page % u64::BITS as usize);
+        change_permission_stage2(
+            self.stage2_table_address,
+            intermediate_physical_address & !(PAGE_SIZE - 1),
+            PAGE_SIZE,
+            true,
+            true,
+        )
+        .is_ok()
+    }
+
     pub fn get_gic_distributor_mmio(&self) -> &Mutex<GicDistributorMmio> {
         &self.gic_distributor_mmio
     }
 
+    pub fn get_virtio_blk_mmio(&self) -> &Mutex<VirtioBlkMmio> {
+        &self.virtio_blk_mmio
+    }
+
     pub fn get_gic_redistributor_mmio(&self) -> &Mutex<GicRedistributorMmio> {
         &self.gic_redistributor_mmio
     }
@@ -137,6 +637,28 @@ impl VM {
     }
 }
 
+impl Drop for VM {
+    fn drop(&mut self) {
+        free_stage2_translation_table(self.stage2_table_address);
+        crate::free_pages(self.ram_physical_base_address, self.ram_size >> PAGE_SHIFT);
+        free_vm_id(self.vm_id);
+    }
+}
+
+/// 使われていない最小の VM ID を割り当てる
+///
+/// VM<n>.CFG や DISK<n> は VM ID で選ぶため、作り直した仮想マシンが同じファイルを使えるよう再利用する
+fn allocate_vm_id() -> usize {
+    let mut used_vm_ids = USED_VM_IDS.lock();
+    let vm_id = (0..).find(|id| !used_vm_ids.contains(id)).unwrap();
+    used_vm_ids.push(vm_id);
+    vm_id
+}
+
+fn free_vm_id(vm_id: usize) {
+    USED_VM_IDS.lock().retain(|id| *id != vm_id);
+}
+
 impl MmioEntry {
     pub fn new(
         base_address: usize,
@@ -151,20 +673,93 @@ impl MmioEntry {
     }
 }
 
-pub fn create_vm(
+/// 共有メモリの IPA が RAM やレジスタの範囲と重ならず、Stage 2 で扱える範囲にあるか確認する
+fn check_shared_memory_range(shared_memory: &SharedMemoryConfig) -> Result<(), VmError> {
+    let start = shared_memory.address;
+    let is_valid = start.checked_add(shared_memory.size).is_some_and(|end| {
+        end <= get_stage2_ipa_size()
+            && (end <= MMIO_AREA_BASE || start >= MMIO_AREA_END)
+            && (end <= RAM_VIRTUAL_BASE || start >= RAM_VIRTUAL_BASE + RAM_SIZE)
+    });
+    if is_valid {
+        Ok(())
+    } else {
+        Err(VmError::InvalidConfig(format!(
+            "shared memory {:#X} - {:#X} overlaps other regions",
+            start,
+            start.wrapping_add(shared_memory.size)
+        )))
+    }
+}
+
+/// ディスクのファイルの内容をすべてメモリ上へ読み込む
+fn load_ram_disk(
+    fat32: &Fat32,
+    blk: &mut impl BlockDevice,
+    disk_file: &FileInfo,
+    disk_file_name: &str,
+) -> Result<RamDisk, VmError> {
+    let size = disk_file.get_file_size();
+    if overlay::is_overlay(fat32, blk, disk_file) || (size & (512 - 1)) != 0 {
+        return Err(VmError::InvalidConfig(format!(
+            "{disk_file_name} cannot be used as a RAM disk"
+        )));
+    }
+    let mut data = Vec::new();
+    data.try_reserve_exact(size)
+        .map_err(|_| VmError::NoMemory)?;
+    data.resize(size, 0);
+    if fat32.read(disk_file, blk, data.as_mut_ptr() as usize, 0, size) != Ok(size) {
+        return Err(VmError::IoError);
+    }
+    Ok(RamDisk::new(data))
+}
+
+/// 仮想マシンの基本要素を作成し、仮想マシンのリストへ登録する
+fn setup_vm(
+    vm_id: usize,
+    disk_file_name: String,
     fat32: &Fat32,
-    blk: &mut VirtioBlk,
+    blk: &mut impl BlockDevice,
     gic_redistributor: &GicRedistributor,
-) -> (usize, usize) {
-    const RAM_VIRTUAL_BASE: usize = 0x40000000;
-    /// RAM SIZE: 256MiB
-    const RAM_SIZE: usize = 0x10000000;
-    const ALIGN_SIZE: usize = 0x200000;
+) -> Result<Arc<VM>, VmError> {
+    let config = VmConfig::load(fat32, blk, vm_id)?;
+
+    /* ディスクの確認(設定した場合のみ、仮想マシンの番号のホストのディスクかメモリ上のディスクを使う) */
+    let backend = if config.use_host_disk {
+        let device = crate::get_host_disk(vm_id)
+            .ok_or_else(|| VmError::FileNotFound(format!("Disk{vm_id}")))?;
+        DiskBackend::Device(Box::new(device))
+    } else if let Some(disk_file) = fat32.search_file(&disk_file_name) {
+        if config.use_ram_disk {
+            DiskBackend::Device(Box::new(load_ram_disk(
+                fat32,
+                blk,
+                &disk_file,
+                &disk_file_name,
+            )?))
+        } else if overlay::is_overlay(fat32, blk, &disk_file) {
+            let overlay = OverlayDisk::open(fat32, blk, disk_file)
+                .map_err(|_| VmError::InvalidOverlay(disk_file_name.clone()))?;
+            DiskBackend::Overlay(overlay)
+        } else {
+            DiskBackend::File(disk_file)
+        }
+    } else {
+        return Err(VmError::FileNotFound(disk_file_name));
+    };
+    let virtio_blk_mmio = Arc::new(Mutex::new(VirtioBlkMmio::new(backend, &disk_file_name)?));
 
     /* 仮想マシンの基本要素の設定 */
-    let ram_physical_address = crate::allocate_pages(RAM_SIZE >> PAGE_SHIFT, PAGE_SHIFT)
-        .expect("Failed to allocate memory for VM.");
-    let vm_id = NEXT_VM_ID.fetch_add(1, Ordering::Relaxed);
+    let shared_memory_address = match &config.shared_memory {
+        Some(shared_memory) => {
+            check_shared_memory_range(shared_memory)?;
+            Some(shmem::get_shared_memory(shared_memory)?)
+        }
+        None => None,
+    };
+    let ram_physical_address =
+        crate::allocate_pages(RAM_SIZE >> PAGE_SHIFT, PAGE_SHIFT).map_err(|_| VmError::NoMemory)?;
     let cpu_mpidr = asm::get_mpidr_el1();
 
     /* 仮想化に関するハードウェアの設定 */
@@ -172,9 +767,21 @@ pub fn create_vm(
     setup_hypervisor_registers();
 
     /* Stage 2 Translation の初期化 */
-    init_stage2_translation_table();
+    let stage2_table_address = init_stage2_translation_table();
     map_address_stage2(ram_physical_address, RAM_VIRTUAL_BASE, RAM_SIZE, true, true)
         .expect("Failed to map memory");
+    if let (Some(physical_address), Some(shared_memory)) =
+        (shared_memory_address, &config.shared_memory)
+    {
+        map_address_stage2(
+            physical_address,
+            shared_memory.address,
+            shared_memory.size,
+            true,
+            true,
+        )
+        .expect("Failed to map memory");
+    }
 
     /* Virtual GICの初期化 */
     vgic::init_vgic(gic_redistributor);
@@ -190,18 +797,55 @@ pub fn create_vm(
     mmio_handlers.push_back(MmioEntry::new(0x9000000, 0x1000, pl011_mmio.clone()));
 
     /* Virtio-Blk */
-    let file_name = [b'D', b'I', b'S', b'K', b'0' + vm_id as u8];
-    let disk_file = fat32
-        .search_file(core::str::from_utf8(&file_name).unwrap())
-        .expect("Failed to find Disk");
+    mmio_handlers.push_back(MmioEntry::new(0xa000000, 0x0200, virtio_blk_mmio.clone()));
+
+    /* Virtio-Console */
+    let virtio_console_mmio = Arc::new(Mutex::new(VirtioConsoleMmio::new()));
     mmio_handlers.push_back(MmioEntry::new(
-        0xa000000,
-        0x0200,
-        Arc::new(Mutex::new(VirtioBlkMmio::new(disk_file))),
+        virtio_console::VIRTIO_CONSOLE_MMIO_ADDRESS,
+        virtio_console::VIRTIO_CONSOLE_MMIO_SIZE,
+        virtio_console_mmio.clone(),
     ));
 
+    /* Virtio-Net */
+    mmio_handlers.push_back(MmioEntry::new(
+        virtio_net::VIRTIO_NET_MMIO_ADDRESS,
+        virtio_net::VIRTIO_NET_MMIO_SIZE,
+        Arc::new(Mutex::new(VirtioNetMmio::new(vm_id))),
+    ));
+
+    /* Virtio-9P */
+    mmio_handlers.push_back(MmioEntry::new(
+        virtio_9p::VIRTIO_9P_MMIO_ADDRESS,
+        virtio_9p::VIRTIO_9P_MMIO_SIZE,
+        Arc::new(Mutex::new(Virtio9pMmio::new())),
+    ));
+
+    /* Virtio-RNG */
+    mmio_handlers.push_back(MmioEntry::new(
+        virtio_rng::VIRTIO_RNG_MMIO_ADDRESS,
+        virtio_rng::VIRTIO_RNG_MMIO_SIZE,
+        Arc::new(Mutex::new(VirtioRngMmio::new())),
+    ));
+
+    /* Virtio-Vsock */
+    mmio_handlers.push_back(MmioEntry::new(
+        virtio_vsock::VIRTIO_VSOCK_MMIO_ADDRESS,
+        virtio_vsock::VIRTIO_VSOCK_MMIO_SIZE,
+        Arc::new(Mutex::new(VirtioVsockMmio::new(vm_id))),
+    ));
+
+    /* 共有メモリ */
+    if let Some(shared_memory) = &config.shared_memory {
+        mmio_handlers.push_back(MmioEntry::new(
+            shmem::SHMEM_MMIO_ADDRESS,
+            shmem::SHMEM_MMIO_SIZE,
+            Arc::new(Mutex::new(ShmemMmio::new(vm_id, shared_memory))),
+        ));
+    }
+
     /* GIC Distributor */
-    let gic_distributor_mmio = Arc::new(Mutex::new(GicDistributorMmio::new()));
+    let gic_distributor_mmio = Arc::new(Mutex::new(GicDistributorMmio::new(cpu_mpidr)));
     mmio_handlers.push_back(MmioEntry::new(
         0x8000000,
         GicDistributorMmio::MMIO_SIZE,
@@ -217,55 +861,152 @@ pub fn create_vm(
     ));
 
     /* VM構造体の作成 */
-    let vm = VM::new(
+    let vm = Arc::new(VM::new(
         vm_id,
         RAM_VIRTUAL_BASE,
         ram_physical_address,
         RAM_SIZE,
+        stage2_table_address,
         mmio_handlers,
         gic_distributor_mmio,
         gic_redistributor_mmio,
         pl011_mmio,
+        virtio_blk_mmio,
+        virtio_console_mmio,
+        cpu_mpidr,
+        disk_file_name,
+        config,
+    ));
+
+    /* VM構造体のリストへの追加 */
+    VM_LIST.lock().push_back(vm.clone());
+    unsafe { asm::set_tpidr_el2(vm_id as u64) };
+    Ok(vm)
+}
+
+pub fn create_vm(
+    fat32: &Fat32,
+    blk: &mut impl BlockDevice,
+    gic_redistributor: &GicRedistributor,
+) -> Result<(usize, usize), VmError> {
+    let kernel = fat32
+        .search_file("IMAGE")
+        .ok_or_else(|| VmError::FileNotFound(String::from("IMAGE")))?;
+    let dtb = fat32
+        .search_file("DTB")
+        .ok_or_else(|| VmError::FileNotFound(String::from("DTB")))?;
+    let vm_id = allocate_vm_id();
+    let disk_file_name = format!("DISK{vm_id}");
+    let vm = setup_vm(vm_id, disk_file_name, fat32, blk, gic_redistributor)
+        .inspect_err(|_| free_vm_id(vm_id))?;
+    let entry_point = load_linux(&vm, fat32, blk, &kernel, &dtb).inspect_err(|_| {
+        /* 起動できない仮想マシンは停止させる */
+        vm.set_stopped();
+    })?;
+
+    switch_active_vm(vm_id);
+    println!(
+        "Created VM{vm_id} on the CPU(MPIDR_EL1: {:#X})",
+        vm.cpu_mpidr
     );
 
-    /* Linux KernelとDevicetreeの読み込み */
-    let kernel = fat32.search_file("IMAGE").unwrap();
-    let dtb = fat32.search_file("DTB").unwrap();
+    Ok((entry_point, RAM_VIRTUAL_BASE))
+}
+
+/// Linux KernelとDevicetreeを仮想マシンのRAMへ読み込み、エントリポイントを返す
+fn load_linux(
+    vm: &VM,
+    fat32: &Fat32,
+    blk: &mut impl BlockDevice,
+    kernel: &FileInfo,
+    dtb: &FileInfo,
+) -> Result<usize, VmError> {
+    const ALIGN_SIZE: usize = 0x200000;
+
     let dtb_size = dtb.get_file_size();
     let kernel_size = kernel.get_file_size();
     let kernel_virtual_address =
         ((RAM_VIRTUAL_BASE + dtb_size - 1) & !(ALIGN_SIZE - 1)) + ALIGN_SIZE;
-    let kernel_physical_address = vm.get_physical_address(kernel_virtual_address).unwrap();
+    let kernel_physical_address = vm
+        .get_physical_address(kernel_virtual_address)
+        .ok_or(VmError::NoMemory)?;
 
     fat32
-        .read(&dtb, blk, ram_physical_address, 0, dtb_size)
-        .expect("Failed to read DTB");
+        .read(dtb, blk, vm.ram_physical_base_address, 0, dtb_size)
+        .map_err(|_| VmError::IoError)?;
+    let guest_dtb = Dtb::new(vm.ram_physical_base_address)
+        .map_err(|_| VmError::InvalidConfig(String::from("DTB")))?;
+    shmem::update_dtb(&guest_dtb, vm.config.shared_memory.as_ref())?;
     fat32
-        .read(&kernel, blk, kernel_physical_address, 0, kernel_size)
-        .expect("Failed to read Kernel");
+        .read(kernel, blk, kernel_physical_address, 0, kernel_size)
+        .map_err(|_| VmError::IoError)?;
 
     /* Linux Kernel Headerの解析 */
     let header = unsafe { &*(kernel_physical_address as *const KernelHeader) };
     if header.magic != 0x644D5241 {
-        panic!("Invalid Kernel Magic: {:#X}", header.magic);
+        return Err(VmError::InvalidKernelMagic(header.magic));
     }
     let mut text_offset = header.text_offset;
     let image_size = header.image_size;
     if image_size == 0 {
         text_offset = 0x80000;
     }
+    Ok(kernel_virtual_address + text_offset as usize)
+}
+
+/// スナップショットから仮想マシンを作成し、保存されていた仮想CPUの状態を返す
+pub fn restore_vm(
+    fat32: &Fat32,
+    blk: &mut impl BlockDevice,
+    gic_redistributor: &GicRedistributor,
+    file: &FileInfo,
+) -> Result<VcpuContext, VmError> {
+    let (header, state) =
+        snapshot::read_snapshot(fat32, blk, file).map_err(|_| VmError::InvalidSnapshot)?;
+    let disk_file_name = String::from(
+        header
+            .get_disk_file_name()
+            .ok_or(VmError::InvalidSnapshot)?,
+    );
+
+    let vm_id = allocate_vm_id();
+    let vm = setup_vm(vm_id, disk_file_name, fat32, blk, gic_redistributor)
+        .inspect_err(|_| free_vm_id(vm_id))?;
+    let context = snapshot::restore_vm(&vm, fat32, blk, file, &header, &state).map_err(|_| {
+        /* 復元できない仮想マシンは停止させる */
+        vm.set_stopped();
+        VmError::InvalidSnapshot
+    })?;
 
-    /* VM構造体のリストへの追加 */
-    VM_LIST.lock().push_back(Arc::new(vm));
     switch_active_vm(vm_id);
+    println!(
+        "Restored VM{vm_id} on the CPU(MPIDR_EL1: {:#X})",
+        vm.cpu_mpidr
+    );
+    Ok(context)
+}
 
-    unsafe { asm::set_tpidr_el2(vm_id as u64) };
-    println!("Created VM{vm_id} on the CPU(MPIDR_EL1: {:#X})", cpu_mpidr);
+/// 次に起動するpCPUでスナップショットを復元するように設定する
+pub fn request_restore(file: FileInfo) -> bool {
+    let mut restore_request = RESTORE_REQUEST.lock();
+    if restore_request.is_some() {
+        return false;
+    }
+    *restore_request = Some(file);
+    true
+}
 
-    (
-        kernel_virtual_address + text_offset as usize,
-        RAM_VIRTUAL_BASE,
-    )
+pub fn take_restore_request() -> Option<FileInfo> {
+    RESTORE_REQUEST.lock().take()
+}
+
+/// 復元した仮想CPUの実行を再開する
+pub fn resume_vm(context: VcpuContext) -> ! {
+    let mut registers = Registers::default();
+    context.restore(&mut registers, true);
+    /* 保存時に注入待ちだった割り込みを登録する */
+    gicv3::inject_interrupt_handler();
+    exception::return_to_lower_el(&registers)
 }
 
 pub fn boot_vm(entry_point: usize, argument: usize) -> ! {
@@ -290,31 +1031,208 @@ fn setup_hypervisor_registers() {
 }
 
 pub fn input_uart(c: u8) {
-    let vm = get_active_vm();
+    let Some(vm) = ACTIVE_VM.lock().clone() else {
+        return;
+    };
+    /* Virtio-Console が使える場合はそちらを優先する */
+    if vm.virtio_console_mmio.lock().push_input(&vm, c) {
+        return;
+    }
     vm.get_pl011_mmio()
         .lock()
         .push(c, &mut vm.get_gic_distributor_mmio().lock());
 }
 
-pub fn get_current_vm() -> Arc<VM> {
+pub fn get_current_vm() -> Result<Arc<VM>, VmError> {
     let vm_id = asm::get_tpidr_el2() as usize;
     VM_LIST
         .lock()
         .iter()
-        .find(|vm| vm.vm_id == vm_id)
-        .unwrap()
-        .clone()
+        .find(|vm| vm.vm_id == vm_id && !vm.is_stopped())
+        .cloned()
+        .ok_or(VmError::VmNotFound)
+}
+
+/// 停止したものも含めた全ての仮想マシンを返す
+pub fn get_vm_list() -> Vec<Arc<VM>> {
+    VM_LIST.lock().iter().cloned().collect()
 }
 
-pub fn get_active_vm() -> Arc<VM> {
-    ACTIVE_VM.lock().clone().unwrap()
+pub fn get_vm(vm_id: usize) -> Option<Arc<VM>> {
+    VM_LIST.lock().iter().find(|vm| vm.vm_id == vm_id).cloned()
 }
 
 pub fn switch_active_vm(vm_id: usize) -> bool {
-    if let Some(vm) = VM_LIST.lock().iter_mut().find(|vm| vm.vm_id == vm_id) {
+    if let Some(vm) = VM_LIST
+        .lock()
+        .iter_mut()
+        .find(|vm| vm.vm_id == vm_id && !vm.is_stopped())
+    {
         *ACTIVE_VM.lock() = Some(vm.clone());
         true
     } else {
         false
     }
 }
+
+/// ゲストが原因のエラーにより、現在のpCPUで動作している仮想マシンを停止する
+///
+/// 他の仮想マシンは動作を続ける。
+/// 停止した仮想マシンのRAMは調査のため残し、コンソールから参照できるようにする。
+pub fn crash_current_vm(error: VmError) -> ! {
+    let elr_el2 = asm::get_elr_el2();
+    let vm_id = stop_current_vm();
+    println!("VM{vm_id} crashed: {error}(ELR_EL2: {:#X})", elr_el2);
+    release_cpu()
+}
+
+/// ゲストの要求により、現在のpCPUで動作している仮想マシンを停止し、RAMなどを解放する
+pub fn shutdown_current_vm() -> ! {
+    let vm_id = stop_current_vm();
+    if let Some(vm) = get_vm(vm_id) {
+        remove_vm(&vm);
+    }
+    println!("VM{vm_id} has been shut down.");
+    release_cpu()
+}
+
+/// 停止した仮想マシンをリストから外す
+///
+/// ホストのディスクへの要求はゲストのRAMへ書き込むため、完了を待ってから外す。
+/// RAMと Stage 2 の Translation table は VM の最後の参照が無くなった時点で解放する
+fn remove_vm(vm: &VM) {
+    crate::mmio::virtio_blk::wait_for_inflight_requests(vm);
+    let mut vm_list = VM_LIST.lock();
+    let (removed, remaining): (LinkedList<_>, LinkedList<_>) = core::mem::take(&mut *vm_list)
+        .into_iter()
+        .partition(|v| v.vm_id == vm.vm_id);
+    *vm_list = remaining;
+    drop(vm_list);
+    /* 最後の参照の場合、ここで解放される */
+    drop(removed);
+}
+
+/// 現在のpCPUで動作している仮想マシンを停止状態にし、その ID を返す
+fn stop_current_vm() -> usize {
+    let vm_id = asm::get_tpidr_el2() as usize;
+    if let Some(vm) = get_vm(vm_id) {
+        vm.set_stopped();
+        vm.is_paused.store(false, Ordering::Release);
+        let mut active_vm = ACTIVE_VM.lock();
+        if active_vm.as_ref().is_some_and(|a| a.vm_id == vm_id) {
+            /* 入力先を動作中の他の仮想マシンへ切り替える */
+            *active_vm = VM_LIST.lock().iter().find(|vm| !vm.is_stopped()).cloned();
+        }
+    }
+    vm_id
+}
+
+/// 仮想マシンを実行しなくなったpCPUを解放する
+///
+/// コンソールの割り込みを処理するBSPは割り込みを待ち続け、
+/// それ以外のpCPUは PSCI の CPU_OFF で停止し、新たな仮想マシンの起動に再利用できるようにする。
+pub fn release_cpu() -> ! {
+    unsafe {
+        asm::set_tpidr_el2(u64::MAX);
+        asm::set_cntv_ctl_el0(0);
+    }
+    vgic::clear_virtual_interrupts();
+    if !crate::is_bsp() {
+        let e = psci::cpu_off();
+        println!("Failed to stop the CPU: {:?}", e);
+    }
+    unsafe { asm::set_daif(asm::get_daif_and_disable_irq_fiq() & !(DAIF_IRQ | DAIF_FIQ)) };
+    loop {
+        asm::wait_for_interrupt();
+    }
+}
+
+/// 仮想CPUへの要求を処理する
+///
+/// 下位のELへ復帰する直前に呼び出される。
+/// 一時停止中は再開されるまでこの関数内で待機する。
+pub fn handle_vcpu_request(registers: &mut Registers) -> Result<(), VmError> {
+    let vm = get_current_vm()?;
+    let mut was_paused = false;
+    loop {
+        let request = vm.vcpu_request.lock().take();
+        match request {
+            Some(VcpuRequest::Save(file_name)) => {
+                let context = VcpuContext::save(registers);
+                /* ディスクの内容と Virtqueue の状態を揃えるため、処理中の要求の完了を待つ */
+                crate::mmio::virtio_blk::wait_for_inflight_requests(&vm);
+                let mut fat32 = crate::FAT32.write();
+                let mut disk = crate::lock_boot_disk();
+                if snapshot::save_vm(&vm, &context, &mut fat32, &mut disk, &file_name).is_ok()
+                    && disk.flush().is_ok()
+                {
+                    println!("Saved VM{} to {file_name}", vm.vm_id);
+                } else {
+                    println!("Failed to save VM{} to {file_name}", vm.vm_id);
+                }
+            }
+            Some(VcpuRequest::Shutdown) => {
+                drop(vm);
+                shutdown_current_vm();
+            }
+            Some(VcpuRequest::Pause) => {
+                *vm.paused_context.lock() = Some(VcpuContext::save(registers));
+                vm.is_paused.store(true, Ordering::Release);
+                println!("VM{} is paused", vm.vm_id);
+                gdb::notify_stop(&vm);
+            }
+            None if vm.is_paused() => {
+                wait_for_resume(&vm);
+                was_paused = true;
+            }
+            None => break,
+        }
+    }
+    if was_paused {
+        if let Some(context) = vm.paused_context.lock().take() {
+            /* デバッガなどによる変更を反映する */
+            unsafe {
+                asm::set_elr_el2(context.elr_el2);
+                asm::set_spsr_el2(context.spsr_el2);
+                asm::set_sp_el0(context.get_system_register("SP_EL0"));
+                asm::set_sp_el1(context.get_system_register("SP_EL1"));
+            }
+            *registers = context.registers;
+        }
+        vm.debug_state.lock().apply();
+        /* 一時停止中に発生した割り込みを注入する */
+        gicv3::inject_interrupt_handler();
+        println!("VM{} is resumed", vm.vm_id);
+    }
+    Ok(())
+}
+
+/// 再開されるか新たな要求が来るまで待機する
+///
+/// 待機中は他の割り込み(コンソールなど)を処理するため割り込みを有効にする。
+/// 仮想タイマーは停止し、再開時に停止していた時間だけ CNTVOFF_EL2 をずらす。
+fn wait_for_resume(vm: &VM) {
+    /* EL2の割り込みハンドラは ELR_EL2 と SPSR_EL2 を保存しないため退避する */
+    let elr_el2 = asm::get_elr_el2();
+    let spsr_el2 = asm::get_spsr_el2();
+    let virtual_count = asm::get_cntpct_el0() - asm::get_cntvoff_el2();
+    let cntv_ctl_el0 = asm::get_cntv_ctl_el0();
+    unsafe { asm::set_cntv_ctl_el0(cntv_ctl_el0 | CNTV_CTL_EL0_IMASK) };
+
+    let daif = unsafe { asm::get_daif_and_disable_irq_fiq() };
+    while vm.is_paused() && vm.vcpu_request.lock().is_none() {
+        asm::wait_for_interrupt();
+        /* 保留中の割り込みを処理する */
+        unsafe {
+            asm::set_daif(daif & !(DAIF_IRQ | DAIF_FIQ));
+            asm::set_daif(daif);
+        }
+    }
+
+    unsafe {
+        asm::set_cntvoff_el2(asm::get_cntpct_el0() - virtual_count);
+        asm::set_cntv_ctl_el0(cntv_ctl_el0);
+        asm::set_elr_el2(elr_el2);
+        asm::set_spsr_el2(spsr_el2);
+    }
+}
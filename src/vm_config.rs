@@ -0,0 +1,111 @@
+//!
+//! 仮想マシンの設定
+//!
+//! FAT32 上の VM<n>.CFG(n は仮想マシンの番号)から読み込む。ファイルが無い場合は既定の設定を使う。
+//! 1行に1つの項目を書き、'#' から行末まではコメントとする。
+//!
+//! | 項目                        | 内容                                                         |
+//! |-----------------------------|--------------------------------------------------------------|
+//! | `management`                | 他の仮想マシンを管理するハイパーコールを許可する             |
+//! | `host_disk`                 | DISK<n> の代わりに n 番目のホストのディスクを使う            |
+//! | `ram_disk`                  | DISK<n> をメモリ上へ読み込んで使う(書き込みは保存しない)     |
+//! | `shmem <名前> <IPA> <サイズ>` | 同じ名前を指定した仮想マシンとの共有メモリを IPA に割り当てる |
+//!
+
+use crate::block_device::BlockDevice;
+use crate::fat32::Fat32;
+use crate::paging::PAGE_SHIFT;
+use crate::vm::VmError;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+
+/// 設定ファイルの最大サイズ
+const MAX_CONFIG_SIZE: usize = 4096;
+
+#[derive(Default)]
+pub struct VmConfig {
+    /// 特権的なハイパーコールで他の仮想マシンを管理できるか
+    pub is_management_vm: bool,
+    /// FAT32 上のファイルの代わりに、仮想マシンの番号と同じ番号のホストのディスクを使うか
+    pub use_host_disk: bool,
+    /// FAT32 上のファイルをメモリ上へ読み込んでディスクとして使うか
+    pub use_ram_disk: bool,
+    /// 他の仮想マシンと共有するメモリ領域
+    pub shared_memory: Option<SharedMemoryConfig>,
+}
+
+pub struct SharedMemoryConfig {
+    /// 領域の名前(同じ名前を指定した仮想マシン同士で共有する)
+    pub name: String,
+    /// 仮想マシンから見たアドレス(IPA)
+    pub address: usize,
+    pub size: usize,
+}
+
+impl VmConfig {
+    /// vm_id の設定ファイルを読み込む
+    pub fn load(fat32: &Fat32, blk: &mut impl BlockDevice, vm_id: usize) -> Result<Self, VmError> {
+        let file_name = format!("VM{vm_id}.CFG");
+        let Some(file) = fat32.search_file(&file_name) else {
+            return Ok(Self::default());
+        };
+        let size = file.get_file_size();
+        if size > MAX_CONFIG_SIZE {
+            return Err(VmError::InvalidConfig(file_name));
+        }
+        let mut buffer = vec![0u8; size];
+        if fat32.read(&file, blk, buffer.as_mut_ptr() as usize, 0, size) != Ok(size) {
+            return Err(VmError::IoError);
+        }
+        let text = core::str::from_utf8(&buffer).or(Err(VmError::InvalidConfig(file_name)))?;
+        Self::parse(text)
+    }
+
+    fn parse(text: &str) -> Result<Self, VmError> {
+        let mut config = Self::default();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut items = line.split_whitespace();
+            let Some(key) = items.next() else {
+                continue;
+            };
+            match (key, items.next()) {
+                ("management", None) => config.is_management_vm = true,
+                ("host_disk", None) => config.use_host_disk = true,
+                ("ram_disk", None) => config.use_ram_disk = true,
+                ("shmem", Some(name)) if config.shared_memory.is_none() => {
+                    config.shared_memory = Some(
+                        SharedMemoryConfig::parse(name, &mut items)
+                            .ok_or_else(|| VmError::InvalidConfig(String::from(line.trim())))?,
+                    );
+                }
+                _ => return Err(VmError::InvalidConfig(String::from(line.trim()))),
+            }
+        }
+        if config.use_host_disk && config.use_ram_disk {
+            return Err(VmError::InvalidConfig(String::from(
+                "host_disk and ram_disk cannot be used together",
+            )));
+        }
+        Ok(config)
+    }
+}
+
+impl SharedMemoryConfig {
+    /// `<IPA> <サイズ>` を読み込む(どちらもページ単位であること)
+    fn parse<'a>(name: &str, items: &mut impl Iterator<Item = &'a str>) -> Option<Self> {
+        let address = crate::str_to_usize(items.next()?)?;
+        let size = crate::str_to_usize(items.next()?)?;
+        let page_mask = (1usize << PAGE_SHIFT) - 1;
+        if items.next().is_some() || size == 0 || ((address | size) & page_mask) != 0 {
+            return None;
+        }
+        Some(Self {
+            name: String::from(name),
+            address,
+            size,
+        })
+    }
+}
@@ -0,0 +1,136 @@
+//!
+//! 仮想マシン間の L2 スイッチ
+//!
+//! 各ポートから受け取った Ethernet フレームを送信元の MAC アドレスで学習し、
+//! 宛先が学習済みであればそのポートへ、それ以外(ブロードキャストなど)は送信元以外の全てのポートへ転送する。
+//! ホストの Virtio-Net を接続した場合は、そのポートを通して物理ネットワークと通信する。
+//!
+
+use crate::drivers::virtio_net::VirtioNet;
+use crate::lock::Mutex;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// 学習する MAC アドレスの最大数(超えた場合は古いものから消す)
+const MAX_MAC_TABLE_ENTRIES: usize = 256;
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// スイッチに接続する機器
+pub trait SwitchPort: Send + Sync {
+    /// スイッチから転送されたフレームを受け取る
+    ///
+    /// 送信元とは異なるpCPUから呼ばれることがある
+    fn receive(&self, frame: &[u8]);
+}
+
+struct Switch {
+    ports: Vec<(usize, Arc<dyn SwitchPort>)>,
+    mac_table: Vec<([u8; 6], usize)>,
+    next_port_id: usize,
+}
+
+static SWITCH: Mutex<Switch> = Mutex::new(Switch {
+    ports: Vec::new(),
+    mac_table: Vec::new(),
+    next_port_id: 0,
+});
+
+/// 物理ネットワークへ接続するホストの Virtio-Net
+static UPLINK: Mutex<Option<(VirtioNet, usize)>> = Mutex::new(None);
+
+/// ホストの Virtio-Net のポート
+struct Uplink;
+
+impl SwitchPort for Uplink {
+    fn receive(&self, frame: &[u8]) {
+        if let Some((net, _)) = UPLINK.lock().as_mut() {
+            /* 送信できない場合は破棄する */
+            let _ = net.transmit(frame);
+        }
+    }
+}
+
+/// ホストの Virtio-Net をスイッチへ接続する
+pub fn connect_uplink(net: VirtioNet) {
+    let port_id = add_port(Arc::new(Uplink));
+    *UPLINK.lock() = Some((net, port_id));
+}
+
+/// ホストの Virtio-Net の割り込みを処理し、受信したフレームを転送する
+pub fn uplink_interrupt_handler() {
+    let Some((frames, port_id)) = UPLINK
+        .lock()
+        .as_mut()
+        .map(|(net, port_id)| (net.receive(), *port_id))
+    else {
+        return;
+    };
+    for frame in frames {
+        forward(port_id, &frame);
+    }
+}
+
+/// ポートを接続し、ポート番号を返す
+pub fn add_port(port: Arc<dyn SwitchPort>) -> usize {
+    let mut switch = SWITCH.lock();
+    let port_id = switch.next_port_id;
+    switch.next_port_id += 1;
+    switch.ports.push((port_id, port));
+    port_id
+}
+
+/// ポートを切り離し、そのポートで学習した MAC アドレスを消す
+pub fn remove_port(port_id: usize) {
+    let mut switch = SWITCH.lock();
+    switch.ports.retain(|(id, _)| *id != port_id);
+    switch.mac_table.retain(|(_, id)| *id != port_id);
+}
+
+/// source_port から送信されたフレームを転送する
+pub fn forward(source_port: usize, frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER_SIZE {
+        return;
+    }
+    let destination: [u8; 6] = frame[0..6].try_into().unwrap();
+    let source: [u8; 6] = frame[6..12].try_into().unwrap();
+
+    /* 転送先の受信処理は他のロックを取るため、スイッチのロックを外してから行う */
+    let targets: Vec<Arc<dyn SwitchPort>> = {
+        let mut switch = SWITCH.lock();
+        if (source[0] & 1) == 0 {
+            switch.learn(source, source_port);
+        }
+        let destination_port = if (destination[0] & 1) == 0 {
+            switch
+                .mac_table
+                .iter()
+                .find(|(mac, _)| *mac == destination)
+                .map(|(_, id)| *id)
+        } else {
+            None
+        };
+        switch
+            .ports
+            .iter()
+            .filter(|(id, _)| *id != source_port && destination_port.is_none_or(|port| port == *id))
+            .map(|(_, port)| port.clone())
+            .collect()
+    };
+    for port in targets {
+        port.receive(frame);
+    }
+}
+
+impl Switch {
+    fn learn(&mut self, mac: [u8; 6], port_id: usize) {
+        if let Some(entry) = self.mac_table.iter_mut().find(|(m, _)| *m == mac) {
+            entry.1 = port_id;
+            return;
+        }
+        if self.mac_table.len() == MAX_MAC_TABLE_ENTRIES {
+            self.mac_table.remove(0);
+        }
+        self.mac_table.push((mac, port_id));
+    }
+}
@@ -0,0 +1,226 @@
+//!
+//! FAT32 のテスト
+//!
+//! メモリ上に作成した FAT32 のイメージを RamDisk として読み書きする
+//!
+
+use crate::block_device::{BlockDevice, RamDisk};
+use crate::fat32::Fat32;
+
+const BYTES_PER_SECTOR: usize = 512;
+const TOTAL_SECTORS: usize = 4096;
+const RESERVED_SECTORS: usize = 32;
+const NUMBER_OF_FATS: usize = 2;
+const FAT_SECTORS: usize = 32;
+const FS_INFO_SECTOR: usize = 1;
+const ROOT_CLUSTER: u32 = 2;
+
+fn write_u16(image: &mut [u8], offset: usize, value: u16) {
+    image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn read_u32(image: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+}
+
+/// クラスタあたり1セクタの空の FAT32 のイメージを作成する
+fn create_image() -> Vec<u8> {
+    let mut image = vec![0u8; TOTAL_SECTORS * BYTES_PER_SECTOR];
+
+    /* BPB */
+    image[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    image[3..11].copy_from_slice(b"MINIVSOR");
+    write_u16(&mut image, 11, BYTES_PER_SECTOR as u16);
+    image[13] = 1;
+    write_u16(&mut image, 14, RESERVED_SECTORS as u16);
+    image[16] = NUMBER_OF_FATS as u8;
+    image[21] = 0xF8;
+    write_u32(&mut image, 32, TOTAL_SECTORS as u32);
+    write_u32(&mut image, 36, FAT_SECTORS as u32);
+    write_u32(&mut image, 44, ROOT_CLUSTER);
+    write_u16(&mut image, 48, FS_INFO_SECTOR as u16);
+    image[82..90].copy_from_slice(b"FAT32   ");
+    image[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    /* FSInfo */
+    let fs_info = FS_INFO_SECTOR * BYTES_PER_SECTOR;
+    write_u32(&mut image, fs_info, 0x41615252);
+    write_u32(&mut image, fs_info + 484, 0x61417272);
+    write_u32(&mut image, fs_info + 488, 1000);
+    write_u32(&mut image, fs_info + 492, 3);
+
+    /* FAT(ルートディレクトリは1クラスタ) */
+    for i in 0..NUMBER_OF_FATS {
+        let fat = (RESERVED_SECTORS + i * FAT_SECTORS) * BYTES_PER_SECTOR;
+        write_u32(&mut image, fat, 0x0FFFFFF8);
+        write_u32(&mut image, fat + 4, 0x0FFFFFFF);
+        write_u32(&mut image, fat + (ROOT_CLUSTER as usize) * 4, 0x0FFFFFFF);
+    }
+    image
+}
+
+fn get_fat_entry(blk: &mut RamDisk, fat_index: usize, cluster: u32) -> u32 {
+    let mut sector = [0u8; BYTES_PER_SECTOR];
+    let entries_per_sector = (BYTES_PER_SECTOR / 4) as u32;
+    let fat = RESERVED_SECTORS + fat_index * FAT_SECTORS;
+    let address = (fat + (cluster / entries_per_sector) as usize) * BYTES_PER_SECTOR;
+    blk.read(
+        sector.as_mut_ptr() as usize,
+        address as u64,
+        BYTES_PER_SECTOR as u64,
+    )
+    .unwrap();
+    read_u32(&sector, ((cluster % entries_per_sector) * 4) as usize)
+}
+
+#[test]
+fn ram_disk_rejects_out_of_range_access() {
+    let mut blk = RamDisk::new(vec![0u8; 1024]);
+    let mut buffer = [0u8; 512];
+    let address = buffer.as_mut_ptr() as usize;
+    assert_eq!(blk.get_size(), 1024);
+    assert!(blk.read(address, 512, 512).is_ok());
+    assert!(blk.read(address, 1024, 512).is_err());
+    assert!(blk.write(address, u64::MAX, 512).is_err());
+}
+
+#[test]
+fn open_empty_image() {
+    let mut blk = RamDisk::new(create_image());
+    let fat32 = Fat32::new(&mut blk, 0, BYTES_PER_SECTOR).unwrap();
+    assert_eq!(fat32.get_bytes_per_sector(), BYTES_PER_SECTOR);
+    assert!(fat32.get_file_list().is_empty());
+    assert!(fat32.search_file("DISK0").is_none());
+}
+
+#[test]
+fn reject_bad_signature() {
+    let mut image = create_image();
+    image[82..90].copy_from_slice(b"FAT16   ");
+    let mut blk = RamDisk::new(image);
+    assert!(Fat32::new(&mut blk, 0, BYTES_PER_SECTOR).is_err());
+}
+
+#[test]
+fn create_write_and_read_file() {
+    let mut blk = RamDisk::new(create_image());
+    let mut fat32 = Fat32::new(&mut blk, 0, BYTES_PER_SECTOR).unwrap();
+
+    /* 3クラスタにまたがるファイル(書き込みはセクタ単位) */
+    let size = BYTES_PER_SECTOR * 3;
+    let data: Vec<u8> = (0..size).map(|i| (i * 7) as u8).collect();
+    let file = fat32.create_file(&mut blk, "test.bin", size).unwrap();
+    assert!(
+        fat32
+            .write(&file, &mut blk, data.as_ptr() as usize, 0, 100)
+            .is_err()
+    );
+    assert_eq!(
+        fat32.write(&file, &mut blk, data.as_ptr() as usize, 0, size),
+        Ok(size)
+    );
+
+    /* 作り直したインスタンスからも読めること */
+    let fat32 = Fat32::new(&mut blk, 0, BYTES_PER_SECTOR).unwrap();
+    let file = fat32.search_file("TEST.BIN").unwrap();
+    assert_eq!(file.get_file_size(), size);
+    assert!(fat32.search_file("test.bin").is_some());
+
+    let mut buffer = vec![0u8; size];
+    assert_eq!(
+        fat32.read(&file, &mut blk, buffer.as_mut_ptr() as usize, 0, size),
+        Ok(size)
+    );
+    assert_eq!(buffer, data);
+
+    /* 途中からの読み込みとファイルの末尾を超える読み込み */
+    let mut buffer = vec![0u8; BYTES_PER_SECTOR];
+    assert_eq!(
+        fat32.read(&file, &mut blk, buffer.as_mut_ptr() as usize, size - 50, 50),
+        Ok(50)
+    );
+    assert_eq!(buffer[..50], data[size - 50..]);
+    assert_eq!(
+        fat32.read(&file, &mut blk, buffer.as_mut_ptr() as usize, size, 1),
+        Ok(0)
+    );
+}
+
+#[test]
+fn extents_of_contiguous_file() {
+    let mut blk = RamDisk::new(create_image());
+    let mut fat32 = Fat32::new(&mut blk, 0, BYTES_PER_SECTOR).unwrap();
+    let size = BYTES_PER_SECTOR * 4;
+    let file = fat32.create_file(&mut blk, "DISK0", size).unwrap();
+
+    /* 空のイメージではクラスタ 3 から連続して確保される */
+    let data_start = (RESERVED_SECTORS + NUMBER_OF_FATS * FAT_SECTORS) * BYTES_PER_SECTOR;
+    let first = (data_start + (file.get_entry_cluster() as usize - 2) * BYTES_PER_SECTOR) as u64;
+    assert_eq!(file.get_entry_cluster(), ROOT_CLUSTER + 1);
+    assert_eq!(fat32.get_extents(&file, 0, size), Ok(vec![(first, size)]));
+    assert_eq!(
+        fat32.get_extents(&file, 10, BYTES_PER_SECTOR),
+        Ok(vec![(first + 10, BYTES_PER_SECTOR)])
+    );
+    assert!(fat32.get_extents(&file, size, 1).is_err());
+
+    /* 全ての FAT にチェーンが書き込まれていること */
+    for i in 0..NUMBER_OF_FATS {
+        assert_eq!(get_fat_entry(&mut blk, i, 3), 4);
+        assert_eq!(get_fat_entry(&mut blk, i, 6), 0x0FFFFFFF);
+        assert_eq!(get_fat_entry(&mut blk, i, 7), 0);
+    }
+}
+
+#[test]
+fn recreate_file_releases_clusters() {
+    let mut blk = RamDisk::new(create_image());
+    let mut fat32 = Fat32::new(&mut blk, 0, BYTES_PER_SECTOR).unwrap();
+    fat32
+        .create_file(&mut blk, "DISK0", BYTES_PER_SECTOR * 4)
+        .unwrap();
+    let file = fat32
+        .create_file(&mut blk, "DISK0", BYTES_PER_SECTOR)
+        .unwrap();
+    assert_eq!(fat32.get_file_list().len(), 1);
+    assert_eq!(file.get_entry_cluster(), ROOT_CLUSTER + 1);
+    for cluster in 4..=6 {
+        assert_eq!(get_fat_entry(&mut blk, 0, cluster), 0);
+    }
+
+    /* FSInfo は不明に設定される */
+    let mut sector = [0u8; BYTES_PER_SECTOR];
+    blk.read(
+        sector.as_mut_ptr() as usize,
+        (FS_INFO_SECTOR * BYTES_PER_SECTOR) as u64,
+        BYTES_PER_SECTOR as u64,
+    )
+    .unwrap();
+    assert_eq!(read_u32(&sector, 488), 0xFFFFFFFF);
+    assert_eq!(read_u32(&sector, 492), 0xFFFFFFFF);
+}
+
+#[test]
+fn reject_invalid_file_name() {
+    let mut blk = RamDisk::new(create_image());
+    let mut fat32 = Fat32::new(&mut blk, 0, BYTES_PER_SECTOR).unwrap();
+    assert!(fat32.create_file(&mut blk, "TOOLONGNAME", 512).is_err());
+    assert!(fat32.create_file(&mut blk, "A.LONG", 512).is_err());
+    assert!(fat32.create_file(&mut blk, "DISK0", 0).is_err());
+    assert!(fat32.get_file_list().is_empty());
+}
+
+#[test]
+fn no_space_left() {
+    let mut blk = RamDisk::new(create_image());
+    let mut fat32 = Fat32::new(&mut blk, 0, BYTES_PER_SECTOR).unwrap();
+    assert!(
+        fat32
+            .create_file(&mut blk, "DISK0", TOTAL_SECTORS * BYTES_PER_SECTOR)
+            .is_err()
+    );
+}
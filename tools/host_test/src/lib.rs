@@ -0,0 +1,45 @@
+//!
+//! ホスト上で実行するテスト
+//!
+//! MiniVisor のハードウェアに依存しないモジュールをそのまま取り込み、
+//! ホストのメモリ確保を使ってテストする。`tools/host_test` で `cargo test` を実行する。
+//!
+
+/* 本体のエラー処理と println! の書き方に合わせる */
+#![allow(clippy::result_unit_err, clippy::uninlined_format_args)]
+
+extern crate alloc;
+
+#[path = "../../../src/block_device.rs"]
+pub mod block_device;
+#[path = "../../../src/fat32.rs"]
+pub mod fat32;
+
+#[cfg(test)]
+mod fat32_test;
+
+pub mod paging {
+    pub const PAGE_SHIFT: usize = 12;
+    pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+}
+
+use std::alloc::Layout;
+
+fn get_layout(number_of_pages: usize) -> Layout {
+    Layout::from_size_align(number_of_pages << paging::PAGE_SHIFT, paging::PAGE_SIZE).unwrap()
+}
+
+/// MiniVisor の allocate_pages の代わりにホストのメモリを確保する(align はページ以下であること)
+pub fn allocate_pages(number_of_pages: usize, align: usize) -> Result<usize, ()> {
+    assert!(align <= paging::PAGE_SHIFT);
+    let address = unsafe { std::alloc::alloc_zeroed(get_layout(number_of_pages)) };
+    if address.is_null() {
+        Err(())
+    } else {
+        Ok(address as usize)
+    }
+}
+
+pub fn free_pages(address: usize, number_of_pages: usize) {
+    unsafe { std::alloc::dealloc(address as *mut u8, get_layout(number_of_pages)) };
+}
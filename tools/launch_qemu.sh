@@ -5,4 +5,5 @@
 $QEMU   -M virt,gic-version=3,secure=off,virtualization=on \
         -smp 4 -bios $BIN_DIR/u-boot.bin -cpu cortex-a53 -m 2G \
         -nographic -device virtio-blk-device,drive=disk \
-        -drive file=$DISK_IMG,format=raw,if=none,media=disk,id=disk
+        -drive file=$DISK_IMG,format=raw,if=none,media=disk,id=disk \
+        -device virtio-net-device,netdev=net0 -netdev user,id=net0